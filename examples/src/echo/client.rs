@@ -0,0 +1,22 @@
+use tokio::net::TcpStream;
+
+use rusty_rpc_lib::start_client;
+use rusty_rpc_macro::interface_file;
+
+interface_file!("src/echo/echo.protocol");
+
+#[tokio::main]
+async fn main() {
+    let stream = TcpStream::connect("127.0.0.1:8080")
+        .await
+        .expect("Failed to connect to server");
+    let mut service = start_client::<dyn EchoService, _>(stream).await;
+
+    assert_eq!("hello", service.echo("hello").await.unwrap());
+    assert_eq!("world", service.echo("world").await.unwrap());
+    assert_eq!(3, service.count().await.unwrap());
+
+    service.close().await.unwrap();
+
+    println!("Client done successfully!");
+}
@@ -0,0 +1,72 @@
+//! A server for the echo protocol, written without the macros. The client is
+//! generated from the protocol file as usual.
+
+use std::mem::drop;
+
+use async_trait::async_trait;
+use tokio::net::TcpListener;
+
+use rusty_rpc_lib::{
+    start_server, MethodArgs, MethodId, ReturnValue, RpcResult, RustyRpcServiceServer,
+    ServerCollection, ServerGuard, ServerMessage,
+};
+
+/// Indexed by method ID, which is the order of the names.
+const METHOD_NAMES: &[&str] = &["count", "echo"];
+
+#[derive(Default)]
+struct EchoServer {
+    calls: i32,
+}
+// This service never returns other services, so it just drops the guard.
+#[async_trait]
+unsafe impl<'a> RustyRpcServiceServer<'a> for EchoServer {
+    async unsafe fn parse_and_call_method_locally(
+        &mut self,
+        self_guard: ServerGuard,
+        method_id: MethodId,
+        method_args: MethodArgs,
+        service_collection: &mut ServerCollection,
+    ) -> RpcResult<ServerMessage> {
+        let wire_format = service_collection.wire_format();
+        self.calls += 1;
+        let return_value = match self.method_name(method_id) {
+            Some("count") => {
+                let () = wire_format.decode(&method_args.0)?;
+                wire_format.encode(&self.calls)
+            }
+            Some("echo") => {
+                let message: &str = wire_format.decode(&method_args.0)?;
+                wire_format.encode(&message)
+            }
+            _ => {
+                return Ok(ServerMessage::Error(format!(
+                    "Invalid method ID: {}",
+                    method_id.0
+                )))
+            }
+        };
+        drop(self_guard);
+        Ok(ServerMessage::MethodReturned(ReturnValue::Data(
+            return_value,
+        )))
+    }
+
+    fn service_name(&self) -> &'static str {
+        "EchoService"
+    }
+
+    fn method_name(&self, method_id: MethodId) -> Option<&'static str> {
+        METHOD_NAMES.get(method_id.0 as usize).copied()
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let listener = TcpListener::bind("127.0.0.1:8080")
+        .await
+        .expect("Failed to bind to port to start server.");
+    start_server::<EchoServer>(listener)
+        .await
+        .expect("Server top-level crashed.")
+}
@@ -3,7 +3,7 @@ use tokio::net::TcpStream;
 use rusty_rpc_lib::start_client;
 use rusty_rpc_macro::interface_file;
 
-interface_file!("examples/src/hello_world/hello_world.protocol");
+interface_file!("src/hello_world/hello_world.protocol");
 
 #[tokio::main]
 async fn main() {
@@ -21,7 +21,7 @@ async fn main() {
     let baz_output = service
         .baz(
             900,
-            Foo {
+            &Foo {
                 x: 80,
                 y: Bar { z: 7 },
             },
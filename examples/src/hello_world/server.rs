@@ -1,23 +1,21 @@
-use std::io;
-
 use tokio::net::TcpListener;
 
-use rusty_rpc_lib::start_server;
+use rusty_rpc_lib::{start_server, RpcResult};
 use rusty_rpc_macro::{interface_file, service_server_impl};
 
-interface_file!("examples/src/hello_world/hello_world.protocol");
+interface_file!("src/hello_world/hello_world.protocol");
 
 #[derive(Default)]
 struct MyServiceServer;
 #[service_server_impl]
 impl MyService for MyServiceServer {
-    async fn foo(&mut self) -> io::Result<i32> {
+    async fn foo(&mut self) -> RpcResult<i32> {
         Ok(123)
     }
-    async fn bar(&mut self, arg: i32) -> io::Result<i32> {
+    async fn bar(&mut self, arg: i32) -> RpcResult<i32> {
         Ok(arg)
     }
-    async fn baz(&mut self, arg1: i32, arg2: Foo) -> io::Result<Foo> {
+    async fn baz(&mut self, arg1: i32, arg2: &Foo) -> RpcResult<Foo> {
         let val = arg1 + arg2.x + arg2.y.z;
         Ok(Foo {
             x: val,
@@ -0,0 +1,26 @@
+//! Items that other crates use, like a crate that publishes a protocol for its
+//! users.
+//!
+//! `Settings` is marked with `#[non_exhaustive]`, so adding a field to it won't
+//! break other crates. They can't create it with a struct expression:
+//!
+//! ```compile_fail
+//! let settings = examples::Settings {
+//!     volume: 50,
+//!     brightness: 80,
+//! };
+//! ```
+//!
+//! Instead, they use the builder:
+//!
+//! ```
+//! let settings = examples::Settings::builder()
+//!     .with_brightness(80)
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(50, settings.volume);
+//! ```
+
+use rusty_rpc_macro::interface_file;
+
+interface_file!("src/settings.protocol");
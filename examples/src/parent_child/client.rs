@@ -3,7 +3,7 @@ use tokio::net::TcpStream;
 use rusty_rpc_lib::start_client;
 use rusty_rpc_macro::interface_file;
 
-interface_file!("examples/src/parent_child/parent_child.protocol");
+interface_file!("src/parent_child/parent_child.protocol");
 
 #[tokio::main]
 async fn main() {
@@ -17,7 +17,6 @@ async fn main() {
     let mut child_service_1 = parent_service.child().await.unwrap();
     child_service_1.set(456).await.unwrap();
     child_service_1.close().await.unwrap();
-    drop(child_service_1);
     // Compilation will fail if the above line is omitted.
     // Compilation will also fail if child_service_1 is used after this line.
 
@@ -1,11 +1,9 @@
-use std::io;
-
 use tokio::net::TcpListener;
 
-use rusty_rpc_lib::{start_server, ServiceRefMut};
+use rusty_rpc_lib::{start_server, RpcResult, ServiceRefMut};
 use rusty_rpc_macro::{interface_file, service_server_impl};
 
-interface_file!("examples/src/parent_child/parent_child.protocol");
+interface_file!("src/parent_child/parent_child.protocol");
 
 struct ParentServer(i32);
 impl Default for ParentServer {
@@ -18,17 +16,17 @@ struct ChildServer<'a>(&'a mut i32);
 
 #[service_server_impl]
 impl ParentService for ParentServer {
-    async fn child(&mut self) -> io::Result<ServiceRefMut<dyn ChildService>> {
+    async fn child<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn ChildService + 'a>> {
         Ok(ServiceRefMut::new(ChildServer(&mut self.0)))
     }
-    async fn get(&mut self) -> io::Result<i32> {
+    async fn get(&mut self) -> RpcResult<i32> {
         Ok(self.0)
     }
 }
 
 #[service_server_impl]
 impl<'a> ChildService for ChildServer<'a> {
-    async fn set(&mut self, new_value: i32) -> io::Result<i32> {
+    async fn set(&mut self, new_value: i32) -> RpcResult<i32> {
         *self.0 = new_value;
         Ok(new_value)
     }
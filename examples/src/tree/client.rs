@@ -3,7 +3,7 @@ use tokio::net::TcpStream;
 use rusty_rpc_lib::start_client;
 use rusty_rpc_macro::interface_file;
 
-interface_file!("examples/src/tree/tree.protocol");
+interface_file!("src/tree/tree.protocol", lock);
 
 #[tokio::main]
 async fn main() {
@@ -1,11 +1,9 @@
-use std::io;
-
 use tokio::net::TcpListener;
 
-use rusty_rpc_lib::{start_server, ServiceRefMut};
+use rusty_rpc_lib::{start_server, RpcResult, ServiceRefMut};
 use rusty_rpc_macro::{interface_file, service_server_impl};
 
-interface_file!("examples/src/tree/tree.protocol");
+interface_file!("src/tree/tree.protocol", lock);
 
 struct Node {
     value: i32,
@@ -15,7 +13,7 @@ struct Node {
 struct TreeServer(Node);
 #[service_server_impl]
 impl TreeService for TreeServer {
-    async fn root(&mut self) -> io::Result<ServiceRefMut<dyn NodeService>> {
+    async fn root<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn NodeService + 'a>> {
         Ok(ServiceRefMut::new(NodeServer(&mut self.0)))
     }
 }
@@ -40,13 +38,16 @@ impl Default for TreeServer {
 struct NodeServer<'a>(&'a mut Node);
 #[service_server_impl]
 impl<'a> NodeService for NodeServer<'a> {
-    async fn nth_child(&mut self, n: i32) -> io::Result<ServiceRefMut<dyn NodeService>> {
+    async fn nth_child<'b>(
+        &'b mut self,
+        n: i32,
+    ) -> RpcResult<ServiceRefMut<'b, dyn NodeService + 'b>> {
         // Crash if invalid n.
         let child_node = self.0.children.get_mut(n as usize).expect("Invalid n");
         Ok(ServiceRefMut::new(NodeServer(child_node)))
     }
 
-    async fn get_value(&mut self) -> io::Result<i32> {
+    async fn get_value(&mut self) -> RpcResult<i32> {
         Ok(self.0.value)
     }
 }
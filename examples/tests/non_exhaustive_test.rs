@@ -0,0 +1,25 @@
+use examples::Settings;
+
+#[test]
+fn non_exhaustive_struct_test() {
+    let settings = Settings::builder()
+        .with_volume(20)
+        .with_brightness(80)
+        .build()
+        .unwrap();
+    assert_eq!(20, settings.volume);
+    assert_eq!(80, settings.brightness);
+    assert_eq!(
+        50,
+        Settings::builder()
+            .with_brightness(0)
+            .build()
+            .unwrap()
+            .volume
+    );
+    assert!(Settings::builder().build().is_err());
+
+    // Other crates can still read the fields, as long as they allow for more.
+    let Settings { volume, .. } = Settings::default();
+    assert_eq!(50, volume);
+}
@@ -0,0 +1,94 @@
+//! Authentication and authorization.
+
+use std::future::Future;
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+
+use crate::messages::{MethodId, ServiceId};
+
+/// Decides whether to accept a connection, based only on the address of the
+/// client, e.g. to allow only certain IP addresses. Set it with
+/// [crate::ServerConfig::accept_filter]. Rejected connections are closed
+/// before anything is read from them.
+///
+/// This is implemented for async closures, such as
+/// `|peer_addr: SocketAddr| async move { peer_addr.ip().is_loopback() }`.
+#[async_trait]
+pub trait AcceptFilter: Send + Sync {
+    /// Returns whether to accept the connection.
+    async fn accept(&self, peer_addr: SocketAddr) -> bool;
+}
+#[async_trait]
+impl<F, Fut> AcceptFilter for F
+where
+    F: Fn(SocketAddr) -> Fut + Send + Sync,
+    Fut: Future<Output = bool> + Send,
+{
+    async fn accept(&self, peer_addr: SocketAddr) -> bool {
+        self(peer_addr).await
+    }
+}
+
+/// Decides whether a client may use the server, based on the credential that
+/// the client sent with [crate::start_client_with_credential]. Set it with
+/// [crate::ServerConfig::authenticator].
+///
+/// This is implemented for async closures, such as
+/// `|credential: Vec<u8>| async move { credential == b"secret" }`.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Returns whether the credential is valid.
+    async fn authenticate(&self, credential: Vec<u8>) -> bool;
+}
+#[async_trait]
+impl<F, Fut> Authenticator for F
+where
+    F: Fn(Vec<u8>) -> Fut + Send + Sync,
+    Fut: Future<Output = bool> + Send,
+{
+    async fn authenticate(&self, credential: Vec<u8>) -> bool {
+        self(credential).await
+    }
+}
+
+/// Information about a connection, available to [Authorizer].
+#[derive(Debug, Clone)]
+pub struct ConnectionContext {
+    /// The address of the client.
+    pub peer_addr: SocketAddr,
+    /// The credential that the client authenticated with, if any.
+    pub credential: Option<Vec<u8>>,
+}
+
+/// A method call that is about to happen, given to [Authorizer].
+#[derive(Debug, Clone)]
+pub struct MethodCall<'a> {
+    pub context: &'a ConnectionContext,
+    pub service_id: ServiceId,
+    pub method_id: MethodId,
+    /// The name of the service, as written in the interface file.
+    pub service_name: &'static str,
+    /// The name of the method, or `None` if the method ID is invalid.
+    pub method_name: Option<&'static str>,
+}
+
+/// Decides whether each method call is allowed. Set it with
+/// [crate::ServerConfig::authorizer].
+///
+/// This is implemented for closures such as
+/// `|call: &MethodCall<'_>| -> Result<(), String> { Ok(()) }`.
+pub trait Authorizer: Send + Sync {
+    /// Returns an error message if the call is not allowed. The message is
+    /// sent to the client, and the method is not called. The connection stays
+    /// open.
+    fn authorize(&self, call: &MethodCall<'_>) -> Result<(), String>;
+}
+impl<F> Authorizer for F
+where
+    F: Fn(&MethodCall<'_>) -> Result<(), String> + Send + Sync,
+{
+    fn authorize(&self, call: &MethodCall<'_>) -> Result<(), String> {
+        self(call)
+    }
+}
@@ -0,0 +1,30 @@
+//! Metadata, such as trace IDs, that is sent along with each method call.
+//!
+//! The metadata of the calls that a task makes is set with
+//! [with_call_metadata]. On the server, the metadata of a call is set while its
+//! method runs, so calls that the method makes to other servers carry the same
+//! metadata, which lets logs from several services be correlated.
+
+use std::collections::HashMap;
+use std::future::Future;
+
+tokio::task_local! {
+    static CALL_METADATA: HashMap<String, String>;
+}
+
+/// Runs `future` with `metadata` as the metadata of the method calls that it
+/// makes. Inside a service method, this replaces the metadata that the call
+/// was received with.
+pub async fn with_call_metadata<F: Future>(
+    metadata: HashMap<String, String>,
+    future: F,
+) -> F::Output {
+    CALL_METADATA.scope(metadata, future).await
+}
+
+/// Returns the metadata of the current task. Inside a service method, this is
+/// the metadata that the client sent with the call. Outside of
+/// [with_call_metadata] and service methods, this is empty.
+pub fn call_metadata() -> HashMap<String, String> {
+    CALL_METADATA.try_with(Clone::clone).unwrap_or_default()
+}
@@ -0,0 +1,696 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::channel::{mpsc, oneshot};
+use futures::future::{pending, poll_fn, ready, select, BoxFuture, Either};
+use futures::{pin_mut, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout, timeout_at, Instant};
+
+use crate::config::ClientConfig;
+use crate::error::{RpcResult, RustyRpcError};
+use crate::interceptor::Next;
+use crate::messages::{ClientMessage, ServerMessage, ServiceId, ServiceRefMut};
+use crate::streaming::{stream_channel, StreamReceiver, StreamSender, INCREMENTAL_PIECE_LENGTH};
+use crate::traits::{ClientStreamSink, RustyRpcServiceClient};
+use crate::wire_format::WireFormat;
+
+/// A call that waits in a batch, along with where to send its response.
+type BatchedCall = (ClientMessage, oneshot::Sender<RpcResult<ServerMessage>>);
+
+/// The client side of a connection. All service proxies of the connection
+/// share one of these.
+pub struct ClientConnection {
+    /// This is `None` if the connection was closed because the server didn't
+    /// respond to a heartbeat in time while the connection was idle. See
+    /// [CallStream::receive] for heartbeats during a call.
+    ///
+    /// There is no task that reads responses for the callers. The caller that
+    /// holds this lock reads the response to its own message, so responses are
+    /// never buffered on the client. If the caller is slow, the response waits
+    /// in the transport, whose flow control then stops the server from sending
+    /// more.
+    ///
+    /// This is in an `Arc` so that a streaming call that runs in the
+    /// background can hold the lock.
+    stream_sink: Arc<Mutex<Option<CallStream>>>,
+    config: ClientConfig,
+    /// Services whose proxies were dropped without being closed, and which
+    /// haven't been dropped on the server side yet. These are sent to the
+    /// server in order, before any other message.
+    pending_drops: std::sync::Mutex<VecDeque<ServiceId>>,
+    /// While this is `Some`, calls are collected here instead of being sent
+    /// right away. See [batch].
+    batch: std::sync::Mutex<Option<Vec<BatchedCall>>>,
+    /// The count of open proxies of each service that has proxies, shared by
+    /// all of them. See [ClientConnection::open_proxy].
+    open_services: std::sync::Mutex<HashMap<ServiceId, Weak<AtomicUsize>>>,
+}
+impl ClientConnection {
+    pub(crate) fn new(stream_sink: Box<dyn ClientStreamSink>, config: ClientConfig) -> Self {
+        ClientConnection {
+            stream_sink: Arc::new(Mutex::new(Some(CallStream::new(stream_sink, &config)))),
+            config,
+            pending_drops: std::sync::Mutex::new(VecDeque::new()),
+            batch: std::sync::Mutex::new(None),
+            open_services: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How the arguments and return values of this connection are encoded.
+    pub fn wire_format(&self) -> WireFormat {
+        self.config.wire_format
+    }
+
+    /// Sends a message to the server through the interceptors, and waits for
+    /// the response. Inside of [batch], the message is instead added to the
+    /// current batch.
+    ///
+    /// If the returned future is dropped while the server is handling the
+    /// message, the server is told to cancel it.
+    pub async fn call(self: &Arc<Self>, msg: ClientMessage) -> RpcResult<ServerMessage> {
+        // The std mutex guard must be dropped before the await.
+        let queued = match self.batch.lock().unwrap().as_mut() {
+            Some(calls) => {
+                let (sender, receiver) = oneshot::channel();
+                calls.push((msg, sender));
+                Ok(receiver)
+            }
+            None => Err(msg),
+        };
+        match queued {
+            Ok(receiver) => receiver
+                .await
+                .unwrap_or(Err(RustyRpcError::ConnectionClosed)),
+            Err(msg) => self.send_and_receive(msg).await,
+        }
+    }
+
+    /// Sends a message that the server doesn't respond to, without going
+    /// through the interceptors. This is sent right away even inside of
+    /// [batch].
+    pub async fn notify(self: &Arc<Self>, msg: ClientMessage) -> RpcResult<()> {
+        let mut locked = self.stream_sink.lock().await;
+        let stream_sink = locked.as_mut().ok_or(RustyRpcError::ConnectionClosed)?;
+        self.send_without_response(stream_sink, msg).await
+    }
+
+    /// Sends `msg`, which calls a streaming method, and then sends the items of
+    /// `requests` to the method and the method's items into `responses`, until
+    /// the server responds to the call. Returns that response. Like
+    /// [ClientConnection::notify], this doesn't go through the interceptors,
+    /// and is sent right away even inside of [batch].
+    ///
+    /// Other calls on this connection wait until the call is over. If
+    /// `requests` yields an error, or sending into `responses` fails, e.g.
+    /// because its receiver was dropped, the call is cancelled, and that error
+    /// is returned.
+    pub async fn call_stream(
+        self: &Arc<Self>,
+        msg: ClientMessage,
+        requests: impl Stream<Item = RpcResult<Vec<u8>>>,
+        responses: impl Sink<Vec<u8>, Error = RustyRpcError>,
+    ) -> RpcResult<ServerMessage> {
+        let mut locked = self.stream_sink.lock().await;
+        let stream_sink = locked.as_mut().ok_or(RustyRpcError::ConnectionClosed)?;
+        let abandon_guard = AbandonGuard(Some(self));
+        let result = match self.send_without_response(stream_sink, msg).await {
+            Ok(()) => run_call_stream(stream_sink, requests, responses).await,
+            Err(e) => Err(e),
+        };
+        abandon_guard.disarm();
+        result
+    }
+
+    /// Sends `msg`, which calls an `@incremental` method, and then sends `data`
+    /// to the method in pieces, which it can handle as they arrive. Returns
+    /// the response to the call. Like [ClientConnection::call_stream], this
+    /// doesn't go through the interceptors, and is sent right away even inside
+    /// of [batch].
+    pub async fn call_incremental(
+        self: &Arc<Self>,
+        msg: ClientMessage,
+        data: &[u8],
+    ) -> RpcResult<ServerMessage> {
+        let pieces = data
+            .chunks(INCREMENTAL_PIECE_LENGTH)
+            .map(|piece| Ok(piece.to_vec()));
+        // The method doesn't send any items.
+        let responses = futures::sink::drain().sink_map_err(|e| match e {});
+        self.call_stream(msg, futures::stream::iter(pieces), responses)
+            .await
+    }
+
+    /// Like [ClientConnection::call_stream], but once `msg` is sent, the call
+    /// runs in the background. Items sent into the returned sender are encoded
+    /// with `encode` and sent to the method, and the method's items arrive in
+    /// the returned receiver, decoded with `decode`. If the call fails, the
+    /// error is the last item of the receiver.
+    pub async fn spawn_call_stream<T: Send + 'static, U: Send + 'static>(
+        self: &Arc<Self>,
+        msg: ClientMessage,
+        encode: impl Fn(T) -> Vec<u8> + Send + 'static,
+        decode: impl Fn(&[u8]) -> RpcResult<U> + Send + 'static,
+    ) -> (StreamSender<T>, StreamReceiver<U>) {
+        let (request_sender, requests) = stream_channel();
+        let (response_sender, response_receiver) = mpsc::channel(0);
+        let mut error_sender = response_sender.clone();
+        let responses = response_sender
+            .sink_map_err(|_| RustyRpcError::ConnectionClosed)
+            .with(move |bytes: Vec<u8>| ready(Ok(decode(&bytes))));
+
+        let mut locked = self.stream_sink.clone().lock_owned().await;
+        let abandon_guard = AbandonGuard(Some(self));
+        let sent = match locked.as_mut() {
+            Some(stream_sink) => self.send_without_response(stream_sink, msg).await,
+            None => Err(RustyRpcError::ConnectionClosed),
+        };
+        abandon_guard.disarm();
+        tokio::spawn(async move {
+            let result = match (sent, locked.as_mut()) {
+                (Ok(()), Some(stream_sink)) => {
+                    let requests = requests.into_encoding(encode);
+                    run_call_stream(stream_sink, requests, responses).await
+                }
+                (Err(e), _) => Err(e),
+                (Ok(()), None) => unreachable!("The connection was closed while it was locked."),
+            };
+            // Other calls can go ahead while the error is received.
+            drop(locked);
+            let error = match result {
+                Ok(ServerMessage::MethodReturned(_)) => return,
+                Ok(ServerMessage::Error(msg)) => RustyRpcError::ServerError(msg),
+                Ok(_) => RustyRpcError::MalformedMessage(
+                    "Invalid response to a streaming call".to_string(),
+                ),
+                Err(e) => e,
+            };
+            // The receiver might have been dropped.
+            let _ = error_sender.send(Err(error)).await;
+        });
+        (
+            request_sender,
+            StreamReceiver::from_results(response_receiver),
+        )
+    }
+
+    /// Sends a message that the server doesn't respond to right away, after the
+    /// pending drops and the cancellations of abandoned calls.
+    async fn send_without_response(
+        &self,
+        stream_sink: &mut CallStream,
+        msg: ClientMessage,
+    ) -> RpcResult<()> {
+        self.send_pending_drops(stream_sink).await?;
+        stream_sink.finish_abandoned_calls().await?;
+        stream_sink.send(msg).await
+    }
+
+    async fn send_and_receive(self: &Arc<Self>, msg: ClientMessage) -> RpcResult<ServerMessage> {
+        let mut locked = self.stream_sink.lock().await;
+        let stream_sink = locked.as_mut().ok_or(RustyRpcError::ConnectionClosed)?;
+        let abandon_guard = AbandonGuard(Some(self));
+        let result = match self.send_pending_drops(stream_sink).await {
+            Ok(()) => {
+                Next::new(&self.config.interceptors, stream_sink)
+                    .run(msg)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        abandon_guard.disarm();
+        result
+    }
+
+    /// Sends a message and waits for the response. Responses to calls that were
+    /// abandoned earlier are skipped first.
+    pub(crate) async fn call_locked(
+        stream_sink: &mut CallStream,
+        msg: ClientMessage,
+    ) -> RpcResult<ServerMessage> {
+        stream_sink.finish_abandoned_calls().await?;
+        stream_sink.send(msg).await?;
+        stream_sink.receive().await
+    }
+
+    async fn send_pending_drops(&self, stream_sink: &mut CallStream) -> RpcResult<()> {
+        loop {
+            // The std mutex guard must be dropped before the await.
+            let next_service_id = self.pending_drops.lock().unwrap().pop_front();
+            let Some(service_id) = next_service_id else {
+                return Ok(());
+            };
+            // This is best-effort, so the server failing to drop the service is
+            // ignored.
+            Self::call_locked(stream_sink, ClientMessage::DropService(service_id)).await?;
+        }
+    }
+
+    /// Sends the calls as one [ClientMessage::Batch], and hands each response
+    /// to its caller. If the batch as a whole fails, every call fails with the
+    /// same error.
+    async fn send_batch(self: Arc<Self>, calls: Vec<BatchedCall>) {
+        let (messages, senders): (Vec<_>, Vec<_>) = calls.into_iter().unzip();
+        let count = senders.len();
+        let responses: Vec<RpcResult<ServerMessage>> =
+            match self.send_and_receive(ClientMessage::Batch(messages)).await {
+                Ok(ServerMessage::Batch(responses)) if responses.len() == count => {
+                    responses.into_iter().map(Ok).collect()
+                }
+                Ok(ServerMessage::Error(msg)) => (0..count)
+                    .map(|_| Err(RustyRpcError::ServerError(msg.clone())))
+                    .collect(),
+                Ok(_) => (0..count)
+                    .map(|_| {
+                        Err(RustyRpcError::MalformedMessage(
+                            "Invalid response to a batch".to_string(),
+                        ))
+                    })
+                    .collect(),
+                Err(e) => (0..count).map(|_| Err(copy_error(&e))).collect(),
+            };
+        for (sender, response) in senders.into_iter().zip(responses) {
+            // The caller might not be waiting anymore.
+            let _ = sender.send(response);
+        }
+    }
+
+    /// Counts a new proxy to the service, and returns the count. Every proxy
+    /// that the server hands out for the same service shares the count with
+    /// the existing ones, as if it were a clone of them, so the service is
+    /// dropped on the server only once, when the last of them is closed.
+    pub fn open_proxy(&self, service_id: ServiceId) -> Arc<AtomicUsize> {
+        let mut open_services = self.open_services.lock().unwrap();
+        if let Some(open_clones) = open_services.get(&service_id).and_then(Weak::upgrade) {
+            // Once the count reaches zero, the service was dropped on the
+            // server, so the ID now refers to a different service.
+            let counted = open_clones
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                    (x != 0).then_some(x + 1)
+                })
+                .is_ok();
+            if counted {
+                return open_clones;
+            }
+        }
+        open_services.retain(|_, open_clones| open_clones.strong_count() != 0);
+        let open_clones = Arc::new(AtomicUsize::new(1));
+        open_services.insert(service_id, Arc::downgrade(&open_clones));
+        open_clones
+    }
+
+    /// Starts sending heartbeats in the background, if they are enabled in the
+    /// config.
+    pub(crate) fn start_heartbeats(self: &Arc<Self>) {
+        if let Some(heartbeat_interval) = self.config.heartbeat_interval {
+            tokio::spawn(send_heartbeats(Arc::downgrade(self), heartbeat_interval));
+        }
+    }
+
+    /// Called when a proxy is dropped without being closed. Depending on
+    /// [ClientConfig::auto_close_on_drop], this either panics, or schedules the
+    /// service to be dropped on the server side.
+    pub fn proxy_dropped_without_close(self: &Arc<Self>, service_id: ServiceId) {
+        if !self.config.auto_close_on_drop {
+            panic!("Service proxy dropped without being closed");
+        }
+        self.close_in_background(service_id);
+    }
+
+    /// Schedules the service to be dropped on the server side. The drop is
+    /// sent in the background, and before the next call on this connection.
+    pub fn close_in_background(self: &Arc<Self>, service_id: ServiceId) {
+        self.pending_drops.lock().unwrap().push_back(service_id);
+        // If there's no runtime, the drop will instead be sent before the next
+        // call on this connection.
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let connection = self.clone();
+            handle.spawn(async move {
+                let mut locked = connection.stream_sink.lock().await;
+                if let Some(stream_sink) = locked.as_mut() {
+                    // Errors will show up again in the next call, if there is
+                    // one.
+                    let _ = connection.send_pending_drops(stream_sink).await;
+                }
+            });
+        }
+    }
+}
+
+/// The stream and sink of a connection, which keeps track of the messages that
+/// haven't been answered yet. There are only unanswered messages between calls
+/// if a call was abandoned while it was in flight, e.g. because its future was
+/// dropped.
+pub(crate) struct CallStream {
+    /// This is `None` once the connection was closed because the server didn't
+    /// respond to a heartbeat during a call.
+    inner: Option<Box<dyn ClientStreamSink>>,
+    unanswered: usize,
+    /// The heartbeat interval and timeout, if heartbeats are enabled.
+    heartbeat: Option<(Duration, Duration)>,
+    /// Pings sent by [CallStream::receive], whose pongs are skipped.
+    unanswered_heartbeats: usize,
+    /// When the server was last heard from, or when the client started waiting
+    /// for it, whichever is later.
+    quiet_since: Instant,
+    /// When the server was pinged, if it has been quiet ever since.
+    pinged_at: Option<Instant>,
+}
+impl CallStream {
+    fn new(inner: Box<dyn ClientStreamSink>, config: &ClientConfig) -> Self {
+        CallStream {
+            inner: Some(inner),
+            unanswered: 0,
+            heartbeat: config
+                .heartbeat_interval
+                .map(|interval| (interval, config.heartbeat_timeout)),
+            unanswered_heartbeats: 0,
+            quiet_since: Instant::now(),
+            pinged_at: None,
+        }
+    }
+
+    fn inner(&mut self) -> RpcResult<&mut Box<dyn ClientStreamSink>> {
+        self.inner.as_mut().ok_or(RustyRpcError::ConnectionClosed)
+    }
+
+    /// Waits for the next message from the server.
+    ///
+    /// The background heartbeats can't be sent while a call waits for its
+    /// response, so if heartbeats are enabled, this sends them instead. If the
+    /// server is quiet for a whole heartbeat interval, it is pinged, which the
+    /// server answers even while a method is running, so a method that just
+    /// takes long is fine. If the server doesn't answer that in time either,
+    /// the connection is closed, and this fails with [RustyRpcError::Timeout].
+    pub(crate) async fn receive(&mut self) -> RpcResult<ServerMessage> {
+        loop {
+            let deadline = match (self.heartbeat, self.pinged_at) {
+                (None, _) => None,
+                (Some((interval, _)), None) => Some(self.quiet_since + interval),
+                (Some((_, heartbeat_timeout)), Some(pinged_at)) => {
+                    Some(pinged_at + heartbeat_timeout)
+                }
+            };
+            let next = match deadline {
+                Some(deadline) => timeout_at(deadline, self.next()).await,
+                None => Ok(self.next().await),
+            };
+            match next {
+                Ok(message) => return message.ok_or(RustyRpcError::ConnectionClosed)?,
+                Err(_) if self.pinged_at.is_none() => {
+                    let inner = self.inner()?;
+                    poll_fn(|cx| inner.poll_ready_unpin(cx)).await?;
+                    inner.start_send_unpin(ClientMessage::Ping)?;
+                    // The ping counts as sent even if this future is dropped
+                    // before it is flushed, since the next send flushes it.
+                    self.unanswered_heartbeats += 1;
+                    self.pinged_at = Some(Instant::now());
+                    self.inner()?.flush().await?;
+                }
+                Err(_) => {
+                    // Dropping the stream closes the underlying connection.
+                    self.inner = None;
+                    return Err(RustyRpcError::Timeout);
+                }
+            }
+        }
+    }
+
+    /// Tells the server to cancel the abandoned call, if there is one, and
+    /// skips its response. The server responds to the call either way, since
+    /// it might have finished before the cancellation arrived.
+    async fn finish_abandoned_calls(&mut self) -> RpcResult<()> {
+        if self.unanswered == 0 {
+            return Ok(());
+        }
+        self.send(ClientMessage::Cancel).await?;
+        while self.unanswered > 0 {
+            self.receive().await?;
+        }
+        Ok(())
+    }
+}
+impl Stream for CallStream {
+    type Item = RpcResult<ServerMessage>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let Some(inner) = this.inner.as_mut() else {
+                return Poll::Ready(None);
+            };
+            let poll = inner.poll_next_unpin(cx);
+            if let Poll::Ready(Some(result)) = &poll {
+                this.quiet_since = Instant::now();
+                this.pinged_at = None;
+                match result {
+                    Ok(ServerMessage::Pong) if this.unanswered_heartbeats > 0 => {
+                        this.unanswered_heartbeats -= 1;
+                        continue;
+                    }
+                    // The items of a streaming call come before the response
+                    // to it.
+                    Ok(ServerMessage::StreamItem(_)) => (),
+                    _ => this.unanswered = this.unanswered.saturating_sub(1),
+                }
+            }
+            return poll;
+        }
+    }
+}
+impl Sink<ClientMessage> for CallStream {
+    type Error = RustyRpcError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<RpcResult<()>> {
+        self.inner()?.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: ClientMessage) -> RpcResult<()> {
+        // Cancellations, oneway calls, and the items of streaming calls are the
+        // only messages that the server doesn't respond to.
+        let expects_response = !matches!(
+            item,
+            ClientMessage::Cancel
+                | ClientMessage::Notify(..)
+                | ClientMessage::StreamItem(_)
+                | ClientMessage::StreamEnd
+        );
+        self.inner()?.start_send_unpin(item)?;
+        if expects_response {
+            if self.unanswered == 0 {
+                self.quiet_since = Instant::now();
+            }
+            self.unanswered += 1;
+        }
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<RpcResult<()>> {
+        self.inner()?.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<RpcResult<()>> {
+        self.inner()?.poll_close_unpin(cx)
+    }
+}
+
+/// Sends the items of `requests` to the streaming method that was just called
+/// on `stream_sink`, and sends the method's items into `responses`, until the
+/// response to the call arrives, which is returned. Once `requests` ends, the
+/// server is told so.
+///
+/// If `requests` yields an error, or sending into `responses` fails, the call is
+/// cancelled. The remaining items from the server are skipped, and the error is
+/// returned once the server responds.
+async fn run_call_stream(
+    stream_sink: &mut CallStream,
+    requests: impl Stream<Item = RpcResult<Vec<u8>>>,
+    responses: impl Sink<Vec<u8>, Error = RustyRpcError>,
+) -> RpcResult<ServerMessage> {
+    pin_mut!(requests);
+    pin_mut!(responses);
+    let mut requests_done = false;
+    let mut cancelled_because_of = None;
+    loop {
+        let next = {
+            let next_request = async {
+                if requests_done {
+                    pending().await
+                } else {
+                    requests.next().await
+                }
+            };
+            let next_message = stream_sink.receive();
+            pin_mut!(next_request);
+            pin_mut!(next_message);
+            match select(next_request, next_message).await {
+                Either::Left((request, _)) => Either::Left(request),
+                Either::Right((message, _)) => Either::Right(message),
+            }
+        };
+        let error = match next {
+            Either::Left(Some(Ok(item))) => {
+                stream_sink.send(ClientMessage::StreamItem(item)).await?;
+                continue;
+            }
+            Either::Left(None) => {
+                requests_done = true;
+                stream_sink.send(ClientMessage::StreamEnd).await?;
+                continue;
+            }
+            Either::Left(Some(Err(e))) => e,
+            Either::Right(message) => match message? {
+                ServerMessage::StreamItem(item) if cancelled_because_of.is_none() => {
+                    match responses.send(item).await {
+                        Ok(()) => continue,
+                        Err(e) => e,
+                    }
+                }
+                ServerMessage::StreamItem(_) => continue,
+                response => return cancelled_because_of.map_or(Ok(response), Err),
+            },
+        };
+        requests_done = true;
+        if cancelled_because_of.is_none() {
+            cancelled_because_of = Some(error);
+            stream_sink.send(ClientMessage::Cancel).await?;
+        }
+    }
+}
+
+/// Cancels the call on the server if it is dropped before [AbandonGuard::disarm]
+/// is called, i.e. if the future of the call was dropped.
+struct AbandonGuard<'a>(Option<&'a Arc<ClientConnection>>);
+impl AbandonGuard<'_> {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+impl Drop for AbandonGuard<'_> {
+    fn drop(&mut self) {
+        let Some(connection) = self.0 else {
+            return;
+        };
+        // If there's no runtime, the call will instead be cancelled before the
+        // next call on this connection.
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let connection = connection.clone();
+            handle.spawn(async move {
+                let mut locked = connection.stream_sink.lock().await;
+                if let Some(stream_sink) = locked.as_mut() {
+                    // Errors will show up again in the next call, if there is
+                    // one.
+                    let _ = stream_sink.finish_abandoned_calls().await;
+                }
+            });
+        }
+    }
+}
+
+/// Runs `future`, sending the calls that it makes on the connection of
+/// `service` in batches. Each time `future` is polled, the calls that it
+/// started during that poll are sent together as one message, and so take one
+/// round trip in total. For example, calls joined with `futures::join!` end up
+/// in the same batch.
+///
+/// The server handles the calls of a batch in the order that they were made. A
+/// call that fails with [RustyRpcError::ServerError] doesn't affect the other
+/// calls in the batch. If the connection fails, all calls in the batch fail.
+///
+/// Calls that other tasks make on the same connection while `future` is being
+/// polled are also added to the batch. Inside of another `batch` on the same
+/// connection, this just runs `future`.
+///
+/// # Panics
+///
+/// Panics if `service` is a server-side service.
+pub async fn batch<T, F>(service: &ServiceRefMut<'_, T>, future: F) -> F::Output
+where
+    T: RustyRpcServiceClient + ?Sized,
+    F: Future,
+{
+    let connection = service.connection().clone();
+    if connection.batch.lock().unwrap().is_some() {
+        return future.await;
+    }
+    pin_mut!(future);
+    let mut output = None;
+    let mut flushes: Vec<BoxFuture<'static, ()>> = Vec::new();
+    poll_fn(|cx| {
+        if output.is_none() {
+            *connection.batch.lock().unwrap() = Some(Vec::new());
+            let poll = future.as_mut().poll(cx);
+            let calls = connection.batch.lock().unwrap().take().unwrap_or_default();
+            if !calls.is_empty() {
+                flushes.push(connection.clone().send_batch(calls).boxed());
+            }
+            if let Poll::Ready(x) = poll {
+                output = Some(x);
+            }
+        }
+        // Batches are sent in order, since each flush locks the connection as
+        // soon as it is first polled.
+        flushes.retain_mut(|flush| flush.poll_unpin(cx).is_pending());
+        match output.take() {
+            Some(x) if flushes.is_empty() => Poll::Ready(x),
+            x => {
+                output = x;
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+/// [RustyRpcError] can't be cloned because of the I/O error, so this copies
+/// its kind and message instead.
+fn copy_error(e: &RustyRpcError) -> RustyRpcError {
+    match e {
+        RustyRpcError::ConnectionClosed => RustyRpcError::ConnectionClosed,
+        RustyRpcError::MalformedMessage(msg) => RustyRpcError::MalformedMessage(msg.clone()),
+        RustyRpcError::ServerError(msg) => RustyRpcError::ServerError(msg.clone()),
+        RustyRpcError::Timeout => RustyRpcError::Timeout,
+        RustyRpcError::AuthenticationFailed => RustyRpcError::AuthenticationFailed,
+        RustyRpcError::Io(e) => RustyRpcError::Io(io::Error::new(e.kind(), e.to_string())),
+    }
+}
+
+/// Pings the server every `interval` for as long as the connection is alive.
+/// If the server doesn't respond in time, the connection is closed. Other
+/// errors just stop the heartbeats, since they will also show up in the next
+/// call. While a call is in flight, the call sends the heartbeats instead (see
+/// [CallStream::receive]), so this waits for the next interval.
+async fn send_heartbeats(connection: Weak<ClientConnection>, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        let Some(connection) = connection.upgrade() else {
+            return;
+        };
+        let Ok(mut locked) = connection.stream_sink.try_lock() else {
+            continue;
+        };
+        let Some(stream_sink) = locked.as_mut() else {
+            return;
+        };
+        let response = timeout(
+            connection.config.heartbeat_timeout,
+            ClientConnection::call_locked(stream_sink, ClientMessage::Ping),
+        )
+        .await;
+        match response {
+            Ok(Ok(ServerMessage::Pong)) => (),
+            Ok(_) => return,
+            Err(_) => {
+                // Dropping the stream closes the underlying connection.
+                *locked = None;
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Splitting a byte stream into frames, each of which holds one message. This
+//! works over anything that implements `AsyncRead + AsyncWrite`, so it doesn't
+//! depend on TCP. Transports that already have frames of their own, such as
+//! WebSocket, skip the codec and use their frames directly.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio_util::codec::LengthDelimitedCodec;
+
+use crate::error::{RpcResult, RustyRpcError};
+use crate::messages::{ClientMessage, ServerMessage};
+use crate::metrics::ByteCounts;
+use crate::traits::ClientStreamSink;
+use crate::wire_format::WireFormat;
+
+/// Creates the codec used for splitting the byte stream into frames. Frames
+/// longer than `max_frame_length` are rejected with an error.
+pub(crate) fn new_codec(max_frame_length: usize) -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(max_frame_length)
+        .new_codec()
+}
+
+/// A stream and sink of frames, each of which holds one message. The server
+/// and the client both work on top of this.
+pub(crate) trait FrameStreamSink:
+    Stream<Item = io::Result<BytesMut>> + Sink<Bytes, Error = io::Error> + Unpin
+{
+}
+impl<T: Stream<Item = io::Result<BytesMut>> + Sink<Bytes, Error = io::Error> + Unpin>
+    FrameStreamSink for T
+{
+}
+
+/// Wraps a [FrameStreamSink], and adds the size of every frame that passes
+/// through it to a [ByteCounts].
+pub(crate) struct CountingFrames<S> {
+    inner: S,
+    counts: Arc<ByteCounts>,
+}
+impl<S> CountingFrames<S> {
+    pub(crate) fn new(inner: S, counts: Arc<ByteCounts>) -> Self {
+        CountingFrames { inner, counts }
+    }
+}
+impl<S: FrameStreamSink> Stream for CountingFrames<S> {
+    type Item = io::Result<BytesMut>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.inner.poll_next_unpin(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            self.counts.add_received(frame.len());
+        }
+        poll
+    }
+}
+impl<S: FrameStreamSink> Sink<Bytes> for CountingFrames<S> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> io::Result<()> {
+        let len = item.len();
+        self.inner.start_send_unpin(item)?;
+        self.counts.add_sent(len);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+/// Turns frames into a stream of messages from the server, and a sink of
+/// messages to the server. Messages that the server split with
+/// [ServerMessage::DataChunk] are put back together.
+///
+/// A message longer than `max_response_length` is replaced with an error. The
+/// rest of its pieces are then skipped without being kept, so the next message
+/// can still be received.
+pub(crate) fn client_stream_sink<S: FrameStreamSink + Send + 'static>(
+    frames: S,
+    wire_format: WireFormat,
+    max_response_length: usize,
+) -> impl ClientStreamSink {
+    let mut chunks = Vec::new();
+    let mut skipping_chunks = false;
+    let too_long = move || {
+        RustyRpcError::MalformedMessage(format!(
+            "The response is longer than the maximum response length of {} bytes.",
+            max_response_length
+        ))
+    };
+    frames
+        .filter_map(move |in_bytes: io::Result<BytesMut>| {
+            let decode = || -> RpcResult<Option<ServerMessage>> {
+                let in_bytes = in_bytes?;
+                let message = match wire_format.decode(&in_bytes)? {
+                    ServerMessage::DataChunk(_) if skipping_chunks => return Ok(None),
+                    ServerMessage::DataEnd(_) if skipping_chunks => {
+                        skipping_chunks = false;
+                        return Ok(None);
+                    }
+                    ServerMessage::DataChunk(chunk) => {
+                        if chunks.len() + chunk.len() > max_response_length {
+                            chunks = Vec::new();
+                            skipping_chunks = true;
+                            return Err(too_long());
+                        }
+                        chunks.extend_from_slice(&chunk);
+                        return Ok(None);
+                    }
+                    ServerMessage::DataEnd(chunk) => {
+                        if chunks.len() + chunk.len() > max_response_length {
+                            chunks = Vec::new();
+                            return Err(too_long());
+                        }
+                        chunks.extend_from_slice(&chunk);
+                        match wire_format.decode(&std::mem::take(&mut chunks))? {
+                            ServerMessage::DataChunk(_) | ServerMessage::DataEnd(_) => {
+                                return Err(RustyRpcError::MalformedMessage(
+                                    "Chunks cannot be nested.".into(),
+                                ))
+                            }
+                            message => message,
+                        }
+                    }
+                    _ if in_bytes.len() > max_response_length => return Err(too_long()),
+                    message => message,
+                };
+                Ok(Some(message))
+            };
+            futures::future::ready(decode().transpose())
+        })
+        .with(move |out_message: ClientMessage| {
+            let bytes = Bytes::from(wire_format.encode(&out_message));
+            futures::future::ready(RpcResult::Ok(bytes))
+        })
+}
@@ -0,0 +1,327 @@
+//! Options for configuring servers and clients.
+
+use std::fmt;
+#[cfg(feature = "tcp")]
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "tcp")]
+use tokio::net::TcpListener;
+
+use crate::auth::{AcceptFilter, Authenticator, Authorizer};
+use crate::interceptor::{ClientInterceptor, ServerInterceptor};
+use crate::metrics::{MetricsSink, NoopMetricsSink};
+#[cfg(feature = "tcp")]
+use crate::traits::RustyRpcServiceServer;
+use crate::wire_format::WireFormat;
+
+/// The default maximum frame length, 16 MiB.
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// The default maximum length of a response that a client accepts, after the
+/// pieces of a split response are put back together, 256 MiB.
+pub const DEFAULT_MAX_RESPONSE_LENGTH: usize = 256 * 1024 * 1024;
+
+/// The default length of the pieces that long responses are split into, 1 MiB.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The default maximum number of live services per connection.
+pub const DEFAULT_MAX_SERVICES_PER_CONNECTION: usize = 65536;
+
+/// The default time that a client waits for the server to respond to a
+/// heartbeat, 10 seconds.
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default time that [crate::connect_client] waits for each connection
+/// attempt, 10 seconds.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default time that [crate::connect_client] waits before retrying a
+/// failed connection attempt, 100 milliseconds.
+pub const DEFAULT_CONNECT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Options for the server side of each connection. Use `Default::default()`
+/// for the default options.
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// The maximum length in bytes of a single frame received from a client.
+    /// If a client announces a longer frame, the connection with that client
+    /// is closed with an error, without allocating space for the frame.
+    pub max_frame_length: usize,
+    /// Responses whose encoding is longer than this many bytes, such as large
+    /// return values, are split into pieces of this length, each of which is
+    /// sent in its own frame. The client puts them back together, so this must
+    /// be somewhat less than the client's [ClientConfig::max_frame_length].
+    pub chunk_size: usize,
+    /// The maximum number of services that can be live at the same time in a
+    /// single connection, including the initial service. Calling a method that
+    /// would create more services makes that method call fail with an error.
+    pub max_services_per_connection: usize,
+    /// If set, the connection is closed if nothing is received from the client
+    /// for this long, and the services of the connection are dropped. Clients
+    /// can keep idle connections open by setting
+    /// [ClientConfig::heartbeat_interval] to something shorter than this.
+    pub idle_timeout: Option<Duration>,
+    /// If set, this is notified of every method call on every connection.
+    pub interceptor: Option<Arc<dyn ServerInterceptor>>,
+    /// Where to report metrics about connections and calls. By default, they
+    /// are ignored.
+    pub metrics: Arc<dyn MetricsSink>,
+    /// If set, this is asked whether to accept each new connection, before
+    /// anything is read from it. Rejected connections are closed immediately.
+    pub accept_filter: Option<Arc<dyn AcceptFilter>>,
+    /// If set, each client must authenticate with a credential that this
+    /// accepts (see [crate::start_client_with_credential]) before it can use
+    /// the initial service. Otherwise, the connection is closed.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// If set, this is asked before every method call whether the call is
+    /// allowed.
+    pub authorizer: Option<Arc<dyn Authorizer>>,
+    /// Whether to set `TCP_NODELAY` on each accepted connection, which turns
+    /// off Nagle's algorithm. Messages are usually small, so this is on by
+    /// default.
+    pub tcp_nodelay: bool,
+    /// How messages are encoded. Clients must use the same format.
+    pub wire_format: WireFormat,
+}
+/// The interceptor is only shown as present or absent. The metrics sink, the
+/// accept filter, the authenticator, and the authorizer are not printed.
+impl fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("max_frame_length", &self.max_frame_length)
+            .field("chunk_size", &self.chunk_size)
+            .field(
+                "max_services_per_connection",
+                &self.max_services_per_connection,
+            )
+            .field("idle_timeout", &self.idle_timeout)
+            .field("tcp_nodelay", &self.tcp_nodelay)
+            .field("wire_format", &self.wire_format)
+            .field("interceptor", &self.interceptor.as_ref().map(|_| ..))
+            .finish_non_exhaustive()
+    }
+}
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_services_per_connection: DEFAULT_MAX_SERVICES_PER_CONNECTION,
+            idle_timeout: None,
+            interceptor: None,
+            metrics: Arc::new(NoopMetricsSink),
+            accept_filter: None,
+            authenticator: None,
+            authorizer: None,
+            tcp_nodelay: true,
+            wire_format: WireFormat::default(),
+        }
+    }
+}
+
+/// Builds a [ServerConfig] one option at a time, and then starts a server with
+/// it. Options that aren't set keep their default values.
+///
+/// Example:
+/// ```ignore
+/// ServerBuilder::new()
+///     .with_max_frame_length(64 * 1024)
+///     .with_idle_timeout(Duration::from_secs(60))
+///     .with_interceptor(Arc::new(Logger))
+///     .serve::<MyServer>(listener)
+///     .await?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ServerBuilder {
+    config: ServerConfig,
+}
+impl ServerBuilder {
+    /// Creates a builder with the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets [ServerConfig::max_frame_length].
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.config.max_frame_length = max_frame_length;
+        self
+    }
+
+    /// Sets [ServerConfig::chunk_size].
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.config.chunk_size = chunk_size;
+        self
+    }
+
+    /// Sets [ServerConfig::max_services_per_connection].
+    pub fn with_max_services_per_connection(mut self, max_services: usize) -> Self {
+        self.config.max_services_per_connection = max_services;
+        self
+    }
+
+    /// Sets [ServerConfig::idle_timeout].
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Sets [ServerConfig::interceptor].
+    pub fn with_interceptor(mut self, interceptor: Arc<dyn ServerInterceptor>) -> Self {
+        self.config.interceptor = Some(interceptor);
+        self
+    }
+
+    /// Sets [ServerConfig::metrics].
+    pub fn with_metrics(mut self, metrics: Arc<dyn MetricsSink>) -> Self {
+        self.config.metrics = metrics;
+        self
+    }
+
+    /// Sets [ServerConfig::accept_filter].
+    pub fn with_accept_filter(mut self, accept_filter: Arc<dyn AcceptFilter>) -> Self {
+        self.config.accept_filter = Some(accept_filter);
+        self
+    }
+
+    /// Sets [ServerConfig::authenticator].
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.config.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Sets [ServerConfig::authorizer].
+    pub fn with_authorizer(mut self, authorizer: Arc<dyn Authorizer>) -> Self {
+        self.config.authorizer = Some(authorizer);
+        self
+    }
+
+    /// Sets [ServerConfig::tcp_nodelay].
+    pub fn with_tcp_nodelay(mut self, tcp_nodelay: bool) -> Self {
+        self.config.tcp_nodelay = tcp_nodelay;
+        self
+    }
+
+    /// Sets [ServerConfig::wire_format].
+    pub fn with_wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.config.wire_format = wire_format;
+        self
+    }
+
+    /// Returns the options, e.g. for [crate::Server::new] or
+    /// [crate::start_websocket_server_with_config].
+    pub fn build(self) -> ServerConfig {
+        self.config
+    }
+
+    /// Starts a server with these options, like [crate::start_server].
+    #[cfg(feature = "tcp")]
+    pub async fn serve<T: for<'a> RustyRpcServiceServer<'a> + Default>(
+        self,
+        listener: TcpListener,
+    ) -> io::Result<()> {
+        self.serve_with(listener, (), |_| T::default()).await
+    }
+
+    /// Starts a server with these options, like [crate::start_server_with].
+    #[cfg(feature = "tcp")]
+    pub async fn serve_with<T, C, F>(
+        self,
+        listener: TcpListener,
+        shared_ctx: C,
+        factory: F,
+    ) -> io::Result<()>
+    where
+        T: for<'a> RustyRpcServiceServer<'a>,
+        C: Send + Sync + 'static,
+        F: Fn(&C) -> T + Send + Sync + 'static,
+    {
+        crate::start_server_with_config(listener, self.config, shared_ctx, factory).await
+    }
+}
+
+/// Options for the client side of a connection. Use `Default::default()` for
+/// the default options.
+#[derive(Clone)]
+pub struct ClientConfig {
+    /// The maximum length in bytes of a single frame received from the server.
+    /// If the server announces a longer frame, the call fails with an error.
+    pub max_frame_length: usize,
+    /// The maximum length in bytes of a single response from the server,
+    /// after the pieces of a response that was split into several frames (see
+    /// [ServerConfig::chunk_size]) are put back together. If a response is
+    /// longer, the call fails with an error as soon as that is known, and the
+    /// rest of the response is skipped without being kept in memory. This
+    /// isn't used with [crate::start_client_with_stream_sink].
+    pub max_response_length: usize,
+    /// If false, dropping a service proxy without closing it first panics. If
+    /// true, dropping such a proxy instead closes it in a background task.
+    /// Messages are still sent to the server in order, so the service is
+    /// always closed before any call that is made after the proxy is dropped.
+    pub auto_close_on_drop: bool,
+    /// If set, the client pings the server this often in the background. If
+    /// the server doesn't respond within `heartbeat_timeout`, the connection is
+    /// closed, and all further calls fail with an error. While a call waits for
+    /// its response, the server is pinged whenever it is quiet this long, so a
+    /// call to a server that died fails with [crate::RustyRpcError::Timeout]
+    /// instead of waiting forever.
+    pub heartbeat_interval: Option<Duration>,
+    /// How long to wait for the server to respond to a heartbeat. Only used if
+    /// `heartbeat_interval` is set.
+    pub heartbeat_timeout: Duration,
+    /// Middleware that every request goes through, outermost first.
+    /// Heartbeats don't go through the interceptors.
+    pub interceptors: Vec<Arc<dyn ClientInterceptor>>,
+    /// How long [crate::connect_client] waits for each connection attempt.
+    pub connect_timeout: Duration,
+    /// How many times [crate::connect_client] retries after a failed
+    /// connection attempt.
+    pub connect_retries: u32,
+    /// How long [crate::connect_client] waits before the first retry. The wait
+    /// doubles after each retry.
+    pub connect_backoff: Duration,
+    /// Whether [crate::connect_client] sets `TCP_NODELAY` on the connection,
+    /// which turns off Nagle's algorithm. This is on by default. Connections
+    /// passed to [crate::start_client] are used as they are.
+    pub tcp_nodelay: bool,
+    /// How messages are encoded. The server must use the same format. With
+    /// [crate::start_client_with_stream_sink], this is only used for the
+    /// arguments and return values inside the messages.
+    pub wire_format: WireFormat,
+}
+/// The interceptors are printed without their contents.
+impl fmt::Debug for ClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientConfig")
+            .field("max_frame_length", &self.max_frame_length)
+            .field("max_response_length", &self.max_response_length)
+            .field("auto_close_on_drop", &self.auto_close_on_drop)
+            .field("heartbeat_interval", &self.heartbeat_interval)
+            .field("heartbeat_timeout", &self.heartbeat_timeout)
+            .field("interceptors", &self.interceptors.len())
+            .field("connect_timeout", &self.connect_timeout)
+            .field("connect_retries", &self.connect_retries)
+            .field("connect_backoff", &self.connect_backoff)
+            .field("tcp_nodelay", &self.tcp_nodelay)
+            .field("wire_format", &self.wire_format)
+            .finish()
+    }
+}
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            max_response_length: DEFAULT_MAX_RESPONSE_LENGTH,
+            auto_close_on_drop: false,
+            heartbeat_interval: None,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            interceptors: Vec::new(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            connect_retries: 0,
+            connect_backoff: DEFAULT_CONNECT_BACKOFF,
+            tcp_nodelay: true,
+            wire_format: WireFormat::default(),
+        }
+    }
+}
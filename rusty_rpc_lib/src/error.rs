@@ -0,0 +1,79 @@
+use std::{error, fmt, io};
+
+/// Returned by the `build()` method of a generated struct builder when a field
+/// without a default value was never set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingFieldError {
+    pub struct_name: &'static str,
+    pub field_name: &'static str,
+}
+impl fmt::Display for MissingFieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Field {} of struct {} was not set.",
+            self.field_name, self.struct_name
+        )
+    }
+}
+impl error::Error for MissingFieldError {}
+
+/// The result type of RPC calls.
+pub type RpcResult<T> = Result<T, RustyRpcError>;
+
+/// An error from making an RPC call or from handling a connection.
+#[derive(Debug)]
+pub enum RustyRpcError {
+    /// The connection was closed by the peer.
+    ConnectionClosed,
+    /// A message from the peer couldn't be decoded, or was otherwise invalid.
+    MalformedMessage(String),
+    /// The server couldn't handle the request. The connection stays open.
+    ServerError(String),
+    /// The peer didn't respond in time.
+    Timeout,
+    /// The server rejected the client's credential, or the client didn't
+    /// send one when the server required it.
+    AuthenticationFailed,
+    /// Any other I/O error.
+    Io(io::Error),
+}
+impl fmt::Display for RustyRpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RustyRpcError::ConnectionClosed => write!(f, "The connection was closed."),
+            RustyRpcError::MalformedMessage(msg) => write!(f, "Malformed message: {}", msg),
+            RustyRpcError::ServerError(msg) => write!(f, "Server error: {}", msg),
+            RustyRpcError::Timeout => write!(f, "The peer didn't respond in time."),
+            RustyRpcError::AuthenticationFailed => write!(f, "Authentication failed."),
+            RustyRpcError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl error::Error for RustyRpcError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RustyRpcError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for RustyRpcError {
+    fn from(e: io::Error) -> Self {
+        RustyRpcError::Io(e)
+    }
+}
+/// For compatibility with code that uses `io::Error`.
+impl From<RustyRpcError> for io::Error {
+    fn from(e: RustyRpcError) -> Self {
+        let kind = match e {
+            RustyRpcError::Io(e) => return e,
+            RustyRpcError::ConnectionClosed => io::ErrorKind::ConnectionAborted,
+            RustyRpcError::MalformedMessage(_) => io::ErrorKind::InvalidData,
+            RustyRpcError::ServerError(_) => io::ErrorKind::Other,
+            RustyRpcError::Timeout => io::ErrorKind::TimedOut,
+            RustyRpcError::AuthenticationFailed => io::ErrorKind::PermissionDenied,
+        };
+        io::Error::new(kind, e)
+    }
+}
@@ -0,0 +1,79 @@
+//! Hooks for observing RPC calls.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::auth::ConnectionContext;
+use crate::client::{CallStream, ClientConnection};
+use crate::error::RpcResult;
+use crate::messages::{ClientMessage, MethodId, ServerMessage, ServiceId};
+use crate::metrics::ByteCounts;
+
+/// Callbacks that the server invokes around every method call, e.g. for
+/// logging. Set it with [crate::ServerConfig::interceptor].
+///
+/// The callbacks are called from the connection's task, so they should return
+/// quickly. All callbacks do nothing by default.
+pub trait ServerInterceptor: Send + Sync {
+    /// Called just before a method is called.
+    fn on_request(&self, service_id: ServiceId, method_id: MethodId) {
+        let _ = (service_id, method_id);
+    }
+
+    /// Called just after a method returns, with the time the call took.
+    fn on_response(&self, service_id: ServiceId, method_id: MethodId, elapsed: Duration) {
+        let _ = (service_id, method_id, elapsed);
+    }
+
+    /// Called once when a connection ends, after its services are dropped,
+    /// with the number of bytes sent and received on it. This isn't called
+    /// for connections that fail authentication.
+    fn on_connection_closed(&self, context: &ConnectionContext, byte_counts: &ByteCounts) {
+        let _ = (context, byte_counts);
+    }
+}
+
+/// Middleware that wraps every request that a client sends, e.g. for tracing,
+/// retries, or metrics. Set it with [crate::ClientConfig::interceptors].
+///
+/// Interceptors stack: the first interceptor in the list is the outermost one.
+/// Each interceptor decides when (and how many times) to pass the request on
+/// to the rest of the chain by calling [Next::run]. Requests on a connection
+/// are sent one at a time, so other calls on the same connection wait while an
+/// interceptor runs.
+#[async_trait]
+pub trait ClientInterceptor: Send + Sync {
+    async fn intercept(&self, msg: ClientMessage, next: &mut Next<'_>) -> RpcResult<ServerMessage>;
+}
+
+/// The rest of the interceptor chain, ending with the actual round trip to the
+/// server.
+pub struct Next<'a> {
+    interceptors: &'a [Arc<dyn ClientInterceptor>],
+    stream_sink: &'a mut CallStream,
+}
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        interceptors: &'a [Arc<dyn ClientInterceptor>],
+        stream_sink: &'a mut CallStream,
+    ) -> Self {
+        Next {
+            interceptors,
+            stream_sink,
+        }
+    }
+
+    /// Passes the request on to the next interceptor, or sends it to the server
+    /// if there are no more interceptors.
+    pub async fn run(&mut self, msg: ClientMessage) -> RpcResult<ServerMessage> {
+        match self.interceptors.split_first() {
+            Some((first, rest)) => {
+                let mut next = Next::new(rest, self.stream_sink);
+                first.intercept(msg, &mut next).await
+            }
+            None => ClientConnection::call_locked(self.stream_sink, msg).await,
+        }
+    }
+}
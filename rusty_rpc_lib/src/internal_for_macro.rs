@@ -3,20 +3,159 @@
 //!
 //! Contains various exports that macros need access to.
 
+pub use crate::call_metadata::call_metadata;
+pub use crate::client::ClientConnection;
+pub use crate::error::RustyRpcError;
 pub use crate::messages::{
-    local_service_from_service_ref, service_ref_from_service_proxy, ClientMessage, MethodArgs,
-    MethodId, ReturnValue, ServerMessage, ServiceId, ServiceRefMut,
+    local_service_from_service_ref, service_ref_from_service_proxy, ChainedService, ClientMessage,
+    MethodArgs, MethodId, ReturnValue, ServerMessage, ServiceId, ServiceRefMut,
 };
+pub use crate::serde_bytes::{ByteBuf, BytesRef};
+pub use crate::serde_char::WireChar;
+pub use crate::serde_int128::{serde_i128, serde_u128, WireI128, WireU128};
+pub use crate::serde_time::{serde_duration, serde_timestamp, WireDuration, WireTimestamp};
+#[cfg(feature = "uuid")]
+pub use crate::serde_uuid::WireUuid;
 pub use crate::server_collection::{RawBox, ServerCollection, ServerEntry, ServerGuard};
+pub use crate::streaming::{IncomingBytes, StreamReceiver, StreamSender};
 pub use crate::traits::{
     ClientStreamSink, RustyRpcServiceClient, RustyRpcServiceProxy, RustyRpcServiceServer,
     RustyRpcServiceServerWithKnownClientType, RustyRpcStruct,
 };
 pub use crate::util::string_io_error;
 
+/// For `#[serde(with = "...")]` on fields of the `bytes` type.
+pub mod serde_bytes {
+    pub use crate::serde_bytes::{deserialize, serialize};
+}
+
+/// For `#[serde(with = "...")]` on fields of the `char` type.
+pub mod serde_char {
+    pub use crate::serde_char::{deserialize, serialize};
+}
+
+/// For `#[serde(with = "...")]` on fields of the `uuid` type.
+#[cfg(feature = "uuid")]
+pub mod serde_uuid {
+    pub use crate::serde_uuid::{deserialize, serialize};
+}
+
+/// For `#[serde(with = "...")]` on `@lossy_utf8` fields of the `string` type.
+pub mod serde_lossy_utf8 {
+    pub use crate::serde_lossy_utf8::{deserialize, serialize};
+}
+
 pub use async_trait::async_trait;
 pub use bytes::Bytes;
-pub use futures::{SinkExt, StreamExt};
 pub use rmp_serde;
+pub use serde::ser::{SerializeMap, Serializer};
 pub use serde::{Deserialize, Serialize};
-pub use tokio::sync::Mutex;
+pub use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "tcp")]
+pub use tokio::net::ToSocketAddrs;
+#[cfg(feature = "blocking")]
+pub use tokio::runtime::Runtime;
+
+pub use crate::__rusty_rpc_if_blocking as if_blocking;
+pub use crate::__rusty_rpc_if_mock as if_mock;
+pub use crate::__rusty_rpc_if_tcp as if_tcp;
+pub use crate::__rusty_rpc_require_uuid as require_uuid;
+#[cfg(feature = "uuid")]
+pub use uuid::Uuid;
+
+/// Creates the runtime that a blocking client runs its connection on.
+#[cfg(feature = "blocking")]
+pub fn new_blocking_runtime() -> std::io::Result<Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+}
+
+/// The error for a response that doesn't fit the message that it answers, e.g.
+/// a pong in response to a method call. `expected` describes what the server
+/// should have sent.
+pub fn unexpected_response(response: &ServerMessage, expected: &str) -> RustyRpcError {
+    let sent = match response {
+        ServerMessage::DropServiceDone => "confirmation for dropped service",
+        ServerMessage::MethodReturned(_) => "return value",
+        ServerMessage::Error(_) => "error",
+        ServerMessage::Pong => "pong",
+        ServerMessage::Authenticated => "authentication confirmation",
+        ServerMessage::Batch(_) => "batch responses",
+        ServerMessage::DataChunk(_) | ServerMessage::DataEnd(_) => {
+            "a chunk that was not put back together"
+        }
+        ServerMessage::StreamItem(_) => "a stream item",
+    };
+    RustyRpcError::MalformedMessage(format!("Server sent {} instead of {}.", sent, expected))
+}
+
+/// The error for a return value of the wrong kind, e.g. data from a method that
+/// returns a service.
+pub fn malformed_return_value(msg: &str) -> RustyRpcError {
+    RustyRpcError::MalformedMessage(msg.to_string())
+}
+
+/// Expands to its input if this crate was built with the `tcp` feature, and to
+/// nothing otherwise. The generated code can't check this crate's features
+/// with `#[cfg]`, since that would check the features of the user's crate.
+#[cfg(feature = "tcp")]
+#[macro_export]
+#[doc(hidden)]
+macro_rules! __rusty_rpc_if_tcp {
+    ($($x:tt)*) => { $($x)* };
+}
+#[cfg(not(feature = "tcp"))]
+#[macro_export]
+#[doc(hidden)]
+macro_rules! __rusty_rpc_if_tcp {
+    ($($x:tt)*) => {};
+}
+
+/// Like [if_tcp], but for the `blocking` feature.
+#[cfg(feature = "blocking")]
+#[macro_export]
+#[doc(hidden)]
+macro_rules! __rusty_rpc_if_blocking {
+    ($($x:tt)*) => { $($x)* };
+}
+#[cfg(not(feature = "blocking"))]
+#[macro_export]
+#[doc(hidden)]
+macro_rules! __rusty_rpc_if_blocking {
+    ($($x:tt)*) => {};
+}
+
+/// Like [if_tcp], but for the `mock` feature.
+#[cfg(feature = "mock")]
+#[macro_export]
+#[doc(hidden)]
+macro_rules! __rusty_rpc_if_mock {
+    ($($x:tt)*) => { $($x)* };
+}
+#[cfg(not(feature = "mock"))]
+#[macro_export]
+#[doc(hidden)]
+macro_rules! __rusty_rpc_if_mock {
+    ($($x:tt)*) => {};
+}
+
+/// Used by protocol files that use the `uuid` type. Expands to nothing if this
+/// crate was built with the `uuid` feature, and to an error that says to enable
+/// it otherwise.
+#[cfg(feature = "uuid")]
+#[macro_export]
+#[doc(hidden)]
+macro_rules! __rusty_rpc_require_uuid {
+    () => {};
+}
+#[cfg(not(feature = "uuid"))]
+#[macro_export]
+#[doc(hidden)]
+macro_rules! __rusty_rpc_require_uuid {
+    () => {
+        ::std::compile_error!(
+            "The protocol file uses the uuid type, which needs the `uuid` feature of rusty_rpc_lib."
+        );
+    };
+}
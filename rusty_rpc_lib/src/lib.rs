@@ -1,31 +1,93 @@
+// Without TCP, there is no way to start a server, so the server side goes
+// unused.
+#![cfg_attr(not(feature = "tcp"), allow(dead_code, unused_imports))]
+
 pub mod internal_for_macro;
 
-pub use messages::ServiceRefMut;
+pub use auth::{AcceptFilter, Authenticator, Authorizer, ConnectionContext, MethodCall};
+pub use call_metadata::{call_metadata, with_call_metadata};
+pub use client::batch;
+pub use config::{
+    ClientConfig, ServerBuilder, ServerConfig, DEFAULT_CHUNK_SIZE, DEFAULT_CONNECT_BACKOFF,
+    DEFAULT_CONNECT_TIMEOUT, DEFAULT_HEARTBEAT_TIMEOUT, DEFAULT_MAX_FRAME_LENGTH,
+    DEFAULT_MAX_RESPONSE_LENGTH, DEFAULT_MAX_SERVICES_PER_CONNECTION,
+};
+pub use error::{MissingFieldError, RpcResult, RustyRpcError};
+pub use interceptor::{ClientInterceptor, Next, ServerInterceptor};
+pub use messages::{
+    ChainedService, ClientMessage, MethodArgs, MethodId, ReturnValue, ServerMessage, ServiceId,
+    ServiceRefMut, WeakServiceRef,
+};
+pub use metrics::{ByteCounts, MetricsSink, NoopMetricsSink};
+#[cfg(feature = "tcp")]
+pub use server::Server;
+pub use server_collection::{ServerCollection, ServerGuard};
+pub use streaming::{stream_channel, IncomingBytes, StreamReceiver, StreamSender};
 pub use traits::{
-    RustyRpcServiceClient, RustyRpcServiceProxy, RustyRpcServiceServer,
+    ClientStreamSink, RustyRpcServiceClient, RustyRpcServiceProxy, RustyRpcServiceServer,
     RustyRpcServiceServerWithKnownClientType,
 };
+#[cfg(feature = "uuid")]
+pub use uuid::Uuid;
+#[cfg(feature = "websocket")]
+pub use websocket::{
+    connect_websocket_client, start_websocket_server, start_websocket_server_with_config,
+    websocket_stream_sink,
+};
+pub use wire_format::WireFormat;
 
+mod auth;
+mod call_metadata;
+mod client;
+mod codec;
+mod config;
+mod error;
+mod interceptor;
 mod messages;
+pub mod metrics;
+mod serde_bytes;
+mod serde_char;
+mod serde_int128;
+mod serde_lossy_utf8;
+mod serde_time;
+#[cfg(feature = "uuid")]
+mod serde_uuid;
+#[cfg(feature = "tcp")]
+mod server;
 mod server_collection;
+mod streaming;
+mod trace;
 mod traits;
 mod util;
+#[cfg(feature = "websocket")]
+mod websocket;
+mod wire_format;
 
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::{ready, Future, Ready};
 use std::io;
 use std::mem::transmute;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 
-use bytes::{Bytes, BytesMut};
-use futures::{SinkExt, StreamExt};
+use bytes::Bytes;
+use futures::future::{pending, poll_fn, select, Either};
+use futures::{pin_mut, SinkExt, StreamExt};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpListener;
-use tokio::sync::{Mutex, MutexGuard};
+#[cfg(feature = "tcp")]
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::MutexGuard;
+use tokio::time::{sleep, timeout};
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
-use messages::{service_ref_from_service_proxy, ClientMessage, ServerMessage, ServiceId};
-use server_collection::{RawBox, ServerCollection, ServerEntry};
-use traits::ClientStreamSink;
-use util::{other_io_error, string_io_error};
+use client::ClientConnection;
+use codec::{CountingFrames, FrameStreamSink};
+use messages::service_ref_from_service_proxy;
+use metrics::ConnectionGauge;
+use server_collection::ServerEntry;
+use streaming::ConnectionCallStream;
 
 /// Starts a server, accepting new connections in an infinite loop.
 ///
@@ -34,92 +96,673 @@ use util::{other_io_error, string_io_error};
 /// created using the `Default` trait.
 ///
 /// To implement [RustyRpcServiceServer], use the `#[service_server_impl]`
-/// attribute in the `rusty_rpc_macro` crate.
+/// attribute in the `rusty_rpc_macro` crate. To set options, use a
+/// [ServerBuilder].
+#[cfg(feature = "tcp")]
 pub async fn start_server<T: for<'a> RustyRpcServiceServer<'a> + Default>(
     listener: TcpListener,
 ) -> std::io::Result<()> {
+    ServerBuilder::new().serve::<T>(listener).await
+}
+
+/// Starts a server like [start_server], but creates the initial service of
+/// each connection by calling `factory` with a reference to `shared_ctx`.
+///
+/// The same `shared_ctx` is handed to the factory for every connection, so it
+/// can be used for state that all connections share. For example, it can be an
+/// `Arc<Mutex<HashMap<K, V>>>` that the factory clones into each initial
+/// service.
+///
+/// Each connection is handled on its own task created with `tokio::spawn`, so
+/// the factory may be called, and the services it creates may run, on
+/// multiple threads at the same time. Method calls within one connection are
+/// handled one at a time, but calls from different connections can interleave
+/// arbitrarily. Any mutable state reachable from `shared_ctx` must therefore
+/// be synchronized (e.g. with a `Mutex`).
+#[cfg(feature = "tcp")]
+pub async fn start_server_with<T, C, F>(
+    listener: TcpListener,
+    shared_ctx: C,
+    factory: F,
+) -> std::io::Result<()>
+where
+    T: for<'a> RustyRpcServiceServer<'a>,
+    C: Send + Sync + 'static,
+    F: Fn(&C) -> T + Send + Sync + 'static,
+{
+    start_server_with_config(listener, ServerConfig::default(), shared_ctx, factory).await
+}
+
+/// Starts a server like [start_server_with], but with the specified options
+/// instead of the default ones.
+#[cfg(feature = "tcp")]
+pub async fn start_server_with_config<T, C, F>(
+    listener: TcpListener,
+    config: ServerConfig,
+    shared_ctx: C,
+    factory: F,
+) -> std::io::Result<()>
+where
+    T: for<'a> RustyRpcServiceServer<'a>,
+    C: Send + Sync + 'static,
+    F: Fn(&C) -> T + Send + Sync + 'static,
+{
+    serve(
+        listener,
+        config,
+        shared_ctx,
+        move |shared_ctx: &C| ready(factory(shared_ctx)),
+        frames_over_tcp,
+    )
+    .await
+}
+
+/// Starts a server like [start_server], but creates the initial service of
+/// each connection by awaiting the future returned by `factory`. This is
+/// useful if creating the service needs to wait for something, such as a
+/// database connection.
+#[cfg(feature = "tcp")]
+pub async fn start_server_with_async<T, F, Fut>(
+    listener: TcpListener,
+    factory: F,
+) -> std::io::Result<()>
+where
+    T: for<'a> RustyRpcServiceServer<'a>,
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = T> + Send,
+{
+    serve(
+        listener,
+        ServerConfig::default(),
+        (),
+        move |_: &()| factory(),
+        frames_over_tcp,
+    )
+    .await
+}
+
+/// Accepts new connections in an infinite loop. Each connection is split into
+/// frames by awaiting `frames(socket, &config)`, and its initial service is
+/// created by awaiting `factory(&shared_ctx)`.
+#[cfg(feature = "tcp")]
+async fn serve<T, C, F, Fut, U, UFut, S>(
+    listener: TcpListener,
+    config: ServerConfig,
+    shared_ctx: C,
+    factory: F,
+    frames: U,
+) -> std::io::Result<()>
+where
+    T: for<'a> RustyRpcServiceServer<'a>,
+    C: Send + Sync + 'static,
+    F: Fn(&C) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = T> + Send,
+    U: Fn(TcpStream, &ServerConfig) -> UFut + Send + Sync + 'static,
+    UFut: Future<Output = io::Result<S>> + Send,
+    S: FrameStreamSink + Send,
+{
+    let shared = Arc::new((config, shared_ctx, factory, frames));
     loop {
-        let (socket, _) = listener.accept().await?;
+        let (socket, peer_addr) = listener.accept().await?;
+        let shared = shared.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection::<T, _>(&mut ServerCollection::new(), socket).await {
+            let (config, shared_ctx, factory, frames) = &*shared;
+            // This runs in the connection's task, so that a slow filter
+            // doesn't hold up accepting other connections.
+            if let Some(accept_filter) = &config.accept_filter {
+                if !accept_filter.accept(peer_addr).await {
+                    return;
+                }
+            }
+            let _connection_gauge = ConnectionGauge::new(&*config.metrics);
+            if let Err(e) = socket.set_nodelay(config.tcp_nodelay) {
+                eprintln!("Failed to set TCP_NODELAY: {}", e);
+            }
+            let frames = match frames(socket, config).await {
+                Ok(frames) => frames,
+                Err(e) => {
+                    eprintln!("Failed to set up connection: {}", e);
+                    return;
+                }
+            };
+            let initial_service = factory(shared_ctx).await;
+            let mut service_collection =
+                ServerCollection::new(config.max_services_per_connection, config.wire_format);
+            if let Err(e) = handle_connection(
+                &mut service_collection,
+                config,
+                frames,
+                peer_addr,
+                initial_service,
+            )
+            .await
+            {
                 eprintln!("Connection handler terminated due to error: {}", e);
             };
         });
     }
 }
 
-async fn handle_connection<
-    T: for<'a> RustyRpcServiceServer<'a> + Default,
-    RW: AsyncRead + AsyncWrite + Unpin,
->(
+/// Splits a TCP connection into frames with the usual length-delimited codec.
+#[cfg(feature = "tcp")]
+fn frames_over_tcp(
+    socket: TcpStream,
+    config: &ServerConfig,
+) -> Ready<io::Result<Framed<TcpStream, LengthDelimitedCodec>>> {
+    ready(Ok(Framed::new(
+        socket,
+        codec::new_codec(config.max_frame_length),
+    )))
+}
+
+async fn handle_connection<T: for<'a> RustyRpcServiceServer<'a>, S: FrameStreamSink>(
     service_collection: &mut ServerCollection,
-    read_write: RW,
-) -> io::Result<()> {
+    config: &ServerConfig,
+    // This implements Stream<Item=io::Result<BytesMut>> and Sink<Bytes>.
+    // So we can send and receive "packets" of byte blocks of arbitrary size.
+    bytes_stream_sink: S,
+    peer_addr: SocketAddr,
+    initial_service: T,
+) -> RpcResult<()> {
+    let connection_span = trace::connection_span(peer_addr);
+    service_collection.current_span = connection_span.clone();
+    let byte_counts = Arc::new(ByteCounts::default());
+    let mut bytes_stream_sink = CountingFrames::new(bytes_stream_sink, byte_counts.clone());
+    let mut context = ConnectionContext {
+        peer_addr,
+        credential: None,
+    };
+    if let Some(authenticator) = &config.authenticator {
+        let credential =
+            authenticate_client(&mut bytes_stream_sink, &**authenticator, config.wire_format)
+                .await?;
+        context.credential = Some(credential);
+    }
+
     // Add initial service.
     let initial_service_id =
-        unsafe { service_collection.register_service(Box::new(T::default()), None) };
+        unsafe { service_collection.register_service(Box::new(initial_service), None)? };
     assert_eq!(initial_service_id.0, 0);
 
-    // This implements Stream<Item=io::Result<BytesMut>> and Sink<Bytes>.
-    // So we can send and receive "packets" of byte blocks of arbitrary size.
-    let mut bytes_stream_sink = Framed::new(read_write, LengthDelimitedCodec::new());
-
-    while let Some(received_bytes_result) = bytes_stream_sink.next().await {
-        let received_bytes = received_bytes_result?; // Handle I/O errors.
-        let client_message =
-            ClientMessage::try_from(received_bytes.freeze()).map_err(other_io_error)?;
-        let message_to_send: ServerMessage = match client_message {
-            ClientMessage::DropService(service_id) => {
-                let service_arc = service_collection
-                    .remove_service_entry_arc(service_id)
-                    .ok_or_else(|| {
-                        string_io_error(format!("Invalid service ID: {}", service_id.0))
-                    })?;
-
-                let service_mutex = Arc::try_unwrap(service_arc)
-                    .ok() // Needed because the Err field doesn't impl Debug.
-                    .expect("Client attempted to drop a service that is still in use.");
-                std::mem::drop(service_mutex.into_inner());
-
-                ServerMessage::DropServiceDone
-            }
-            ClientMessage::CallMethod(service_id, method_id, method_args) => {
-                let service_entry_arc = service_collection
-                    .get_service_entry_arc(service_id)
-                    .ok_or_else(|| {
-                        string_io_error(format!("Invalid service ID: {}", service_id.0))
-                    })?;
-                // Leak since the parse_and_call_method_locally method should
-                // deallocate or store the guard.
-                let service_entry_guard =
-                    Box::leak(Box::new(service_entry_arc.try_lock().expect(
-                        "Service somehow in use while trying to call a method on it.",
-                    )));
-                let future = unsafe {
-                    let service_entry_raw = transmute::<
-                        &mut MutexGuard<'_, ServerEntry>,
-                        *mut MutexGuard<'static, ServerEntry>,
-                    >(service_entry_guard);
-                    let server = service_entry_guard.server();
-                    server.parse_and_call_method_locally(
-                        RawBox::new(service_entry_raw),
-                        method_id,
-                        method_args,
-                        service_collection,
-                    )
-                    // service_entry_raw goes out of scope before await,
-                    // so the returned future from this function is still Sync+Send.
+    let result = trace::instrument(
+        handle_messages(service_collection, config, &context, &mut bytes_stream_sink),
+        connection_span,
+    )
+    .await;
+    // Services that the client didn't close are dropped as soon as the
+    // connection ends.
+    service_collection.close_all_services().await;
+    if let Some(interceptor) = &config.interceptor {
+        interceptor.on_connection_closed(&context, &byte_counts);
+    }
+    match result {
+        // The client went away, possibly while a response was being sent. This
+        // is a normal way for a connection to end.
+        Err(e) if is_disconnect(&e) => Ok(()),
+        result => result,
+    }
+}
+
+/// Handles messages from the client until it stops sending them.
+///
+/// Messages are handled one at a time. The client waits for the response to
+/// each call before it sends the next message, and a call that arrives while
+/// another one is running is rejected as malformed (see
+/// [handle_until_cancelled]). So at most one method call of each connection
+/// runs at a time, and no limit on concurrent calls is needed.
+///
+/// The client doesn't wait for oneway calls, so it can send messages faster
+/// than they are handled. But only a few messages are read ahead while a
+/// oneway call runs (see [handle_oneway]), so TCP backpressure slows down such
+/// a client instead of the messages being buffered without bound.
+async fn handle_messages<S: FrameStreamSink>(
+    service_collection: &mut ServerCollection,
+    config: &ServerConfig,
+    context: &ConnectionContext,
+    bytes_stream_sink: &mut S,
+) -> RpcResult<()> {
+    let mut read_ahead = VecDeque::new();
+    loop {
+        let next_message = next_client_message(config, bytes_stream_sink, &mut read_ahead);
+        let next_message = match config.idle_timeout {
+            Some(idle_timeout) => timeout(idle_timeout, next_message)
+                .await
+                .map_err(|_| RustyRpcError::Timeout)?,
+            None => next_message.await,
+        };
+        let Some(client_message_result) = next_message else {
+            break;
+        };
+        let client_message = client_message_result?; // Handle I/O errors.
+        let message_to_send = match client_message {
+            // Fatal errors end the whole connection, but failed calls in a
+            // batch only fail their own part of it.
+            ClientMessage::Batch(messages) => {
+                let handle_batch = async {
+                    let mut responses = Vec::with_capacity(messages.len());
+                    for message in messages {
+                        responses.push(
+                            handle_message(service_collection, config, context, message).await?,
+                        );
+                    }
+                    Ok(ServerMessage::Batch(responses))
                 };
-                future.await?
+                handle_until_cancelled(
+                    config,
+                    bytes_stream_sink,
+                    &mut read_ahead,
+                    handle_batch,
+                    None,
+                )
+                .await?
+            }
+            message @ ClientMessage::CallMethod(..) => {
+                let (call_stream, method_call_stream) = streaming::server_call_channels();
+                service_collection.call_stream = Some(method_call_stream);
+                let handle_call = handle_message(service_collection, config, context, message);
+                let result = handle_until_cancelled(
+                    config,
+                    bytes_stream_sink,
+                    &mut read_ahead,
+                    handle_call,
+                    Some(call_stream),
+                )
+                .await;
+                service_collection.call_stream = None;
+                result?
+            }
+            // The call that this was meant for already finished. A streaming
+            // method can return before the client is done sending items.
+            ClientMessage::Cancel | ClientMessage::StreamItem(_) | ClientMessage::StreamEnd => {
+                continue
+            }
+            // There is no response, so the client doesn't wait, and can send
+            // the next message while the call runs. So the call can't be
+            // cancelled.
+            ClientMessage::Notify(service_id, method_id, method_args, metadata) => {
+                let message =
+                    ClientMessage::CallMethod(service_id, method_id, method_args, metadata);
+                let handle_call = handle_message(service_collection, config, context, message);
+                handle_oneway(config, bytes_stream_sink, &mut read_ahead, handle_call).await?;
+                continue;
+            }
+            message => handle_message(service_collection, config, context, message).await?,
+        };
+
+        send_server_message(config, bytes_stream_sink, message_to_send).await?;
+    }
+
+    Ok(())
+}
+
+/// The most messages, other than heartbeats, that are read ahead while a
+/// oneway call runs. The client can send the next call and then wait for it,
+/// sending heartbeats, so there must be room for more than one.
+const MAX_READ_AHEAD: usize = 2;
+
+/// Returns the next message from the client, or `None` once the client is
+/// done. Messages that were read ahead come first, in the order they arrived.
+async fn next_client_message<S: FrameStreamSink>(
+    config: &ServerConfig,
+    bytes_stream_sink: &mut S,
+    read_ahead: &mut VecDeque<Option<RpcResult<ClientMessage>>>,
+) -> Option<RpcResult<ClientMessage>> {
+    match read_ahead.pop_front() {
+        Some(next) => next,
+        None => receive_client_message(config, bytes_stream_sink).await,
+    }
+}
+
+/// Receives and decodes the next message from the client, or returns `None`
+/// once the client is done.
+async fn receive_client_message<S: FrameStreamSink>(
+    config: &ServerConfig,
+    bytes_stream_sink: &mut S,
+) -> Option<RpcResult<ClientMessage>> {
+    let received_bytes = match bytes_stream_sink.next().await? {
+        Ok(x) => x,
+        Err(e) => return Some(Err(e.into())),
+    };
+    config
+        .metrics
+        .increment_counter(metrics::RECEIVED_BYTES_TOTAL, received_bytes.len() as u64);
+    Some(config.wire_format.decode(&received_bytes))
+}
+
+/// Awaits `future`, which handles a oneway call. The client doesn't wait for
+/// the call, so meanwhile, heartbeats are answered right away, so that a long
+/// call doesn't look like a dead server. Other messages, and the end of the
+/// messages, are put into `read_ahead` to be handled after the call. Once
+/// [MAX_READ_AHEAD] of them were read, nothing more is read until the call is
+/// done.
+async fn handle_oneway<S: FrameStreamSink>(
+    config: &ServerConfig,
+    bytes_stream_sink: &mut S,
+    read_ahead: &mut VecDeque<Option<RpcResult<ClientMessage>>>,
+    future: impl Future<Output = RpcResult<ServerMessage>>,
+) -> RpcResult<()> {
+    pin_mut!(future);
+    // Nothing comes after the end of the messages or an error.
+    while read_ahead.len() < MAX_READ_AHEAD
+        && !matches!(read_ahead.back(), Some(None | Some(Err(_))))
+    {
+        let next = {
+            let next_message = receive_client_message(config, bytes_stream_sink);
+            pin_mut!(next_message);
+            match select(future.as_mut(), next_message).await {
+                Either::Left((result, _)) => return result.map(drop),
+                Either::Right((next, _)) => next,
+            }
+        };
+        match next {
+            Some(Ok(ClientMessage::Ping)) => {
+                send_server_message(config, bytes_stream_sink, ServerMessage::Pong).await?
+            }
+            next => read_ahead.push_back(next),
+        }
+    }
+    future.await.map(drop)
+}
+
+/// Awaits `future`, unless the client sends [ClientMessage::Cancel] first, in
+/// which case `future` is dropped, which cancels it.
+///
+/// The only other messages that the client sends while it waits for a
+/// response are heartbeats, which are answered right away, and the items of a
+/// streaming call. `call_stream` holds the ends of
+/// the channels whose other ends a streaming method takes from the
+/// [ServerCollection]. The client's items are passed on to the method, and the
+/// method's items are sent to the client before the response. The next item is
+/// only read once the method has room for it, so a method that doesn't keep up
+/// slows down the client instead of the items being buffered. Messages in
+/// `read_ahead` are handled before any that weren't read yet.
+async fn handle_until_cancelled<S: FrameStreamSink>(
+    config: &ServerConfig,
+    bytes_stream_sink: &mut S,
+    read_ahead: &mut VecDeque<Option<RpcResult<ClientMessage>>>,
+    future: impl Future<Output = RpcResult<ServerMessage>>,
+    call_stream: Option<ConnectionCallStream>,
+) -> RpcResult<ServerMessage> {
+    pin_mut!(future);
+    let (mut requests, mut responses) = call_stream.unzip();
+    loop {
+        let next = {
+            let next_response = async {
+                match &mut responses {
+                    Some(responses) => responses.next().await,
+                    None => pending().await,
+                }
+            };
+            let next_message = async {
+                if let Some(requests) = &mut requests {
+                    // This fails if the method dropped its receiver, in which
+                    // case items are discarded.
+                    let _ = poll_fn(|cx| requests.poll_ready(cx)).await;
+                }
+                next_client_message(config, bytes_stream_sink, read_ahead).await
+            };
+            pin_mut!(next_response);
+            pin_mut!(next_message);
+            match select(future.as_mut(), select(next_response, next_message)).await {
+                Either::Left((result, _)) => Either::Left(result),
+                Either::Right((Either::Left((item, _)), _)) => Either::Right(Either::Left(item)),
+                Either::Right((Either::Right((message, _)), _)) => {
+                    Either::Right(Either::Right(message))
+                }
+            }
+        };
+        let client_message_result = match next {
+            Either::Left(result) => {
+                // Items that the method sent after it returned are dropped.
+                if let (Ok(_), Some(responses)) = (&result, &mut responses) {
+                    responses.close();
+                    while let Some(item) = responses.next().await {
+                        let message = ServerMessage::StreamItem(item);
+                        send_server_message(config, bytes_stream_sink, message).await?;
+                    }
+                }
+                return result;
+            }
+            Either::Right(Either::Left(Some(item))) => {
+                let message = ServerMessage::StreamItem(item);
+                send_server_message(config, bytes_stream_sink, message).await?;
+                continue;
+            }
+            // The method won't send any more items.
+            Either::Right(Either::Left(None)) => {
+                responses = None;
+                continue;
+            }
+            Either::Right(Either::Right(Some(client_message_result))) => client_message_result,
+            // The client is gone, so sending the response will fail. Until then,
+            // the call finishes as usual, except that a streaming method can't
+            // send or receive items anymore.
+            Either::Right(Either::Right(None)) => {
+                drop(requests);
+                drop(responses);
+                return future.await;
             }
         };
+        match client_message_result? {
+            ClientMessage::Cancel => {
+                return Ok(ServerMessage::Error("The call was cancelled.".to_string()))
+            }
+            ClientMessage::StreamItem(item) => match &mut requests {
+                // The method might have stopped receiving, which is fine.
+                Some(requests) => {
+                    let _ = requests.start_send(item);
+                }
+                None => {
+                    return Err(RustyRpcError::MalformedMessage(
+                        "Received a stream item while no stream was open.".to_string(),
+                    ))
+                }
+            },
+            ClientMessage::StreamEnd if requests.is_some() => requests = None,
+            ClientMessage::Ping => {
+                send_server_message(config, bytes_stream_sink, ServerMessage::Pong).await?
+            }
+            _ => {
+                return Err(RustyRpcError::MalformedMessage(
+                    "Received a message while a call was in progress.".to_string(),
+                ))
+            }
+        }
+    }
+}
 
-        bytes_stream_sink.send(Bytes::from(message_to_send)).await?;
+/// Whether the error means that the client closed the connection, as opposed to
+/// something actually going wrong.
+fn is_disconnect(e: &RustyRpcError) -> bool {
+    match e {
+        RustyRpcError::ConnectionClosed => true,
+        RustyRpcError::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+        ),
+        _ => false,
     }
+}
 
+/// Handles one message from the client, and returns the response. Returning an
+/// error means that the connection should be closed.
+async fn handle_message(
+    service_collection: &mut ServerCollection,
+    config: &ServerConfig,
+    context: &ConnectionContext,
+    client_message: ClientMessage,
+) -> RpcResult<ServerMessage> {
+    let message_to_send = match client_message {
+        ClientMessage::DropService(service_id) => {
+            // If the service is still in use, then it isn't dropped, and
+            // drop_service reports the error.
+            if let Some(service_entry_arc) = service_collection.get_service_entry_arc(service_id) {
+                if let Ok(mut service_entry_guard) = service_entry_arc.try_lock() {
+                    unsafe { service_entry_guard.server() }.on_drop().await;
+                }
+            }
+            match service_collection.drop_service(service_id) {
+                Ok(()) => ServerMessage::DropServiceDone,
+                Err(e) => ServerMessage::Error(e.to_string()),
+            }
+        }
+        ClientMessage::CallMethod(service_id, method_id, method_args, metadata) => {
+            let service_entry_arc = service_collection
+                .get_service_entry_arc(service_id)
+                .ok_or_else(|| {
+                    RustyRpcError::MalformedMessage(format!("Invalid service ID: {}", service_id.0))
+                })?;
+            // The service is locked if a service that borrows from it is still
+            // alive. Only this task can close that service, so waiting for the
+            // lock would deadlock.
+            let Ok(mut service_entry_guard) = service_entry_arc.try_lock() else {
+                let msg = format!(
+                    "Service {} is still in use by a service that borrows from it.",
+                    service_id.0
+                );
+                return Ok(ServerMessage::Error(msg));
+            };
+            let request_id = service_collection.call_count;
+            service_collection.call_count += 1;
+            let call_span = trace::call_span(
+                service_entry_guard.span(),
+                service_id,
+                method_id,
+                request_id,
+            );
+            // Services that the call returns get this span as their parent.
+            service_collection.current_span = call_span.clone();
+            if let Some(authorizer) = &config.authorizer {
+                let server = unsafe { service_entry_guard.server() };
+                let call = MethodCall {
+                    context,
+                    service_id,
+                    method_id,
+                    service_name: server.service_name(),
+                    method_name: server.method_name(method_id),
+                };
+                if let Err(msg) = authorizer.authorize(&call) {
+                    return Ok(ServerMessage::Error(msg));
+                }
+            }
+            // The parse_and_call_method_locally method either drops the guard
+            // or stores it in a returned service. The guard is dropped even if
+            // the call fails or is cancelled.
+            let future = unsafe {
+                let service_entry_guard = ServerGuard::new(transmute::<
+                    MutexGuard<'_, ServerEntry>,
+                    MutexGuard<'static, ServerEntry>,
+                >(service_entry_guard));
+                let server = (*service_entry_guard.get()).server();
+                server.parse_and_call_method_locally(
+                    service_entry_guard,
+                    method_id,
+                    method_args,
+                    service_collection,
+                )
+            };
+            if let Some(interceptor) = &config.interceptor {
+                interceptor.on_request(service_id, method_id);
+            }
+            let start_time = Instant::now();
+            let result = trace::instrument(with_call_metadata(metadata, future), call_span).await;
+            let elapsed = start_time.elapsed();
+            if let Some(interceptor) = &config.interceptor {
+                interceptor.on_response(service_id, method_id, elapsed);
+            }
+            config.metrics.increment_counter(metrics::CALLS_TOTAL, 1);
+            config
+                .metrics
+                .record_histogram(metrics::CALL_DURATION_SECONDS, elapsed.as_secs_f64());
+            result?
+        }
+        ClientMessage::Ping => ServerMessage::Pong,
+        ClientMessage::Authenticate(_) => match config.authenticator {
+            // Any credential is fine if there's no authenticator.
+            None => ServerMessage::Authenticated,
+            Some(_) => ServerMessage::Error("Already authenticated.".to_string()),
+        },
+        ClientMessage::Batch(_) => ServerMessage::Error("Batches cannot be nested.".to_string()),
+        ClientMessage::Cancel => {
+            ServerMessage::Error("Cancellations cannot be batched.".to_string())
+        }
+        ClientMessage::Notify(..) => {
+            ServerMessage::Error("Oneway calls cannot be batched.".to_string())
+        }
+        ClientMessage::StreamItem(_) | ClientMessage::StreamEnd => {
+            ServerMessage::Error("Stream items cannot be batched.".to_string())
+        }
+    };
+    Ok(message_to_send)
+}
+
+async fn send_server_message<S: FrameStreamSink>(
+    config: &ServerConfig,
+    bytes_stream_sink: &mut S,
+    message: ServerMessage,
+) -> RpcResult<()> {
+    let encoded = config.wire_format.encode(&message);
+    if encoded.len() <= config.chunk_size {
+        return send_frame(config, bytes_stream_sink, encoded).await;
+    }
+    // A chunk size of 0 would never make progress.
+    let mut chunks = encoded.chunks(config.chunk_size.max(1)).peekable();
+    while let Some(chunk) = chunks.next() {
+        let chunk_message = if chunks.peek().is_some() {
+            ServerMessage::DataChunk(chunk.to_vec())
+        } else {
+            ServerMessage::DataEnd(chunk.to_vec())
+        };
+        let encoded_chunk = config.wire_format.encode(&chunk_message);
+        send_frame(config, bytes_stream_sink, encoded_chunk).await?;
+    }
     Ok(())
 }
 
+/// Sends one frame to the client, and counts its bytes.
+async fn send_frame<S: FrameStreamSink>(
+    config: &ServerConfig,
+    bytes_stream_sink: &mut S,
+    bytes: Vec<u8>,
+) -> RpcResult<()> {
+    config
+        .metrics
+        .increment_counter(metrics::SENT_BYTES_TOTAL, bytes.len() as u64);
+    bytes_stream_sink.send(Bytes::from(bytes)).await?;
+    Ok(())
+}
+
+/// Receives the client's credential and checks it. If it is accepted, it is
+/// returned. Otherwise, the client is told so, and an error is returned.
+async fn authenticate_client<S: FrameStreamSink>(
+    bytes_stream_sink: &mut S,
+    authenticator: &dyn Authenticator,
+    wire_format: WireFormat,
+) -> RpcResult<Vec<u8>> {
+    let received_bytes = bytes_stream_sink
+        .next()
+        .await
+        .ok_or(RustyRpcError::ConnectionClosed)??;
+    let client_message: ClientMessage = wire_format.decode(&received_bytes)?;
+    let error_message = match client_message {
+        ClientMessage::Authenticate(credential) => {
+            if authenticator.authenticate(credential.clone()).await {
+                let response = wire_format.encode(&ServerMessage::Authenticated);
+                bytes_stream_sink.send(Bytes::from(response)).await?;
+                return Ok(credential);
+            }
+            "Authentication failed."
+        }
+        _ => "Authentication required.",
+    };
+    let response = wire_format.encode(&ServerMessage::Error(error_message.to_string()));
+    bytes_stream_sink.send(Bytes::from(response)).await?;
+    Err(RustyRpcError::AuthenticationFailed)
+}
+
 /// Start a client connection with the specified initial service.
 pub async fn start_client<
     T: RustyRpcServiceClient + ?Sized + 'static,
@@ -127,19 +770,138 @@ pub async fn start_client<
 >(
     read_write: RW,
 ) -> ServiceRefMut<'static, T> {
-    let initial_service_id = ServiceId(0);
-    let bytes_stream_sink = Framed::new(read_write, LengthDelimitedCodec::new());
-    let client_stream_sink = bytes_stream_sink
-        .map(
-            |in_bytes: io::Result<BytesMut>| -> io::Result<ServerMessage> {
-                in_bytes.and_then(|x| ServerMessage::try_from(x.freeze()).map_err(other_io_error))
-            },
-        )
-        .with(|out_message: ClientMessage| {
-            futures::future::ready(io::Result::Ok(Bytes::from(out_message)))
-        });
-    let wrapped: Arc<Mutex<dyn ClientStreamSink + 'static>> =
-        Arc::new(Mutex::new(client_stream_sink));
-    let proxy = T::ServiceProxy::from_service_id(initial_service_id, wrapped as _);
+    start_client_with_config(read_write, ClientConfig::default()).await
+}
+
+/// Start a client connection like [start_client], but with the specified
+/// options instead of the default ones.
+pub async fn start_client_with_config<
+    T: RustyRpcServiceClient + ?Sized + 'static,
+    RW: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+>(
+    read_write: RW,
+    config: ClientConfig,
+) -> ServiceRefMut<'static, T> {
+    let (connection, _) = new_client_connection(read_write, config);
+    initial_service_for_connection(connection)
+}
+
+/// Start a client connection like [start_client_with_config], and also return
+/// the number of bytes sent and received on it. The counts keep being updated
+/// until the connection is closed.
+pub async fn start_client_with_byte_counts<
+    T: RustyRpcServiceClient + ?Sized + 'static,
+    RW: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+>(
+    read_write: RW,
+    config: ClientConfig,
+) -> (ServiceRefMut<'static, T>, Arc<ByteCounts>) {
+    let (connection, byte_counts) = new_client_connection(read_write, config);
+    (initial_service_for_connection(connection), byte_counts)
+}
+
+/// Start a client connection like [start_client_with_config], but over a
+/// stream and sink of messages instead of a byte stream. This doesn't need
+/// TCP, or any other part of tokio's I/O, so it can be used for other
+/// transports. The messages must reach a server as frames like the ones that
+/// [start_client] sends.
+pub fn start_client_with_stream_sink<
+    T: RustyRpcServiceClient + ?Sized + 'static,
+    S: ClientStreamSink + 'static,
+>(
+    stream_sink: S,
+    config: ClientConfig,
+) -> ServiceRefMut<'static, T> {
+    let connection = Arc::new(ClientConnection::new(Box::new(stream_sink), config));
+    initial_service_for_connection(connection)
+}
+
+/// Connect to a server over TCP, and start a client connection like
+/// [start_client_with_config].
+///
+/// Each connection attempt is given up after `config.connect_timeout`. A
+/// failed attempt is retried up to `config.connect_retries` times, waiting
+/// `config.connect_backoff` before the first retry, and twice as long before
+/// each retry after that.
+#[cfg(feature = "tcp")]
+pub async fn connect_client<T, A>(
+    addr: A,
+    config: ClientConfig,
+) -> RpcResult<ServiceRefMut<'static, T>>
+where
+    T: RustyRpcServiceClient + ?Sized + 'static,
+    A: ToSocketAddrs + Clone + fmt::Debug,
+{
+    let mut backoff = config.connect_backoff;
+    let mut attempt = 0;
+    let stream = loop {
+        let last_error =
+            match timeout(config.connect_timeout, TcpStream::connect(addr.clone())).await {
+                Ok(Ok(stream)) => match stream.set_nodelay(config.tcp_nodelay) {
+                    Ok(()) => break stream,
+                    Err(e) => e,
+                },
+                Ok(Err(e)) => e,
+                Err(_) => io::Error::new(io::ErrorKind::TimedOut, "Connection attempt timed out."),
+            };
+        if attempt == config.connect_retries {
+            return Err(RustyRpcError::Io(io::Error::new(
+                last_error.kind(),
+                format!(
+                    "Unable to connect to {:?} after {} attempts: {}",
+                    addr,
+                    attempt + 1,
+                    last_error
+                ),
+            )));
+        }
+        attempt += 1;
+        sleep(backoff).await;
+        backoff *= 2;
+    };
+    Ok(start_client_with_config(stream, config).await)
+}
+
+/// Start a client connection like [start_client_with_config], but first
+/// authenticate with the given credential. See [ServerConfig::authenticator].
+pub async fn start_client_with_credential<
+    T: RustyRpcServiceClient + ?Sized + 'static,
+    RW: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+>(
+    read_write: RW,
+    config: ClientConfig,
+    credential: Vec<u8>,
+) -> RpcResult<ServiceRefMut<'static, T>> {
+    let (connection, _) = new_client_connection(read_write, config);
+    match connection
+        .call(ClientMessage::Authenticate(credential))
+        .await?
+    {
+        ServerMessage::Authenticated => Ok(initial_service_for_connection(connection)),
+        ServerMessage::Error(_) => Err(RustyRpcError::AuthenticationFailed),
+        _ => Err(RustyRpcError::MalformedMessage(
+            "Server sent an unexpected response to authentication.".to_string(),
+        )),
+    }
+}
+
+fn new_client_connection<RW: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    read_write: RW,
+    config: ClientConfig,
+) -> (Arc<ClientConnection>, Arc<ByteCounts>) {
+    let byte_counts = Arc::new(ByteCounts::default());
+    let frames = Framed::new(read_write, codec::new_codec(config.max_frame_length));
+    let frames = CountingFrames::new(frames, byte_counts.clone());
+    let client_stream_sink =
+        codec::client_stream_sink(frames, config.wire_format, config.max_response_length);
+    let connection = Arc::new(ClientConnection::new(Box::new(client_stream_sink), config));
+    (connection, byte_counts)
+}
+
+fn initial_service_for_connection<T: RustyRpcServiceClient + ?Sized + 'static>(
+    connection: Arc<ClientConnection>,
+) -> ServiceRefMut<'static, T> {
+    connection.start_heartbeats();
+    let proxy = T::ServiceProxy::from_service_id(ServiceId(0), connection);
     service_ref_from_service_proxy(proxy)
 }
@@ -1,13 +1,21 @@
 use std::{
+    collections::HashMap,
+    fmt,
     marker::PhantomData,
     ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Weak,
+    },
 };
 
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    traits::RustyRpcServiceServerWithKnownClientType, RustyRpcServiceClient, RustyRpcServiceServer,
+    client::ClientConnection, error::RpcResult, traits::RustyRpcServiceServerWithKnownClientType,
+    wire_format::decode_message_pack, RustyRpcServiceClient, RustyRpcServiceProxy,
+    RustyRpcServiceServer,
 };
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -19,15 +27,36 @@ impl ServiceId {
 }
 
 /// The message that the server responds to the client, giving back the RPC return value.
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum ServerMessage {
     DropServiceDone,
     MethodReturned(ReturnValue),
+    /// The request could not be handled. The connection stays open.
+    Error(String),
+    /// The response to [ClientMessage::Ping].
+    Pong,
+    /// The response to [ClientMessage::Authenticate] if the credential was
+    /// accepted.
+    Authenticated,
+    /// The responses to the messages of a [ClientMessage::Batch], in the same
+    /// order.
+    Batch(Vec<ServerMessage>),
+    /// A piece of a message that is too long to be sent in one frame (see
+    /// [crate::ServerConfig::chunk_size]). The encoded message is the
+    /// concatenation of the pieces in the `DataChunk` messages, followed by the
+    /// piece in the `DataEnd` message.
+    DataChunk(#[serde(with = "crate::serde_bytes")] Vec<u8>),
+    /// The last piece of a message that was split with
+    /// [ServerMessage::DataChunk].
+    DataEnd(#[serde(with = "crate::serde_bytes")] Vec<u8>),
+    /// An item that a streaming method sent to the client while it runs. This
+    /// isn't a response, since the call is only over once the method returns.
+    StreamItem(#[serde(with = "crate::serde_bytes")] Vec<u8>),
 }
 impl TryFrom<Bytes> for ServerMessage {
     type Error = rmp_serde::decode::Error;
-    fn try_from(bytes: Bytes) -> Result<ServerMessage, Self::Error> {
-        rmp_serde::decode::from_slice(&bytes)
+    fn try_from(bytes: Bytes) -> Result<ServerMessage, rmp_serde::decode::Error> {
+        decode_message_pack(&bytes)
     }
 }
 impl From<ServerMessage> for Bytes {
@@ -39,26 +68,56 @@ impl From<ServerMessage> for Bytes {
 }
 
 /// Represents the return value of an RPC call, as written on the wire.
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum ReturnValue {
-    Data(Vec<u8>),
+    Data(#[serde(with = "crate::serde_bytes")] Vec<u8>),
     Service(ServiceId),
+    /// Several services returned together, in order.
+    Services(Vec<ServiceId>),
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MethodId(pub u64);
 
 /// The message that the client sends to the server in order to call an RPC.
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
     DropService(ServiceId),
-    CallMethod(ServiceId, MethodId, MethodArgs),
+    /// The map holds metadata, such as a trace ID, which the service method
+    /// can read with [crate::call_metadata].
+    CallMethod(ServiceId, MethodId, MethodArgs, HashMap<String, String>),
+    /// Sent periodically to check that the server is still alive.
+    Ping,
+    /// Sent as the first message if the client has a credential.
+    Authenticate(Vec<u8>),
+    /// Several messages that the server handles one after another, in order.
+    /// If one of them fails with [ServerMessage::Error], the rest are still
+    /// handled. Batches cannot be nested.
+    Batch(Vec<ClientMessage>),
+    /// Cancels the method call or batch that the server is currently handling,
+    /// which then gets [ServerMessage::Error] as its response. There is no
+    /// response to this message itself. Since the client waits for each
+    /// response before sending the next message, there is at most one call to
+    /// cancel, and the server ignores this message if that call already
+    /// finished.
+    Cancel,
+    /// Calls a oneway method, like [ClientMessage::CallMethod]. The server
+    /// doesn't respond, even if the call fails, so the client doesn't wait for
+    /// the call to finish.
+    Notify(ServiceId, MethodId, MethodArgs, HashMap<String, String>),
+    /// An item for the streaming method that the server is currently running.
+    /// Like [ClientMessage::Cancel], this can be sent while the client waits
+    /// for the response to the call, and there is no response to it.
+    StreamItem(#[serde(with = "crate::serde_bytes")] Vec<u8>),
+    /// Tells the streaming method that the server is currently running that the
+    /// client won't send any more items.
+    StreamEnd,
 }
 impl TryFrom<Bytes> for ClientMessage {
     type Error = rmp_serde::decode::Error;
 
     fn try_from(bytes: Bytes) -> Result<ClientMessage, Self::Error> {
-        rmp_serde::decode::from_slice(&bytes)
+        decode_message_pack(&bytes)
     }
 }
 impl From<ClientMessage> for Bytes {
@@ -71,8 +130,8 @@ impl From<ClientMessage> for Bytes {
 
 /// Represents the data used to specify the method and arguments for a given RPC
 /// call, as written on the wire.
-#[derive(Serialize, Deserialize)]
-pub struct MethodArgs(pub Vec<u8>);
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MethodArgs(#[serde(with = "crate::serde_bytes")] pub Vec<u8>);
 
 enum InnerServiceRefMut<'a, T: RustyRpcServiceClient + ?Sized + 'a> {
     RemoteServiceRefMut(T::ServiceProxy, PhantomData<&'a T>),
@@ -90,6 +149,17 @@ enum InnerServiceRefMut<'a, T: RustyRpcServiceClient + ?Sized + 'a> {
 ///
 /// The type `T` should be something like `dyn MyService` (bare unsized dyn
 /// trait).
+///
+/// A service returned as `&mut service` borrows from the service whose method
+/// returned it. Until the returned service is closed, calling a method on the
+/// service it borrows from fails with [crate::RustyRpcError::ServerError]. The
+/// server handles the calls of a connection one at a time, so waiting for the
+/// borrow to end instead would never finish.
+///
+/// On the client side, dropping this without closing it panics, unless
+/// [crate::ClientConfig::auto_close_on_drop] is set, so ignoring a returned
+/// service is warned about.
+#[must_use = "service refs must be closed"]
 pub struct ServiceRefMut<'a, T: RustyRpcServiceClient + ?Sized + 'a>(
     /// Do enum inside struct to get private enum variants.
     InnerServiceRefMut<'a, T>,
@@ -102,6 +172,118 @@ impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> ServiceRefMut<'a, T> {
             PhantomData,
         ))
     }
+
+    /// Used on the client side, to wrap a proxy that was taken out with
+    /// [ServiceRefMut::map_proxy], so that it can be closed.
+    pub fn from_proxy(proxy: T::ServiceProxy) -> Self {
+        service_ref_from_service_proxy(proxy)
+    }
+
+    /// Used on the client side. Passes the proxy to `f`, and returns the
+    /// result. This is useful for wrapping the proxy in a type that also
+    /// implements the service trait, e.g. to log each call before forwarding
+    /// it to the proxy. The wrapper should close the proxy with
+    /// [ServiceRefMut::from_proxy] and [ServiceRefMut::close] when it is done.
+    /// Panics on the server side.
+    pub fn map_proxy<U>(self, f: impl FnOnce(T::ServiceProxy) -> U) -> U {
+        match self.0 {
+            InnerServiceRefMut::RemoteServiceRefMut(x, _) => f(x),
+            InnerServiceRefMut::OwnedLocalService(..) => {
+                panic!("Tried to map_proxy() a ServiceRefMut on server side.")
+            }
+        }
+    }
+
+    /// Used on the client side. Turns this into a [ChainedService], which
+    /// closes the service when it is dropped, so that a call can be chained
+    /// on it without binding it to a variable. Panics on the server side.
+    pub fn chained(self) -> ChainedService<'a, T> {
+        self.map_proxy(|proxy| ChainedService {
+            proxy: Some(proxy),
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Used on the client side. Returns a [WeakServiceRef] to the service,
+    /// which doesn't keep it open. Panics on the server side.
+    pub fn downgrade(&self) -> WeakServiceRef<'a, T> {
+        match &self.0 {
+            InnerServiceRefMut::RemoteServiceRefMut(x, _) => WeakServiceRef {
+                service_id: x.service_id(),
+                connection: Arc::downgrade(x.connection()),
+                open_clones: x.open_clones().clone(),
+                _phantom: PhantomData,
+            },
+            InnerServiceRefMut::OwnedLocalService(..) => {
+                panic!("Tried to downgrade() a ServiceRefMut on server side.")
+            }
+        }
+    }
+
+    /// Whether this is an owned server-side service, created with
+    /// [ServiceRefMut::new]. Such a service cannot be dereferenced.
+    pub fn is_local(&self) -> bool {
+        matches!(self.0, InnerServiceRefMut::OwnedLocalService(..))
+    }
+
+    /// Whether this is a client's reference to a service on the server. Such a
+    /// service can be dereferenced to call its methods.
+    pub fn is_remote(&self) -> bool {
+        matches!(self.0, InnerServiceRefMut::RemoteServiceRefMut(..))
+    }
+
+    /// Closes the service. On the client side, this deallocates the associated
+    /// resources on the server side. On the server side, this calls
+    /// [RustyRpcServiceServer::on_drop] and drops the owned service.
+    pub async fn close(self) -> RpcResult<()> {
+        match self.0 {
+            InnerServiceRefMut::RemoteServiceRefMut(mut x, _) => x.close_proxy().await,
+            InnerServiceRefMut::OwnedLocalService(mut x, _) => {
+                x.on_drop().await;
+                drop(x);
+                Ok(())
+            }
+        }
+    }
+
+    /// The connection of a client-side service. Panics on the server side.
+    pub(crate) fn connection(&self) -> &Arc<ClientConnection> {
+        match &self.0 {
+            InnerServiceRefMut::RemoteServiceRefMut(x, _) => x.connection(),
+            InnerServiceRefMut::OwnedLocalService(_, _) => {
+                panic!("Tried to batch calls on a ServiceRefMut on server side.")
+            }
+        }
+    }
+}
+/// Prints the ID of the service for remote services. Owned local services are
+/// printed without their contents.
+impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> fmt::Debug for ServiceRefMut<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            InnerServiceRefMut::RemoteServiceRefMut(x, _) => f
+                .debug_tuple("RemoteServiceRefMut")
+                .field(&x.service_id())
+                .finish(),
+            InnerServiceRefMut::OwnedLocalService(..) => {
+                f.debug_tuple("OwnedLocalService").finish_non_exhaustive()
+            }
+        }
+    }
+}
+/// Used only on the client side. See [RustyRpcServiceProxy] for how clones are
+/// closed.
+impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> Clone for ServiceRefMut<'a, T> {
+    fn clone(&self) -> Self {
+        match &self.0 {
+            InnerServiceRefMut::RemoteServiceRefMut(x, _) => ServiceRefMut(
+                InnerServiceRefMut::RemoteServiceRefMut(x.clone(), PhantomData),
+            ),
+            InnerServiceRefMut::OwnedLocalService(..) => {
+                panic!("Tried to clone() a ServiceRefMut on server side.")
+            }
+        }
+    }
 }
 /// Used only on the client side.
 impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> Deref for ServiceRefMut<'a, T> {
@@ -126,6 +308,108 @@ impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> DerefMut for ServiceRefMut<'a,
     }
 }
 
+/// A client-side service that is closed when it is dropped, so that it can be
+/// used as a temporary. For each method that returns a service, the proxy has
+/// a version whose name ends with `_chained` that returns this, so calls can be
+/// chained like `service.get_child_chained().await?.get_value().await?`.
+///
+/// The service is closed like with [crate::ClientConfig::auto_close_on_drop],
+/// regardless of that option: the close is sent in the background, and before
+/// the next call on the connection. Errors from closing it are ignored. Use
+/// [ChainedService::close] to see them.
+pub struct ChainedService<'a, T: RustyRpcServiceClient + ?Sized + 'a> {
+    /// Only `None` while being closed or dropped.
+    proxy: Option<T::ServiceProxy>,
+    _phantom: PhantomData<&'a T>,
+}
+impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> ChainedService<'a, T> {
+    /// Closes the service now, like [ServiceRefMut::close].
+    pub async fn close(mut self) -> RpcResult<()> {
+        let mut proxy = self.proxy.take().expect("ChainedService closed twice.");
+        proxy.close_proxy().await
+    }
+}
+impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> Drop for ChainedService<'a, T> {
+    fn drop(&mut self) {
+        if let Some(proxy) = self.proxy.take() {
+            proxy.close_in_background();
+        }
+    }
+}
+impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> fmt::Debug for ChainedService<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ChainedService")
+            .field(&self.proxy.as_ref().map(|x| x.service_id()))
+            .finish()
+    }
+}
+impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> Deref for ChainedService<'a, T> {
+    type Target = T::ServiceProxy;
+    fn deref(&self) -> &T::ServiceProxy {
+        self.proxy
+            .as_ref()
+            .expect("ChainedService used after close.")
+    }
+}
+impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> DerefMut for ChainedService<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.proxy
+            .as_mut()
+            .expect("ChainedService used after close.")
+    }
+}
+
+/// A client-side reference to a service that doesn't keep the service open,
+/// created with [ServiceRefMut::downgrade]. It doesn't have to be closed, and
+/// dropping it does nothing, so the service is still dropped on the server once
+/// the [ServiceRefMut]s to it are closed. Until then,
+/// [WeakServiceRef::upgrade] gives a usable service.
+pub struct WeakServiceRef<'a, T: RustyRpcServiceClient + ?Sized + 'a> {
+    service_id: ServiceId,
+    connection: Weak<ClientConnection>,
+    /// Shared with the proxies of the service.
+    open_clones: Arc<AtomicUsize>,
+    _phantom: PhantomData<&'a T>,
+}
+impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> WeakServiceRef<'a, T> {
+    /// Returns another [ServiceRefMut] to the service, which must be closed like
+    /// a clone of it. Returns `None` if every [ServiceRefMut] to the service was
+    /// already closed, or the connection is gone.
+    pub fn upgrade(&self) -> Option<ServiceRefMut<'a, T>> {
+        let connection = self.connection.upgrade()?;
+        // The service is only dropped on the server once the count reaches
+        // zero, and then it is never increased again.
+        self.open_clones
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                (x != 0).then_some(x + 1)
+            })
+            .ok()?;
+        let proxy = T::ServiceProxy::from_open_clones(
+            self.service_id,
+            connection,
+            self.open_clones.clone(),
+        );
+        Some(service_ref_from_service_proxy(proxy))
+    }
+}
+impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> Clone for WeakServiceRef<'a, T> {
+    fn clone(&self) -> Self {
+        WeakServiceRef {
+            service_id: self.service_id,
+            connection: self.connection.clone(),
+            open_clones: self.open_clones.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+impl<'a, T: RustyRpcServiceClient + ?Sized + 'a> fmt::Debug for WeakServiceRef<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("WeakServiceRef")
+            .field(&self.service_id)
+            .finish()
+    }
+}
+
 /// For macro and internal use only.
 pub fn service_ref_from_service_proxy<'a, T: RustyRpcServiceClient + ?Sized + 'a>(
     service_proxy: T::ServiceProxy,
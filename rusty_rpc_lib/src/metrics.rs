@@ -0,0 +1,91 @@
+//! Metrics about servers, for monitoring systems such as Prometheus.
+//!
+//! The server reports metrics to a [MetricsSink], which can forward them to
+//! any metrics library. The metric names are the constants in this module.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Gauge: the number of currently open connections.
+pub const ACTIVE_CONNECTIONS: &str = "rusty_rpc_active_connections";
+/// Counter: the total number of method calls.
+pub const CALLS_TOTAL: &str = "rusty_rpc_calls_total";
+/// Histogram: the time each method call took, in seconds.
+pub const CALL_DURATION_SECONDS: &str = "rusty_rpc_call_duration_seconds";
+/// Counter: the total size of the frames received, in bytes.
+pub const RECEIVED_BYTES_TOTAL: &str = "rusty_rpc_received_bytes_total";
+/// Counter: the total size of the frames sent, in bytes.
+pub const SENT_BYTES_TOTAL: &str = "rusty_rpc_sent_bytes_total";
+
+/// Receives metrics from the server. Set it with
+/// [crate::ServerConfig::metrics]. All methods do nothing by default.
+///
+/// The methods are called from the connection tasks, so they should return
+/// quickly.
+pub trait MetricsSink: Send + Sync {
+    /// Adds `value` to the counter `name`.
+    fn increment_counter(&self, name: &'static str, value: u64) {
+        let _ = (name, value);
+    }
+
+    /// Adds `delta` (which might be negative) to the gauge `name`.
+    fn add_to_gauge(&self, name: &'static str, delta: i64) {
+        let _ = (name, delta);
+    }
+
+    /// Records one observation of `value` in the histogram `name`.
+    fn record_histogram(&self, name: &'static str, value: f64) {
+        let _ = (name, value);
+    }
+}
+
+/// A [MetricsSink] that ignores all metrics. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopMetricsSink;
+impl MetricsSink for NoopMetricsSink {}
+
+/// The number of bytes sent and received on one connection, counted as the
+/// total size of the frames, like [SENT_BYTES_TOTAL] and
+/// [RECEIVED_BYTES_TOTAL]. The counts are updated while the connection is
+/// open, and can still be read after it is closed.
+///
+/// Clients get this from [crate::start_client_with_byte_counts]. Servers give
+/// it to [crate::ServerInterceptor::on_connection_closed].
+#[derive(Debug, Default)]
+pub struct ByteCounts {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+impl ByteCounts {
+    /// The number of bytes sent so far.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes received so far.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Keeps [ACTIVE_CONNECTIONS] up to date for as long as it is alive, even if
+/// the connection handler panics.
+pub(crate) struct ConnectionGauge<'a>(&'a dyn MetricsSink);
+impl<'a> ConnectionGauge<'a> {
+    pub(crate) fn new(sink: &'a dyn MetricsSink) -> Self {
+        sink.add_to_gauge(ACTIVE_CONNECTIONS, 1);
+        ConnectionGauge(sink)
+    }
+}
+impl Drop for ConnectionGauge<'_> {
+    fn drop(&mut self) {
+        self.0.add_to_gauge(ACTIVE_CONNECTIONS, -1);
+    }
+}
@@ -0,0 +1,66 @@
+//! Serialization for the `bytes` type in the protocol file. By default, serde
+//! writes byte slices as arrays of integers. These write them as a MessagePack
+//! binary instead, which can be deserialized without copying.
+
+use std::fmt;
+
+use serde::de::{Error, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The most bytes that are allocated up front for an array of bytes. The length
+/// of the array comes from the peer, which might claim far more bytes than it
+/// sends.
+pub(crate) const MAX_PREALLOCATED_BYTES: usize = 4096;
+
+/// Serializes the bytes as a binary. A `&[u8]` can be deserialized from it
+/// without copying.
+pub struct BytesRef<'a>(pub &'a [u8]);
+impl<'a> Serialize for BytesRef<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+/// Deserializes bytes that were serialized with [BytesRef].
+pub struct ByteBuf(pub Vec<u8>);
+impl<'de> Deserialize<'de> for ByteBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_byte_buf(ByteBufVisitor)
+    }
+}
+
+struct ByteBufVisitor;
+impl<'de> Visitor<'de> for ByteBufVisitor {
+    type Value = ByteBuf;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("bytes")
+    }
+
+    fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<ByteBuf, E> {
+        Ok(ByteBuf(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<ByteBuf, E> {
+        Ok(ByteBuf(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ByteBuf, A::Error> {
+        let capacity = seq.size_hint().unwrap_or(0).min(MAX_PREALLOCATED_BYTES);
+        let mut bytes = Vec::with_capacity(capacity);
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(ByteBuf(bytes))
+    }
+}
+
+/// For use with `#[serde(with = "...")]` on `Vec<u8>` struct fields.
+pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    BytesRef(bytes).serialize(serializer)
+}
+
+/// For use with `#[serde(with = "...")]` on `Vec<u8>` struct fields.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    ByteBuf::deserialize(deserializer).map(|x| x.0)
+}
@@ -0,0 +1,37 @@
+//! Serialization for the `char` type in the protocol file. A `char` is written
+//! as its Unicode scalar value, a `u32`, instead of as a one-character string
+//! like serde would.
+
+use serde::de::{Error, Unexpected};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Serializes a `char` as its Unicode scalar value. Deserializing a number
+/// that isn't a Unicode scalar value, such as a surrogate, fails with an error.
+#[derive(Default)]
+pub struct WireChar(pub char);
+impl Serialize for WireChar {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        u32::from(self.0).serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for WireChar {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = u32::deserialize(deserializer)?;
+        char::from_u32(value).map(WireChar).ok_or_else(|| {
+            D::Error::invalid_value(
+                Unexpected::Unsigned(value.into()),
+                &"a Unicode scalar value",
+            )
+        })
+    }
+}
+
+/// For use with `#[serde(with = "...")]` on `char` struct fields.
+pub fn serialize<S: Serializer>(value: &char, serializer: S) -> Result<S::Ok, S::Error> {
+    WireChar(*value).serialize(serializer)
+}
+
+/// For use with `#[serde(with = "...")]` on `char` struct fields.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<char, D::Error> {
+    WireChar::deserialize(deserializer).map(|x| x.0)
+}
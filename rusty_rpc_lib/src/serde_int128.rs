@@ -0,0 +1,77 @@
+//! Serialization for the `i128` and `u128` types in the protocol file.
+//! MessagePack has no 128-bit integers, so these are written as 16-byte
+//! big-endian binaries instead.
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::serde_bytes::{ByteBuf, BytesRef};
+
+/// Reads the 16 bytes that a 128-bit integer was written as.
+pub(crate) fn deserialize_16_bytes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<[u8; 16], D::Error> {
+    let bytes = ByteBuf::deserialize(deserializer)?.0;
+    bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| D::Error::invalid_length(bytes.len(), &"16 bytes"))
+}
+
+/// Serializes an `i128` as 16 big-endian bytes.
+#[derive(Default)]
+pub struct WireI128(pub i128);
+impl Serialize for WireI128 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        BytesRef(&self.0.to_be_bytes()).serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for WireI128 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_16_bytes(deserializer).map(|x| WireI128(i128::from_be_bytes(x)))
+    }
+}
+
+/// Serializes a `u128` as 16 big-endian bytes.
+#[derive(Default)]
+pub struct WireU128(pub u128);
+impl Serialize for WireU128 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        BytesRef(&self.0.to_be_bytes()).serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for WireU128 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_16_bytes(deserializer).map(|x| WireU128(u128::from_be_bytes(x)))
+    }
+}
+
+/// For use with `#[serde(with = "...")]` on `i128` struct fields.
+pub mod serde_i128 {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::WireI128;
+
+    pub fn serialize<S: Serializer>(value: &i128, serializer: S) -> Result<S::Ok, S::Error> {
+        WireI128(*value).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i128, D::Error> {
+        WireI128::deserialize(deserializer).map(|x| x.0)
+    }
+}
+
+/// For use with `#[serde(with = "...")]` on `u128` struct fields.
+pub mod serde_u128 {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::WireU128;
+
+    pub fn serialize<S: Serializer>(value: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+        WireU128(*value).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+        WireU128::deserialize(deserializer).map(|x| x.0)
+    }
+}
@@ -0,0 +1,48 @@
+//! Deserialization for `string` fields marked with `@lossy_utf8`. The
+//! MessagePack decoder hands a string that isn't valid UTF-8 to the visitor as
+//! bytes, which are then decoded with replacement characters. The CBOR decoder
+//! rejects such strings itself, so there this only accepts byte strings too.
+
+use std::fmt;
+
+use serde::de::{Error, Visitor};
+use serde::{Deserializer, Serializer};
+
+struct LossyStringVisitor;
+impl<'de> Visitor<'de> for LossyStringVisitor {
+    type Value = String;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string")
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<String, E> {
+        Ok(v.to_string())
+    }
+
+    fn visit_string<E: Error>(self, v: String) -> Result<String, E> {
+        Ok(v)
+    }
+
+    fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<String, E> {
+        Ok(String::from_utf8_lossy(v).into_owned())
+    }
+
+    fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<String, E> {
+        Ok(match String::from_utf8(v) {
+            Ok(x) => x,
+            Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+        })
+    }
+}
+
+/// For use with `#[serde(with = "...")]` on `String` struct fields. Strings
+/// are written as usual.
+pub fn serialize<S: Serializer>(string: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(string)
+}
+
+/// For use with `#[serde(with = "...")]` on `String` struct fields.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    deserializer.deserialize_string(LossyStringVisitor)
+}
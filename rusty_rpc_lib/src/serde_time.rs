@@ -0,0 +1,115 @@
+//! Serialization for the `duration` and `timestamp` types in the protocol file.
+//! Both are written as a pair of integers with nanosecond resolution, so that
+//! peers in other languages don't have to know how serde writes `std::time`
+//! types.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const NANOS_PER_SEC: u32 = 1_000_000_000;
+
+/// Serializes a `Duration` as `(seconds: u64, nanoseconds: u32)`, where the
+/// nanoseconds are less than one second.
+#[derive(Default)]
+pub struct WireDuration(pub Duration);
+impl Serialize for WireDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.0.as_secs(), self.0.subsec_nanos()).serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for WireDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (secs, nanos) = <(u64, u32)>::deserialize(deserializer)?;
+        if nanos >= NANOS_PER_SEC {
+            return Err(D::Error::custom("nanoseconds must be less than one second"));
+        }
+        Ok(WireDuration(Duration::new(secs, nanos)))
+    }
+}
+
+/// Serializes a `SystemTime` as `(seconds: i64, nanoseconds: u32)` since the
+/// Unix epoch, 1970-01-01 00:00:00 UTC. The nanoseconds are less than one
+/// second and count forward, so a time just before the epoch is `(-1,
+/// 999_999_999)`, as in Protocol Buffers' `Timestamp`. Defaults to the epoch.
+pub struct WireTimestamp(pub SystemTime);
+impl Default for WireTimestamp {
+    fn default() -> Self {
+        WireTimestamp(UNIX_EPOCH)
+    }
+}
+impl Serialize for WireTimestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error;
+        let out_of_range = || S::Error::custom("timestamp is out of range");
+        let (secs, nanos) = match self.0.duration_since(UNIX_EPOCH) {
+            Ok(x) => (
+                i64::try_from(x.as_secs()).map_err(|_| out_of_range())?,
+                x.subsec_nanos(),
+            ),
+            Err(e) => {
+                // i64::MIN seconds is one second further from the epoch than
+                // i64::MAX seconds, so the seconds are negated as an i128.
+                let before = e.duration();
+                let (secs, nanos) = match before.subsec_nanos() {
+                    0 => (i128::from(before.as_secs()), 0),
+                    nanos => (i128::from(before.as_secs()) + 1, NANOS_PER_SEC - nanos),
+                };
+                (i64::try_from(-secs).map_err(|_| out_of_range())?, nanos)
+            }
+        };
+        (secs, nanos).serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for WireTimestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (secs, nanos) = <(i64, u32)>::deserialize(deserializer)?;
+        if nanos >= NANOS_PER_SEC {
+            return Err(D::Error::custom("nanoseconds must be less than one second"));
+        }
+        let time = if secs >= 0 {
+            UNIX_EPOCH.checked_add(Duration::new(secs.unsigned_abs(), nanos))
+        } else {
+            UNIX_EPOCH
+                .checked_sub(Duration::from_secs(secs.unsigned_abs()))
+                .and_then(|x| x.checked_add(Duration::from_nanos(nanos.into())))
+        };
+        time.map(WireTimestamp)
+            .ok_or_else(|| D::Error::custom("timestamp is out of range"))
+    }
+}
+
+/// For use with `#[serde(with = "...")]` on `Duration` struct fields.
+pub mod serde_duration {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::WireDuration;
+
+    pub fn serialize<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        WireDuration(*value).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        WireDuration::deserialize(deserializer).map(|x| x.0)
+    }
+}
+
+/// For use with `#[serde(with = "...")]` on `SystemTime` struct fields.
+pub mod serde_timestamp {
+    use std::time::SystemTime;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::WireTimestamp;
+
+    pub fn serialize<S: Serializer>(value: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        WireTimestamp(*value).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        WireTimestamp::deserialize(deserializer).map(|x| x.0)
+    }
+}
@@ -0,0 +1,32 @@
+//! Serialization for the `uuid` type in the protocol file. A UUID is written
+//! as its 16 bytes, like a 128-bit integer.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use uuid::Uuid;
+
+use crate::serde_bytes::BytesRef;
+use crate::serde_int128::deserialize_16_bytes;
+
+/// Serializes a `Uuid` as its 16 bytes.
+#[derive(Default)]
+pub struct WireUuid(pub Uuid);
+impl Serialize for WireUuid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        BytesRef(self.0.as_bytes()).serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for WireUuid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_16_bytes(deserializer).map(|x| WireUuid(Uuid::from_bytes(x)))
+    }
+}
+
+/// For use with `#[serde(with = "...")]` on `Uuid` struct fields.
+pub fn serialize<S: Serializer>(value: &Uuid, serializer: S) -> Result<S::Ok, S::Error> {
+    WireUuid(*value).serialize(serializer)
+}
+
+/// For use with `#[serde(with = "...")]` on `Uuid` struct fields.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uuid, D::Error> {
+    WireUuid::deserialize(deserializer).map(|x| x.0)
+}
@@ -0,0 +1,73 @@
+//! Running several listeners, each with its own initial service, together.
+
+use std::future::{pending, Future};
+use std::io;
+use std::pin::Pin;
+
+use futures::future::{select, try_join_all, Either};
+use futures::pin_mut;
+use tokio::net::TcpListener;
+
+use crate::{start_server_with_config, RustyRpcServiceServer, ServerConfig};
+
+/// A server that accepts connections on several listeners at once. Each
+/// listener has its own initial service, but they all use the same
+/// [ServerConfig], so they share things like the [crate::MetricsSink], and they
+/// stop together.
+///
+/// Example:
+/// ```ignore
+/// Server::new(config)
+///     .listener(public_listener, (), |_| PublicServer::default())
+///     .listener(admin_listener, admin_state, |state| AdminServer::new(state))
+///     .run_until(shutdown_signal)
+///     .await?;
+/// ```
+pub struct Server {
+    config: ServerConfig,
+    listeners: Vec<Pin<Box<dyn Future<Output = io::Result<()>> + Send>>>,
+}
+impl Server {
+    /// Creates a server with no listeners, whose connections use `config`.
+    pub fn new(config: ServerConfig) -> Self {
+        Server {
+            config,
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Adds a listener. The initial service of each of its connections is
+    /// created by calling `factory` with a reference to `shared_ctx`, like in
+    /// [crate::start_server_with].
+    pub fn listener<T, C, F>(mut self, listener: TcpListener, shared_ctx: C, factory: F) -> Self
+    where
+        T: for<'a> RustyRpcServiceServer<'a>,
+        C: Send + Sync + 'static,
+        F: Fn(&C) -> T + Send + Sync + 'static,
+    {
+        self.listeners.push(Box::pin(start_server_with_config(
+            listener,
+            self.config.clone(),
+            shared_ctx,
+            factory,
+        )));
+        self
+    }
+
+    /// Accepts connections on all the listeners in an infinite loop. Returns
+    /// the error if accepting a connection on any of them fails.
+    pub async fn run(self) -> io::Result<()> {
+        self.run_until(pending()).await
+    }
+
+    /// Like [Server::run], but stops accepting connections once `shutdown`
+    /// completes. Connections that are already open are not closed.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> io::Result<()> {
+        let listeners = try_join_all(self.listeners);
+        pin_mut!(shutdown);
+        match select(listeners, shutdown).await {
+            Either::Left((result, _)) => result.map(|_| ()),
+            Either::Right(((), _)) => Ok(()),
+        }
+    }
+}
@@ -1,15 +1,28 @@
 use std::collections::{hash_map::Entry, HashMap};
-use std::mem::transmute;
+use std::io;
+use std::mem::{forget, transmute};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread::panicking;
 
 use tokio::sync::{Mutex, MutexGuard};
 
+/// Used for the maps in [ServerCollection]. Unlike the [Mutex] around each
+/// service, these are never held across an `.await`, so a blocking mutex is
+/// fine, and waits for other users instead of failing.
+type SyncMutex<T> = std::sync::Mutex<T>;
+
+use crate::streaming::MethodCallStream;
+use crate::trace::Span;
+use crate::util::string_io_error;
+use crate::wire_format::WireFormat;
 use crate::{messages::ServiceId, traits::RustyRpcServiceServer};
 
 pub struct RawBox<T>(*mut T);
 impl<T> RawBox<T> {
+    /// # Safety
+    ///
+    /// `value` must be valid for as long as this `RawBox` is used.
     pub unsafe fn new(value: *mut T) -> Self {
         RawBox(value)
     }
@@ -22,8 +35,31 @@ unsafe impl<T: Send> Send for RawBox<T> {}
 
 /// This acts like Box<MutexGuard<...>>, except that other people can safely
 /// have references to this parent_guard while this ServerEntry is not in the
-/// process of being dropped.
-pub type ServerGuard = RawBox<MutexGuard<'static, ServerEntry>>;
+/// process of being dropped. The ServerEntry is unlocked when this is dropped,
+/// so it is also unlocked if a method call fails or is cancelled.
+pub struct ServerGuard(RawBox<MutexGuard<'static, ServerEntry>>);
+impl ServerGuard {
+    /// # Safety
+    ///
+    /// The guard is not actually `'static`. The caller must make sure that the
+    /// ServerEntry outlives the returned ServerGuard.
+    pub(crate) unsafe fn new(guard: MutexGuard<'static, ServerEntry>) -> Self {
+        ServerGuard(RawBox::new(Box::into_raw(Box::new(guard))))
+    }
+
+    /// Returns the locked ServerEntry. Unlike with a Box, the pointer stays
+    /// valid when the ServerGuard is moved.
+    pub(crate) fn get(&self) -> *mut MutexGuard<'static, ServerEntry> {
+        self.0.get()
+    }
+}
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        unsafe {
+            drop(Box::from_raw(self.0.get()));
+        }
+    }
+}
 
 /// Represents a server that can live for some unknown lifetime, and might
 /// reference a parent server with a longer lifetime.
@@ -32,21 +68,34 @@ pub struct ServerEntry {
     server_: Box<dyn for<'a> RustyRpcServiceServer<'a>>,
     /// Not actually 'static, but unknown lifetime. This field is never read
     /// from, but it matters that it's dropped when this ServerEntry is dropped.
+    /// It is shared between services that were returned together from the same
+    /// method call, and the parent is unlocked when the last of them is dropped.
     #[allow(dead_code)]
-    parent_guard: Option<ServerGuard>,
+    parent_guard: Option<Arc<ServerGuard>>,
+    /// The parent of the spans of the calls to this service.
+    span: Span,
 }
 impl ServerEntry {
+    /// # Safety
+    ///
+    /// The returned server's lifetime is not actually `'_`. The caller must
+    /// not let it outlive the borrows that the server holds.
     pub unsafe fn server(&mut self) -> &mut dyn RustyRpcServiceServer<'_> {
         &mut *self.server_
     }
+
+    pub(crate) fn span(&self) -> &Span {
+        &self.span
+    }
 }
 impl Drop for ServerEntry {
     fn drop(&mut self) {
-        if !panicking() {
-            if let Some(guard) = &mut self.parent_guard {
-                unsafe {
-                    drop(Box::from_raw(guard.0));
-                }
+        // The parent guard is dropped after server_, so nothing borrows from
+        // the parent by the time it is unlocked. If this is the last reference
+        // to the guard, then the parent is unlocked.
+        if panicking() {
+            if let Some(guard) = self.parent_guard.take() {
+                forget(guard);
             }
         }
     }
@@ -54,65 +103,268 @@ impl Drop for ServerEntry {
 
 /// State for one ongoing connection with one client.
 pub struct ServerCollection {
-    active_services: Mutex<HashMap<ServiceId, Arc<Mutex<ServerEntry>>>>,
+    active_services: SyncMutex<HashMap<ServiceId, Arc<Mutex<ServerEntry>>>>,
+    /// IDs of dropped services, which are reused before allocating new ones.
+    free_service_ids: SyncMutex<Vec<ServiceId>>,
+    /// The smallest ID that has never been allocated. Since IDs are recycled,
+    /// this never exceeds the largest number of services that were ever alive
+    /// at the same time, so it can't overflow.
     next_service_id: AtomicU64,
+    max_services: usize,
+    wire_format: WireFormat,
+    /// The span of the call that is being handled, or of the connection before
+    /// the first call. Services that are registered get it as their span.
+    pub(crate) current_span: Span,
+    /// The number of calls so far, to tell them apart in traces.
+    pub(crate) call_count: u64,
+    /// The ends of the channels that a streaming method uses, while a call
+    /// that isn't oneway or batched is being handled.
+    pub(crate) call_stream: Option<MethodCallStream>,
 }
 impl ServerCollection {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(max_services: usize, wire_format: WireFormat) -> Self {
         ServerCollection {
-            active_services: Mutex::new(HashMap::new()),
+            active_services: SyncMutex::new(HashMap::new()),
+            free_service_ids: SyncMutex::new(Vec::new()),
             next_service_id: AtomicU64::new(0),
+            max_services,
+            wire_format,
+            current_span: Span::none(),
+            call_count: 0,
+            call_stream: None,
         }
     }
 
-    fn get_and_increment_next_service_id(&self) -> ServiceId {
-        // This wraps around on overflow
-        ServiceId(self.next_service_id.fetch_add(1, Ordering::SeqCst))
+    /// How the arguments and return values of this connection are encoded.
+    pub fn wire_format(&self) -> WireFormat {
+        self.wire_format
+    }
+
+    /// Takes the channels of the call that is being handled, which a streaming
+    /// method receives the client's items from and sends its own items into.
+    /// Returns `None` if the call is oneway or batched, since the client doesn't
+    /// wait for such a call on its own, so it can't send or receive items.
+    pub fn take_call_stream(&mut self) -> Option<MethodCallStream> {
+        self.call_stream.take()
+    }
+
+    /// Returns the IDs of the services that are currently registered, in
+    /// increasing order. This is a snapshot, so services can be registered or
+    /// dropped while the caller uses it.
+    pub fn active_service_ids(&self) -> Vec<ServiceId> {
+        let mut service_ids: Vec<ServiceId> = self
+            .active_services
+            .lock()
+            .expect("active_service_ids lock poisoned")
+            .keys()
+            .copied()
+            .collect();
+        service_ids.sort_unstable_by_key(|x| x.0);
+        service_ids
+    }
+
+    /// Returns an ID that no live service has.
+    fn allocate_service_id(&self) -> ServiceId {
+        let mut free_service_ids = self
+            .free_service_ids
+            .lock()
+            .expect("allocate_service_id lock poisoned");
+        free_service_ids
+            .pop()
+            .unwrap_or_else(|| ServiceId(self.next_service_id.fetch_add(1, Ordering::SeqCst)))
+    }
+
+    fn free_service_id(&self, service_id: ServiceId) {
+        self.free_service_ids
+            .lock()
+            .expect("free_service_id lock poisoned")
+            .push(service_id);
     }
 
     /// Add a service to the collection, and return its ID.
-    #[must_use]
+    ///
+    /// If the collection already has the maximum number of services, then
+    /// `service` and `parent_guard` are dropped, and an error is returned.
+    ///
+    /// # Safety
+    ///
+    /// If `service` borrows from a parent service, `parent_guard` must be the
+    /// guard of that parent, so that the parent stays locked for as long as the
+    /// new service is registered.
     pub unsafe fn register_service<'a: 'service, 'service>(
         &'a self,
         service: Box<dyn RustyRpcServiceServer<'service>>,
         parent_guard: Option<ServerGuard>,
-    ) -> ServiceId {
-        // Keep trying new service IDs until it's available.
-        // This would go into an infinite loop if all possible ServiceIds were
-        // used, but we would run out of memory before that would ever happen.
-        loop {
-            let mut locked = self
-                .active_services
-                .try_lock()
-                .expect("register_service lock failed");
-            let curr_service_id = self.get_and_increment_next_service_id();
-            match locked.entry(curr_service_id) {
-                Entry::Vacant(entry) => {
-                    let server_entry: ServerEntry = ServerEntry {
-                        server_: transmute::<
-                            Box<dyn RustyRpcServiceServer<'service>>,
-                            Box<dyn for<'b> RustyRpcServiceServer<'b>>,
-                        >(service),
-                        parent_guard,
-                    };
-                    entry.insert(Arc::new(Mutex::new(server_entry)));
-                    return curr_service_id;
-                }
-                Entry::Occupied(_) => (),
+    ) -> io::Result<ServiceId> {
+        let service_ids = self.insert_services(vec![service], parent_guard)?;
+        Ok(service_ids[0])
+    }
+
+    /// Like [ServerCollection::register_service], but for several services at
+    /// once that all borrow from the same parent. The parent stays locked until
+    /// all of them are dropped. Either all services are registered, or none
+    /// are.
+    ///
+    /// # Safety
+    ///
+    /// Same as [ServerCollection::register_service].
+    pub unsafe fn register_services<'a: 'service, 'service>(
+        &'a self,
+        services: Vec<Box<dyn RustyRpcServiceServer<'service>>>,
+        parent_guard: Option<ServerGuard>,
+    ) -> io::Result<Vec<ServiceId>> {
+        self.insert_services(services, parent_guard)
+    }
+
+    /// Like [ServerCollection::register_service], but for services that don't
+    /// borrow anything, and therefore don't need a parent guard.
+    pub fn register_static_service(
+        &self,
+        service: Box<dyn RustyRpcServiceServer<'static>>,
+    ) -> io::Result<ServiceId> {
+        let service_ids = unsafe { self.insert_services(vec![service], None)? };
+        Ok(service_ids[0])
+    }
+
+    /// # Safety
+    ///
+    /// Same as [ServerCollection::register_service].
+    unsafe fn insert_services<'service>(
+        &self,
+        services: Vec<Box<dyn RustyRpcServiceServer<'service> + 'service>>,
+        parent_guard: Option<ServerGuard>,
+    ) -> io::Result<Vec<ServiceId>> {
+        let mut locked = self
+            .active_services
+            .lock()
+            .expect("register_service lock poisoned");
+        if locked.len() + services.len() > self.max_services {
+            // The services might borrow from the parent, so they must be
+            // dropped before the parent is unlocked.
+            drop(services);
+            drop(parent_guard);
+            return Err(string_io_error(format!(
+                "Too many live services in this connection (the maximum is {}).",
+                self.max_services
+            )));
+        }
+        let parent_guard = parent_guard.map(Arc::new);
+        let mut service_ids = Vec::with_capacity(services.len());
+        for service in services {
+            let service_id = self.allocate_service_id();
+            debug_assert!(!locked.contains_key(&service_id));
+            let server_entry: ServerEntry = ServerEntry {
+                server_: transmute::<
+                    Box<dyn RustyRpcServiceServer<'service>>,
+                    Box<dyn for<'b> RustyRpcServiceServer<'b>>,
+                >(service),
+                parent_guard: parent_guard.clone(),
+                span: self.current_span.clone(),
+            };
+            locked.insert(service_id, Arc::new(Mutex::new(server_entry)));
+            service_ids.push(service_id);
+        }
+        // This only frees the guard if there were no services.
+        drop(parent_guard);
+        Ok(service_ids)
+    }
+
+    /// Unregisters the service with a given ID and drops it. Fails if there is
+    /// no such service, or if the service is still in use (e.g., if a child
+    /// service borrows from it).
+    pub(crate) fn drop_service(&self, service_id: ServiceId) -> io::Result<()> {
+        let mut locked = self
+            .active_services
+            .lock()
+            .expect("drop_service lock poisoned");
+        let entry = match locked.entry(service_id) {
+            Entry::Occupied(entry) => entry,
+            Entry::Vacant(_) => {
+                return Err(string_io_error(format!(
+                    "Invalid service ID: {}",
+                    service_id.0
+                )))
             }
+        };
+        // Nobody else can clone the Arc while we're holding the lock on
+        // active_services.
+        if Arc::strong_count(entry.get()) != 1 || entry.get().try_lock().is_err() {
+            return Err(string_io_error(format!(
+                "Service {} is still in use.",
+                service_id.0
+            )));
         }
+        let service_mutex = Arc::try_unwrap(entry.remove())
+            .ok() // Needed because the Err field doesn't impl Debug.
+            .expect("Service somehow in use while dropping it.");
+        drop(service_mutex.into_inner());
+        self.free_service_id(service_id);
+        Ok(())
     }
 
-    /// Unregisters the service with a given ID and returns it.
-    pub(crate) fn remove_service_entry_arc(
-        &self,
-        service_id: ServiceId,
-    ) -> Option<Arc<Mutex<ServerEntry>>> {
+    /// Unregisters and drops all services. Each service is dropped before the
+    /// services that it borrows from.
+    pub(crate) fn drop_all_services(&self) {
         let mut locked = self
             .active_services
-            .try_lock()
-            .expect("remove_service_arc lock failed");
-        locked.remove(&service_id)
+            .lock()
+            .expect("drop_all_services lock poisoned");
+        while !locked.is_empty() {
+            // A service is unlocked once nothing borrows from it anymore.
+            let droppable: Vec<ServiceId> = locked
+                .iter()
+                .filter(|(_, entry)| Arc::strong_count(entry) == 1 && entry.try_lock().is_ok())
+                .map(|(service_id, _)| *service_id)
+                .collect();
+            if droppable.is_empty() {
+                // The remaining services are still in use somewhere else, so
+                // dropping them might free something that is still borrowed.
+                // Leak them instead.
+                locked.drain().for_each(forget);
+                return;
+            }
+            for service_id in droppable {
+                drop(locked.remove(&service_id));
+            }
+        }
+    }
+
+    /// Like [ServerCollection::drop_all_services], but calls
+    /// [RustyRpcServiceServer::on_connection_closed] on each service just
+    /// before it is dropped. Used when the connection ends.
+    pub(crate) async fn close_all_services(&self) {
+        loop {
+            let droppable: Vec<Arc<Mutex<ServerEntry>>> = {
+                let mut locked = self
+                    .active_services
+                    .lock()
+                    .expect("close_all_services lock poisoned");
+                let droppable_ids: Vec<ServiceId> = locked
+                    .iter()
+                    .filter(|(_, entry)| Arc::strong_count(entry) == 1 && entry.try_lock().is_ok())
+                    .map(|(service_id, _)| *service_id)
+                    .collect();
+                droppable_ids
+                    .iter()
+                    .filter_map(|service_id| locked.remove(service_id))
+                    .collect()
+            };
+            if droppable.is_empty() {
+                break;
+            }
+            for entry in droppable {
+                let mut entry = Arc::try_unwrap(entry)
+                    .ok() // Needed because the Err field doesn't impl Debug.
+                    .expect("Service somehow in use while dropping it.")
+                    .into_inner();
+                unsafe { entry.server() }.on_connection_closed().await;
+                // This may unlock the parent, so that it is droppable in the
+                // next round.
+                drop(entry);
+            }
+        }
+        // Whatever is left is still in use somewhere else, and is leaked.
+        self.drop_all_services();
     }
 
     pub(crate) fn get_service_entry_arc(
@@ -121,8 +373,114 @@ impl ServerCollection {
     ) -> Option<Arc<Mutex<ServerEntry>>> {
         let locked = self
             .active_services
-            .try_lock()
-            .expect("get_service_arc lock failed");
+            .lock()
+            .expect("get_service_arc lock poisoned");
         locked.get(&service_id).cloned()
     }
 }
+/// Services must not be dropped in the arbitrary order of the map, since a
+/// service might borrow from another one.
+impl Drop for ServerCollection {
+    fn drop(&mut self) {
+        self.drop_all_services();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::thread;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::error::RpcResult;
+    use crate::messages::{MethodArgs, MethodId, ServerMessage};
+
+    struct DummyServer;
+    #[async_trait]
+    unsafe impl<'a> RustyRpcServiceServer<'a> for DummyServer {
+        async unsafe fn parse_and_call_method_locally(
+            &mut self,
+            _self_guard: ServerGuard,
+            _method_id: MethodId,
+            _method_args: MethodArgs,
+            _service_collection: &mut ServerCollection,
+        ) -> RpcResult<ServerMessage> {
+            Ok(ServerMessage::Error("Not implemented.".to_string()))
+        }
+        fn service_name(&self) -> &'static str {
+            "DummyService"
+        }
+        fn method_name(&self, _method_id: MethodId) -> Option<&'static str> {
+            None
+        }
+    }
+
+    #[test]
+    fn active_service_ids_test() {
+        let service_collection = ServerCollection::new(10, WireFormat::default());
+        assert_eq!(
+            Vec::<ServiceId>::new(),
+            service_collection.active_service_ids()
+        );
+        for _ in 0..4 {
+            service_collection
+                .register_static_service(Box::new(DummyServer))
+                .unwrap();
+        }
+        service_collection.drop_service(ServiceId(1)).unwrap();
+        assert_eq!(
+            vec![ServiceId(0), ServiceId(2), ServiceId(3)],
+            service_collection.active_service_ids()
+        );
+        // The freed ID is reused.
+        service_collection
+            .register_static_service(Box::new(DummyServer))
+            .unwrap();
+        assert_eq!(
+            vec![ServiceId(0), ServiceId(1), ServiceId(2), ServiceId(3)],
+            service_collection.active_service_ids()
+        );
+    }
+
+    #[test]
+    fn concurrent_registration_test() {
+        const THREADS: usize = 8;
+        const SERVICES_PER_THREAD: usize = 100;
+        let service_collection = Arc::new(ServerCollection::new(
+            THREADS * SERVICES_PER_THREAD,
+            WireFormat::default(),
+        ));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let service_collection = service_collection.clone();
+                thread::spawn(move || {
+                    let mut service_ids = Vec::new();
+                    for i in 0..SERVICES_PER_THREAD {
+                        let service_id = service_collection
+                            .register_static_service(Box::new(DummyServer))
+                            .unwrap();
+                        assert!(service_collection
+                            .get_service_entry_arc(service_id)
+                            .is_some());
+                        // Drop some of the services, so that their IDs are reused.
+                        if i % 2 == 0 {
+                            service_collection.drop_service(service_id).unwrap();
+                        } else {
+                            service_ids.push(service_id);
+                        }
+                    }
+                    service_ids
+                })
+            })
+            .collect();
+        let mut all_service_ids = HashSet::new();
+        for thread in threads {
+            for service_id in thread.join().unwrap() {
+                assert!(all_service_ids.insert(service_id));
+            }
+        }
+        assert_eq!(THREADS * SERVICES_PER_THREAD / 2, all_service_ids.len());
+    }
+}
@@ -0,0 +1,245 @@
+//! The two directions of a streaming method call. See the `stream` methods of
+//! the interface file. `@incremental` methods also use a streaming call, in
+//! which the client sends the pieces of the argument.
+
+use std::fmt;
+use std::future::ready;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc;
+use futures::stream::BoxStream;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+
+use crate::error::{RpcResult, RustyRpcError};
+use crate::serde_bytes::MAX_PREALLOCATED_BYTES;
+
+/// The number of items that a [stream_channel] holds in addition to one item
+/// for each [StreamSender]. Like the connection itself, the channels of a call
+/// don't buffer much, so a side that doesn't keep up slows the other side down.
+const CHANNEL_BUFFER: usize = 0;
+
+/// The length of the pieces that the argument of an `@incremental` method is
+/// sent in. Each piece is one message, so this is well below the default
+/// maximum frame length.
+pub(crate) const INCREMENTAL_PIECE_LENGTH: usize = 64 * 1024;
+
+/// Sends the items of one direction of a streaming call. Sending fails with
+/// [RustyRpcError::ConnectionClosed] once the other side stopped receiving,
+/// e.g. because the call is over. Closing or dropping this ends the stream.
+pub struct StreamSender<T> {
+    inner: Pin<Box<dyn Sink<T, Error = RustyRpcError> + Send>>,
+}
+impl<T: Send + 'static> StreamSender<T> {
+    /// Encodes each item with `encode` and sends it into `sender`.
+    #[doc(hidden)]
+    pub fn encoding(
+        sender: mpsc::Sender<Vec<u8>>,
+        encode: impl Fn(T) -> Vec<u8> + Send + 'static,
+    ) -> Self {
+        let sink = sender
+            .sink_map_err(|_| RustyRpcError::ConnectionClosed)
+            .with(move |item| ready(Ok(encode(item))));
+        StreamSender {
+            inner: Box::pin(sink),
+        }
+    }
+
+    /// Turns this into a sink that decodes the items that it gets with
+    /// `decode`. An item that can't be decoded fails the send.
+    #[doc(hidden)]
+    pub fn into_decoding(
+        self,
+        decode: impl Fn(&[u8]) -> RpcResult<T> + Send + 'static,
+    ) -> impl Sink<Vec<u8>, Error = RustyRpcError> + Send + Unpin {
+        self.with(move |bytes: Vec<u8>| ready(decode(&bytes)))
+    }
+}
+impl<T> Sink<T> for StreamSender<T> {
+    type Error = RustyRpcError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<RpcResult<()>> {
+        self.inner.as_mut().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> RpcResult<()> {
+        self.inner.as_mut().start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<RpcResult<()>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<RpcResult<()>> {
+        self.inner.as_mut().poll_close(cx)
+    }
+}
+impl<T> fmt::Debug for StreamSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamSender").finish_non_exhaustive()
+    }
+}
+
+/// Receives the items of one direction of a streaming call. The stream ends
+/// when the other side closes its [StreamSender]. An item that couldn't be
+/// decoded, or an error that ended the call, shows up as an `Err` item.
+pub struct StreamReceiver<T> {
+    inner: BoxStream<'static, RpcResult<T>>,
+}
+impl<T: Send + 'static> StreamReceiver<T> {
+    pub(crate) fn from_results(receiver: mpsc::Receiver<RpcResult<T>>) -> Self {
+        StreamReceiver {
+            inner: receiver.boxed(),
+        }
+    }
+
+    /// Decodes each item of `receiver` with `decode`.
+    #[doc(hidden)]
+    pub fn decoding(
+        receiver: mpsc::Receiver<Vec<u8>>,
+        decode: impl Fn(&[u8]) -> RpcResult<T> + Send + 'static,
+    ) -> Self {
+        StreamReceiver {
+            inner: receiver.map(move |bytes| decode(&bytes)).boxed(),
+        }
+    }
+
+    /// Turns this into a stream of the items encoded with `encode`.
+    #[doc(hidden)]
+    pub fn into_encoding(
+        self,
+        encode: impl Fn(T) -> Vec<u8> + Send + 'static,
+    ) -> impl Stream<Item = RpcResult<Vec<u8>>> + Send + Unpin {
+        self.map(move |item| item.map(&encode))
+    }
+}
+impl<T> Stream for StreamReceiver<T> {
+    type Item = RpcResult<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+impl<T> fmt::Debug for StreamReceiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamReceiver").finish_non_exhaustive()
+    }
+}
+
+/// The `bytes` argument of an `@incremental` method on the server. It arrives in
+/// pieces while the method runs, so the method can start on the first piece
+/// before the client sent the rest. Together, the pieces are the bytes that the
+/// client passed.
+///
+/// If the call is cancelled or the connection ends before all of the bytes
+/// arrived, the last item is [RustyRpcError::ConnectionClosed].
+pub struct IncomingBytes {
+    len: u64,
+    received: u64,
+    pieces: mpsc::Receiver<Vec<u8>>,
+    done: bool,
+}
+impl IncomingBytes {
+    /// `len` is the total length that the client announced.
+    #[doc(hidden)]
+    pub fn new(len: u64, pieces: mpsc::Receiver<Vec<u8>>) -> Self {
+        IncomingBytes {
+            len,
+            received: 0,
+            pieces,
+            done: false,
+        }
+    }
+
+    /// The total number of bytes, including the ones that already arrived.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether there are no bytes at all.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Waits for the rest of the bytes, and returns the ones that weren't
+    /// received from this stream yet.
+    pub async fn into_vec(mut self) -> RpcResult<Vec<u8>> {
+        let remaining = usize::try_from(self.len - self.received).unwrap_or(usize::MAX);
+        let mut bytes = Vec::with_capacity(remaining.min(MAX_PREALLOCATED_BYTES));
+        while let Some(piece) = self.next().await {
+            bytes.extend_from_slice(&piece?);
+        }
+        Ok(bytes)
+    }
+}
+impl Stream for IncomingBytes {
+    type Item = RpcResult<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let item = match self.pieces.poll_next_unpin(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Some(piece)) => {
+                self.received += piece.len() as u64;
+                if self.received <= self.len {
+                    return Poll::Ready(Some(Ok(piece)));
+                }
+                RustyRpcError::MalformedMessage(
+                    "The client sent more bytes than it announced.".to_string(),
+                )
+            }
+            Poll::Ready(None) if self.received == self.len => {
+                self.done = true;
+                return Poll::Ready(None);
+            }
+            Poll::Ready(None) => RustyRpcError::ConnectionClosed,
+        };
+        self.done = true;
+        Poll::Ready(Some(Err(item)))
+    }
+}
+impl fmt::Debug for IncomingBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IncomingBytes")
+            .field("len", &self.len)
+            .field("received", &self.received)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Creates a channel whose ends can be passed to a streaming method. On the
+/// client, the method then sends what is sent into the returned [StreamSender],
+/// and the server's responses arrive in the [StreamReceiver] of another
+/// channel.
+pub fn stream_channel<T: Send + 'static>() -> (StreamSender<T>, StreamReceiver<T>) {
+    let (sender, receiver) = mpsc::channel(CHANNEL_BUFFER);
+    let sender = StreamSender {
+        inner: Box::pin(sender.sink_map_err(|_| RustyRpcError::ConnectionClosed)),
+    };
+    let receiver = StreamReceiver {
+        inner: receiver.map(Ok).boxed(),
+    };
+    (sender, receiver)
+}
+
+/// The ends of the channels of a streaming call on the server that the method
+/// gets. It receives the client's items from the receiver, and sends its own
+/// items into the sender.
+pub type MethodCallStream = (mpsc::Receiver<Vec<u8>>, mpsc::Sender<Vec<u8>>);
+
+/// The other ends of a [MethodCallStream], which the connection keeps. It sends
+/// the client's items into the sender, and sends the items from the receiver to
+/// the client.
+pub(crate) type ConnectionCallStream = (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>);
+
+/// Creates the channels of a streaming call on the server.
+pub(crate) fn server_call_channels() -> (ConnectionCallStream, MethodCallStream) {
+    let (request_sender, request_receiver) = mpsc::channel(CHANNEL_BUFFER);
+    let (response_sender, response_receiver) = mpsc::channel(CHANNEL_BUFFER);
+    (
+        (request_sender, response_receiver),
+        (request_receiver, response_sender),
+    )
+}
@@ -0,0 +1,71 @@
+//! Spans for the `tracing` crate. Without the `tracing` feature, these do
+//! nothing.
+//!
+//! Each connection has a span, and each method call has a span whose parent is
+//! the span of the call that returned the service, or the connection span for
+//! the initial service. So calls on a returned service appear under the call
+//! that returned it.
+
+use std::future::Future;
+use std::net::SocketAddr;
+
+use crate::messages::{MethodId, ServiceId};
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+/// Stands in for `tracing::Span` without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone)]
+pub(crate) struct Span;
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn none() -> Self {
+        Span
+    }
+}
+
+/// The span of a connection, which is the root of the spans of its calls.
+pub(crate) fn connection_span(peer_addr: SocketAddr) -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::info_span!("connection", %peer_addr);
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = peer_addr;
+        Span
+    }
+}
+
+/// The span of a method call. `request_id` numbers the calls of the
+/// connection, starting from 0, since the messages themselves have no IDs.
+pub(crate) fn call_span(
+    parent: &Span,
+    service_id: ServiceId,
+    method_id: MethodId,
+    request_id: u64,
+) -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::info_span!(
+        parent: parent,
+        "call",
+        service_id = service_id.0,
+        method_id = method_id.0,
+        request_id
+    );
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (parent, service_id, method_id, request_id);
+        Span
+    }
+}
+
+/// Runs `future` in `span`.
+pub(crate) fn instrument<F: Future>(future: F, span: Span) -> impl Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    return tracing::Instrument::instrument(future, span);
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = span;
+        future
+    }
+}
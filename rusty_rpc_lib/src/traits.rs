@@ -1,12 +1,13 @@
-use std::io;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use futures::{Sink, Stream};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use tokio::sync::Mutex;
 
+use crate::client::ClientConnection;
+use crate::error::{RpcResult, RustyRpcError};
 use crate::messages::{ClientMessage, MethodArgs, MethodId, ServerMessage, ServiceId};
 use crate::server_collection::ServerGuard;
 use crate::ServerCollection;
@@ -25,31 +26,71 @@ pub trait RustyRpcServiceClient {
     /// type `T` is an implementation of a certain service, then `Response<T>`
     /// will implement the corresponding service trait.
     type ServiceProxy: RustyRpcServiceProxy;
+
+    /// The name and ID of each method of the service, in the order of their
+    /// names. This can be used to call methods by name, e.g. in a gateway that
+    /// forwards calls without knowing the service at compile time.
+    const METHOD_IDS: &'static [(&'static str, MethodId)];
 }
 
 /// Used with [RustyRpcServiceClient]. Something that implements
 /// `RustyRpcServiceProxy<dyn T>` will also always be generated so it implements
 /// `T`. This type is a proxy that deallocates server-side resources when the
 /// `.close()` method is called. If it is dropped without being closed, it will
-/// panic.
+/// panic, unless [crate::ClientConfig::auto_close_on_drop] is set.
+///
+/// Cloning a proxy gives another proxy to the same service. Each clone must be
+/// closed separately, and the server-side resources are deallocated when the
+/// last clone is closed. Proxies that the server returns for a service that
+/// the client already has a proxy to count as clones of it.
 #[allow(drop_bounds)]
-pub trait RustyRpcServiceProxy: Drop {
+#[async_trait]
+pub trait RustyRpcServiceProxy: Drop + Send + Clone {
+    #[doc(hidden)]
+    fn from_service_id(service_id: ServiceId, connection: Arc<ClientConnection>) -> Self;
+
+    /// Used by [crate::ServiceRefMut::close].
+    #[doc(hidden)]
+    async fn close_proxy(&mut self) -> RpcResult<()>;
+
+    /// Used by [crate::ChainedService]. Closes the proxy without
+    /// waiting, like dropping it with [crate::ClientConfig::auto_close_on_drop]
+    /// set.
+    #[doc(hidden)]
+    fn close_in_background(self);
+
+    /// The ID of the server-side service that this proxy refers to.
+    #[doc(hidden)]
+    fn service_id(&self) -> ServiceId;
+
+    /// The connection that this proxy sends calls through.
+    #[doc(hidden)]
+    fn connection(&self) -> &Arc<ClientConnection>;
+
+    /// The number of clones of this proxy that aren't closed yet. Used by
+    /// [crate::WeakServiceRef].
+    #[doc(hidden)]
+    fn open_clones(&self) -> &Arc<AtomicUsize>;
+
+    /// Creates another clone of the proxy whose clones are counted by
+    /// `open_clones`. The caller must have already counted the new clone.
     #[doc(hidden)]
-    fn from_service_id(
+    fn from_open_clones(
         service_id: ServiceId,
-        stream_sink: Arc<Mutex<dyn ClientStreamSink>>,
+        connection: Arc<ClientConnection>,
+        open_clones: Arc<AtomicUsize>,
     ) -> Self;
 }
 
 /// Alias for `Stream + Sink`, so we can use it as a dyn trait. Represents the
 /// communication channel endpoint on the client's side
 pub trait ClientStreamSink:
-    Stream<Item = io::Result<ServerMessage>> + Sink<ClientMessage, Error = io::Error> + Send + Unpin
+    Stream<Item = RpcResult<ServerMessage>> + Sink<ClientMessage, Error = RustyRpcError> + Send + Unpin
 {
 }
 impl<
-        T: Stream<Item = io::Result<ServerMessage>>
-            + Sink<ClientMessage, Error = io::Error>
+        T: Stream<Item = RpcResult<ServerMessage>>
+            + Sink<ClientMessage, Error = RustyRpcError>
             + Send
             + Unpin,
     > ClientStreamSink for T
@@ -65,25 +106,81 @@ pub trait RustyRpcServiceServerWithKnownClientType<'a, T: RustyRpcServiceClient
 }
 
 /// This trait will be automatically implemented by any user type marked with
-/// the `#[service_server_impl]` attribute in the `rusty_rpc_macro` crate. Users
-/// should not manually implement this trait.
+/// the `#[service_server_impl]` attribute in the `rusty_rpc_macro` crate.
+///
+/// It can also be implemented by hand, for services whose methods aren't known
+/// at compile time, such as a gateway that forwards calls elsewhere. Such a
+/// service has to follow the same wire format as the generated code:
+///
+/// - The methods of a service are numbered by [MethodId]. Methods with an
+///   `@id(...)` in the interface file have that ID, and the others are numbered
+///   from 0 in the order of their names, skipping the IDs that are taken.
+/// - [MethodArgs] holds the arguments encoded with the connection's
+///   [crate::WireFormat], which is [ServerCollection::wire_format]. No
+///   arguments are encoded as `()`, a single argument is encoded by itself,
+///   and several arguments are encoded as a tuple.
+/// - A returned value is encoded the same way and sent as
+///   [ReturnValue::Data]. A returned service is registered in the
+///   [ServerCollection], and its ID is sent as [ReturnValue::Service], or
+///   [ReturnValue::Services] for several services.
+/// - A method that fails returns `Ok(ServerMessage::Error(...))`, which the
+///   client receives as [crate::RustyRpcError::ServerError]. Returning `Err`
+///   closes the connection, so it should only be used for malformed arguments.
 ///
 /// Client-side access to services (via [crate::messages::ServiceRefMut]) CANNOT
 /// use this trait.
 ///
 /// For some reason the Send + Sync + 'static bound is needed for
 /// `tokio::spawn`.
+///
+/// # Safety
+///
+/// Implementations must either free `self_guard` or hand it over to a service
+/// registered in `service_collection`. This is handled by the macro.
+///
+/// [ReturnValue]: crate::ReturnValue
+/// [ReturnValue::Data]: crate::ReturnValue::Data
+/// [ReturnValue::Service]: crate::ReturnValue::Service
+/// [ReturnValue::Services]: crate::ReturnValue::Services
 #[async_trait]
 pub unsafe trait RustyRpcServiceServer<'a>: Send + Sync + 'a {
-    /// self_guard is allocated with box. It won't be deallocated by caller.
-    #[doc(hidden)]
+    /// Parses the arguments, calls the method, and serializes the return
+    /// value, as described above.
+    ///
+    /// `self_guard` keeps this service locked. It must be dropped once
+    /// nothing returned by the method borrows from `self` anymore, or passed
+    /// to [ServerCollection::register_service] along with a service that
+    /// borrows from `self`.
+    ///
+    /// # Safety
+    ///
+    /// Only the server calls this, with the guard that locks `self`.
     async unsafe fn parse_and_call_method_locally(
         &mut self,
         self_guard: ServerGuard,
         method_id: MethodId,
         method_args: MethodArgs,
         service_collection: &mut ServerCollection,
-    ) -> io::Result<ServerMessage>;
+    ) -> RpcResult<ServerMessage>;
+
+    /// The name of the service, as written in the interface file. Used for
+    /// authorization and error messages.
+    fn service_name(&self) -> &'static str;
+
+    /// The name of the method with the given ID, if there is one.
+    fn method_name(&self, method_id: MethodId) -> Option<&'static str>;
+
+    /// Called when the client closes this service, just before it is dropped,
+    /// so that it can clean up asynchronously, e.g. by flushing its data. The
+    /// client waits for this to finish. This isn't called for services that
+    /// are still open when the connection ends. Does nothing by default.
+    async fn on_drop(&mut self) {}
+
+    /// Called for each service that is still open when the connection ends,
+    /// e.g. because the client disconnected without closing it, just before
+    /// it is dropped. Services are notified before the services that they
+    /// borrow from. Does nothing by default.
+    async fn on_connection_closed(&mut self) {}
 }
 
 /// This trait will be automatically implemented by struct types generated by
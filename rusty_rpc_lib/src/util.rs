@@ -3,7 +3,7 @@ use std::{error, io};
 use simple_error::SimpleError;
 
 pub fn other_io_error(e: impl Into<Box<dyn error::Error + Send + Sync>>) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, e)
+    io::Error::other(e)
 }
 
 pub fn string_io_error(s: impl Into<String>) -> io::Error {
@@ -0,0 +1,148 @@
+//! Servers and clients that talk over WebSocket instead of raw TCP. Each
+//! message is sent as one binary WebSocket message, so no extra framing is
+//! needed.
+
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use futures::future::ready;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::client::ClientConnection;
+use crate::codec::{self, FrameStreamSink};
+use crate::config::{ClientConfig, ServerConfig, DEFAULT_MAX_RESPONSE_LENGTH};
+use crate::error::RpcResult;
+use crate::messages::ServiceRefMut;
+use crate::traits::{ClientStreamSink, RustyRpcServiceClient, RustyRpcServiceServer};
+use crate::wire_format::WireFormat;
+use crate::{initial_service_for_connection, serve};
+
+/// Starts a server like [crate::start_server], but accepts WebSocket
+/// connections instead of raw TCP connections.
+pub async fn start_websocket_server<T: for<'a> RustyRpcServiceServer<'a> + Default>(
+    listener: TcpListener,
+) -> io::Result<()> {
+    start_websocket_server_with_config(listener, ServerConfig::default(), (), |_| T::default())
+        .await
+}
+
+/// Starts a server like [crate::start_server_with_config], but accepts
+/// WebSocket connections instead of raw TCP connections.
+pub async fn start_websocket_server_with_config<T, C, F>(
+    listener: TcpListener,
+    config: ServerConfig,
+    shared_ctx: C,
+    factory: F,
+) -> io::Result<()>
+where
+    T: for<'a> RustyRpcServiceServer<'a>,
+    C: Send + Sync + 'static,
+    F: Fn(&C) -> T + Send + Sync + 'static,
+{
+    serve(
+        listener,
+        config,
+        shared_ctx,
+        move |shared_ctx: &C| ready(factory(shared_ctx)),
+        accept_frames,
+    )
+    .await
+}
+
+/// Does the WebSocket handshake on the server side.
+fn accept_frames(
+    socket: TcpStream,
+    config: &ServerConfig,
+) -> impl Future<Output = io::Result<impl FrameStreamSink + Send>> {
+    let websocket_config = websocket_config(config.max_frame_length);
+    async move {
+        let websocket = tokio_tungstenite::accept_async_with_config(socket, Some(websocket_config))
+            .await
+            .map_err(to_io_error)?;
+        Ok(websocket_frames(websocket))
+    }
+}
+
+/// Connects to a WebSocket server at `url` (e.g. `"ws://127.0.0.1:8080"`),
+/// and starts a client connection like [crate::start_client_with_config].
+pub async fn connect_websocket_client<T: RustyRpcServiceClient + ?Sized + 'static>(
+    url: &str,
+    config: ClientConfig,
+) -> RpcResult<ServiceRefMut<'static, T>> {
+    let websocket_config = websocket_config(config.max_frame_length);
+    let (websocket, _) = tokio_tungstenite::connect_async_with_config(url, Some(websocket_config))
+        .await
+        .map_err(to_io_error)?;
+    if let MaybeTlsStream::Plain(stream) = websocket.get_ref() {
+        stream.set_nodelay(config.tcp_nodelay)?;
+    }
+    let stream_sink = codec::client_stream_sink(
+        websocket_frames(websocket),
+        config.wire_format,
+        config.max_response_length,
+    );
+    let connection = Arc::new(ClientConnection::new(Box::new(stream_sink), config));
+    Ok(initial_service_for_connection(connection))
+}
+
+/// Turns a WebSocket connection on which the handshake was already done into
+/// a stream and sink of messages, for use with
+/// [crate::start_client_with_stream_sink]. The messages are encoded with
+/// [crate::WireFormat::MessagePack], and responses are limited to
+/// [crate::DEFAULT_MAX_RESPONSE_LENGTH].
+pub fn websocket_stream_sink<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    websocket: WebSocketStream<S>,
+) -> impl ClientStreamSink {
+    codec::client_stream_sink(
+        websocket_frames(websocket),
+        WireFormat::MessagePack,
+        DEFAULT_MAX_RESPONSE_LENGTH,
+    )
+}
+
+/// Each binary WebSocket message is one frame. Pings and pongs are answered by
+/// tungstenite itself, so they are skipped here.
+fn websocket_frames<S: AsyncRead + AsyncWrite + Unpin>(
+    websocket: WebSocketStream<S>,
+) -> impl FrameStreamSink {
+    websocket
+        .filter_map(|message| {
+            ready(match message {
+                Ok(Message::Binary(bytes)) => Some(Ok(BytesMut::from(&bytes[..]))),
+                Ok(Message::Text(_)) => Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Received a text WebSocket message.",
+                ))),
+                Ok(Message::Ping(_) | Message::Pong(_) | Message::Close(_) | Message::Frame(_)) => {
+                    None
+                }
+                Err(e) => Some(Err(to_io_error(e))),
+            })
+        })
+        .sink_map_err(to_io_error)
+        .with(|bytes: Bytes| ready(Ok(Message::Binary(bytes.to_vec()))))
+}
+
+fn websocket_config(max_frame_length: usize) -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(max_frame_length),
+        ..Default::default()
+    }
+}
+
+fn to_io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::Error::new(io::ErrorKind::ConnectionAborted, e)
+        }
+        e => io::Error::other(e),
+    }
+}
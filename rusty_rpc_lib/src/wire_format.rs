@@ -0,0 +1,64 @@
+//! How messages, and the arguments and return values inside them, are encoded.
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{RpcResult, RustyRpcError};
+
+/// The deepest nesting of arrays and maps that is decoded. Decoding recurses
+/// into each level, so without a limit, a small message could overflow the
+/// stack. `serde_cbor` has the same limit.
+const MAX_DEPTH: usize = 128;
+
+/// The encoding that a connection uses. The client and the server must use the
+/// same one. Set it with [crate::ServerConfig::wire_format] and
+/// [crate::ClientConfig::wire_format].
+///
+/// Byte strings, including the encoded arguments and return values inside
+/// messages, are written as binaries instead of as arrays of integers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    /// MessagePack, encoded with `rmp_serde`. This is the default.
+    #[default]
+    MessagePack,
+    /// CBOR, encoded with `serde_cbor`. This is useful for talking to programs
+    /// that aren't written in Rust, since CBOR libraries are more common than
+    /// MessagePack ones.
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+impl WireFormat {
+    /// Encodes a value. This only panics if the value can't be encoded at all,
+    /// which doesn't happen with the types that interface files can describe.
+    pub fn encode<T: Serialize>(self, value: &T) -> Vec<u8> {
+        match self {
+            WireFormat::MessagePack => {
+                rmp_serde::to_vec(value).expect("Serialization with MessagePack somehow failed.")
+            }
+            #[cfg(feature = "cbor")]
+            WireFormat::Cbor => {
+                serde_cbor::to_vec(value).expect("Serialization with CBOR somehow failed.")
+            }
+        }
+    }
+
+    /// Decodes a value, which can borrow from `bytes`. Fails with
+    /// [RustyRpcError::MalformedMessage] if `bytes` doesn't hold such a value.
+    pub fn decode<'de, T: Deserialize<'de>>(self, bytes: &'de [u8]) -> RpcResult<T> {
+        let result = match self {
+            WireFormat::MessagePack => decode_message_pack(bytes).map_err(|e| e.to_string()),
+            #[cfg(feature = "cbor")]
+            WireFormat::Cbor => serde_cbor::from_slice(bytes).map_err(|e| e.to_string()),
+        };
+        result.map_err(RustyRpcError::MalformedMessage)
+    }
+}
+
+/// Decodes a value like `rmp_serde::from_slice`, but fails if it is nested more
+/// than [MAX_DEPTH] levels deep.
+pub(crate) fn decode_message_pack<'de, T: Deserialize<'de>>(
+    bytes: &'de [u8],
+) -> Result<T, rmp_serde::decode::Error> {
+    let mut deserializer = rmp_serde::Deserializer::from_read_ref(bytes);
+    deserializer.set_max_depth(MAX_DEPTH);
+    T::deserialize(&mut deserializer)
+}
@@ -0,0 +1,95 @@
+//! Measures how long a large `bytes` argument takes to reach the server's
+//! method, for an `@incremental` method and for a normal one. The normal
+//! method only runs once the whole argument arrived, while the incremental one
+//! starts on the first piece. Run with `cargo bench --bench time_to_first_byte`.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use rusty_rpc_lib::{
+    start_client_with_config, start_server_with_config, ClientConfig, IncomingBytes, RpcResult,
+    ServerConfig,
+};
+use rusty_rpc_macro::{interface_file, service_server_impl};
+use tokio::net::{TcpListener, TcpStream};
+
+interface_file!("benches/upload.interface");
+
+const DATA_LENGTH: usize = 64 * 1024 * 1024;
+const ITERATIONS: u32 = 10;
+
+/// Records when the method first saw some of the bytes.
+struct UploadServer(Arc<Mutex<Option<Instant>>>);
+#[service_server_impl]
+impl UploadService for UploadServer {
+    async fn upload(&mut self, data: &[u8]) -> RpcResult<i32> {
+        Ok(data.len() as i32)
+    }
+    async fn upload_incremental(&mut self, mut data: IncomingBytes) -> RpcResult<i32> {
+        let mut len = 0;
+        while let Some(piece) = data.next().await {
+            self.0.lock().unwrap().get_or_insert_with(Instant::now);
+            len += piece?.len();
+        }
+        Ok(len as i32)
+    }
+    async fn upload_whole(&mut self, data: &[u8]) -> RpcResult<i32> {
+        self.0.lock().unwrap().get_or_insert_with(Instant::now);
+        Ok(data.len() as i32)
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let first_byte = Arc::new(Mutex::new(None));
+    // The normal method receives the whole argument in one frame, so both sides
+    // must allow frames that long.
+    let server_config = ServerConfig {
+        max_frame_length: 2 * DATA_LENGTH,
+        ..Default::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let shared_first_byte = first_byte.clone();
+    tokio::spawn(async move {
+        start_server_with_config(listener, server_config, shared_first_byte, |x| {
+            UploadServer(x.clone())
+        })
+        .await
+        .unwrap()
+    });
+
+    let client_config = ClientConfig {
+        max_frame_length: 2 * DATA_LENGTH,
+        ..Default::default()
+    };
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut service = start_client_with_config::<dyn UploadService, _>(stream, client_config).await;
+    let data = vec![0x5a; DATA_LENGTH];
+
+    for incremental in [true, false] {
+        let mut to_first_byte = Duration::ZERO;
+        let mut to_return = Duration::ZERO;
+        for _ in 0..ITERATIONS {
+            *first_byte.lock().unwrap() = None;
+            let start = Instant::now();
+            let len = if incremental {
+                service.upload(&data).await.unwrap()
+            } else {
+                service.upload_whole(&data).await.unwrap()
+            };
+            to_return += start.elapsed();
+            assert_eq!(DATA_LENGTH as i32, len);
+            to_first_byte += first_byte.lock().unwrap().unwrap() - start;
+        }
+        println!(
+            "{} MiB, {}: {:?} to the first byte, {:?} to return",
+            DATA_LENGTH / (1024 * 1024),
+            if incremental { "incremental" } else { "whole" },
+            to_first_byte / ITERATIONS,
+            to_return / ITERATIONS,
+        );
+    }
+    service.close().await.unwrap();
+}
@@ -2,44 +2,165 @@
 
 use std::collections::BTreeMap;
 
+use serde::Serialize;
+
 /// Represents the entire RPC interface file.
 /// Represented as maps from names to structs/services.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct RpcInterface {
     pub structs: BTreeMap<Identifier, Struct>,
     pub services: BTreeMap<Identifier, Service>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct Struct {
-    /// Map from field names to field type.
-    pub fields: BTreeMap<Identifier, DataType>,
+    /// Map from field names to fields.
+    pub fields: BTreeMap<Identifier, Field>,
+    /// Derives to add to the generated struct, in addition to the default ones.
+    pub extra_derives: Vec<RustPath>,
+    /// Set with `@mirror(path::to::Type)`. The generated struct gets `From`
+    /// conversions to and from each of these types, which must have the same
+    /// fields.
+    pub mirrors: Vec<RustPath>,
+    /// Set with `@copy`. The generated struct is `Copy`, which is only allowed
+    /// if all of its fields are.
+    pub copy: bool,
+    /// Set if the struct is marked with `#[non_exhaustive]`. Other crates then
+    /// have to use the builder to create it, so adding fields doesn't break
+    /// them.
+    pub non_exhaustive: bool,
+    /// Set with `@rust_name("...")` to give the generated struct another name.
+    /// Struct names aren't sent over the network, so only the Rust code is
+    /// affected.
+    pub rust_name: Option<Identifier>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Field {
+    pub field_type: DataType,
+    /// Used in the generated `Default` impl. If this is `None`, the field type's
+    /// `Default` impl is used instead.
+    pub default_value: Option<Literal>,
+    /// Set with `@rust_name("...")`. The generated field has this name, but it
+    /// is still encoded under the name in the interface file.
+    pub rust_name: Option<Identifier>,
+    /// Set with `@skip_if_default`. The field is left out of the encoded
+    /// struct when it has its default value, and the default value is used
+    /// when decoding a struct without it.
+    pub skip_if_default: bool,
+    /// Set with `@lossy_utf8` on a `string` field. Invalid UTF-8 is decoded
+    /// with replacement characters instead of failing to decode the struct.
+    pub lossy_utf8: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct Service {
+    /// The service written after a colon, as in `service Derived : Base`. Its
+    /// methods are copied into `methods` after parsing.
+    pub base: Option<Identifier>,
     /// Map from method name to method type.
     pub methods: BTreeMap<Identifier, Method>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct Method {
+    /// The method ID that was written with `@id(...)`. Methods without one are
+    /// numbered automatically.
+    pub id: Option<u64>,
+    /// Set if the method is marked with `@deprecated`.
+    pub deprecated: Option<Deprecation>,
+    /// Set with `@rust_name("...")`. The generated method has this name, but
+    /// its ID still comes from the name in the interface file.
+    pub rust_name: Option<Identifier>,
+    /// Set with `@named_args`. The arguments are encoded as a map from
+    /// parameter names to values instead of as a tuple, and missing arguments
+    /// get their type's default value. So a parameter can be added without
+    /// breaking clients that don't send it yet.
+    pub named_args: bool,
+    /// Set with `@incremental`. Only for methods whose only parameter is
+    /// `bytes`. The client sends the bytes in pieces instead of in one message,
+    /// so the server can handle them as they arrive, through the
+    /// `_incremental` version of the method.
+    pub incremental: bool,
+    /// Set if the method is marked with `oneway`. The client doesn't wait for
+    /// the call to finish, and the server doesn't respond to it. The return
+    /// type of such a method is [ReturnType::Nothing].
+    pub oneway: bool,
     // Currently only &mut self. &self is not supported.
     pub non_self_params: Vec<(Identifier, DataType)>,
     pub return_type: ReturnType,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Deprecation {
+    /// Shown in the warnings for using the deprecated item.
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub enum ReturnType {
     ServiceRefMut(Identifier),
+    /// A service that doesn't borrow from the service that returned it.
+    OwnedService(Identifier),
+    /// Several services that all borrow from the service that returned them.
+    ServiceRefMutTuple(Vec<Identifier>),
     Data(DataType),
+    /// A `Result` of two data types, written as `Result<T, E>`, for methods
+    /// whose errors the client should be able to inspect. The `Err` value is
+    /// sent like a return value, so it is separate from the errors of the call
+    /// itself.
+    Result(DataType, DataType),
+    /// The return type of `oneway` methods, which don't return anything.
+    Nothing,
+    /// The return type of a streaming method, written as
+    /// `name(&mut self, stream T) -> stream U`. The client sends items of the
+    /// first type and the server sends items of the second type, both while
+    /// the call runs. Such a method has no other parameters.
+    BidiStream(DataType, DataType),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub enum DataType {
     I32,
+    /// Sent as 16 big-endian bytes, since MessagePack has no 128-bit integers.
+    I128,
+    /// Sent like `I128`.
+    U128,
+    /// A `std::time::Duration`, sent as seconds and nanoseconds.
+    Duration,
+    /// A `std::time::SystemTime`, sent as seconds and nanoseconds since the
+    /// Unix epoch. Times before the epoch have negative seconds.
+    Timestamp,
+    /// A `uuid::Uuid`, sent as its 16 bytes. Needs the `uuid` feature of
+    /// rusty_rpc_lib.
+    Uuid,
+    /// A `char`, sent as its Unicode scalar value. Numbers that aren't Unicode
+    /// scalar values, such as surrogates, are rejected when received.
+    Char,
+    /// A byte string. Method parameters of this type are borrowed from the
+    /// received message instead of being copied.
+    Bytes,
+    /// A UTF-8 string. Like with `Bytes`, method parameters are borrowed from
+    /// the received message. Methods that return strings get a `_borrowed`
+    /// version, whose strings can borrow from the service.
+    String,
+    /// Method parameters of this type are passed by reference, so that the
+    /// client can keep using the struct after the call.
     Struct(Identifier),
+    /// A service that borrows from the service whose method returned the
+    /// struct. Only allowed in struct fields.
+    ServiceRef(Identifier),
+}
+
+/// A literal value written in the interface file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Literal {
+    Int(i64),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub struct Identifier(pub String);
+
+/// A path to a Rust item, such as `serde::Serialize`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RustPath(pub Vec<Identifier>);
@@ -1,15 +1,24 @@
 mod interface;
 mod parser;
 
-use std::{env::current_dir, fs};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env::{self, current_dir},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
 use proc_macro2::{Span, TokenStream};
-use quote::{format_ident, quote};
-use syn::{parse, parse_macro_input, parse_quote, FnArg, ItemImpl, LitStr, Lifetime, GenericParam};
+use quote::{format_ident, quote, ToTokens};
+use syn::{ext::IdentExt, parse, parse_macro_input, parse_quote, FnArg, ItemImpl, LitStr, Lifetime, GenericParam, Token};
 
-use interface::{DataType, Identifier, ReturnType, Service, Struct};
+use interface::{
+    DataType, Deprecation, Field, Identifier, Literal, Method, ReturnType, RpcInterface, RustPath,
+    Service, Struct,
+};
 
-use crate::parser::parse_interface;
+use crate::parser::{describe_parse_error, parse_interface};
 
 macro_rules! my_compile_error {
     ($msg:expr) => {{
@@ -23,41 +32,553 @@ macro_rules! my_compile_error {
 /// corresponding to the items in the specified protocol file.
 ///
 /// Example: `interface_file!("src/something.protocol");`
+///
+/// The path is relative to the directory of the crate's `Cargo.toml`. Paths
+/// that aren't found there are relative to the directory that the compiler runs
+/// in, as in older versions.
+///
+/// With `lock` after the path, the method IDs of each service are also recorded
+/// in a lock file next to the protocol file (`src/something.protocol.lock`).
+/// Later builds fail if the ID of a method in the lock file changed, for
+/// example because a method was added or removed in a way that shifted the
+/// automatically-assigned IDs. Methods that are new are added to the lock file.
+///
+/// Example: `interface_file!("src/something.protocol", lock);`
+///
+/// With `error = SomeType`, the generated methods return
+/// `Result<T, SomeType>` instead of `Result<T, RustyRpcError>`. The error type
+/// must implement `From<RustyRpcError>`, so that errors from the connection can
+/// be returned, and `Display`, since the server sends the message of an error
+/// returned by a method to the client. The client gets that message back as
+/// `RustyRpcError::ServerError`, converted to the error type.
+///
+/// Example: `interface_file!("src/something.protocol", error = anyhow::Error);`
 #[proc_macro]
 pub fn interface_file(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let input = parse_macro_input!(input as LitStr);
-    let protocol_file_path = current_dir().unwrap().join(input.value());
-    let interface_file_contents = match fs::read_to_string(&protocol_file_path) {
-        Ok(s) => s,
-        Err(_) => my_compile_error!("Unable to read the specified protocol file."),
+    let InterfaceFileInput {
+        path: input,
+        lock,
+        error_type,
+    } = parse_macro_input!(input as InterfaceFileInput);
+    let error_type: syn::Type = error_type
+        .unwrap_or_else(|| parse_quote! { ::rusty_rpc_lib::internal_for_macro::RustyRpcError });
+    let (protocol_file_path, rpc_interface) = match read_interface_file(&input) {
+        Ok(x) => x,
+        Err(e) => my_compile_error!(e),
     };
-    let rpc_interface = match parse_interface(interface_file_contents.as_bytes()) {
-        Ok((_, x)) => x,
-        Err(e) => my_compile_error!(format!("Error parsing the interface file: {e}")),
+    if lock {
+        if let Err(e) = check_lock_file(&protocol_file_path, &rpc_interface) {
+            my_compile_error!(format!("Error in the interface file {}: {}", input.value(), e));
+        }
+    }
+    let rpc_interface = match rename_structs(rpc_interface) {
+        Ok(x) => x,
+        Err(e) => my_compile_error!(e),
     };
 
     let all_code_for_structs = rpc_interface
         .structs
         .iter()
-        .map(|(x, y)| code_for_struct(x, y));
+        .map(|(x, y)| code_for_struct(x, y, &rpc_interface));
     let all_code_for_services = rpc_interface
         .services
         .iter()
-        .map(|(x, y)| code_for_service(x, y));
+        .map(|(x, y)| code_for_service(x, y, &rpc_interface, &error_type));
+
+    let require_uuid = if interface_uses_uuid(&rpc_interface) {
+        quote! { ::rusty_rpc_lib::internal_for_macro::require_uuid!(); }
+    } else {
+        quote! {}
+    };
 
     let path_str = protocol_file_path.to_str().unwrap();
     quote! {
-        const _HACK_TO_FORCE_RECOMPILE_UPON_CHANGING_PROTOCOL_FILE: &'static str = include_str!(#path_str);
+        #require_uuid
+        // Unnamed, so that using several protocol files in one module doesn't
+        // define it twice.
+        const _: &'static str = include_str!(#path_str);
+        // Gives a clearer error than the generated methods would if the error
+        // type doesn't have the required traits.
+        const _: fn() = || {
+            fn assert_error_type<E>()
+            where
+                E: ::std::convert::From<::rusty_rpc_lib::internal_for_macro::RustyRpcError>
+                    + ::std::fmt::Display
+                    + ::std::marker::Send,
+            {
+            }
+            assert_error_type::<#error_type>();
+        };
         #(#all_code_for_structs)*
         #(#all_code_for_services)*
     }
     .into()
 }
 
+/// Macro to be used as an expression. It will write a JSON description of the
+/// items in the specified protocol file, for use by other tools (e.g., code
+/// generators for other languages). The JSON file is written next to the
+/// protocol file, with its extension replaced by `json`. The macro evaluates
+/// to the same JSON as a `&'static str`. The path is resolved like in
+/// [interface_file!].
+///
+/// Example: `const SCHEMA: &str = interface_schema_file!("src/something.protocol");`
+#[proc_macro]
+pub fn interface_schema_file(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as LitStr);
+    let (protocol_file_path, rpc_interface) = match read_interface_file(&input) {
+        Ok(x) => x,
+        Err(e) => my_compile_error!(e),
+    };
+
+    let schema = serde_json::to_string_pretty(&rpc_interface)
+        .expect("Serializing the interface somehow failed.");
+    if fs::write(protocol_file_path.with_extension("json"), &schema).is_err() {
+        my_compile_error!("Unable to write the schema file.");
+    }
+
+    let path_str = protocol_file_path.to_str().unwrap();
+    quote! {
+        {
+            const _HACK_TO_FORCE_RECOMPILE_UPON_CHANGING_PROTOCOL_FILE: &'static str = include_str!(#path_str);
+            #schema
+        }
+    }
+    .into()
+}
+
+/// The input of `interface_file!`: the path to the protocol file, optionally
+/// followed by `, lock` and `, error = SomeType`.
+struct InterfaceFileInput {
+    path: LitStr,
+    lock: bool,
+    error_type: Option<syn::Type>,
+}
+impl parse::Parse for InterfaceFileInput {
+    fn parse(input: parse::ParseStream) -> syn::Result<Self> {
+        let path = input.parse()?;
+        let mut lock = false;
+        let mut error_type = None;
+        while input.parse::<Option<Token![,]>>()?.is_some() && !input.is_empty() {
+            let option: syn::Ident = input.parse()?;
+            if option == "lock" && !lock {
+                lock = true;
+            } else if option == "error" && error_type.is_none() {
+                input.parse::<Token![=]>()?;
+                error_type = Some(input.parse()?);
+            } else {
+                return Err(syn::Error::new(
+                    option.span(),
+                    "Expected `lock` or `error = SomeType`, each at most once.",
+                ));
+            }
+        }
+        Ok(InterfaceFileInput {
+            path,
+            lock,
+            error_type,
+        })
+    }
+}
+
+/// The method IDs of each service, by service name and then method name, as
+/// stored in a lock file.
+type MethodIdLock = BTreeMap<String, BTreeMap<String, u64>>;
+
+/// Compares the method IDs of the interface with the lock file next to the
+/// protocol file, then adds any new methods to the lock file, creating it if
+/// necessary. Methods that are in the lock file but no longer in the interface
+/// are kept, so that their IDs are still checked if they come back.
+fn check_lock_file(protocol_file_path: &Path, rpc_interface: &RpcInterface) -> Result<(), String> {
+    let mut lock_file_path = protocol_file_path.as_os_str().to_owned();
+    lock_file_path.push(".lock");
+    let lock_file_path = PathBuf::from(lock_file_path);
+    let lock_file_name = lock_file_path.file_name().unwrap().to_string_lossy();
+
+    let old_lock = match fs::read_to_string(&lock_file_path) {
+        Ok(contents) => serde_json::from_str::<MethodIdLock>(&contents)
+            .map_err(|e| format!("Unable to parse the lock file {}: {}", lock_file_name, e))?,
+        Err(_) => MethodIdLock::new(),
+    };
+    let mut new_lock = old_lock.clone();
+    for (service_name, service) in &rpc_interface.services {
+        let method_ids = assign_method_ids(service_name, service)?;
+        let locked_ids = new_lock.entry(service_name.0.clone()).or_default();
+        for (method_name, method_id) in service.methods.keys().zip(method_ids) {
+            let locked_id = *locked_ids.entry(method_name.0.clone()).or_insert(method_id);
+            if locked_id != method_id {
+                return Err(format!(
+                    "Method {} of service {} has ID {}, but the lock file {} says that it has ID {}. \
+                    Use @id({}) to keep the old ID, or remove the method from the lock file if \
+                    changing the ID is intended.",
+                    method_name.0,
+                    service_name.0,
+                    method_id,
+                    lock_file_name,
+                    locked_id,
+                    locked_id
+                ));
+            }
+        }
+    }
+
+    if new_lock != old_lock || !lock_file_path.exists() {
+        let contents = serde_json::to_string_pretty(&new_lock)
+            .expect("Serializing the lock file somehow failed.");
+        fs::write(&lock_file_path, contents + "\n")
+            .map_err(|_| format!("Unable to write the lock file {}.", lock_file_name))?;
+    }
+    Ok(())
+}
+
+/// Where the protocol file at `path` is. Paths are relative to the directory of
+/// the crate that uses the macro, so that they don't depend on where cargo is
+/// run from. For compatibility, a path that doesn't exist there is relative to
+/// the directory that the compiler runs in, which is the workspace root for
+/// crates in a workspace.
+fn resolve_protocol_file_path(path: &str, manifest_dir: Option<&Path>, current_dir: &Path) -> PathBuf {
+    if let Some(manifest_dir) = manifest_dir {
+        let crate_relative_path = manifest_dir.join(path);
+        if crate_relative_path.exists() {
+            return crate_relative_path;
+        }
+    }
+    current_dir.join(path)
+}
+
+/// The protocol files that were already parsed by this compiler process, with
+/// their contents at that time. A client and a server module that use the same
+/// protocol file then only parse it once. The contents are compared on each
+/// use, so an edited file is parsed again, e.g. in a long-running IDE process.
+static PARSED_INTERFACES: Mutex<BTreeMap<PathBuf, (String, RpcInterface)>> =
+    Mutex::new(BTreeMap::new());
+
+/// Reads and parses the protocol file at the specified path.
+fn read_interface_file(path: &LitStr) -> Result<(PathBuf, RpcInterface), String> {
+    let manifest_dir = env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from);
+    let protocol_file_path =
+        resolve_protocol_file_path(&path.value(), manifest_dir.as_deref(), &current_dir().unwrap());
+    let interface_file_contents = fs::read_to_string(&protocol_file_path)
+        .map_err(|_| "Unable to read the specified protocol file.".to_string())?;
+    let mut parsed_interfaces = PARSED_INTERFACES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((contents, rpc_interface)) = parsed_interfaces.get(&protocol_file_path) {
+        if *contents == interface_file_contents {
+            return Ok((protocol_file_path, rpc_interface.clone()));
+        }
+    }
+    let mut rpc_interface = match parse_interface(interface_file_contents.as_bytes()) {
+        Ok((_, x)) => x,
+        Err(e) => {
+            return Err(format!(
+                "Error parsing the interface file {} at {}",
+                path.value(),
+                describe_parse_error(interface_file_contents.as_bytes(), &e)
+            ))
+        }
+    };
+    check_type_references(&rpc_interface)
+        .and_then(|()| copy_inherited_methods(&mut rpc_interface))
+        .map_err(|e| format!("Error in the interface file {}: {}", path.value(), e))?;
+    parsed_interfaces.insert(
+        protocol_file_path.clone(),
+        (interface_file_contents, rpc_interface.clone()),
+    );
+    Ok((protocol_file_path, rpc_interface))
+}
+
+/// Checks that every struct and service that the interface refers to is
+/// defined, so that a typo doesn't turn into an error about a missing Rust
+/// type.
+fn check_type_references(rpc_interface: &RpcInterface) -> Result<(), String> {
+    for (struct_name, struct_) in &rpc_interface.structs {
+        for (field_name, field) in &struct_.fields {
+            check_data_type_reference(&field.field_type, rpc_interface).map_err(|e| {
+                format!("Field {} of struct {} {}", field_name.0, struct_name.0, e)
+            })?;
+        }
+    }
+    for (service_name, service) in &rpc_interface.services {
+        if let Some(base) = &service.base {
+            if !rpc_interface.services.contains_key(base) {
+                return Err(format!(
+                    "Service {} inherits from service {}, which is not defined.",
+                    service_name.0, base.0
+                ));
+            }
+        }
+        for (method_name, method) in &service.methods {
+            for (param_name, param_type) in &method.non_self_params {
+                check_data_type_reference(param_type, rpc_interface).map_err(|e| {
+                    format!(
+                        "Parameter {} of method {} of service {} {}",
+                        param_name.0, method_name.0, service_name.0, e
+                    )
+                })?;
+            }
+            let returned_services = match &method.return_type {
+                ReturnType::ServiceRefMut(x) | ReturnType::OwnedService(x) => vec![x],
+                ReturnType::ServiceRefMutTuple(x) => x.iter().collect(),
+                ReturnType::Nothing => vec![],
+                ReturnType::Data(x) => {
+                    check_data_type_reference(x, rpc_interface).map_err(|e| {
+                        format!(
+                            "The return value of method {} of service {} {}",
+                            method_name.0, service_name.0, e
+                        )
+                    })?;
+                    vec![]
+                }
+                ReturnType::Result(ok_type, err_type) => {
+                    for (variant, x) in [("Ok", ok_type), ("Err", err_type)] {
+                        check_data_type_reference(x, rpc_interface).map_err(|e| {
+                            format!(
+                                "The {} value of method {} of service {} {}",
+                                variant, method_name.0, service_name.0, e
+                            )
+                        })?;
+                        if let DataType::Struct(x) = x {
+                            if struct_has_services(x, rpc_interface) {
+                                return Err(format!(
+                                    "The {} value of method {} of service {} is struct {}, which contains a service. Results can only contain data.",
+                                    variant, method_name.0, service_name.0, x.0
+                                ));
+                            }
+                        }
+                    }
+                    vec![]
+                }
+                ReturnType::BidiStream(request_type, response_type) => {
+                    for (direction, x) in [("request", request_type), ("response", response_type)] {
+                        check_data_type_reference(x, rpc_interface).map_err(|e| {
+                            format!(
+                                "The {} items of method {} of service {} {}",
+                                direction, method_name.0, service_name.0, e
+                            )
+                        })?;
+                        if let DataType::Struct(x) = x {
+                            if struct_has_services(x, rpc_interface) {
+                                return Err(format!(
+                                    "The {} items of method {} of service {} are struct {}, which contains a service. Streams can only contain data.",
+                                    direction, method_name.0, service_name.0, x.0
+                                ));
+                            }
+                        }
+                    }
+                    vec![]
+                }
+            };
+            if let Some(x) = returned_services
+                .into_iter()
+                .find(|x| !rpc_interface.services.contains_key(x))
+            {
+                return Err(format!(
+                    "Method {} of service {} returns service {}, which is not defined.",
+                    method_name.0, service_name.0, x.0
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies the methods of each base service into the services that inherit from
+/// it. The copied methods get the IDs that they have in the base service, so a
+/// client of the base service can also call them on a derived service.
+fn copy_inherited_methods(rpc_interface: &mut RpcInterface) -> Result<(), String> {
+    let mut flattened_services = BTreeMap::new();
+    for service_name in rpc_interface.services.keys() {
+        flatten_service(
+            service_name,
+            &rpc_interface.services,
+            &mut flattened_services,
+            &mut Vec::new(),
+        )?;
+    }
+    rpc_interface.services = flattened_services;
+    Ok(())
+}
+
+/// Adds `service_name`, with the methods of its base services, to
+/// `flattened_services`. `derived_services` are the services that are being
+/// flattened and inherit from `service_name`, to detect cycles.
+fn flatten_service(
+    service_name: &Identifier,
+    services: &BTreeMap<Identifier, Service>,
+    flattened_services: &mut BTreeMap<Identifier, Service>,
+    derived_services: &mut Vec<Identifier>,
+) -> Result<(), String> {
+    if flattened_services.contains_key(service_name) {
+        return Ok(());
+    }
+    if let Some(i) = derived_services.iter().position(|x| x == service_name) {
+        let cycle: Vec<&str> = derived_services[i..]
+            .iter()
+            .chain([service_name])
+            .map(|x| &*x.0)
+            .collect();
+        return Err(format!(
+            "Service {} inherits from itself ({}).",
+            service_name.0,
+            cycle.join(" -> ")
+        ));
+    }
+    let service = &services[service_name];
+    let mut methods = service.methods.clone();
+    if let Some(base_name) = &service.base {
+        derived_services.push(service_name.clone());
+        flatten_service(base_name, services, flattened_services, derived_services)?;
+        derived_services.pop();
+        let base = &flattened_services[base_name];
+        let base_method_ids = assign_method_ids(base_name, base)?;
+        for ((method_name, method), id) in base.methods.iter().zip(base_method_ids) {
+            if methods.contains_key(method_name) {
+                return Err(format!(
+                    "Service {} has a method named {}, which it already inherits from service {}.",
+                    service_name.0, method_name.0, base_name.0
+                ));
+            }
+            let method = Method {
+                id: Some(id),
+                ..method.clone()
+            };
+            methods.insert(method_name.clone(), method);
+        }
+    }
+    flattened_services.insert(
+        service_name.clone(),
+        Service {
+            base: service.base.clone(),
+            methods,
+        },
+    );
+    Ok(())
+}
+
+/// Renames the structs that have a `@rust_name(...)`, along with every use of
+/// them. The names of structs aren't sent over the network, so the generated
+/// code can just use the new names everywhere.
+fn rename_structs(mut rpc_interface: RpcInterface) -> Result<RpcInterface, String> {
+    let renames: BTreeMap<Identifier, Identifier> = rpc_interface
+        .structs
+        .iter()
+        .filter_map(|(name, struct_)| Some((name.clone(), struct_.rust_name.clone()?)))
+        .collect();
+    if renames.is_empty() {
+        return Ok(rpc_interface);
+    }
+    let rename = |name: &mut Identifier| {
+        if let Some(new_name) = renames.get(name) {
+            *name = new_name.clone();
+        }
+    };
+    let rename_data_type = |data_type: &mut DataType| {
+        if let DataType::Struct(x) = data_type {
+            rename(x);
+        }
+    };
+    let mut structs = BTreeMap::new();
+    for (mut struct_name, mut struct_) in rpc_interface.structs {
+        rename(&mut struct_name);
+        struct_.fields.values_mut().for_each(|x| rename_data_type(&mut x.field_type));
+        if structs.contains_key(&struct_name) || rpc_interface.services.contains_key(&struct_name) {
+            return Err(format!(
+                "The Rust name {} is used for more than one struct or service.",
+                struct_name.0
+            ));
+        }
+        structs.insert(struct_name, struct_);
+    }
+    rpc_interface.structs = structs;
+    for method in rpc_interface
+        .services
+        .values_mut()
+        .flat_map(|x| x.methods.values_mut())
+    {
+        method
+            .non_self_params
+            .iter_mut()
+            .for_each(|x| rename_data_type(&mut x.1));
+        match &mut method.return_type {
+            ReturnType::Data(x) => rename_data_type(x),
+            ReturnType::Result(ok_type, err_type) => {
+                rename_data_type(ok_type);
+                rename_data_type(err_type);
+            }
+            ReturnType::BidiStream(request_type, response_type) => {
+                rename_data_type(request_type);
+                rename_data_type(response_type);
+            }
+            _ => {}
+        }
+    }
+    Ok(rpc_interface)
+}
+
+/// Whether any struct field, method parameter, or return value has the `uuid`
+/// type, which only works if rusty_rpc_lib has the `uuid` feature.
+fn interface_uses_uuid(rpc_interface: &RpcInterface) -> bool {
+    let field_types = rpc_interface
+        .structs
+        .values()
+        .flat_map(|x| x.fields.values().map(|x| &x.field_type));
+    let method_types = rpc_interface
+        .services
+        .values()
+        .flat_map(|x| x.methods.values())
+        .flat_map(|method| {
+            let return_types = match &method.return_type {
+                ReturnType::Data(x) => vec![x],
+                ReturnType::Result(x, y) | ReturnType::BidiStream(x, y) => vec![x, y],
+                ReturnType::ServiceRefMut(_)
+                | ReturnType::OwnedService(_)
+                | ReturnType::ServiceRefMutTuple(_)
+                | ReturnType::Nothing => vec![],
+            };
+            method
+                .non_self_params
+                .iter()
+                .map(|(_, x)| x)
+                .chain(return_types)
+        });
+    field_types
+        .chain(method_types)
+        .any(|x| *x == DataType::Uuid)
+}
+
+/// Returns the end of the error message if `data_type` refers to an undefined
+/// struct or service.
+fn check_data_type_reference(
+    data_type: &DataType,
+    rpc_interface: &RpcInterface,
+) -> Result<(), String> {
+    match data_type {
+        DataType::Struct(x) if !rpc_interface.structs.contains_key(x) => {
+            Err(format!("has type {}, which is not a defined struct.", x.0))
+        }
+        DataType::ServiceRef(x) if !rpc_interface.services.contains_key(x) => {
+            Err(format!(
+                "has type &mut service {}, which is not a defined service.",
+                x.0
+            ))
+        }
+        DataType::I32
+        | DataType::I128
+        | DataType::U128
+        | DataType::Duration
+        | DataType::Timestamp
+        | DataType::Uuid
+        | DataType::Char
+        | DataType::Bytes
+        | DataType::String
+        | DataType::Struct(_)
+        | DataType::ServiceRef(_) => Ok(()),
+    }
+}
+
 /// Macro to be used on each service implementation. It will automatically call
 /// `#[async_trait]` for you.
 /// 
-/// If your struct has lifetime parameters, then give them to this macro. E.g., `#[service_server_impl('a, 'b, 'c)]`
+/// If your struct has lifetime parameters, declare them on the impl. E.g., `impl<'a, 'b> MyService for MyServiceImpl<'a, 'b>`
 ///
 /// Example:
 /// ```ignore
@@ -94,32 +615,73 @@ pub fn service_server_impl(
             _ => None
         } 
     }).collect();
-    let (generics, trait_lifetime) = match &*lifetimes {
-        [] => (quote! { <'a> }, quote! { 'a }),
-        [lifetime] => (quote! { #input_generics }, quote! { #lifetime }),
-        _ => my_compile_error!("More than one lifetime parameter not supported for service_server_impl"),
+    let (generics, where_clause, trait_lifetime) = match &*lifetimes {
+        [] => (quote! { <'a> }, quote! {}, quote! { 'a }),
+        [lifetime] => (quote! { #input_generics }, quote! {}, quote! { #lifetime }),
+        _ => {
+            // The service is only usable while all of the lifetimes are alive,
+            // so use a new lifetime that is outlived by all of them. Lifetimes
+            // must come before type and const parameters.
+            let service_lifetime = Lifetime::new("'rusty_rpc_service", Span::call_site());
+            let mut generics = input_generics.clone();
+            generics.params.insert(0, parse_quote! { #service_lifetime });
+            let type_params: Vec<&syn::Ident> = input_generics.type_params().map(|x| &x.ident).collect();
+            let where_clause = generics.make_where_clause();
+            for lifetime in &lifetimes {
+                where_clause.predicates.push(parse_quote! { #lifetime: #service_lifetime });
+            }
+            // The user can't name the new lifetime, so the bounds that the
+            // service needs to live that long are added here.
+            for type_param in type_params {
+                where_clause.predicates.push(parse_quote! { #type_param: #service_lifetime });
+            }
+            let where_clause = &generics.where_clause;
+            (quote! { #generics }, quote! { #where_clause }, quote! { #service_lifetime })
+        }
     };
 
     let internal = quote! { ::rusty_rpc_lib::internal_for_macro };
+    let server_traits = code_for_server_traits(
+        &generics,
+        &where_clause,
+        &trait_lifetime,
+        &service_type_name,
+        &service_trait_name,
+    );
     quote! {
         #[#internal::async_trait]
         #original_input
 
+        #server_traits
+    }.into()
+}
+
+/// Implements the traits that let the server call the methods of the service
+/// trait on `service_type_name`, by forwarding to the trait.
+fn code_for_server_traits(
+    generics: &TokenStream,
+    where_clause: &TokenStream,
+    trait_lifetime: &TokenStream,
+    service_type_name: &impl ToTokens,
+    service_trait_name: &impl ToTokens,
+) -> TokenStream {
+    let internal = quote! { ::rusty_rpc_lib::internal_for_macro };
+    quote! {
         impl #generics
         #internal::RustyRpcServiceServerWithKnownClientType<#trait_lifetime, dyn #service_trait_name + #trait_lifetime>
-        for #service_type_name {
+        for #service_type_name #where_clause {
         }
         #[#internal::async_trait]
         unsafe impl #generics
         #internal::RustyRpcServiceServer<#trait_lifetime>
-        for #service_type_name {
+        for #service_type_name #where_clause {
             async unsafe fn parse_and_call_method_locally(
                 &mut self,
                 self_guard: #internal::ServerGuard,
                 method_id: #internal::MethodId,
                 method_args: #internal::MethodArgs,
                 service_collection: &mut #internal::ServerCollection,
-            ) -> ::std::io::Result<#internal::ServerMessage> {
+            ) -> ::std::result::Result<#internal::ServerMessage, #internal::RustyRpcError> {
                 <#service_type_name as #service_trait_name>::_rusty_rpc_forward__parse_and_call_method_locally(
                     self,
                     self_guard,
@@ -128,122 +690,1275 @@ pub fn service_server_impl(
                     service_collection
                 ).await
             }
+            fn service_name(&self) -> &'static str {
+                <#service_type_name as #service_trait_name>::_rusty_rpc_forward__service_name(self)
+            }
+            fn method_name(&self, method_id: #internal::MethodId) -> ::std::option::Option<&'static str> {
+                <#service_type_name as #service_trait_name>::_rusty_rpc_forward__method_name(self, method_id)
+            }
+            async fn on_drop(&mut self) {
+                <#service_type_name as #service_trait_name>::on_drop(self).await
+            }
+            async fn on_connection_closed(&mut self) {
+                <#service_type_name as #service_trait_name>::on_connection_closed(self).await
+            }
         }
-    }.into()
+    }
 }
 
-fn code_for_struct(struct_name: &Identifier, struct_: &Struct) -> TokenStream {
+fn code_for_struct(
+    struct_name: &Identifier,
+    struct_: &Struct,
+    rpc_interface: &RpcInterface,
+) -> TokenStream {
     let internal = quote! { ::rusty_rpc_lib::internal_for_macro };
+    if let Some(cycle) = find_struct_cycle(struct_name, struct_name, rpc_interface, &mut BTreeSet::new()) {
+        let cycle: Vec<&str> = cycle.iter().map(|x| &*x.0).collect();
+        return compile_error(format!(
+            "Struct {} contains itself ({}), so it would have an infinite size.",
+            struct_name.0,
+            cycle.join(" -> ")
+        ));
+    }
+    for (field_name, field) in &struct_.fields {
+        if let DataType::Struct(x) = &field.field_type {
+            if struct_has_services(x, rpc_interface) {
+                return compile_error(format!(
+                    "Field {} of struct {} has type {}, which contains a service. Structs that contain services can only be returned from methods.",
+                    field_name.0, struct_name.0, x.0
+                ));
+            }
+        }
+    }
+    if let Some((field_name, _)) = struct_
+        .fields
+        .iter()
+        .find(|(_, x)| x.lossy_utf8 && x.field_type != DataType::String)
+    {
+        return compile_error(format!(
+            "Field {} of struct {} cannot be lossy_utf8, since it is not a string.",
+            field_name.0, struct_name.0
+        ));
+    }
+    if struct_.copy {
+        for (field_name, field) in &struct_.fields {
+            let field_is_copy = match &field.field_type {
+                DataType::I32
+                | DataType::I128
+                | DataType::U128
+                | DataType::Duration
+                | DataType::Timestamp
+                | DataType::Uuid
+                | DataType::Char => true,
+                DataType::Struct(x) => rpc_interface.structs.get(x).is_some_and(|x| x.copy),
+                DataType::Bytes | DataType::String | DataType::ServiceRef(_) => false,
+            };
+            if !field_is_copy {
+                return compile_error(format!(
+                    "Struct {} cannot be copy, since its field {} is not. Only integers and copy structs are.",
+                    struct_name.0, field_name.0
+                ));
+            }
+        }
+    }
+    if struct_has_services(struct_name, rpc_interface) {
+        if let Some((field_name, _)) = struct_.fields.iter().find(|(_, x)| x.skip_if_default) {
+            return compile_error(format!(
+                "Field {} of struct {} cannot be skip_if_default, since the struct contains a service.",
+                field_name.0, struct_name.0
+            ));
+        }
+        return code_for_service_struct(struct_name, struct_);
+    }
+    let eq_derives = if struct_is_eq(struct_name, rpc_interface, &mut BTreeSet::new()) {
+        quote! { ::std::cmp::PartialEq, ::std::cmp::Eq, ::std::hash::Hash }
+    } else {
+        quote! { ::std::cmp::PartialEq }
+    };
+    let copy_derive = if struct_.copy {
+        quote! { ::std::marker::Copy, }
+    } else {
+        quote! {}
+    };
     let struct_name = to_syn_ident(struct_name);
 
+    // These are always derived or implemented, so deriving them again would
+    // cause conflicting impls.
+    let builtin_derives = [
+        "Debug",
+        "Serialize",
+        "Deserialize",
+        "Clone",
+        "PartialEq",
+        "Eq",
+        "Hash",
+        "Default",
+    ];
+    let mut extra_derives: Vec<syn::Path> = Vec::new();
+    for path in &struct_.extra_derives {
+        if let [x] = &*path.0 {
+            if builtin_derives.contains(&&*x.0) || (struct_.copy && x.0 == "Copy") {
+                return compile_error(format!(
+                    "Struct {struct_name} already implements {}, so it cannot be derived.",
+                    x.0
+                ));
+            }
+        }
+        extra_derives.push(rust_path_to_syn_path(path));
+    }
+
+    let default_values: Vec<TokenStream> = struct_
+        .fields
+        .iter()
+        .map(|(field_name, field)| {
+            match (&field.default_value, &field.field_type) {
+                // `SystemTime` has no `Default` impl.
+                (None, DataType::Timestamp) => quote! { ::std::time::UNIX_EPOCH },
+                (None, _) => quote! { ::std::default::Default::default() },
+                (Some(Literal::Int(x)), DataType::I32) => match i32::try_from(*x) {
+                    Ok(x) => quote! { #x },
+                    Err(_) => compile_error(format!(
+                        "Default value of field {} is out of range.",
+                        field_name.0
+                    )),
+                },
+                (Some(Literal::Int(x)), DataType::I128) => {
+                    let x = i128::from(*x);
+                    quote! { #x }
+                }
+                (Some(Literal::Int(x)), DataType::U128) => match u128::try_from(*x) {
+                    Ok(x) => quote! { #x },
+                    Err(_) => compile_error(format!(
+                        "Default value of field {} is out of range.",
+                        field_name.0
+                    )),
+                },
+                (Some(_), _) => {
+                    compile_error(format!(
+                        "Default value of field {} does not match the field type.",
+                        field_name.0
+                    ))
+                }
+            }
+        })
+        .collect();
+    let default_field_tokens: Vec<TokenStream> = struct_
+        .fields
+        .iter()
+        .zip(&default_values)
+        .map(|((field_name, field), default_value)| {
+            let field_name_ident = rust_ident(field_name, &field.rust_name);
+            quote! { #field_name_ident: #default_value, }
+        })
+        .collect();
+    // Fields that are skipped when they have their default value get a
+    // function that returns the default value, for `#[serde(default = "...")]`.
+    let skipped_fields: Vec<(syn::Ident, TokenStream, syn::Ident, &TokenStream)> = struct_
+        .fields
+        .iter()
+        .zip(&default_values)
+        .filter(|((_, field), _)| field.skip_if_default)
+        .map(|((field_name, field), default_value)| {
+            let field_name = rust_ident(field_name, &field.rust_name);
+            let default_fn_name = format_ident!("__rusty_rpc_default_{}", field_name.unraw());
+            let type_token_stream = data_type_to_token_stream(&field.field_type);
+            (field_name, type_token_stream, default_fn_name, default_value)
+        })
+        .collect();
     let struct_field_tokens: Vec<TokenStream> = struct_
         .fields
         .iter()
-        .map(|(field_name, field_type)| {
-            let field_name = to_syn_ident(field_name);
-            let type_token_stream = data_type_to_token_stream(field_type);
-            quote! { pub #field_name: #type_token_stream, }
+        .map(|(field_name, field)| {
+            let rename_attribute = rename_attribute(field_name, field);
+            let field_name = rust_ident(field_name, &field.rust_name);
+            let type_token_stream = data_type_to_token_stream(&field.field_type);
+            let serde_attribute = serde_with_attribute(field);
+            let default_attribute = if field.skip_if_default {
+                let default_fn_path = format!(
+                    "{}::__rusty_rpc_default_{}",
+                    struct_name,
+                    field_name.unraw()
+                );
+                quote! { #[serde(default = #default_fn_path)] }
+            } else {
+                quote! {}
+            };
+            quote! { #serde_attribute #default_attribute #rename_attribute pub #field_name: #type_token_stream, }
         })
         .collect();
+    let (serialize_derive, serialize_impl) = if skipped_fields.is_empty() {
+        (quote! { #internal::Serialize, }, quote! {})
+    } else {
+        (quote! {}, code_for_skipping_serialize(&struct_name, struct_, &skipped_fields))
+    };
+    let default_fns = skipped_fields
+        .iter()
+        .map(|(_, type_token_stream, default_fn_name, default_value)| {
+            quote! {
+                #[doc(hidden)]
+                fn #default_fn_name() -> #type_token_stream {
+                    #default_value
+                }
+            }
+        });
+    let builder_tokens = code_for_struct_builder(&struct_name, struct_);
+    let mirror_tokens = code_for_struct_mirrors(&struct_name, struct_);
+    let non_exhaustive_attribute = if struct_.non_exhaustive {
+        quote! { #[non_exhaustive] }
+    } else {
+        quote! {}
+    };
     quote! {
-        #[derive(::std::fmt::Debug, #internal::Serialize, #internal::Deserialize, ::std::clone::Clone)]
+        #[derive(::std::fmt::Debug, #serialize_derive #internal::Deserialize, ::std::clone::Clone, #copy_derive #eq_derives #(, #extra_derives)*)]
+        #non_exhaustive_attribute
         pub struct #struct_name {
             #(#struct_field_tokens)*
         }
+        impl #struct_name {
+            #(#default_fns)*
+        }
+        #serialize_impl
+        #builder_tokens
+        #mirror_tokens
         impl #internal::RustyRpcStruct for #struct_name {
         }
+        impl ::std::default::Default for #struct_name {
+            fn default() -> Self {
+                Self {
+                    #(#default_field_tokens)*
+                }
+            }
+        }
+    }
+}
+
+/// `From` conversions in both directions between the struct and each of its
+/// `@mirror(...)` types. Destructuring without `..` makes the compiler point out
+/// fields that only one of the structs has. The fields are converted with
+/// `Into`, so a field can have a type that mirrors the field's type.
+fn code_for_struct_mirrors(struct_name: &syn::Ident, struct_: &Struct) -> TokenStream {
+    let field_names: Vec<syn::Ident> = struct_
+        .fields
+        .iter()
+        .map(|(field_name, field)| rust_ident(field_name, &field.rust_name))
+        .collect();
+    let impls = struct_.mirrors.iter().map(|mirror| {
+        let mirror = rust_path_to_syn_path(mirror);
+        quote! {
+            impl ::std::convert::From<#mirror> for #struct_name {
+                fn from(value: #mirror) -> Self {
+                    let #mirror { #(#field_names),* } = value;
+                    Self {
+                        #(#field_names: ::std::convert::Into::into(#field_names)),*
+                    }
+                }
+            }
+            impl ::std::convert::From<#struct_name> for #mirror {
+                fn from(value: #struct_name) -> Self {
+                    let #struct_name { #(#field_names),* } = value;
+                    Self {
+                        #(#field_names: ::std::convert::Into::into(#field_names)),*
+                    }
+                }
+            }
+        }
+    });
+    quote! { #(#impls)* }
+}
+
+/// The arguments of a `@named_args` method, as something that serializes as a
+/// map from parameter names to `arguments`. Structs are serialized as arrays in
+/// MessagePack, so this is written by hand instead of with a derived struct.
+fn code_for_named_arguments(method: &Method, arguments: &[TokenStream]) -> TokenStream {
+    let internal = quote! { ::rusty_rpc_lib::internal_for_macro };
+    let type_params: Vec<syn::Ident> = (0..arguments.len())
+        .map(|i| format_ident!("T{}", i))
+        .collect();
+    let indices = (0..arguments.len()).map(syn::Index::from);
+    let param_names = method.non_self_params.iter().map(|(x, _)| &x.0);
+    let len = arguments.len();
+    quote! {
+        {
+            struct NamedArgs<#(#type_params),*>(#(#type_params),*);
+            impl<#(#type_params: #internal::Serialize),*> #internal::Serialize for NamedArgs<#(#type_params),*> {
+                fn serialize<S: #internal::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+                    use #internal::SerializeMap;
+                    let mut map = serializer.serialize_map(::std::option::Option::Some(#len))?;
+                    #(map.serialize_entry(#param_names, &self.#indices)?;)*
+                    map.end()
+                }
+            }
+            NamedArgs(#(#arguments),*)
+        }
+    }
+}
+
+/// Implements `Serialize` for a struct with `@skip_if_default` fields. The
+/// struct is encoded as a map from the index of each field to its value, so
+/// that leaving out a field doesn't shift the ones after it, as it would if the
+/// struct were encoded as an array. The derived `Deserialize` accepts field
+/// indices as keys.
+fn code_for_skipping_serialize(
+    struct_name: &syn::Ident,
+    struct_: &Struct,
+    skipped_fields: &[(syn::Ident, TokenStream, syn::Ident, &TokenStream)],
+) -> TokenStream {
+    let internal = quote! { ::rusty_rpc_lib::internal_for_macro };
+    let field_names: Vec<syn::Ident> = struct_
+        .fields
+        .iter()
+        .map(|(field_name, field)| rust_ident(field_name, &field.rust_name))
+        .collect();
+    let is_present: Vec<TokenStream> = field_names
+        .iter()
+        .map(|field_name| {
+            match skipped_fields.iter().find(|(x, ..)| x == field_name) {
+                Some((_, _, default_fn_name, _)) => {
+                    quote! { self.#field_name != Self::#default_fn_name() }
+                }
+                None => quote! { true },
+            }
+        })
+        .collect();
+    let indices = 0..field_names.len() as u64;
+    let values = struct_
+        .fields
+        .values()
+        .zip(&field_names)
+        .map(|(field, field_name)| match field.field_type {
+            DataType::Bytes => quote! { &#internal::BytesRef(&self.#field_name) },
+            DataType::I128 => quote! { &#internal::WireI128(self.#field_name) },
+            DataType::U128 => quote! { &#internal::WireU128(self.#field_name) },
+            DataType::Duration => quote! { &#internal::WireDuration(self.#field_name) },
+            DataType::Timestamp => quote! { &#internal::WireTimestamp(self.#field_name) },
+            DataType::Uuid => quote! { &#internal::WireUuid(self.#field_name) },
+            DataType::Char => quote! { &#internal::WireChar(self.#field_name) },
+            _ => quote! { &self.#field_name },
+        });
+    quote! {
+        impl #internal::Serialize for #struct_name {
+            fn serialize<S: #internal::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+                use #internal::SerializeMap;
+                let is_present = [#(#is_present),*];
+                let len = is_present.iter().filter(|x| **x).count();
+                let mut map = serializer.serialize_map(::std::option::Option::Some(len))?;
+                #(
+                    if is_present[#indices as usize] {
+                        map.serialize_entry(&#indices, #values)?;
+                    }
+                )*
+                map.end()
+            }
+        }
+    }
+}
+
+/// Whether the struct can derive `Eq` and `Hash`, which is the case when all
+/// of its fields can. `visited` is used to avoid infinite recursion.
+fn struct_is_eq(
+    struct_name: &Identifier,
+    rpc_interface: &RpcInterface,
+    visited: &mut BTreeSet<Identifier>,
+) -> bool {
+    if !visited.insert(struct_name.clone()) {
+        return true;
+    }
+    let struct_ = match rpc_interface.structs.get(struct_name) {
+        Some(x) => x,
+        None => return true,
+    };
+    struct_
+        .fields
+        .values()
+        .all(|field| match &field.field_type {
+            DataType::I32
+            | DataType::I128
+            | DataType::U128
+            | DataType::Duration
+            | DataType::Timestamp
+            | DataType::Uuid
+            | DataType::Char
+            | DataType::Bytes
+            | DataType::String => true,
+            DataType::Struct(x) => struct_is_eq(x, rpc_interface, visited),
+            DataType::ServiceRef(_) => false,
+        })
+}
+
+/// Whether the struct has a field that is a service.
+fn struct_has_services(struct_name: &Identifier, rpc_interface: &RpcInterface) -> bool {
+    rpc_interface.structs.get(struct_name).is_some_and(|struct_| {
+        struct_
+            .fields
+            .values()
+            .any(|field| matches!(field.field_type, DataType::ServiceRef(_)))
+    })
+}
+
+/// Generates a struct that has service fields. The services borrow from the
+/// service that returned the struct, so the struct has a lifetime and can't be
+/// serialized. Instead, a hidden wire struct with the IDs of the services in
+/// their place is sent.
+fn code_for_service_struct(struct_name: &Identifier, struct_: &Struct) -> TokenStream {
+    let internal = quote! { ::rusty_rpc_lib::internal_for_macro };
+    if !struct_.extra_derives.is_empty() {
+        return compile_error(format!(
+            "Struct {} contains a service, so it cannot derive anything.",
+            struct_name.0
+        ));
+    }
+    if !struct_.mirrors.is_empty() {
+        return compile_error(format!(
+            "Struct {} contains a service, so it cannot be mirrored.",
+            struct_name.0
+        ));
+    }
+    if struct_.non_exhaustive {
+        // There's no builder for these, so other crates couldn't create them.
+        return compile_error(format!(
+            "Struct {} contains a service, so it cannot be non_exhaustive.",
+            struct_name.0
+        ));
+    }
+    if let Some((field_name, _)) = struct_.fields.iter().find(|(_, x)| x.default_value.is_some()) {
+        return compile_error(format!(
+            "Struct {} contains a service, so field {} cannot have a default value.",
+            struct_name.0, field_name.0
+        ));
+    }
+    let struct_name = to_syn_ident(struct_name);
+    let wire_name = format_ident!("{}_RustyRpcWire", struct_name);
+    let wire_doc = format!("The form of [{}] that is sent over the network.", struct_name.unraw());
+
+    let field_names: Vec<syn::Ident> = struct_
+        .fields
+        .iter()
+        .map(|(field_name, field)| rust_ident(field_name, &field.rust_name))
+        .collect();
+    let field_types = struct_.fields.values().map(|x| data_type_to_token_stream(&x.field_type));
+    let wire_fields = struct_.fields.iter().map(|(field_name, field)| {
+        let rename_attribute = rename_attribute(field_name, field);
+        let field_name = rust_ident(field_name, &field.rust_name);
+        match field.field_type {
+            DataType::ServiceRef(_) => quote! { #rename_attribute pub #field_name: #internal::ServiceId, },
+            ref x => {
+                let serde_attribute = serde_with_attribute(field);
+                let field_type = data_type_to_token_stream(x);
+                quote! { #serde_attribute #rename_attribute pub #field_name: #field_type, }
+            }
+        }
+    });
+    let mut service_field_names: Vec<syn::Ident> = Vec::new();
+    let mut proxy_names: Vec<syn::Ident> = Vec::new();
+    let mut data_field_names: Vec<syn::Ident> = Vec::new();
+    for (field_name, field) in &struct_.fields {
+        match &field.field_type {
+            DataType::ServiceRef(x) => {
+                service_field_names.push(rust_ident(field_name, &field.rust_name));
+                proxy_names.push(format_ident!("{}_RustyRpcServiceProxy", to_syn_ident(x)));
+            }
+            _ => data_field_names.push(rust_ident(field_name, &field.rust_name)),
+        }
+    }
+    let service_count = service_field_names.len();
+
+    quote! {
+        #[derive(::std::fmt::Debug)]
+        pub struct #struct_name<'a> {
+            #(pub #field_names: #field_types,)*
+        }
+
+        #[doc = #wire_doc]
+        #[doc(hidden)]
+        #[derive(#internal::Serialize, #internal::Deserialize)]
+        pub struct #wire_name {
+            #(#wire_fields)*
+        }
+        impl #wire_name {
+            /// Registers the services in the struct as borrowing from the
+            /// service that `self_guard` locks.
+            ///
+            /// # Safety
+            ///
+            /// Same as `ServerCollection::register_services`.
+            pub unsafe fn from_local<'a: 'service, 'service>(
+                value: #struct_name<'service>,
+                self_guard: #internal::ServerGuard,
+                service_collection: &'a #internal::ServerCollection,
+            ) -> ::std::io::Result<Self> {
+                let #struct_name { #(#field_names),* } = value;
+                let local_services = ::std::vec![#(
+                    #internal::local_service_from_service_ref(#service_field_names)
+                        .expect("Server somehow returned a remote ServiceRefMut.")
+                        as ::std::boxed::Box<_>
+                ),*];
+                let service_ids = service_collection
+                    .register_services(local_services, ::std::option::Option::Some(self_guard))?;
+                let [#(#service_field_names),*] = <[#internal::ServiceId; #service_count]>::try_from(service_ids)
+                    .expect("Registered the wrong number of services.");
+                ::std::result::Result::Ok(Self { #(#field_names),* })
+            }
+
+            /// Creates proxies for the services in the struct.
+            pub fn into_remote<'a>(
+                self,
+                connection: &::std::sync::Arc<#internal::ClientConnection>,
+            ) -> #struct_name<'a> {
+                #struct_name {
+                    #(#data_field_names: self.#data_field_names,)*
+                    #(
+                        #service_field_names: #internal::service_ref_from_service_proxy(
+                            <#proxy_names as #internal::RustyRpcServiceProxy>::from_service_id(
+                                self.#service_field_names,
+                                connection.clone(),
+                            )
+                        ),
+                    )*
+                }
+            }
+        }
+    }
+}
+
+/// Finds a chain of fields that leads from the struct `current` to the struct
+/// `target`, and returns the names of the structs along the way. `visited` is
+/// used to avoid infinite recursion.
+fn find_struct_cycle(
+    target: &Identifier,
+    current: &Identifier,
+    rpc_interface: &RpcInterface,
+    visited: &mut BTreeSet<Identifier>,
+) -> Option<Vec<Identifier>> {
+    let struct_ = rpc_interface.structs.get(current)?;
+    for field in struct_.fields.values() {
+        let field_struct_name = match &field.field_type {
+            DataType::Struct(x) => x,
+            DataType::I32
+            | DataType::I128
+            | DataType::U128
+            | DataType::Duration
+            | DataType::Timestamp
+            | DataType::Uuid
+            | DataType::Char
+            | DataType::Bytes
+            | DataType::String
+            | DataType::ServiceRef(_) => continue,
+        };
+        if field_struct_name == target {
+            return Some(vec![current.clone(), target.clone()]);
+        }
+        if visited.insert(field_struct_name.clone()) {
+            if let Some(mut cycle) = find_struct_cycle(target, field_struct_name, rpc_interface, visited) {
+                cycle.insert(0, current.clone());
+                return Some(cycle);
+            }
+        }
+    }
+    None
+}
+
+/// Generates a builder for the struct, which has a setter for each field.
+/// Fields with a default value in the protocol file can be omitted.
+fn code_for_struct_builder(struct_name: &syn::Ident, struct_: &Struct) -> TokenStream {
+    let builder_name = format_ident!("{}Builder", struct_name);
+    let struct_name_str = struct_name.unraw().to_string();
+    let field_names: Vec<syn::Ident> = struct_
+        .fields
+        .iter()
+        .map(|(field_name, field)| rust_ident(field_name, &field.rust_name))
+        .collect();
+    let field_types: Vec<TokenStream> = struct_
+        .fields
+        .values()
+        .map(|field| data_type_to_token_stream(&field.field_type))
+        .collect();
+    let setter_names: Vec<syn::Ident> = field_names
+        .iter()
+        .map(|field_name| format_ident!("with_{}", field_name))
+        .collect();
+    let field_values: Vec<TokenStream> = struct_
+        .fields
+        .iter()
+        .map(|(field_name, field)| {
+            let field_name_ident = rust_ident(field_name, &field.rust_name);
+            let field_name_str = &field.rust_name.as_ref().unwrap_or(field_name).0;
+            match field.default_value {
+                Some(_) => quote! {
+                    self.#field_name_ident.unwrap_or(default_value.#field_name_ident)
+                },
+                None => quote! {
+                    match self.#field_name_ident {
+                        ::std::option::Option::Some(x) => x,
+                        ::std::option::Option::None => {
+                            return ::std::result::Result::Err(::rusty_rpc_lib::MissingFieldError {
+                                struct_name: #struct_name_str,
+                                field_name: #field_name_str,
+                            })
+                        }
+                    }
+                },
+            }
+        })
+        .collect();
+    let builder_doc = format!(
+        "Builder for [{struct_name}]. Fields that don't have a default value in the protocol file must be set before calling `build()`."
+    );
+    quote! {
+        #[doc = #builder_doc]
+        #[derive(::std::fmt::Debug, ::std::clone::Clone, ::std::default::Default)]
+        pub struct #builder_name {
+            #(#field_names: ::std::option::Option<#field_types>,)*
+        }
+        impl #builder_name {
+            pub fn new() -> Self {
+                ::std::default::Default::default()
+            }
+            #(
+                pub fn #setter_names(mut self, value: #field_types) -> Self {
+                    self.#field_names = ::std::option::Option::Some(value);
+                    self
+                }
+            )*
+            #[allow(unused_variables)]
+            pub fn build(self) -> ::std::result::Result<#struct_name, ::rusty_rpc_lib::MissingFieldError> {
+                let default_value = <#struct_name as ::std::default::Default>::default();
+                ::std::result::Result::Ok(#struct_name {
+                    #(#field_names: #field_values,)*
+                })
+            }
+        }
+        impl #struct_name {
+            /// Creates a builder for this struct.
+            pub fn builder() -> #builder_name {
+                #builder_name::new()
+            }
+        }
     }
 }
 
-fn code_for_service(service_name: &Identifier, service: &Service) -> TokenStream {
+fn code_for_service(
+    service_name: &Identifier,
+    service: &Service,
+    rpc_interface: &RpcInterface,
+    error_type: &syn::Type,
+) -> TokenStream {
     let internal = quote! { ::rusty_rpc_lib::internal_for_macro };
+    for (method_name, method) in &service.methods {
+        for (param_name, param_type) in &method.non_self_params {
+            if let DataType::Struct(x) = param_type {
+                if struct_has_services(x, rpc_interface) {
+                    return compile_error(format!(
+                        "Parameter {} of method {} has type {}, which contains a service. Structs that contain services can only be returned from methods.",
+                        param_name.0, method_name.0, x.0
+                    ));
+                }
+            }
+        }
+    }
+    for (method_name, method) in &service.methods {
+        if method.incremental
+            && (!matches!(&method.non_self_params[..], [(_, DataType::Bytes)])
+                || method.oneway
+                || method.named_args
+                || matches!(method.return_type, ReturnType::BidiStream(..)))
+        {
+            return compile_error(format!(
+                "Method {} cannot be incremental. Incremental methods take exactly one bytes parameter, and cannot be oneway, streaming, or have named arguments.",
+                method_name.0
+            ));
+        }
+    }
+    let mut rust_method_names = BTreeSet::new();
+    for (method_name, method) in &service.methods {
+        let rust_name = method.rust_name.as_ref().unwrap_or(method_name);
+        if !rust_method_names.insert(rust_name) {
+            return compile_error(format!(
+                "Service {} has more than one method with the Rust name {}.",
+                service_name.0, rust_name.0
+            ));
+        }
+    }
+    if let Some(method_name) = rust_method_names
+        .iter()
+        .find(|method_name| ["on_drop", "on_connection_closed"].contains(&&*method_name.0))
+    {
+        return compile_error(format!(
+            "Service {} has a method named {}, which is reserved for cleaning up services.",
+            service_name.0, method_name.0
+        ));
+    }
+    // In the order of service.methods.
+    let method_ids = match assign_method_ids(service_name, service) {
+        Ok(x) => x,
+        Err(e) => return compile_error(e),
+    };
     let service_name = to_syn_ident(service_name);
     let service_proxy_name = format_ident!("{}_RustyRpcServiceProxy", service_name);
+    let service_client_name = format_ident!("{}Client", service_name);
+    let service_client_doc = format!(
+        "Starts clients whose initial service is [{}]. Each constructor returns a proxy for that service.",
+        service_name.unraw()
+    );
     let lifetime: Lifetime = parse_quote! { 'a };
 
     let method_headers: Vec<TokenStream> = service
         .methods
         .iter()
-        .map(|(method_name, method_type)| {
-            let method_name = to_syn_ident(method_name);
-            let non_self_params: Vec<FnArg> = method_type
+        .map(|(method_name, method_type)| {
+            let method_name = rust_ident(method_name, &method_type.rust_name);
+            let mut non_self_params: Vec<FnArg> = method_type
+                .non_self_params
+                .iter()
+                .map(|(param_name, param_type)| -> FnArg {
+                    let param_name = to_syn_ident(param_name);
+                    let param_type = param_type_to_token_stream(param_type);
+                    parse_quote! { #param_name: #param_type }
+                })
+                .collect();
+            // On the server, the method receives the client's items and sends
+            // its own. On the client, the proxy sends the items that it
+            // receives, and sends the server's items on.
+            if let ReturnType::BidiStream(request_type, response_type) = &method_type.return_type {
+                let request_type = data_type_to_token_stream(request_type);
+                let response_type = data_type_to_token_stream(response_type);
+                non_self_params.push(parse_quote! { requests: #internal::StreamReceiver<#request_type> });
+                non_self_params.push(parse_quote! { responses: #internal::StreamSender<#response_type> });
+            }
+            let return_type = return_type_to_token_stream(&method_type.return_type, lifetime.clone(), rpc_interface, error_type);
+
+            // Without the semicolon or {}
+            quote! {
+                async fn #method_name<#lifetime>(&#lifetime mut self, #(#non_self_params),*) -> #return_type
+            }
+        })
+        .collect();
+
+    // Only on the trait, since attributes on trait impls are useless.
+    let method_deprecations: Vec<TokenStream> = service
+        .methods
+        .values()
+        .map(|method| match &method.deprecated {
+            None => quote! {},
+            Some(Deprecation { note: None }) => quote! { #[deprecated] },
+            Some(Deprecation { note: Some(note) }) => quote! { #[deprecated(note = #note)] },
+        })
+        .collect();
+
+    // Methods that return strings get a version that the server calls instead,
+    // which can return strings that borrow from the service.
+    let borrowed_methods: Vec<TokenStream> = service
+        .methods
+        .iter()
+        .zip(&method_deprecations)
+        .filter(|((_, method_type), _)| returns_string(&method_type.return_type) && !method_type.incremental)
+        .map(|((method_name, method_type), deprecation)| {
+            let method_name = rust_ident(method_name, &method_type.rust_name);
+            let borrowed_name = format_ident!("{}_borrowed", method_name.unraw());
+            let borrowed_doc = format!(
+                "Like [{0}::{1}], but the returned strings can borrow from the service. The server calls this method instead of [{0}::{1}], so implementing it avoids copying the strings. By default, it calls [{0}::{1}]. Clients always return owned strings.",
+                service_name.unraw(),
+                method_name.unraw()
+            );
+            let param_names: Vec<syn::Ident> = method_type
+                .non_self_params
+                .iter()
+                .map(|x| to_syn_ident(&x.0))
+                .collect();
+            let param_types: Vec<TokenStream> = method_type
+                .non_self_params
+                .iter()
+                .map(|x| param_type_to_token_stream(&x.1))
+                .collect();
+            let return_type = borrowed_return_type_to_token_stream(&method_type.return_type, &lifetime, error_type);
+            let borrowed_value = to_borrowed_return_value(&method_type.return_type);
+            quote! {
+                #[doc = #borrowed_doc]
+                #deprecation
+                async fn #borrowed_name<#lifetime>(&#lifetime mut self, #(#param_names: #param_types),*) -> #return_type {
+                    #[allow(deprecated)]
+                    let result = self.#method_name(#(#param_names),*).await;
+                    result.map(|x| #borrowed_value)
+                }
+            }
+        })
+        .collect();
+
+    // Incremental methods get a version that the server calls instead, which
+    // receives the bytes as they arrive.
+    let incremental_methods: Vec<TokenStream> = service
+        .methods
+        .iter()
+        .zip(&method_deprecations)
+        .filter(|((_, method_type), _)| method_type.incremental)
+        .map(|((method_name, method_type), deprecation)| {
+            let method_name = rust_ident(method_name, &method_type.rust_name);
+            let incremental_name = format_ident!("{}_incremental", method_name.unraw());
+            let incremental_doc = format!(
+                "Like [{0}::{1}], but the bytes arrive while this method runs, so it can handle the first ones before the client sent the rest. The server calls this method instead of [{0}::{1}]. By default, it waits for all of the bytes and calls [{0}::{1}].",
+                service_name.unraw(),
+                method_name.unraw()
+            );
+            let param_name = to_syn_ident(&method_type.non_self_params[0].0);
+            let return_type = return_type_to_token_stream(&method_type.return_type, lifetime.clone(), rpc_interface, error_type);
+            quote! {
+                #[doc = #incremental_doc]
+                #deprecation
+                async fn #incremental_name<#lifetime>(&#lifetime mut self, #param_name: #internal::IncomingBytes) -> #return_type {
+                    let #param_name = #param_name.into_vec().await?;
+                    #[allow(deprecated)]
+                    self.#method_name(&#param_name).await
+                }
+            }
+        })
+        .collect();
+
+    // Methods that return a single service get a version that returns a
+    // `ChainedService`, which closes the service when it is dropped.
+    let chained_methods: Vec<TokenStream> = service
+        .methods
+        .iter()
+        .zip(&method_deprecations)
+        .filter_map(|((method_name, method_type), deprecation)| {
+            let chained_return_type = match &method_type.return_type {
+                ReturnType::ServiceRefMut(x) => {
+                    let x = to_syn_ident(x);
+                    quote! { #internal::ChainedService<#lifetime, dyn #x + #lifetime> }
+                }
+                ReturnType::OwnedService(x) => {
+                    let x = to_syn_ident(x);
+                    quote! { #internal::ChainedService<'static, dyn #x> }
+                }
+                _ => return None,
+            };
+            let method_name = rust_ident(method_name, &method_type.rust_name);
+            let chained_name = format_ident!("{}_chained", method_name.unraw());
+            let chained_doc = format!(
+                "Like [{}::{}], but the returned service is closed when it is dropped, so that a call can be chained on it.",
+                service_name.unraw(),
+                method_name.unraw()
+            );
+            let param_names: Vec<syn::Ident> = method_type
+                .non_self_params
+                .iter()
+                .map(|x| to_syn_ident(&x.0))
+                .collect();
+            let param_types: Vec<TokenStream> = method_type
+                .non_self_params
+                .iter()
+                .map(|x| param_type_to_token_stream(&x.1))
+                .collect();
+            Some(quote! {
+                #[doc = #chained_doc]
+                #deprecation
+                pub async fn #chained_name<#lifetime>(
+                    &#lifetime mut self,
+                    #(#param_names: #param_types),*
+                ) -> ::std::result::Result<#chained_return_type, #error_type> {
+                    #[allow(deprecated)]
+                    let service = <Self as #service_name>::#method_name(self, #(#param_names),*).await?;
+                    ::std::result::Result::Ok(service.chained())
+                }
+            })
+        })
+        .collect();
+
+    // Streaming methods get a version that runs the call in the background, and
+    // returns the sender and receiver of its items.
+    let stream_methods: Vec<TokenStream> = service
+        .methods
+        .iter()
+        .zip(&method_ids)
+        .zip(&method_deprecations)
+        .filter_map(|(((method_name, method_type), method_id), deprecation)| {
+            let ReturnType::BidiStream(request_type, response_type) = &method_type.return_type else {
+                return None;
+            };
+            let method_name = rust_ident(method_name, &method_type.rust_name);
+            let stream_name = format_ident!("{}_stream", method_name.unraw());
+            let stream_doc = format!(
+                "Like [{}::{}], but the call runs in the background until the returned sender is closed and the server's method returns. The server's items arrive in the returned receiver, followed by an error if the call fails. Other calls on this connection wait until then.",
+                service_name.unraw(),
+                method_name.unraw()
+            );
+            let arguments = if method_type.named_args {
+                code_for_named_arguments(method_type, &[])
+            } else {
+                quote! { () }
+            };
+            let (encode, decode) = code_for_stream_items(request_type, response_type);
+            let request_type = data_type_to_token_stream(request_type);
+            let response_type = data_type_to_token_stream(response_type);
+            Some(quote! {
+                #[doc = #stream_doc]
+                #deprecation
+                pub async fn #stream_name(
+                    &mut self,
+                ) -> (#internal::StreamSender<#request_type>, #internal::StreamReceiver<#response_type>) {
+                    let arguments = #arguments;
+                    let wire_format = self.connection.wire_format();
+                    let msg_to_send = #internal::ClientMessage::CallMethod(
+                        self.service_id,
+                        #internal::MethodId(#method_id),
+                        #internal::MethodArgs(wire_format.encode(&arguments)),
+                        #internal::call_metadata()
+                    );
+                    self.connection.spawn_call_stream(msg_to_send, #encode, #decode).await
+                }
+            })
+        })
+        .collect();
+
+    let service_blocking_client_name = format_ident!("{}BlockingClient", service_name);
+    let service_blocking_client_doc = format!(
+        "A client whose initial service is [{}], with methods that block until the call is done instead of being async. The connection runs on a single-threaded tokio runtime that this client owns, so this must not be used from inside another runtime.\n\nOnly the methods that return data are available here. Like a proxy, this must be closed before it is dropped.",
+        service_name.unraw()
+    );
+    // Returned services would need a runtime to be used, so methods that return
+    // services don't get a blocking version.
+    let blocking_methods: Vec<TokenStream> = service
+        .methods
+        .iter()
+        .zip(&method_deprecations)
+        .filter(|((_, method_type), _)| match &method_type.return_type {
+            ReturnType::Data(DataType::Struct(x)) => !struct_has_services(x, rpc_interface),
+            ReturnType::Data(_) | ReturnType::Result(..) => true,
+            _ => false,
+        })
+        .map(|((method_name, method_type), deprecation)| {
+            let method_name = rust_ident(method_name, &method_type.rust_name);
+            let param_names: Vec<syn::Ident> = method_type
+                .non_self_params
+                .iter()
+                .map(|x| to_syn_ident(&x.0))
+                .collect();
+            let param_types: Vec<TokenStream> = method_type
+                .non_self_params
+                .iter()
+                .map(|x| param_type_to_token_stream(&x.1))
+                .collect();
+            let return_type = return_type_to_token_stream(&method_type.return_type, lifetime.clone(), rpc_interface, error_type);
+            quote! {
+                #deprecation
+                pub fn #method_name<#lifetime>(&#lifetime mut self, #(#param_names: #param_types),*) -> #return_type {
+                    #[allow(deprecated)]
+                    let result = self.runtime.block_on(self.service.#method_name(#(#param_names),*));
+                    result
+                }
+            }
+        })
+        .collect();
+
+    let service_mock_name = format_ident!("{}Mock", service_name);
+    let service_mock_doc = format!(
+        "A [{0}] for tests, which doesn't need a connection. Each method calls the closure that was set with the `on_` method of the same name, passing it [{0}Mock::state] and the arguments, and panics if none was set.\n\nThis can be used wherever a [{0}] is expected, and it can be wrapped with `ServiceRefMut::new` like any other service.",
+        service_name.unraw()
+    );
+    let mock_handler_names: Vec<syn::Ident> = service
+        .methods
+        .iter()
+        .map(|(method_name, method_type)| {
+            let method_name = rust_ident(method_name, &method_type.rust_name);
+            format_ident!("on_{}", method_name.unraw())
+        })
+        .collect();
+    let mock_handler_types: Vec<TokenStream> = service
+        .methods
+        .values()
+        .map(|method_type| {
+            let mut param_types: Vec<TokenStream> = method_type
+                .non_self_params
+                .iter()
+                .map(|x| param_type_to_token_stream(&x.1))
+                .collect();
+            if let ReturnType::BidiStream(request_type, response_type) = &method_type.return_type {
+                let request_type = data_type_to_token_stream(request_type);
+                let response_type = data_type_to_token_stream(response_type);
+                param_types.push(quote! { #internal::StreamReceiver<#request_type> });
+                param_types.push(quote! { #internal::StreamSender<#response_type> });
+            }
+            let return_type = return_type_to_token_stream(&method_type.return_type, lifetime.clone(), rpc_interface, error_type);
+            // The state is what returned values can borrow from.
+            quote! {
+                for<#lifetime> ::std::ops::FnMut(&#lifetime mut S, #(#param_types),*) -> #return_type
+            }
+        })
+        .collect();
+    let mock_setters: Vec<TokenStream> = service
+        .methods
+        .iter()
+        .zip(&mock_handler_names)
+        .zip(&mock_handler_types)
+        .zip(&method_deprecations)
+        .map(|((((method_name, method_type), handler_name), handler_type), deprecation)| {
+            let method_name = rust_ident(method_name, &method_type.rust_name);
+            let setter_doc = format!(
+                "Sets the closure that [{}::{}] calls.",
+                service_name.unraw(),
+                method_name.unraw()
+            );
+            quote! {
+                #[doc = #setter_doc]
+                #deprecation
+                pub fn #handler_name(
+                    mut self,
+                    handler: impl #handler_type + ::std::marker::Send + ::std::marker::Sync + 'static,
+                ) -> Self {
+                    self.#handler_name = ::std::option::Option::Some(::std::boxed::Box::new(handler));
+                    self
+                }
+            }
+        })
+        .collect();
+    let mock_method_impl: Vec<TokenStream> = method_headers
+        .iter()
+        .zip(&service.methods)
+        .zip(&mock_handler_names)
+        .map(|((method_header, (method_name, method_type)), handler_name)| {
+            let mut param_names: Vec<syn::Ident> = method_type
                 .non_self_params
                 .iter()
-                .map(|(param_name, param_type)| -> FnArg {
-                    let param_name = to_syn_ident(param_name);
-                    let param_type = data_type_to_token_stream(param_type);
-                    parse_quote! { #param_name: #param_type }
-                })
+                .map(|x| to_syn_ident(&x.0))
                 .collect();
-            let return_type = return_type_to_token_stream(&method_type.return_type, lifetime.clone());
-
-            // Without the semicolon or {}
+            if let ReturnType::BidiStream(..) = &method_type.return_type {
+                param_names.push(format_ident!("requests"));
+                param_names.push(format_ident!("responses"));
+            }
+            let method_name = rust_ident(method_name, &method_type.rust_name);
+            let unset_message = format!(
+                "{0}::{1} was called, but no closure was set for it with {0}::{2}.",
+                service_mock_name,
+                method_name.unraw(),
+                handler_name
+            );
             quote! {
-                async fn #method_name<#lifetime>(&#lifetime mut self, #(#non_self_params),*) -> #return_type
+                #method_header {
+                    let Self { state, #handler_name, .. } = self;
+                    match #handler_name {
+                        ::std::option::Option::Some(handler) => handler(state, #(#param_names),*),
+                        ::std::option::Option::None => ::std::panic!(#unset_message),
+                    }
+                }
             }
         })
         .collect();
+    let mock_server_traits = code_for_server_traits(
+        &quote! { <'a, S: ::std::marker::Send + ::std::marker::Sync + 'a> },
+        &quote! {},
+        &quote! { 'a },
+        &quote! { #service_mock_name<S> },
+        &service_name,
+    );
 
     let proxy_method_impl: Vec<TokenStream> = method_headers
         .iter()
         .zip(&service.methods)
-        .enumerate()
+        .zip(&method_ids)
         .map(
-            |(method_id, (method_header, (_method_name, method_type)))| {
-                let param_names: Vec<syn::Ident> = method_type
+            |((method_header, (_method_name, method_type)), method_id)| {
+                let arguments: Vec<TokenStream> = method_type
                     .non_self_params
                     .iter()
-                    .map(|x| to_syn_ident(&x.0))
+                    .map(|(param_name, param_type)| {
+                        let param_name = to_syn_ident(param_name);
+                        match param_type {
+                            DataType::Bytes => quote! { #internal::BytesRef(#param_name) },
+                            DataType::I128 => quote! { #internal::WireI128(#param_name) },
+                            DataType::U128 => quote! { #internal::WireU128(#param_name) },
+                            DataType::Duration => quote! { #internal::WireDuration(#param_name) },
+                            DataType::Timestamp => quote! { #internal::WireTimestamp(#param_name) },
+                            DataType::Uuid => quote! { #internal::WireUuid(#param_name) },
+                            DataType::Char => quote! { #internal::WireChar(#param_name) },
+                            _ => quote! { #param_name },
+                        }
+                    })
                     .collect();
+                let arguments = if method_type.named_args {
+                    code_for_named_arguments(method_type, &arguments)
+                } else {
+                    quote! { (#(#arguments),*) }
+                };
+                if let ReturnType::BidiStream(request_type, response_type) = &method_type.return_type {
+                    let (encode, decode) = code_for_stream_items(request_type, response_type);
+                    return quote! {
+                        #method_header {
+                            let arguments = #arguments;
+                            let wire_format = self.connection.wire_format();
+                            let msg_to_send = #internal::ClientMessage::CallMethod(
+                                self.service_id,
+                                #internal::MethodId(#method_id),
+                                #internal::MethodArgs(wire_format.encode(&arguments)),
+                                #internal::call_metadata()
+                            );
+                            let requests = requests.into_encoding(#encode);
+                            let responses = responses.into_decoding(#decode);
+                            let response_msg = self.connection.call_stream(msg_to_send, requests, responses).await?;
+                            match response_msg {
+                                #internal::ServerMessage::MethodReturned(_) => ::std::result::Result::Ok(()),
+                                #internal::ServerMessage::Error(msg) => ::std::result::Result::Err(
+                                    ::std::convert::From::from(#internal::RustyRpcError::ServerError(msg))),
+                                // Stream items are sent on instead of being returned.
+                                other => ::std::result::Result::Err(::std::convert::From::from(
+                                    #internal::unexpected_response(&other, "return value"))),
+                            }
+                        }
+                    };
+                }
+                if method_type.oneway {
+                    return quote! {
+                        #method_header {
+                            let arguments = #arguments;
+                            let serialized_arguments = self.connection.wire_format().encode(&arguments);
+                            let msg_to_send = #internal::ClientMessage::Notify(
+                                self.service_id,
+                                #internal::MethodId(#method_id),
+                                #internal::MethodArgs(serialized_arguments),
+                                #internal::call_metadata()
+                            );
+                            ::std::result::Result::Ok(self.connection.notify(msg_to_send).await?)
+                        }
+                    };
+                }
                 let code_to_parse_return_type = match &method_type.return_type {
-                    ReturnType::ServiceRefMut(returned_service_name) => {
+                    ReturnType::Nothing => unreachable!("Only oneway methods return nothing."),
+                    ReturnType::BidiStream(..) => unreachable!("Streaming methods are handled above."),
+                    ReturnType::ServiceRefMut(returned_service_name)
+                    | ReturnType::OwnedService(returned_service_name) => {
                         let returned_service_name = to_syn_ident(returned_service_name);
                         let returned_proxy_name = format_ident!("{}_RustyRpcServiceProxy", returned_service_name);
                         quote! {
                             match raw_return_value {
-                                #internal::ReturnValue::Data(_) => panic!(
-                                    "Server returned data instead of service."),
+                                #internal::ReturnValue::Data(_) => return ::std::result::Result::Err(::std::convert::From::from(
+                                    #internal::malformed_return_value("Server returned data instead of service."))),
                                 #internal::ReturnValue::Service(service_id) => {
                                     let proxy = <#returned_proxy_name as #internal::RustyRpcServiceProxy>::from_service_id(
                                         service_id,
-                                        self.stream_sink.clone()
+                                        self.connection.clone()
                                     );
                                     #internal::service_ref_from_service_proxy(proxy)
                                 },
+                                #internal::ReturnValue::Services(_) => return ::std::result::Result::Err(::std::convert::From::from(
+                                    #internal::malformed_return_value("Server returned multiple services instead of one."))),
+                            }
+                        }
+                    },
+                    ReturnType::ServiceRefMutTuple(returned_service_names) => {
+                        let service_id_names: Vec<syn::Ident> = (0..returned_service_names.len())
+                            .map(|i| format_ident!("service_id_{}", i))
+                            .collect();
+                        let returned_proxy_names = returned_service_names
+                            .iter()
+                            .map(|x| format_ident!("{}_RustyRpcServiceProxy", to_syn_ident(x)));
+                        quote! {
+                            match raw_return_value {
+                                #internal::ReturnValue::Data(_) => return ::std::result::Result::Err(::std::convert::From::from(
+                                    #internal::malformed_return_value("Server returned data instead of services."))),
+                                #internal::ReturnValue::Service(_) => return ::std::result::Result::Err(::std::convert::From::from(
+                                    #internal::malformed_return_value("Server returned one service instead of multiple."))),
+                                #internal::ReturnValue::Services(service_ids) => {
+                                    let [#(#service_id_names),*] = match <[#internal::ServiceId; _]>::try_from(service_ids) {
+                                        ::std::result::Result::Ok(service_ids) => service_ids,
+                                        ::std::result::Result::Err(_) => return ::std::result::Result::Err(::std::convert::From::from(
+                                    #internal::malformed_return_value("Server returned the wrong number of services."))),
+                                    };
+                                    (#(
+                                        #internal::service_ref_from_service_proxy(
+                                            <#returned_proxy_names as #internal::RustyRpcServiceProxy>::from_service_id(
+                                                #service_id_names,
+                                                self.connection.clone()
+                                            )
+                                        )
+                                    ),*)
+                                },
+                            }
+                        }
+                    },
+                    ReturnType::Data(DataType::Struct(struct_name)) if struct_has_services(struct_name, rpc_interface) => {
+                        let wire_name = format_ident!("{}_RustyRpcWire", to_syn_ident(struct_name));
+                        quote! {
+                            match raw_return_value {
+                                #internal::ReturnValue::Data(bytes) =>
+                                    self.connection.wire_format().decode::<#wire_name>(&bytes)?
+                                    .into_remote(&self.connection),
+                                #internal::ReturnValue::Service(_) | #internal::ReturnValue::Services(_) => return ::std::result::Result::Err(::std::convert::From::from(
+                                    #internal::malformed_return_value("Server returned service instead of data.")))
+                            }
+                        }
+                    },
+                    ReturnType::Data(
+                        ref data_type @ (DataType::Bytes
+                        | DataType::I128
+                        | DataType::U128
+                        | DataType::Duration
+                        | DataType::Timestamp
+                        | DataType::Uuid
+                        | DataType::Char),
+                    ) => {
+                        let wire_type = return_wire_type_to_token_stream(data_type);
+                        quote! {
+                        match raw_return_value {
+                            #internal::ReturnValue::Data(bytes) =>
+                                self.connection.wire_format().decode::<#wire_type>(&bytes)?.0,
+                            #internal::ReturnValue::Service(_) | #internal::ReturnValue::Services(_) => return ::std::result::Result::Err(::std::convert::From::from(
+                                    #internal::malformed_return_value("Server returned service instead of data.")))
+                        }
+                        }
+                    },
+                    ReturnType::Result(ref ok_type, ref err_type) => {
+                        let ok_wire_type = return_wire_type_to_token_stream(ok_type);
+                        let err_wire_type = return_wire_type_to_token_stream(err_type);
+                        let from_ok_wire = from_return_wire_type(ok_type);
+                        let from_err_wire = from_return_wire_type(err_type);
+                        quote! {
+                            match raw_return_value {
+                                #internal::ReturnValue::Data(bytes) =>
+                                    self.connection.wire_format()
+                                    .decode::<::std::result::Result<#ok_wire_type, #err_wire_type>>(&bytes)?
+                                    .map(|x| #from_ok_wire)
+                                    .map_err(|x| #from_err_wire),
+                                #internal::ReturnValue::Service(_) | #internal::ReturnValue::Services(_) => return ::std::result::Result::Err(::std::convert::From::from(
+                                    #internal::malformed_return_value("Server returned service instead of data.")))
                             }
                         }
                     },
                     ReturnType::Data(_) => quote! {
                         match raw_return_value {
                             #internal::ReturnValue::Data(bytes) =>
-                                #internal::rmp_serde::from_slice(&bytes)
-                                .expect("Server sent malformed return value"),
-                            #internal::ReturnValue::Service(_) => panic!(
-                                "Server returned service instead of data.")
+                                self.connection.wire_format().decode(&bytes)?,
+                            #internal::ReturnValue::Service(_) | #internal::ReturnValue::Services(_) => return ::std::result::Result::Err(::std::convert::From::from(
+                                    #internal::malformed_return_value("Server returned service instead of data.")))
                         }
                     },
                 };
+                // Incremental methods only announce the length in the call,
+                // and send the bytes themselves as stream items.
+                let (arguments, code_to_call) = if method_type.incremental {
+                    let param_name = to_syn_ident(&method_type.non_self_params[0].0);
+                    (
+                        quote! { (#param_name.len() as u64) },
+                        quote! { self.connection.call_incremental(msg_to_send, #param_name).await? },
+                    )
+                } else {
+                    (arguments, quote! { self.connection.call(msg_to_send).await? })
+                };
                 quote! {
                     #method_header {
-                        let arguments = (#(#param_names),*);
-                        let serialized_arguments = #internal::rmp_serde::to_vec(&arguments)
-                            .expect("Serializing arguments somehow failed.");
+                        let arguments = #arguments;
+                        let serialized_arguments = self.connection.wire_format().encode(&arguments);
                         let msg_to_send = #internal::ClientMessage::CallMethod(
                             self.service_id,
-                            #internal::MethodId(#method_id as u64),
-                            #internal::MethodArgs(serialized_arguments)
+                            #internal::MethodId(#method_id),
+                            #internal::MethodArgs(serialized_arguments),
+                            #internal::call_metadata()
                         );
 
-                        let mut locked = self.stream_sink.lock().await;
-                        use #internal::{SinkExt, StreamExt};
-                        locked.send(msg_to_send).await?;
-                        let response_msg: #internal::ServerMessage = locked.next().await.ok_or_else(|| #internal::string_io_error(
-                            "Server closed communication while client waiting for return value."))??;
-                        
+                        let response_msg = #code_to_call;
+
                         let raw_return_value = match response_msg {
-                            #internal::ServerMessage::DropServiceDone => panic!(
-                                "Server sent confirmation for dropped service instead of return value."),
                             #internal::ServerMessage::MethodReturned(x) => x,
+                            #internal::ServerMessage::Error(msg) => return Err(
+                                ::std::convert::From::from(#internal::RustyRpcError::ServerError(msg))),
+                            other => return Err(::std::convert::From::from(
+                                #internal::unexpected_response(&other, "return value"))),
                         };
                         let return_value = #code_to_parse_return_type;
                         Ok(return_value)
@@ -256,123 +1971,426 @@ fn code_for_service(service_name: &Identifier, service: &Service) -> TokenStream
     let parse_and_call_method_locally_impl_branches: Vec<TokenStream> = service
         .methods
         .iter()
-        .enumerate()
-        .map(|(method_id, (method_name, method_type))| {
-            let method_name = to_syn_ident(method_name);
+        .zip(&method_ids)
+        .map(|((method_name, method_type), method_id)| {
+            let method_name = rust_ident(method_name, &method_type.rust_name);
+            let called_method_name = if method_type.incremental {
+                format_ident!("{}_incremental", method_name.unraw())
+            } else if returns_string(&method_type.return_type) {
+                format_ident!("{}_borrowed", method_name.unraw())
+            } else {
+                method_name.clone()
+            };
             let param_names: Vec<syn::Ident> = method_type
                 .non_self_params
                 .iter()
                 .map(|x| to_syn_ident(&x.0))
                 .collect();
-            let param_types: Vec<TokenStream> = method_type
+            let param_wire_types: Vec<TokenStream> = method_type
+                .non_self_params
+                .iter()
+                .map(|x| param_wire_type_to_token_stream(&x.1))
+                .collect();
+            let mut param_values: Vec<TokenStream> = method_type
                 .non_self_params
                 .iter()
-                .map(|x| data_type_to_token_stream(&x.1))
+                .map(|(param_name, param_type)| {
+                    let param_name = to_syn_ident(param_name);
+                    match param_type {
+                        DataType::Struct(_) => quote! { &#param_name },
+                        DataType::I128
+                        | DataType::U128
+                        | DataType::Duration
+                        | DataType::Timestamp
+                        | DataType::Uuid
+                        | DataType::Char => quote! { #param_name.0 },
+                        _ => quote! { #param_name },
+                    }
+                })
                 .collect();
+            let code_to_open_streams = match &method_type.return_type {
+                ReturnType::BidiStream(request_type, response_type) => {
+                    param_values.push(quote! { requests });
+                    param_values.push(quote! { responses });
+                    // The server encodes the items that the client decodes, and
+                    // vice versa.
+                    let (encode, decode) = code_for_stream_items(response_type, request_type);
+                    quote! {
+                        let ::std::option::Option::Some((requests, responses)) = service_collection.take_call_stream() else {
+                            ::std::mem::drop(self_guard);
+                            return ::std::result::Result::Ok(#internal::ServerMessage::Error(
+                                ::std::string::ToString::to_string("Streaming methods cannot be batched or oneway.")));
+                        };
+                        let wire_format = service_collection.wire_format();
+                        let requests = #internal::StreamReceiver::decoding(requests, #decode);
+                        let responses = #internal::StreamSender::encoding(responses, #encode);
+                    }
+                }
+                // The client only announced the length, and sends the bytes
+                // as stream items.
+                _ if method_type.incremental => {
+                    let param_name = &param_names[0];
+                    quote! {
+                        let ::std::option::Option::Some((requests, responses)) = service_collection.take_call_stream() else {
+                            ::std::mem::drop(self_guard);
+                            return ::std::result::Result::Ok(#internal::ServerMessage::Error(
+                                ::std::string::ToString::to_string("Incremental methods cannot be batched or oneway.")));
+                        };
+                        ::std::mem::drop(responses);
+                        let #param_name = #internal::IncomingBytes::new(#param_name, requests);
+                    }
+                }
+                _ => quote! {},
+            };
+            let code_to_parse_arguments = if method_type.incremental {
+                let param_name = &param_names[0];
+                quote! {
+                    let #param_name: u64 = service_collection.wire_format().decode(&method_args.0)?;
+                }
+            } else if method_type.named_args {
+                let param_types = method_type
+                    .non_self_params
+                    .iter()
+                    .map(|(_, param_type)| match param_type {
+                        DataType::Bytes => quote! { &'a [u8] },
+                        DataType::String => quote! { &'a str },
+                        _ => param_wire_type_to_token_stream(param_type),
+                    });
+                quote! {
+                    // Missing arguments get their default values, and unknown
+                    // ones are ignored.
+                    #[derive(#internal::Deserialize)]
+                    struct NamedArgs<'a> {
+                        #(
+                            #[serde(default)]
+                            #param_names: #param_types,
+                        )*
+                        #[serde(skip)]
+                        _phantom: ::std::marker::PhantomData<&'a ()>,
+                    }
+                    let NamedArgs { #(#param_names,)* .. } =
+                        service_collection.wire_format().decode(&method_args.0)?;
+                }
+            } else {
+                quote! {
+                    let (#(#param_names),*) : (#(#param_wire_types),*) =
+                        service_collection.wire_format().decode(&method_args.0)?;
+                }
+            };
             let code_to_serialize_return_type = match method_type.return_type {
+                    // The server only responds if a oneway method is called
+                    // like a normal method.
+                    ReturnType::Nothing => quote! {
+                        {
+                            ::std::mem::drop(self_guard);
+                            #internal::ReturnValue::Data(service_collection.wire_format().encode(&return_value))
+                        }
+                    },
+                    // The items were already sent, so the client only learns
+                    // that the call is over.
+                    ReturnType::BidiStream(..) => quote! {
+                        {
+                            ::std::mem::drop(self_guard);
+                            #internal::ReturnValue::Data(service_collection.wire_format().encode(&return_value))
+                        }
+                    },
+                    ReturnType::Data(DataType::Struct(ref struct_name)) if struct_has_services(struct_name, rpc_interface) => {
+                        let wire_name = format_ident!("{}_RustyRpcWire", to_syn_ident(struct_name));
+                        quote! {
+                        {
+                            let register_result = unsafe {
+                                #wire_name::from_local(return_value, self_guard, service_collection)
+                            };
+                            match register_result {
+                                ::std::result::Result::Ok(wire_value) => #internal::ReturnValue::Data(
+                                    service_collection.wire_format().encode(&wire_value)
+                                ),
+                                ::std::result::Result::Err(e) => return ::std::result::Result::Ok(
+                                    #internal::ServerMessage::Error(e.to_string())),
+                            }
+                        }
+                        }
+                    },
                     ReturnType::ServiceRefMut(_) => quote! {
                         {
                             let local_service = #internal::local_service_from_service_ref(return_value)
                                 .expect("Server somehow returned a remote ServiceRefMut.");
-                            let service_id = unsafe {
+                            let register_result = unsafe {
                                 service_collection.register_service(
                                     local_service as ::std::boxed::Box<_>,
                                     Some(self_guard)
                                 )
                             };
-                            #internal::ReturnValue::Service(service_id)
+                            match register_result {
+                                ::std::result::Result::Ok(service_id) =>
+                                    #internal::ReturnValue::Service(service_id),
+                                ::std::result::Result::Err(e) => return ::std::result::Result::Ok(
+                                    #internal::ServerMessage::Error(e.to_string())),
+                            }
                         }
                     },
-                    ReturnType::Data(_) => quote! {
+                    ReturnType::ServiceRefMutTuple(ref returned_service_names) => {
+                        let return_value_names: Vec<syn::Ident> = (0..returned_service_names.len())
+                            .map(|i| format_ident!("return_value_{}", i))
+                            .collect();
+                        quote! {
                         {
-                            unsafe {
-                                ::std::mem::drop(::std::boxed::Box::from_raw(self_guard.get()));
+                            let (#(#return_value_names),*) = return_value;
+                            let local_services = ::std::vec![#(
+                                #internal::local_service_from_service_ref(#return_value_names)
+                                    .expect("Server somehow returned a remote ServiceRefMut.")
+                                    as ::std::boxed::Box<_>
+                            ),*];
+                            let register_result = unsafe {
+                                service_collection.register_services(local_services, Some(self_guard))
+                            };
+                            match register_result {
+                                ::std::result::Result::Ok(service_ids) =>
+                                    #internal::ReturnValue::Services(service_ids),
+                                ::std::result::Result::Err(e) => return ::std::result::Result::Ok(
+                                    #internal::ServerMessage::Error(e.to_string())),
                             }
-                            #internal::ReturnValue::Data(
-                                #internal::rmp_serde::to_vec(&return_value)
-                                    .expect("Serializing return value somehow failed.")
-                            )
+                        }
+                        }
+                    },
+                    ReturnType::OwnedService(_) => quote! {
+                        {
+                            // The returned service is 'static, so it doesn't
+                            // borrow from self, and self doesn't need to stay
+                            // locked.
+                            ::std::mem::drop(self_guard);
+                            let local_service = #internal::local_service_from_service_ref(return_value)
+                                .expect("Server somehow returned a remote ServiceRefMut.");
+                            let register_result =
+                                service_collection.register_static_service(local_service);
+                            match register_result {
+                                ::std::result::Result::Ok(service_id) =>
+                                    #internal::ReturnValue::Service(service_id),
+                                ::std::result::Result::Err(e) => return ::std::result::Result::Ok(
+                                    #internal::ServerMessage::Error(e.to_string())),
+                            }
+                        }
+                    },
+                    ReturnType::Result(ref ok_type, ref err_type) => {
+                        let ok_value = to_return_wire_type(ok_type);
+                        let err_value = to_return_wire_type(err_type);
+                        quote! {
+                        {
+                            let serialized = service_collection.wire_format().encode(&match &return_value {
+                                ::std::result::Result::Ok(x) => ::std::result::Result::Ok(#ok_value),
+                                ::std::result::Result::Err(x) => ::std::result::Result::Err(#err_value),
+                            });
+                            ::std::mem::drop(self_guard);
+                            #internal::ReturnValue::Data(serialized)
+                        }
+                        }
+                    },
+                    ReturnType::Data(ref data_type) => {
+                        let return_value = match data_type {
+                            DataType::Bytes => quote! { #internal::BytesRef(&return_value) },
+                            DataType::I128 => quote! { #internal::WireI128(return_value) },
+                            DataType::U128 => quote! { #internal::WireU128(return_value) },
+                            DataType::Duration => quote! { #internal::WireDuration(return_value) },
+                            DataType::Timestamp => quote! { #internal::WireTimestamp(return_value) },
+                            DataType::Uuid => quote! { #internal::WireUuid(return_value) },
+                            DataType::Char => quote! { #internal::WireChar(return_value) },
+                            _ => quote! { return_value },
+                        };
+                        quote! {
+                        {
+                            // The return value might borrow from self, so self
+                            // stays locked until it is serialized.
+                            let serialized = service_collection.wire_format().encode(&#return_value);
+                            ::std::mem::drop(self_guard);
+                            #internal::ReturnValue::Data(serialized)
+                        }
                         }
                     },
                 };
 
             quote! {
-                if method_id.0 == #method_id as u64 {
-                    let (#(#param_names),*) : (#(#param_types),*) =
-                        #internal::rmp_serde::from_slice(&method_args.0)
-                        .expect("Client sent malformed arguments.");
-                    let return_value = self.#method_name(#(#param_names),*).await
-                        .expect("Server implementation of service method failed.");
+                #method_id => {
+                    #code_to_parse_arguments
+                    #code_to_open_streams
+                    let return_value = match self.#called_method_name(#(#param_values),*).await {
+                        ::std::result::Result::Ok(x) => x,
+                        ::std::result::Result::Err(e) => return ::std::result::Result::Ok(
+                            #internal::ServerMessage::Error(e.to_string())),
+                    };
                     let serialized_return_value = #code_to_serialize_return_type;
                     let msg_to_send = #internal::ServerMessage::MethodReturned(serialized_return_value);
                     ::std::result::Result::Ok(msg_to_send)
-                } else
+                }
             }
         })
         .collect();
     
+    let service_name_str = service_name.unraw().to_string();
+    let method_name_strs = service.methods.keys().map(|x| &x.0);
+    let method_id_names = service.methods.keys().map(|x| &x.0);
+
     quote! {
         #[#internal::async_trait]
         pub trait #service_name: Send + Sync {
             /// This method should be automatically implemented by using the `#[service_server_impl]` macro
             #[doc(hidden)]
+            fn _rusty_rpc_forward__service_name(&self) -> &'static str {
+                #service_name_str
+            }
+            /// This method should be automatically implemented by using the `#[service_server_impl]` macro
+            #[doc(hidden)]
+            fn _rusty_rpc_forward__method_name(&self, method_id: #internal::MethodId) -> ::std::option::Option<&'static str> {
+                match method_id.0 {
+                    #(#method_ids => ::std::option::Option::Some(#method_name_strs),)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+
+            /// This method should be automatically implemented by using the `#[service_server_impl]` macro
+            #[doc(hidden)]
+            // Deprecated methods still have to be callable by clients.
+            #[allow(deprecated)]
             async fn _rusty_rpc_forward__parse_and_call_method_locally(
                 &mut self,
                 self_guard: #internal::ServerGuard,
                 method_id: #internal::MethodId,
                 method_args: #internal::MethodArgs,
                 service_collection: &mut #internal::ServerCollection,
-            ) -> ::std::io::Result<#internal::ServerMessage> {
-                #(#parse_and_call_method_locally_impl_branches)*
-                {
-                    // Final else branch
-                    panic!("Client sent invalid method_id.")
+            ) -> ::std::result::Result<#internal::ServerMessage, #internal::RustyRpcError> {
+                match method_id.0 {
+                    #(#parse_and_call_method_locally_impl_branches)*
+                    _ => {
+                        ::std::mem::drop(self_guard);
+                        ::std::result::Result::Ok(#internal::ServerMessage::Error(
+                            ::std::format!("Invalid method ID: {}", method_id.0)))
+                    }
                 }
             }
 
+            /// Called on the server when the client closes this service, just
+            /// before it is dropped, so that it can clean up asynchronously.
+            /// The client waits for this to finish. This isn't called for
+            /// services that are still open when the connection ends. Does
+            /// nothing by default.
+            async fn on_drop(&mut self) {}
+
+            /// Called on the server for each service that is still open when
+            /// the connection ends, e.g. because the client disconnected, just
+            /// before it is dropped. Does nothing by default.
+            async fn on_connection_closed(&mut self) {}
+
             #(
+                #method_deprecations
                 #method_headers ;
             )*
+
+            #(#borrowed_methods)*
+
+            #(#incremental_methods)*
         }
         impl<'a> #internal::RustyRpcServiceClient for dyn #service_name + 'a {
             type ServiceProxy = #service_proxy_name;
+            const METHOD_IDS: &'static [(&'static str, #internal::MethodId)] = &[
+                #((#method_id_names, #internal::MethodId(#method_ids)),)*
+            ];
         }
 
         /// ServiceProxy for #service_name
         pub struct #service_proxy_name {
             service_id: #internal::ServiceId,
-            stream_sink: ::std::sync::Arc<#internal::Mutex<dyn #internal::ClientStreamSink>>,
+            connection: ::std::sync::Arc<#internal::ClientConnection>,
             is_closed: ::std::sync::atomic::AtomicBool,
+            /// The number of clones of this proxy that aren't closed yet. The
+            /// service is dropped on the server when this reaches zero.
+            open_clones: ::std::sync::Arc<::std::sync::atomic::AtomicUsize>,
         }
+        impl ::std::clone::Clone for #service_proxy_name {
+            fn clone(&self) -> Self {
+                self.open_clones.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+                Self {
+                    service_id: self.service_id,
+                    connection: self.connection.clone(),
+                    is_closed: ::std::sync::atomic::AtomicBool::new(false),
+                    open_clones: self.open_clones.clone(),
+                }
+            }
+        }
+        #[#internal::async_trait]
         impl #internal::RustyRpcServiceProxy for #service_proxy_name {
             fn from_service_id(
                 service_id: #internal::ServiceId,
-                stream_sink: ::std::sync::Arc<#internal::Mutex<dyn #internal::ClientStreamSink>>,
+                connection: ::std::sync::Arc<#internal::ClientConnection>,
+            ) -> Self {
+                let open_clones = connection.open_proxy(service_id);
+                Self {
+                    service_id,
+                    connection,
+                    is_closed: ::std::sync::atomic::AtomicBool::new(false),
+                    open_clones,
+                }
+            }
+            fn service_id(&self) -> #internal::ServiceId {
+                self.service_id
+            }
+            fn connection(&self) -> &::std::sync::Arc<#internal::ClientConnection> {
+                &self.connection
+            }
+            fn open_clones(&self) -> &::std::sync::Arc<::std::sync::atomic::AtomicUsize> {
+                &self.open_clones
+            }
+            fn from_open_clones(
+                service_id: #internal::ServiceId,
+                connection: ::std::sync::Arc<#internal::ClientConnection>,
+                open_clones: ::std::sync::Arc<::std::sync::atomic::AtomicUsize>,
             ) -> Self {
-                Self { service_id, stream_sink, is_closed: ::std::sync::atomic::AtomicBool::new(false) }
+                Self {
+                    service_id,
+                    connection,
+                    is_closed: ::std::sync::atomic::AtomicBool::new(false),
+                    open_clones,
+                }
+            }
+            async fn close_proxy(&mut self) -> ::std::result::Result<(), #internal::RustyRpcError> {
+                self.close().await
+            }
+            fn close_in_background(self) {
+                let ordering = ::std::sync::atomic::Ordering::SeqCst;
+                if !self.is_closed.swap(true, ordering) && self.open_clones.fetch_sub(1, ordering) == 1 {
+                    self.connection.close_in_background(self.service_id);
+                }
             }
         }
         impl #service_proxy_name {
-            /// This method should be called only once before it is dropped.
-            async fn close(&mut self) -> ::std::io::Result<()> {
-                let Self { service_id, stream_sink, is_closed } = self;
+            #(#chained_methods)*
+            #(#stream_methods)*
+
+            /// This method should be called only once before it is dropped. The
+            /// service is only dropped on the server once all clones are closed.
+            ///
+            /// # Panics
+            ///
+            /// Panics if the proxy was already closed. Closing a service consumes
+            /// it, so this can only happen if the proxy is misused.
+            async fn close(&mut self) -> ::std::result::Result<(), #internal::RustyRpcError> {
+                let Self { service_id, connection, is_closed, open_clones } = self;
                 let ordering = ::std::sync::atomic::Ordering::SeqCst;
-                is_closed.compare_exchange(false, true, ordering, ordering).map_err(|_| #internal::string_io_error(
-                    "Service proxy closed twice."))?;
-                
+                assert!(
+                    is_closed.compare_exchange(false, true, ordering, ordering).is_ok(),
+                    "Service proxy closed twice."
+                );
+                if open_clones.fetch_sub(1, ordering) != 1 {
+                    return Ok(());
+                }
+
                 let msg_to_send = #internal::ClientMessage::DropService(*service_id);
 
-                let mut locked = stream_sink.lock().await;
-                use #internal::{SinkExt, StreamExt};
-                locked.send(msg_to_send).await?;
-                let response: #internal::ServerMessage = locked.next().await.ok_or_else(|| #internal::string_io_error(
-                    "Server closed communication while client waiting for confirmation for dropped service."))??;
+                let response = connection.call(msg_to_send).await?;
 
                 match response {
                     #internal::ServerMessage::DropServiceDone => (),
-                    #internal::ServerMessage::MethodReturned(_) => {
-                        panic!("Server sent return value instead of confirmation for dropped service.")
-                    }
+                    #internal::ServerMessage::Error(msg) => return Err(#internal::RustyRpcError::ServerError(msg)),
+                    other => return Err(#internal::unexpected_response(
+                        &other, "confirmation for dropped service")),
                 };
                 Ok(())
             }
@@ -383,8 +2401,8 @@ fn code_for_service(service_name: &Identifier, service: &Service) -> TokenStream
                     return;
                 }
                 let ordering = ::std::sync::atomic::Ordering::SeqCst;
-                if !self.is_closed.load(ordering) {
-                    panic!("Service proxy dropped without being closed");
+                if !self.is_closed.load(ordering) && self.open_clones.fetch_sub(1, ordering) == 1 {
+                    self.connection.proxy_dropped_without_close(self.service_id);
                 }
             }
         }
@@ -392,33 +2410,529 @@ fn code_for_service(service_name: &Identifier, service: &Service) -> TokenStream
         impl #service_name for #service_proxy_name {
             #(#proxy_method_impl)*
         }
+
+        #[doc = #service_client_doc]
+        pub enum #service_client_name {}
+        impl #service_client_name {
+            /// Starts a client over an existing connection, like
+            /// `rusty_rpc_lib::start_client`.
+            pub async fn from_stream<RW>(
+                read_write: RW,
+            ) -> #internal::ServiceRefMut<'static, dyn #service_name>
+            where
+                RW: #internal::AsyncRead + #internal::AsyncWrite + ::std::marker::Send + ::std::marker::Unpin + 'static,
+            {
+                ::rusty_rpc_lib::start_client::<dyn #service_name, RW>(read_write).await
+            }
+        }
+        #internal::if_tcp! {
+            impl #service_client_name {
+                /// Connects to a server over TCP, like
+                /// `rusty_rpc_lib::connect_client` with the default options.
+                pub async fn connect<A>(
+                    addr: A,
+                ) -> ::std::result::Result<#internal::ServiceRefMut<'static, dyn #service_name>, #internal::RustyRpcError>
+                where
+                    A: #internal::ToSocketAddrs + ::std::clone::Clone + ::std::fmt::Debug,
+                {
+                    ::rusty_rpc_lib::connect_client::<dyn #service_name, A>(
+                        addr,
+                        ::std::default::Default::default(),
+                    ).await
+                }
+            }
+        }
+        #internal::if_mock! {
+            #[doc = #service_mock_doc]
+            pub struct #service_mock_name<S = ()> {
+                /// Passed to each closure, so that the closures can keep track
+                /// of the calls, and return values that borrow from it.
+                pub state: S,
+                #(
+                    #mock_handler_names: ::std::option::Option<::std::boxed::Box<
+                        dyn #mock_handler_types + ::std::marker::Send + ::std::marker::Sync
+                    >>,
+                )*
+            }
+            impl #service_mock_name {
+                /// Creates a mock without state, and without any closures.
+                pub fn new() -> Self {
+                    Self::with_state(())
+                }
+            }
+            impl ::std::default::Default for #service_mock_name {
+                fn default() -> Self {
+                    Self::new()
+                }
+            }
+            impl<S> #service_mock_name<S> {
+                /// Creates a mock with the given state, and without any
+                /// closures.
+                pub fn with_state(state: S) -> Self {
+                    Self {
+                        state,
+                        #(#mock_handler_names: ::std::option::Option::None,)*
+                    }
+                }
+
+                #(#mock_setters)*
+            }
+            #[#internal::async_trait]
+            impl<S: ::std::marker::Send + ::std::marker::Sync> #service_name for #service_mock_name<S> {
+                #(#mock_method_impl)*
+            }
+            #mock_server_traits
+        }
+        #internal::if_blocking! {
+            #[doc = #service_blocking_client_doc]
+            pub struct #service_blocking_client_name {
+                // The service is dropped before the runtime that it runs on.
+                service: #internal::ServiceRefMut<'static, dyn #service_name>,
+                runtime: #internal::Runtime,
+            }
+            impl #service_blocking_client_name {
+                /// Connects to a server over TCP, like
+                /// `rusty_rpc_lib::connect_client` with the default options.
+                pub fn connect<A>(
+                    addr: A,
+                ) -> ::std::result::Result<Self, #internal::RustyRpcError>
+                where
+                    A: #internal::ToSocketAddrs + ::std::clone::Clone + ::std::fmt::Debug,
+                {
+                    let runtime = #internal::new_blocking_runtime().map_err(#internal::RustyRpcError::Io)?;
+                    let service = runtime.block_on(::rusty_rpc_lib::connect_client::<dyn #service_name, A>(
+                        addr,
+                        ::std::default::Default::default(),
+                    ))?;
+                    ::std::result::Result::Ok(Self { service, runtime })
+                }
+
+                /// Closes the connection, like `ServiceRefMut::close`.
+                pub fn close(self) -> ::std::result::Result<(), #internal::RustyRpcError> {
+                    let Self { service, runtime } = self;
+                    runtime.block_on(service.close())
+                }
+
+                #(#blocking_methods)*
+            }
+        }
+    }
+}
+
+/// Returns the ID of each method of the service, in the order of
+/// `service.methods`. Methods with an `@id(...)` get that ID. The others are
+/// numbered in the order of their names, skipping the IDs that are written
+/// explicitly, so adding a method with an explicit ID doesn't change the IDs of
+/// the other methods.
+fn assign_method_ids(service_name: &Identifier, service: &Service) -> Result<Vec<u64>, String> {
+    let mut explicit_ids = BTreeMap::<u64, &Identifier>::new();
+    for (method_name, method) in &service.methods {
+        if let Some(id) = method.id {
+            if let Some(other_method_name) = explicit_ids.insert(id, method_name) {
+                return Err(format!(
+                    "Methods {} and {} of service {} both have ID {}.",
+                    other_method_name.0, method_name.0, service_name.0, id
+                ));
+            }
+        }
     }
+    let mut next_id = 0;
+    let method_ids = service
+        .methods
+        .values()
+        .map(|method| {
+            method.id.unwrap_or_else(|| {
+                while explicit_ids.contains_key(&next_id) {
+                    next_id += 1;
+                }
+                next_id += 1;
+                next_id - 1
+            })
+        })
+        .collect();
+    Ok(method_ids)
+}
+
+/// Like `my_compile_error!`, but for use in helper functions that return a
+/// `TokenStream` that is spliced into the output.
+fn compile_error(msg: impl std::fmt::Display) -> TokenStream {
+    parse::Error::new(Span::call_site(), msg).into_compile_error()
 }
 
+/// Rust keywords that can be used as identifiers by writing them as raw
+/// identifiers. The parser rejects the keywords that can't be raw identifiers.
+const RUST_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do",
+    "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let",
+    "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+    "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
+
 fn to_syn_ident(ident: &Identifier) -> syn::Ident {
-    syn::Ident::new(&ident.0, Span::call_site())
+    if RUST_KEYWORDS.contains(&&*ident.0) {
+        format_ident!("r#{}", ident.0)
+    } else {
+        syn::Ident::new(&ident.0, Span::call_site())
+    }
+}
+
+/// `#[serde(rename = ...)]` for a field with a `@rust_name(...)`, so that it is
+/// still encoded under its name in the interface file.
+fn rename_attribute(field_name: &Identifier, field: &Field) -> TokenStream {
+    match field.rust_name {
+        Some(_) => {
+            let wire_name = &field_name.0;
+            quote! { #[serde(rename = #wire_name)] }
+        }
+        None => quote! {},
+    }
+}
+
+/// The identifier of a generated field or method, which is its
+/// `@rust_name(...)` if it has one.
+fn rust_ident(name: &Identifier, rust_name: &Option<Identifier>) -> syn::Ident {
+    to_syn_ident(rust_name.as_ref().unwrap_or(name))
+}
+
+fn rust_path_to_syn_path(path: &RustPath) -> syn::Path {
+    let segments = path.0.iter().map(to_syn_ident);
+    parse_quote! { #(#segments)::* }
 }
 
 fn data_type_to_token_stream(type_: &DataType) -> TokenStream {
     match type_ {
         DataType::I32 => quote! { i32 },
+        DataType::I128 => quote! { i128 },
+        DataType::U128 => quote! { u128 },
+        DataType::Duration => quote! { ::std::time::Duration },
+        DataType::Timestamp => quote! { ::std::time::SystemTime },
+        DataType::Uuid => quote! { ::rusty_rpc_lib::internal_for_macro::Uuid },
+        DataType::Char => quote! { char },
+        DataType::Bytes => quote! { ::std::vec::Vec<u8> },
+        DataType::String => quote! { ::std::string::String },
         DataType::Struct(type_identifier) => {
             let temp = to_syn_ident(type_identifier);
             quote! { #temp }
         }
+        DataType::ServiceRef(x) => {
+            let temp = to_syn_ident(x);
+            quote! { ::rusty_rpc_lib::internal_for_macro::ServiceRefMut<'a, dyn #temp + 'a> }
+        }
+    }
+}
+
+/// The `#[serde(with = "...")]` attribute for struct fields whose type isn't
+/// encoded the way serde encodes it by default, or that are `@lossy_utf8`.
+fn serde_with_attribute(field: &Field) -> TokenStream {
+    let module = match field.field_type {
+        DataType::String if field.lossy_utf8 => {
+            "::rusty_rpc_lib::internal_for_macro::serde_lossy_utf8"
+        }
+        DataType::Bytes => "::rusty_rpc_lib::internal_for_macro::serde_bytes",
+        DataType::I128 => "::rusty_rpc_lib::internal_for_macro::serde_i128",
+        DataType::U128 => "::rusty_rpc_lib::internal_for_macro::serde_u128",
+        DataType::Duration => "::rusty_rpc_lib::internal_for_macro::serde_duration",
+        DataType::Timestamp => "::rusty_rpc_lib::internal_for_macro::serde_timestamp",
+        DataType::Uuid => "::rusty_rpc_lib::internal_for_macro::serde_uuid",
+        DataType::Char => "::rusty_rpc_lib::internal_for_macro::serde_char",
+        _ => return quote! {},
+    };
+    quote! { #[serde(with = #module)] }
+}
+
+/// Like `data_type_to_token_stream`, but for method parameters. Structs are
+/// passed by reference, so that the client doesn't have to give them up.
+fn param_type_to_token_stream(type_: &DataType) -> TokenStream {
+    match type_ {
+        DataType::Struct(_) => {
+            let temp = data_type_to_token_stream(type_);
+            quote! { &#temp }
+        }
+        DataType::I128
+        | DataType::U128
+        | DataType::Duration
+        | DataType::Timestamp
+        | DataType::Uuid
+        | DataType::Char => data_type_to_token_stream(type_),
+        _ => param_wire_type_to_token_stream(type_),
+    }
+}
+
+/// The type that the server parses a method parameter into. Byte strings and
+/// strings borrow from the received message, and 128-bit integers are parsed
+/// from their bytes.
+fn param_wire_type_to_token_stream(type_: &DataType) -> TokenStream {
+    match type_ {
+        DataType::Bytes => quote! { &[u8] },
+        DataType::String => quote! { &str },
+        DataType::I128 => quote! { ::rusty_rpc_lib::internal_for_macro::WireI128 },
+        DataType::U128 => quote! { ::rusty_rpc_lib::internal_for_macro::WireU128 },
+        DataType::Duration => quote! { ::rusty_rpc_lib::internal_for_macro::WireDuration },
+        DataType::Timestamp => quote! { ::rusty_rpc_lib::internal_for_macro::WireTimestamp },
+        DataType::Uuid => quote! { ::rusty_rpc_lib::internal_for_macro::WireUuid },
+        DataType::Char => quote! { ::rusty_rpc_lib::internal_for_macro::WireChar },
+        _ => data_type_to_token_stream(type_),
+    }
+}
+
+/// The type that the client parses the `Ok` or `Err` value of a
+/// [ReturnType::Result] into. Byte strings are sent as binaries, like other
+/// returned byte strings.
+fn return_wire_type_to_token_stream(type_: &DataType) -> TokenStream {
+    match type_ {
+        DataType::Bytes => quote! { ::rusty_rpc_lib::internal_for_macro::ByteBuf },
+        DataType::I128 => quote! { ::rusty_rpc_lib::internal_for_macro::WireI128 },
+        DataType::U128 => quote! { ::rusty_rpc_lib::internal_for_macro::WireU128 },
+        DataType::Duration => quote! { ::rusty_rpc_lib::internal_for_macro::WireDuration },
+        DataType::Timestamp => quote! { ::rusty_rpc_lib::internal_for_macro::WireTimestamp },
+        DataType::Uuid => quote! { ::rusty_rpc_lib::internal_for_macro::WireUuid },
+        DataType::Char => quote! { ::rusty_rpc_lib::internal_for_macro::WireChar },
+        _ => data_type_to_token_stream(type_),
+    }
+}
+
+/// Converts `x`, a reference to the `Ok` or `Err` value of a
+/// [ReturnType::Result], into something that serializes like
+/// [return_wire_type_to_token_stream].
+fn to_return_wire_type(type_: &DataType) -> TokenStream {
+    match type_ {
+        DataType::Bytes => quote! { ::rusty_rpc_lib::internal_for_macro::BytesRef(x) },
+        DataType::I128 => quote! { ::rusty_rpc_lib::internal_for_macro::WireI128(*x) },
+        DataType::U128 => quote! { ::rusty_rpc_lib::internal_for_macro::WireU128(*x) },
+        DataType::Duration => quote! { ::rusty_rpc_lib::internal_for_macro::WireDuration(*x) },
+        DataType::Timestamp => quote! { ::rusty_rpc_lib::internal_for_macro::WireTimestamp(*x) },
+        DataType::Uuid => quote! { ::rusty_rpc_lib::internal_for_macro::WireUuid(*x) },
+        DataType::Char => quote! { ::rusty_rpc_lib::internal_for_macro::WireChar(*x) },
+        _ => quote! { x },
+    }
+}
+
+/// The closures that encode the items of a streaming method that are sent,
+/// which are of `sent_type`, and decode the items that are received, which are
+/// of `received_type`. They are encoded like the `Ok` value of a
+/// [ReturnType::Result]. Expects `wire_format` to be in scope.
+fn code_for_stream_items(sent_type: &DataType, received_type: &DataType) -> (TokenStream, TokenStream) {
+    let sent_rust_type = data_type_to_token_stream(sent_type);
+    let sent_value = to_return_wire_type(sent_type);
+    let received_wire_type = return_wire_type_to_token_stream(received_type);
+    let from_received_wire = from_return_wire_type(received_type);
+    let encode = quote! {
+        move |x: #sent_rust_type| {
+            let x = &x;
+            wire_format.encode(&#sent_value)
+        }
+    };
+    let decode = quote! {
+        move |bytes: &[u8]| wire_format.decode::<#received_wire_type>(bytes).map(|x| #from_received_wire)
+    };
+    (encode, decode)
+}
+
+/// Converts `x`, of the type given by [return_wire_type_to_token_stream], back.
+fn from_return_wire_type(type_: &DataType) -> TokenStream {
+    match type_ {
+        DataType::Bytes
+        | DataType::I128
+        | DataType::U128
+        | DataType::Duration
+        | DataType::Timestamp
+        | DataType::Uuid
+        | DataType::Char => quote! { x.0 },
+        _ => quote! { x },
+    }
+}
+
+/// Whether the method gets a `_borrowed` version, whose returned strings can
+/// borrow from the service. See [borrowed_return_type_to_token_stream].
+fn returns_string(type_: &ReturnType) -> bool {
+    match type_ {
+        ReturnType::Data(x) => *x == DataType::String,
+        ReturnType::Result(ok_type, err_type) => {
+            *ok_type == DataType::String || *err_type == DataType::String
+        }
+        _ => false,
     }
 }
 
-fn return_type_to_token_stream(type_: &ReturnType, lifetime: Lifetime) -> TokenStream {
+/// The return type of the `_borrowed` version of a method that
+/// [returns_string]. It is like the return type of the method itself, except
+/// that strings are `Cow`s, which the server can borrow from the service, since
+/// the return value is serialized before the service is unlocked. Clients
+/// always get owned strings, so only the server calls this version.
+fn borrowed_return_type_to_token_stream(
+    type_: &ReturnType,
+    lifetime: &Lifetime,
+    error_type: &syn::Type,
+) -> TokenStream {
+    let borrowed_type = |x: &DataType| match x {
+        DataType::String => quote! { ::std::borrow::Cow<#lifetime, str> },
+        _ => data_type_to_token_stream(x),
+    };
+    let inner_return_type = match type_ {
+        ReturnType::Data(x) => borrowed_type(x),
+        ReturnType::Result(ok_type, err_type) => {
+            let ok_type = borrowed_type(ok_type);
+            let err_type = borrowed_type(err_type);
+            quote! { ::std::result::Result<#ok_type, #err_type> }
+        }
+        _ => unreachable!("Only methods that return strings have a borrowed version."),
+    };
+    quote! {
+        ::std::result::Result<#inner_return_type, #error_type>
+    }
+}
+
+/// Converts `x`, the return value of a method that [returns_string], to the
+/// return type of its `_borrowed` version.
+fn to_borrowed_return_value(type_: &ReturnType) -> TokenStream {
+    let cow = quote! { ::std::borrow::Cow::Owned };
+    match type_ {
+        ReturnType::Result(ok_type, err_type) => {
+            let map_ok = (*ok_type == DataType::String).then(|| quote! { .map(#cow) });
+            let map_err = (*err_type == DataType::String).then(|| quote! { .map_err(#cow) });
+            quote! { x #map_ok #map_err }
+        }
+        _ => quote! { #cow(x) },
+    }
+}
+
+fn return_type_to_token_stream(
+    type_: &ReturnType,
+    lifetime: Lifetime,
+    rpc_interface: &RpcInterface,
+    error_type: &syn::Type,
+) -> TokenStream {
+    let internal = quote! { ::rusty_rpc_lib::internal_for_macro };
     let inner_return_type = match type_ {
+        // The items of a streaming method are sent while it runs.
+        ReturnType::Nothing | ReturnType::BidiStream(..) => quote! { () },
         ReturnType::ServiceRefMut(x) => {
-            let internal = quote! { ::rusty_rpc_lib::internal_for_macro };
             let temp = to_syn_ident(x);
-            quote! { #internal::ServiceRefMut<dyn #temp + #lifetime> }
+            quote! { #internal::ServiceRefMut<#lifetime, dyn #temp + #lifetime> }
+        }
+        ReturnType::OwnedService(x) => {
+            let temp = to_syn_ident(x);
+            quote! { #internal::ServiceRefMut<'static, dyn #temp> }
+        }
+        ReturnType::ServiceRefMutTuple(x) => {
+            let temp = x.iter().map(to_syn_ident);
+            quote! { (#(#internal::ServiceRefMut<#lifetime, dyn #temp + #lifetime>),*) }
+        }
+        ReturnType::Data(DataType::Struct(x)) if struct_has_services(x, rpc_interface) => {
+            let temp = to_syn_ident(x);
+            quote! { #temp<#lifetime> }
         }
         ReturnType::Data(x) => data_type_to_token_stream(x),
+        ReturnType::Result(ok_type, err_type) => {
+            let ok_type = data_type_to_token_stream(ok_type);
+            let err_type = data_type_to_token_stream(err_type);
+            quote! { ::std::result::Result<#ok_type, #err_type> }
+        }
     };
     quote! {
-        ::std::io::Result<#inner_return_type>
+        ::std::result::Result<#inner_return_type, #error_type>
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_struct_cycle() {
+        let input = r#"
+            struct Foo { bar: Bar, }
+            struct Bar { x: i32, baz: Baz, }
+            struct Baz { foo: Foo, }
+            struct Qux { foo: Foo, }
+            struct Leaf { x: i32, }
+        "#;
+        let (_, rpc_interface) = parse_interface(input.as_bytes()).unwrap();
+        let ident = |s: &str| Identifier(s.to_string());
+        assert_eq!(
+            Some(vec![ident("Foo"), ident("Bar"), ident("Baz"), ident("Foo")]),
+            find_struct_cycle(&ident("Foo"), &ident("Foo"), &rpc_interface, &mut BTreeSet::new())
+        );
+        // Qux contains a recursive struct, but isn't part of the cycle itself.
+        assert_eq!(
+            None,
+            find_struct_cycle(&ident("Qux"), &ident("Qux"), &rpc_interface, &mut BTreeSet::new())
+        );
+        assert_eq!(
+            None,
+            find_struct_cycle(&ident("Leaf"), &ident("Leaf"), &rpc_interface, &mut BTreeSet::new())
+        );
+    }
+
+    #[test]
+    fn test_resolve_protocol_file_path() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let workspace_dir = manifest_dir.parent().unwrap();
+        // Building from a subdirectory of the crate doesn't change where a
+        // crate-relative path points to.
+        for current_dir in [manifest_dir, &manifest_dir.join("src"), workspace_dir] {
+            assert_eq!(
+                manifest_dir.join("tests/simple_interface_file.interface"),
+                resolve_protocol_file_path(
+                    "tests/simple_interface_file.interface",
+                    Some(manifest_dir),
+                    current_dir
+                )
+            );
+        }
+        // Paths that used to be relative to the workspace root still work.
+        assert_eq!(
+            workspace_dir.join("rusty_rpc_macro/tests/simple_interface_file.interface"),
+            resolve_protocol_file_path(
+                "rusty_rpc_macro/tests/simple_interface_file.interface",
+                Some(manifest_dir),
+                workspace_dir
+            )
+        );
+        assert_eq!(
+            workspace_dir.join("x.protocol"),
+            resolve_protocol_file_path("x.protocol", None, workspace_dir)
+        );
+    }
+
+    #[test]
+    fn test_assign_method_ids() {
+        let method_ids = |input: &str| {
+            let (_, rpc_interface) = parse_interface(input.as_bytes()).unwrap();
+            let service_name = Identifier("Foo".to_string());
+            let service = &rpc_interface.services[&service_name];
+            assign_method_ids(&service_name, service).map(|ids| {
+                service
+                    .methods
+                    .keys()
+                    .map(|x| x.0.clone())
+                    .zip(ids)
+                    .collect::<Vec<_>>()
+            })
+        };
+        let name_ids = |x: &[(&str, u64)]| -> Vec<(String, u64)> {
+            x.iter().map(|&(name, id)| (name.to_string(), id)).collect()
+        };
+
+        // Without explicit IDs, methods are numbered in the order of their names.
+        let input = "service Foo { b(&mut self) -> i32; c(&mut self) -> i32; }";
+        assert_eq!(Ok(name_ids(&[("b", 0), ("c", 1)])), method_ids(input));
+
+        // Inserting a method with an explicit ID doesn't change the other IDs.
+        // Automatic IDs skip the explicit ones.
+        let input = "service Foo { @id(1) a(&mut self) -> i32; b(&mut self) -> i32; @id(0) c(&mut self) -> i32; }";
+        assert_eq!(Ok(name_ids(&[("a", 1), ("b", 2), ("c", 0)])), method_ids(input));
+        let input = "service Foo { @id(1) a(&mut self) -> i32; b(&mut self) -> i32; @id(0) c(&mut self) -> i32; @id(3) aa(&mut self) -> i32; }";
+        assert_eq!(
+            Ok(name_ids(&[("a", 1), ("aa", 3), ("b", 2), ("c", 0)])),
+            method_ids(input)
+        );
+
+        let input = "service Foo { @id(5) a(&mut self) -> i32; @id(5) b(&mut self) -> i32; }";
+        assert_eq!(
+            Err("Methods a and b of service Foo both have ID 5.".to_string()),
+            method_ids(input)
+        );
     }
 }
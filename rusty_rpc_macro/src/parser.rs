@@ -11,171 +11,467 @@ specification-document := definition *
 definition := service-definition | struct-definition
 
 // mirrors rust's struct definition
-struct-definition := "struct" identifier "{" struct-field * "}"
-struct-field := identifier ":" type ","
+struct-definition := struct-attribute* "struct" identifier "{" struct-field * "}"
+struct-attribute := derive-attribute | non-exhaustive-attribute | rust-name | mirror | copy
+derive-attribute := "#" "[" "derive" "(" rust-path ( "," rust-path )* ","? ")" "]"
+non-exhaustive-attribute := "#" "[" "non_exhaustive" "]"
+rust-path := identifier ( "::" identifier )*
+// Generates `From` conversions between the struct and a Rust struct with the
+// same field names, whose field types convert into each other with `Into`.
+mirror := "@" "mirror" "(" rust-path ")"
+// Makes the struct `Copy`. All of its fields must be integers or `@copy`
+// structs.
+copy := "@" "copy"
+struct-field := field-annotation* identifier ":" field-type ( "=" literal )? ","
+field-annotation := rust-name | skip-if-default | lossy-utf8
+// Leaves the field out of the encoded struct when it has its default value.
+skip-if-default := "@" "skip_if_default"
+// Only for `string` fields. Decodes invalid UTF-8 with replacement characters
+// instead of failing, for peers that send strings in other encodings.
+lossy-utf8 := "@" "lossy_utf8"
+// A struct with a service field can only be returned from methods, and can't
+// be in other structs.
+field-type := "&" "mut" service-type | data-type
 
-service-definition := "service" identifier "{" service-method * "}"
+service-definition := "service" identifier ( ":" identifier )? "{" service-method * "}"
+// A service after a colon is a base service. The derived service has all of
+// the methods of the base service, with the same method IDs, plus its own.
 // Currently, `&self` is not supported.
-service-method := identifier "(" ( "&" "self" ) ( "," identifier ":" type )* ")" "->" type ";"
+service-method := method-id? deprecated? rust-name? named-args? incremental? ( "oneway" method-signature | method-signature "->" type | stream-signature "->" "stream" data-type ) ";"
+method-signature := identifier "(" ( "&" "self" ) ( "," identifier ":" type )* ","? ")"
+// A oneway method has no return type. The client sends the call without
+// waiting for it to finish, and the server doesn't respond.
+stream-signature := identifier "(" "&" "mut" "self" "," "stream" data-type ","? ")"
+// A streaming method gets a stream of items from the client, and sends a
+// stream of items back, at the same time. The call is over once the server's
+// method returns.
+// Fixes the method ID that is sent over the network, so that adding, removing,
+// or renaming other methods doesn't change it.
+method-id := "@" "id" "(" digit digit* ")"
+// The method still works, but using it in Rust gives a warning with the note.
+deprecated := "@" "deprecated" ( "(" string-literal ")" )?
+// The name of the generated Rust item, if it should differ from the name that
+// the wire format uses. E.g., for a method that clashes with another method of
+// the type that implements the service.
+rust-name := "@" "rust_name" "(" '"' identifier '"' ")"
+// Encodes the arguments by parameter name instead of by position, so that
+// parameters can be added without breaking old clients. Missing arguments get
+// the default value of their type.
+named-args := "@" "named_args"
+// Only for methods whose only parameter is `bytes`. The bytes are sent in
+// pieces, which the server can handle as they arrive.
+incremental := "@" "incremental"
 
-// Currently, `&Service` is not supported.
-return-type := "&" "mut" service-type | data-type
-data-type := "i32" | struct-type
+// Currently, `&Service` is not supported. A bare service type is a service
+// that doesn't borrow from `self`.
+return-type := "&" "mut" service-type | service-type | service-tuple | data-type
+service-tuple := "(" "&" "mut" service-type ( "," "&" "mut" service-type )+ ","? ")"
+service-type := "service" identifier
+// `duration` and `timestamp` are `std::time::Duration` and
+// `std::time::SystemTime`, with nanosecond resolution. Timestamps count from
+// the Unix epoch. `uuid` needs the `uuid` feature of rusty_rpc_lib.
+data-type := "i32" | "i128" | "u128" | "duration" | "timestamp" | "uuid" | "char" | "bytes" | "string" | struct-type
 struct-type := identifier
 
+// Currently, only integer literals are supported.
+literal := "-"? digit digit*
+// Escapes are not supported.
+string-literal := '"' (any character except '"', '\' and newline)* '"'
+
 identifier := A string that starts with an alphanumberic character followed by zero or more alphanumberic characters and/or underscores. Except that it must not match a reserved word.
 
 Reserved word list: "struct", "service", "self", "mut", "crate", "super", "Self".
 Note: "crate", "super" and "Self" aren't otherwise in the grammar, but are reserved because Rust identifiers cannot be these keywords,
 even when using raw identifiers. See https://doc.rust-lang.org/1.60.0/reference/identifiers.html
+Other Rust keywords (e.g. "type" or "match") are allowed, and are turned into raw identifiers (e.g. `r#type`) in the generated code.
 */
 
 use nom::{
     branch::alt,
-    bytes::complete::tag,
+    bytes::complete::{tag, take_while},
     character::{
-        complete::{multispace0, multispace1, satisfy},
+        complete::{i64, multispace0, multispace1, satisfy, u64},
         is_alphabetic, is_alphanumeric,
     },
-    combinator::{eof, map, map_res, value, verify},
-    error::ParseError,
-    multi::many0,
-    sequence::{pair, preceded, terminated, tuple},
-    IResult, Parser,
+    combinator::{cut, eof, map, map_opt, opt, verify},
+    error::{ErrorKind, ParseError},
+    multi::{many0, separated_list1},
+    sequence::{delimited, pair, preceded, terminated, tuple},
+    Err, IResult, Parser,
 };
-use std::{
-    collections::{btree_map::Entry, BTreeMap},
-    iter::once,
+use std::{collections::BTreeMap, iter::once};
+
+use crate::interface::{
+    DataType, Deprecation, Field, Identifier, Literal, Method, ReturnType, RpcInterface, RustPath,
+    Service, Struct,
 };
 
-use crate::interface::{DataType, Identifier, Method, ReturnType, RpcInterface, Service, Struct};
+/// An error in an interface file. The slices are the rest of the input from
+/// where the error is.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InterfaceError<'a> {
+    /// The input doesn't match the grammar.
+    Syntax(&'a [u8]),
+    /// Two things with the same name are defined in the same place.
+    Duplicate {
+        name: Identifier,
+        /// What the second definition is, e.g. "field x in struct Foo".
+        description: String,
+        first: &'a [u8],
+        second: &'a [u8],
+    },
+}
+impl<'a> ParseError<&'a [u8]> for InterfaceError<'a> {
+    fn from_error_kind(input: &'a [u8], _kind: ErrorKind) -> Self {
+        InterfaceError::Syntax(input)
+    }
+    /// Keeps the innermost error, which is where parsing actually failed.
+    fn append(_input: &'a [u8], _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+type ParseResult<'a, O> = IResult<&'a [u8], O, InterfaceError<'a>>;
 
-pub fn parse_interface(input: &[u8]) -> IResult<&[u8], RpcInterface> {
+/// Something with a name, along with the rest of the input from the name.
+type Named<'a, T> = (&'a [u8], Identifier, T);
+
+pub fn parse_interface(input: &[u8]) -> ParseResult<'_, RpcInterface> {
     enum Definition {
-        Struct(Identifier, Struct),
-        Service(Identifier, Service),
+        Struct(Struct),
+        Service(Service),
     }
 
-    // Parser that returns Vec<Definition>
+    // Parser that returns Vec<Named<Definition>>
     let parse_definitions = many0_padded_by_multispace(alt((
-        map(parse_struct, |(x, y)| Definition::Struct(x, y)),
-        map(parse_service, |(x, y)| Definition::Service(x, y)),
+        map(parse_struct, |(x, y, z)| (x, y, Definition::Struct(z))),
+        map(parse_service, |(x, y, z)| (x, y, Definition::Service(z))),
     )));
 
-    fn definitions_to_interface(definitions: Vec<Definition>) -> Result<RpcInterface, String> {
-        let mut output = RpcInterface {
-            structs: BTreeMap::new(),
-            services: BTreeMap::new(),
-        };
-        for definition in definitions {
-            // Insert the definition in to the appropriate struct in `output`.
-            // If there's a duplicate definition name, report an error.
-            match definition {
-                Definition::Struct(x, y) => {
-                    match output.structs.entry(x) {
-                        Entry::Vacant(entry) => entry.insert(y),
-                        Entry::Occupied(entry) => {
-                            let msg = format!("Duplicate struct definition: {:?}", entry.key());
-                            eprintln!("{msg}");
-                            return Err(msg);
-                        }
-                    };
-                }
-                Definition::Service(x, y) => {
-                    match output.services.entry(x) {
-                        Entry::Vacant(entry) => entry.insert(y),
-                        Entry::Occupied(entry) => {
-                            let msg = format!("Duplicate service definition: {:?}", entry.key());
-                            eprintln!("{msg}");
-                            return Err(msg);
-                        }
-                    };
-                }
-            };
+    let (input, definitions) = terminated(parse_definitions, eof)(input)?;
+    // Structs and services share a namespace, since both become Rust types.
+    let definitions = collect_unique(definitions, |name| format!("definition of {}", name.0))?;
+    let mut output = RpcInterface {
+        structs: BTreeMap::new(),
+        services: BTreeMap::new(),
+    };
+    for (name, definition) in definitions {
+        match definition {
+            Definition::Struct(x) => {
+                output.structs.insert(name, x);
+            }
+            Definition::Service(x) => {
+                output.services.insert(name, x);
+            }
+        }
+    }
+    Ok((input, output))
+}
+
+/// Collects named things into a map. Fails if two of them have the same name.
+/// `describe` describes the thing with a given name, for the error message.
+fn collect_unique<'a, T>(
+    items: Vec<Named<'a, T>>,
+    describe: impl Fn(&Identifier) -> String,
+) -> Result<BTreeMap<Identifier, T>, Err<InterfaceError<'a>>> {
+    let mut positions = BTreeMap::<Identifier, &[u8]>::new();
+    let mut output = BTreeMap::new();
+    for (position, name, item) in items {
+        if let Some(&first) = positions.get(&name) {
+            return Err(Err::Failure(InterfaceError::Duplicate {
+                description: describe(&name),
+                name,
+                first,
+                second: position,
+            }));
         }
-        Ok(output)
+        positions.insert(name.clone(), position);
+        output.insert(name, item);
     }
+    Ok(output)
+}
 
-    terminated(map_res(parse_definitions, definitions_to_interface), eof)(input)
+/// Returns the rest of the input without consuming anything, to remember
+/// where something is.
+fn position(input: &[u8]) -> ParseResult<'_, &[u8]> {
+    Ok((input, input))
+}
+
+/// Describes where in `input` parsing failed, with the line and column
+/// numbers (starting from 1) and the offending line. For duplicate
+/// definitions, both definitions are shown.
+pub fn describe_parse_error(input: &[u8], error: &Err<InterfaceError>) -> String {
+    let error = match error {
+        Err::Error(e) | Err::Failure(e) => e,
+        Err::Incomplete(_) => return describe_position(input, &[], ""),
+    };
+    match error {
+        InterfaceError::Syntax(remaining) => describe_position(input, remaining, ""),
+        InterfaceError::Duplicate {
+            name,
+            description,
+            first,
+            second,
+        } => format!(
+            "{}\n{}",
+            describe_position(input, second, &format!(" duplicate {description}")),
+            describe_position(input, first, &format!(" {} was first defined here", name.0)),
+        ),
+    }
+}
+
+/// Describes the position where `remaining` starts in `input`, followed by
+/// `message`.
+fn describe_position(input: &[u8], remaining: &[u8], message: &str) -> String {
+    let offset = input.len() - remaining.len();
+    let before = &input[..offset];
+    let line_number = before.iter().filter(|&&ch| ch == b'\n').count() + 1;
+    let line_start = before
+        .iter()
+        .rposition(|&ch| ch == b'\n')
+        .map_or(0, |i| i + 1);
+    let line_end = input[line_start..]
+        .iter()
+        .position(|&ch| ch == b'\n')
+        .map_or(input.len(), |i| line_start + i);
+    let column = offset - line_start + 1;
+    let line = String::from_utf8_lossy(&input[line_start..line_end]);
+    format!(
+        "line {line_number}, column {column}:{message}\n{line}\n{:>column$}",
+        "^"
+    )
+}
+
+fn parse_struct(input: &[u8]) -> ParseResult<'_, Named<'_, Struct>> {
+    enum Attribute {
+        Derive(Vec<RustPath>),
+        NonExhaustive,
+        RustName(Identifier),
+        Mirror(RustPath),
+        Copy,
+    }
+
+    let parse_attribute = alt((
+        map(parse_derive_attribute, Attribute::Derive),
+        map(parse_non_exhaustive_attribute, |()| {
+            Attribute::NonExhaustive
+        }),
+        map(parse_rust_name, Attribute::RustName),
+        map(parse_mirror_attribute, Attribute::Mirror),
+        map(tuple((tag("@"), multispace0, tag("copy"))), |_| {
+            Attribute::Copy
+        }),
+    ));
+    let (input, (attributes, _, _, position, struct_name, _, _, field_vec, _)) = tuple((
+        many0(terminated(parse_attribute, multispace0)),
+        tag("struct"),
+        multispace1,
+        position,
+        parse_identifier,
+        multispace0,
+        tag("{"),
+        many0_padded_by_multispace(parse_struct_field),
+        // Report an invalid field where it is, instead of at the start of
+        // the struct.
+        cut(tag("}")),
+    ))(input)?;
+    let fields = collect_unique(field_vec, |field_name| {
+        format!("field {} in struct {}", field_name.0, struct_name.0)
+    })?;
+    let mut extra_derives = Vec::new();
+    let mut non_exhaustive = false;
+    let mut rust_name = None;
+    let mut mirrors = Vec::new();
+    let mut copy = false;
+    for attribute in attributes {
+        match attribute {
+            Attribute::Derive(x) => extra_derives.extend(x),
+            Attribute::NonExhaustive => non_exhaustive = true,
+            Attribute::RustName(x) => rust_name = Some(x),
+            Attribute::Mirror(x) => mirrors.push(x),
+            Attribute::Copy => copy = true,
+        }
+    }
+    Ok((
+        input,
+        (
+            position,
+            struct_name,
+            Struct {
+                fields,
+                extra_derives,
+                mirrors,
+                copy,
+                non_exhaustive,
+                rust_name,
+            },
+        ),
+    ))
 }
 
-fn parse_struct(input: &[u8]) -> IResult<&[u8], (Identifier, Struct)> {
-    map_res(
+fn parse_derive_attribute(input: &[u8]) -> ParseResult<'_, Vec<RustPath>> {
+    let parse_derive_list = delimited(
+        pair(tag("("), multispace0),
+        separated_list1(tuple((multispace0, tag(","), multispace0)), parse_rust_path),
+        tuple((multispace0, opt(pair(tag(","), multispace0)), tag(")"))),
+    );
+    map(
         tuple((
-            tag("struct"),
-            multispace1,
-            parse_identifier,
+            tag("#"),
+            multispace0,
+            tag("["),
             multispace0,
-            tag("{"),
-            many0_padded_by_multispace(parse_struct_field),
-            tag("}"),
+            tag("derive"),
+            multispace0,
+            parse_derive_list,
+            multispace0,
+            tag("]"),
         )),
-        |(_, _, struct_name, _, _, field_vec, _)| -> _ {
-            let mut field_map = BTreeMap::<Identifier, DataType>::new();
-            for (field_name, field_type) in field_vec {
-                match field_map.entry(field_name) {
-                    Entry::Vacant(entry) => entry.insert(field_type),
-                    Entry::Occupied(entry) => {
-                        let msg = format!("Duplicate struct field definition: {:?}", entry.key());
-                        eprintln!("{msg}");
-                        return Err(msg);
-                    }
-                };
-            }
-            Ok((struct_name, Struct { fields: field_map }))
-        },
+        |(_, _, _, _, _, _, derive_list, _, _)| derive_list,
     )(input)
 }
 
-fn parse_struct_field(input: &[u8]) -> IResult<&[u8], (Identifier, DataType)> {
+fn parse_non_exhaustive_attribute(input: &[u8]) -> ParseResult<'_, ()> {
     map(
         tuple((
-            parse_identifier,
+            tag("#"),
             multispace0,
-            tag(":"),
+            tag("["),
             multispace0,
-            parse_data_type,
+            tag("non_exhaustive"),
             multispace0,
-            tag(","),
+            tag("]"),
         )),
-        |(field_name, _, _, _, field_type, _, _)| (field_name, field_type),
+        |_| (),
     )(input)
 }
 
-fn parse_service(input: &[u8]) -> IResult<&[u8], (Identifier, Service)> {
-    map_res(
+fn parse_mirror_attribute(input: &[u8]) -> ParseResult<'_, RustPath> {
+    delimited(
         tuple((
-            tag("service"),
-            multispace1,
+            tag("@"),
+            multispace0,
+            tag("mirror"),
+            multispace0,
+            tag("("),
+            multispace0,
+        )),
+        parse_rust_path,
+        pair(multispace0, tag(")")),
+    )(input)
+}
+
+fn parse_rust_path(input: &[u8]) -> ParseResult<'_, RustPath> {
+    map(
+        separated_list1(
+            tuple((multispace0, tag("::"), multispace0)),
+            parse_identifier,
+        ),
+        RustPath,
+    )(input)
+}
+
+fn parse_struct_field(input: &[u8]) -> ParseResult<'_, Named<'_, Field>> {
+    enum Annotation {
+        RustName(Identifier),
+        SkipIfDefault,
+        LossyUtf8,
+    }
+
+    let parse_annotation = alt((
+        map(parse_rust_name, Annotation::RustName),
+        map(
+            tuple((tag("@"), multispace0, tag("skip_if_default"))),
+            |_| Annotation::SkipIfDefault,
+        ),
+        map(tuple((tag("@"), multispace0, tag("lossy_utf8"))), |_| {
+            Annotation::LossyUtf8
+        }),
+    ));
+    let parse_default_value = terminated(
+        preceded(pair(tag("="), multispace0), parse_literal),
+        multispace0,
+    );
+    map(
+        tuple((
+            many0(terminated(parse_annotation, multispace0)),
+            position,
             parse_identifier,
             multispace0,
-            tag("{"),
-            many0_padded_by_multispace(parse_method),
-            tag("}"),
+            tag(":"),
+            multispace0,
+            alt((
+                parse_service_ref_mut_type.map(DataType::ServiceRef),
+                parse_data_type,
+            )),
+            multispace0,
+            opt(parse_default_value),
+            tag(","),
         )),
-        |(_, _, service_name, _, _, method_vec, _)| -> _ {
-            let mut method_map = BTreeMap::<Identifier, Method>::new();
-            for (method_name, method_type) in method_vec {
-                match method_map.entry(method_name) {
-                    Entry::Vacant(entry) => entry.insert(method_type),
-                    Entry::Occupied(entry) => {
-                        let msg = format!("Duplicate struct method definition: {:?}", entry.key());
-                        eprintln!("{msg}");
-                        return Err(msg);
-                    }
-                };
+        |(annotations, position, field_name, _, _, _, field_type, _, default_value, _)| {
+            let mut rust_name = None;
+            let mut skip_if_default = false;
+            let mut lossy_utf8 = false;
+            for annotation in annotations {
+                match annotation {
+                    Annotation::RustName(x) => rust_name = Some(x),
+                    Annotation::SkipIfDefault => skip_if_default = true,
+                    Annotation::LossyUtf8 => lossy_utf8 = true,
+                }
             }
-            Ok((
-                service_name,
-                Service {
-                    methods: method_map,
+            (
+                position,
+                field_name,
+                Field {
+                    field_type,
+                    default_value,
+                    rust_name,
+                    skip_if_default,
+                    lossy_utf8,
                 },
-            ))
+            )
         },
     )(input)
 }
 
-fn parse_method(input: &[u8]) -> IResult<&[u8], (Identifier, Method)> {
+fn parse_literal(input: &[u8]) -> ParseResult<'_, Literal> {
+    map(i64, Literal::Int)(input)
+}
+
+fn parse_string_literal(input: &[u8]) -> ParseResult<'_, String> {
+    map_opt(
+        delimited(
+            tag("\""),
+            take_while(|ch| ch != b'"' && ch != b'\\' && ch != b'\n'),
+            tag("\""),
+        ),
+        |x: &[u8]| String::from_utf8(x.to_vec()).ok(),
+    )(input)
+}
+
+fn parse_service(input: &[u8]) -> ParseResult<'_, Named<'_, Service>> {
+    let (input, (_, _, position, service_name, _, base, _, method_vec, _)) = tuple((
+        tag("service"),
+        multispace1,
+        position,
+        parse_identifier,
+        multispace0,
+        opt(delimited(
+            pair(tag(":"), multispace0),
+            parse_identifier,
+            multispace0,
+        )),
+        tag("{"),
+        many0_padded_by_multispace(parse_method),
+        cut(tag("}")),
+    ))(input)?;
+    let methods = collect_unique(method_vec, |method_name| {
+        format!("method {} in service {}", method_name.0, service_name.0)
+    })?;
+    Ok((input, (position, service_name, Service { base, methods })))
+}
+
+fn parse_method(input: &[u8]) -> ParseResult<'_, Named<'_, Method>> {
     let parse_parameter = map(
         tuple((
             tag(","),
@@ -188,8 +484,50 @@ fn parse_method(input: &[u8]) -> IResult<&[u8], (Identifier, Method)> {
         )),
         |(_, _, param_name, _, _, _, param_type)| (param_name, param_type),
     );
-    map(
+    let parse_method_id = delimited(
         tuple((
+            tag("@"),
+            multispace0,
+            tag("id"),
+            multispace0,
+            tag("("),
+            multispace0,
+        )),
+        u64,
+        pair(multispace0, tag(")")),
+    );
+    let parse_deprecated = map(
+        preceded(
+            tuple((tag("@"), multispace0, tag("deprecated"))),
+            opt(delimited(
+                tuple((multispace0, tag("("), multispace0)),
+                parse_string_literal,
+                pair(multispace0, tag(")")),
+            )),
+        ),
+        |note| Deprecation { note },
+    );
+    let parse_request_stream = preceded(
+        tuple((tag(","), multispace0, tag("stream"), multispace1)),
+        parse_data_type,
+    );
+    let (input, (position, method_name, request_stream, mut method)) = map(
+        tuple((
+            tuple((
+                opt(terminated(parse_method_id, multispace0)),
+                opt(terminated(parse_deprecated, multispace0)),
+                opt(terminated(parse_rust_name, multispace0)),
+                opt(terminated(
+                    pair(tag("@"), pair(multispace0, tag("named_args"))),
+                    multispace0,
+                )),
+                opt(terminated(
+                    pair(tag("@"), pair(multispace0, tag("incremental"))),
+                    multispace0,
+                )),
+            )),
+            opt(terminated(tag("oneway"), multispace1)),
+            position,
             parse_identifier,
             multispace0,
             tag("("),
@@ -199,29 +537,136 @@ fn parse_method(input: &[u8]) -> IResult<&[u8], (Identifier, Method)> {
             tag("mut"),
             multispace1,
             tag("self"),
-            many0_padded_by_multispace(parse_parameter),
+            alt((
+                map(parse_request_stream, |x| (Some(x), Vec::new())),
+                map(many0_padded_by_multispace(parse_parameter), |x| (None, x)),
+            )),
+            opt(pair(tag(","), multispace0)),
             tag(")"),
             multispace0,
-            tag("->"),
-            multispace0,
-            parse_return_type,
-            multispace0,
-            tag(";"),
         )),
-        |(method_name, _, _, _, _, _, _, _, _, non_self_params, _, _, _, _, return_type, _, _)| {
+        |(
+            (id, deprecated, rust_name, named_args, incremental),
+            oneway,
+            position,
+            method_name,
+            _,
+            _,
+            _,
+            _,
+            _,
+            _,
+            _,
+            _,
+            params,
+            _,
+            _,
+            _,
+        )| {
+            let (request_stream, non_self_params) = params;
             (
+                position,
                 method_name,
+                request_stream,
                 Method {
+                    id,
+                    deprecated,
+                    rust_name,
+                    named_args: named_args.is_some(),
+                    incremental: incremental.is_some(),
+                    oneway: oneway.is_some(),
                     non_self_params,
-                    return_type,
+                    return_type: ReturnType::Nothing,
                 },
             )
         },
+    )(input)?;
+    // Only oneway methods have no return type.
+    let input = match (method.oneway, request_stream) {
+        (true, None) => input,
+        (false, None) => {
+            let (input, return_type) =
+                delimited(pair(tag("->"), multispace0), parse_return_type, multispace0)(input)?;
+            method.return_type = return_type;
+            input
+        }
+        (false, Some(request_type)) => {
+            let (input, response_type) = delimited(
+                tuple((tag("->"), multispace0, tag("stream"), multispace1)),
+                parse_data_type,
+                multispace0,
+            )(input)?;
+            method.return_type = ReturnType::BidiStream(request_type, response_type);
+            input
+        }
+        (true, Some(_)) => return Err(Err::Error(InterfaceError::Syntax(input))),
+    };
+    let (input, _) = tag(";")(input)?;
+    Ok((input, (position, method_name, method)))
+}
+
+fn parse_rust_name(input: &[u8]) -> ParseResult<'_, Identifier> {
+    delimited(
+        tuple((
+            tag("@"),
+            multispace0,
+            tag("rust_name"),
+            multispace0,
+            tag("("),
+            multispace0,
+            tag("\""),
+        )),
+        parse_identifier,
+        pair(tag("\""), pair(multispace0, tag(")"))),
     )(input)
 }
 
-fn parse_return_type(input: &[u8]) -> IResult<&[u8], ReturnType> {
-    let parse_service_type = map(
+fn parse_return_type(input: &[u8]) -> ParseResult<'_, ReturnType> {
+    let parse_service_type = parse_service_ref_mut_type.map(ReturnType::ServiceRefMut);
+    let parse_owned_service_type = map(
+        tuple((tag("service"), multispace1, parse_identifier)),
+        |(_, _, x)| ReturnType::OwnedService(x),
+    );
+    let parse_service_tuple = map_opt(
+        delimited(
+            pair(tag("("), multispace0),
+            separated_list1(
+                tuple((multispace0, tag(","), multispace0)),
+                parse_service_ref_mut_type,
+            ),
+            tuple((multispace0, opt(pair(tag(","), multispace0)), tag(")"))),
+        ),
+        // A tuple must have at least two elements.
+        |x| (x.len() >= 2).then_some(ReturnType::ServiceRefMutTuple(x)),
+    );
+    let parse_result_type = map(
+        tuple((
+            tag("Result"),
+            multispace0,
+            tag("<"),
+            multispace0,
+            parse_data_type,
+            multispace0,
+            tag(","),
+            multispace0,
+            parse_data_type,
+            multispace0,
+            opt(pair(tag(","), multispace0)),
+            tag(">"),
+        )),
+        |(_, _, _, _, ok_type, _, _, _, err_type, _, _, _)| ReturnType::Result(ok_type, err_type),
+    );
+    alt((
+        parse_service_type,
+        parse_owned_service_type,
+        parse_service_tuple,
+        parse_result_type,
+        parse_data_type.map(ReturnType::Data),
+    ))(input)
+}
+
+fn parse_service_ref_mut_type(input: &[u8]) -> ParseResult<'_, Identifier> {
+    map(
         tuple((
             tag("&"),
             multispace0,
@@ -231,19 +676,26 @@ fn parse_return_type(input: &[u8]) -> IResult<&[u8], ReturnType> {
             multispace1,
             parse_identifier,
         )),
-        |(_, _, _, _, _, _, x)| ReturnType::ServiceRefMut(x),
-    );
-    alt((parse_service_type, parse_data_type.map(ReturnType::Data)))(input)
+        |(_, _, _, _, _, _, x)| x,
+    )(input)
 }
 
-fn parse_data_type(input: &[u8]) -> IResult<&[u8], DataType> {
-    alt((
-        value(DataType::I32, tag("i32")),
-        map(parse_identifier, DataType::Struct),
-    ))(input)
+fn parse_data_type(input: &[u8]) -> ParseResult<'_, DataType> {
+    map(parse_identifier, |type_name| match &*type_name.0 {
+        "i32" => DataType::I32,
+        "i128" => DataType::I128,
+        "u128" => DataType::U128,
+        "duration" => DataType::Duration,
+        "timestamp" => DataType::Timestamp,
+        "uuid" => DataType::Uuid,
+        "char" => DataType::Char,
+        "bytes" => DataType::Bytes,
+        "string" => DataType::String,
+        _ => DataType::Struct(type_name),
+    })(input)
 }
 
-fn parse_identifier(input: &[u8]) -> IResult<&[u8], Identifier> {
+fn parse_identifier(input: &[u8]) -> ParseResult<'_, Identifier> {
     // This parses an identifier except it returns a String and it lets through keywords.
     let parse_almost_identifier = pair(
         satisfy(|ch| is_alphabetic(ch as u8)),
@@ -278,15 +730,24 @@ mod tests {
     #[test]
     fn test_parse_interface() {
         let input = r#"
+            # [ derive ( PartialOrd , std :: cmp :: Ord , ) ]
+            # [ non_exhaustive ]
+            @ mirror ( domain :: Foo )
             struct Foo {
-                x : i32 ,
+                w : & mut service MyService ,
+                @ rust_name ( "ex" ) x : i32 ,
                 y : Foo ,
+                @ skip_if_default z : i32 = -5 ,
+                @ lossy_utf8 @ rust_name ( "name" ) n : string ,
             }
 
             service MyService {
-                foo ( & mut self ) -> i32 ;
-                bar ( & mut self , arg1 : i32 , arg2 : Foo ) -> Foo ;
-                baz ( & mut self ) -> & mut service MyService ;
+                @ deprecated ( "use bar" ) foo ( & mut self ) -> i32 ;
+                @ named_args bar ( & mut self , arg1 : i32 , arg2 : Foo ) -> Foo ;
+                @ rust_name ( "get_self" ) baz ( & mut self ) -> & mut service MyService ;
+                @ id ( 7 ) qux ( & mut self ) -> service MyService ;
+                split ( & mut self ) -> ( & mut service MyService , & mut service MyService , ) ;
+                oneway notify ( & mut self , x : i32 ) ;
             }
         "#;
         let ident = |s: &str| Identifier(s.to_string());
@@ -296,18 +757,83 @@ mod tests {
                 foo_ident(),
                 Struct {
                     fields: BTreeMap::from([
-                        (ident("x"), DataType::I32),
-                        (ident("y"), DataType::Struct(foo_ident())),
+                        (
+                            ident("w"),
+                            Field {
+                                field_type: DataType::ServiceRef(ident("MyService")),
+                                default_value: None,
+                                rust_name: None,
+                                skip_if_default: false,
+                                lossy_utf8: false,
+                            },
+                        ),
+                        (
+                            ident("x"),
+                            Field {
+                                field_type: DataType::I32,
+                                default_value: None,
+                                rust_name: Some(ident("ex")),
+                                skip_if_default: false,
+                                lossy_utf8: false,
+                            },
+                        ),
+                        (
+                            ident("y"),
+                            Field {
+                                field_type: DataType::Struct(foo_ident()),
+                                default_value: None,
+                                rust_name: None,
+                                skip_if_default: false,
+                                lossy_utf8: false,
+                            },
+                        ),
+                        (
+                            ident("z"),
+                            Field {
+                                field_type: DataType::I32,
+                                default_value: Some(Literal::Int(-5)),
+                                rust_name: None,
+                                skip_if_default: true,
+                                lossy_utf8: false,
+                            },
+                        ),
+                        (
+                            ident("n"),
+                            Field {
+                                field_type: DataType::String,
+                                default_value: None,
+                                rust_name: Some(ident("name")),
+                                skip_if_default: false,
+                                lossy_utf8: true,
+                            },
+                        ),
                     ]),
+                    extra_derives: vec![
+                        RustPath(vec![ident("PartialOrd")]),
+                        RustPath(vec![ident("std"), ident("cmp"), ident("Ord")]),
+                    ],
+                    mirrors: vec![RustPath(vec![ident("domain"), ident("Foo")])],
+                    copy: false,
+                    non_exhaustive: true,
+                    rust_name: None,
                 },
             )]),
             services: BTreeMap::from([(
                 ident("MyService"),
                 Service {
+                    base: None,
                     methods: BTreeMap::from([
                         (
                             ident("foo"),
                             Method {
+                                id: None,
+                                deprecated: Some(Deprecation {
+                                    note: Some("use bar".to_string()),
+                                }),
+                                rust_name: None,
+                                named_args: false,
+                                incremental: false,
+                                oneway: false,
                                 non_self_params: vec![],
                                 return_type: ReturnType::Data(DataType::I32),
                             },
@@ -315,6 +841,12 @@ mod tests {
                         (
                             ident("bar"),
                             Method {
+                                id: None,
+                                deprecated: None,
+                                rust_name: None,
+                                named_args: true,
+                                incremental: false,
+                                oneway: false,
                                 non_self_params: vec![
                                     (ident("arg1"), DataType::I32),
                                     (ident("arg2"), DataType::Struct(foo_ident())),
@@ -325,10 +857,58 @@ mod tests {
                         (
                             ident("baz"),
                             Method {
+                                id: None,
+                                deprecated: None,
+                                rust_name: Some(ident("get_self")),
+                                named_args: false,
+                                incremental: false,
+                                oneway: false,
                                 non_self_params: vec![],
                                 return_type: ReturnType::ServiceRefMut(ident("MyService")),
                             },
                         ),
+                        (
+                            ident("qux"),
+                            Method {
+                                id: Some(7),
+                                deprecated: None,
+                                rust_name: None,
+                                named_args: false,
+                                incremental: false,
+                                oneway: false,
+                                non_self_params: vec![],
+                                return_type: ReturnType::OwnedService(ident("MyService")),
+                            },
+                        ),
+                        (
+                            ident("split"),
+                            Method {
+                                id: None,
+                                deprecated: None,
+                                rust_name: None,
+                                named_args: false,
+                                incremental: false,
+                                oneway: false,
+                                non_self_params: vec![],
+                                return_type: ReturnType::ServiceRefMutTuple(vec![
+                                    ident("MyService"),
+                                    ident("MyService"),
+                                ]),
+                            },
+                        ),
+                        (
+                            ident("notify"),
+                            Method {
+                                id: None,
+                                deprecated: None,
+                                rust_name: None,
+                                named_args: false,
+                                incremental: false,
+                                oneway: true,
+                                non_self_params: vec![(ident("x"), DataType::I32)],
+                                return_type: ReturnType::Nothing,
+                            },
+                        ),
                     ]),
                 },
             )]),
@@ -338,4 +918,230 @@ mod tests {
             parse_interface(input.as_bytes())
         );
     }
+
+    #[test]
+    fn test_parse_keyword_identifiers() {
+        let input = r#"
+            struct Token {
+                type: i32,
+            }
+
+            service MatchService {
+                match(&mut self, async: Token) -> i32;
+            }
+        "#;
+        let (_, interface) = parse_interface(input.as_bytes()).unwrap();
+        let token = &interface.structs[&Identifier("Token".to_string())];
+        assert!(token.fields.contains_key(&Identifier("type".to_string())));
+        let service = &interface.services[&Identifier("MatchService".to_string())];
+        assert!(service
+            .methods
+            .contains_key(&Identifier("match".to_string())));
+
+        // These can't be used as Rust identifiers, even raw ones.
+        for keyword in ["crate", "super", "Self", "self"] {
+            let input = format!("struct Foo {{ {}: i32, }}", keyword);
+            assert!(parse_interface(input.as_bytes()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_base_service() {
+        let input = "service Base {}\nservice Derived : Base { foo(&mut self) -> i32; }\nservice Other:Base{}";
+        let (_, interface) = parse_interface(input.as_bytes()).unwrap();
+        let base = |name: &str| {
+            interface.services[&Identifier(name.to_string())]
+                .base
+                .clone()
+        };
+        assert_eq!(None, base("Base"));
+        assert_eq!(Some(Identifier("Base".to_string())), base("Derived"));
+        assert_eq!(Some(Identifier("Base".to_string())), base("Other"));
+
+        assert!(parse_interface(b"service Derived : { }").is_err());
+        assert!(parse_interface(b"service Derived : Base Other { }").is_err());
+    }
+
+    #[test]
+    fn test_parse_result_return_type() {
+        let input = r#"
+            service Foo {
+                foo(&mut self) -> Result<string, Error>;
+                bar(&mut self) -> Result < bytes , i32 , >;
+                baz(&mut self) -> ResultSet;
+            }
+        "#;
+        let (_, interface) = parse_interface(input.as_bytes()).unwrap();
+        let methods = &interface.services[&Identifier("Foo".to_string())].methods;
+        let return_type = |name: &str| methods[&Identifier(name.to_string())].return_type.clone();
+        assert_eq!(
+            ReturnType::Result(
+                DataType::String,
+                DataType::Struct(Identifier("Error".to_string()))
+            ),
+            return_type("foo")
+        );
+        assert_eq!(
+            ReturnType::Result(DataType::Bytes, DataType::I32),
+            return_type("bar")
+        );
+        assert_eq!(
+            ReturnType::Data(DataType::Struct(Identifier("ResultSet".to_string()))),
+            return_type("baz")
+        );
+
+        assert!(parse_interface(b"service Foo { foo(&mut self) -> Result<i32>; }").is_err());
+    }
+
+    #[test]
+    fn test_parse_oneway() {
+        let input = r#"
+            service Foo {
+                oneway foo(&mut self, a: i32);
+                oneway(&mut self) -> i32;
+            }
+        "#;
+        let (_, interface) = parse_interface(input.as_bytes()).unwrap();
+        let methods = &interface.services[&Identifier("Foo".to_string())].methods;
+        let foo = &methods[&Identifier("foo".to_string())];
+        assert!(foo.oneway);
+        assert_eq!(ReturnType::Nothing, foo.return_type);
+        // A method can still be named `oneway`.
+        let oneway = &methods[&Identifier("oneway".to_string())];
+        assert!(!oneway.oneway);
+        assert_eq!(ReturnType::Data(DataType::I32), oneway.return_type);
+
+        // Oneway methods have no return type, and the others need one.
+        assert!(parse_interface(b"service Foo { oneway foo(&mut self) -> i32; }").is_err());
+        assert!(parse_interface(b"service Foo { foo(&mut self); }").is_err());
+    }
+
+    #[test]
+    fn test_parse_incremental() {
+        let input = r#"
+            service Foo {
+                @incremental upload(&mut self, data: bytes) -> i32;
+                @named_args @incremental both(&mut self, data: bytes) -> i32;
+                checksum(&mut self, data: bytes) -> i32;
+            }
+        "#;
+        let (_, interface) = parse_interface(input.as_bytes()).unwrap();
+        let methods = &interface.services[&Identifier("Foo".to_string())].methods;
+        assert!(methods[&Identifier("upload".to_string())].incremental);
+        assert!(methods[&Identifier("both".to_string())].named_args);
+        assert!(methods[&Identifier("both".to_string())].incremental);
+        assert!(!methods[&Identifier("checksum".to_string())].incremental);
+    }
+
+    #[test]
+    fn test_parse_bidi_stream() {
+        let input = r#"
+            service Foo {
+                chat(&mut self, stream Message) -> stream string;
+                foo(&mut self, stream: i32) -> i32;
+            }
+        "#;
+        let (_, interface) = parse_interface(input.as_bytes()).unwrap();
+        let methods = &interface.services[&Identifier("Foo".to_string())].methods;
+        let chat = &methods[&Identifier("chat".to_string())];
+        assert!(chat.non_self_params.is_empty());
+        assert_eq!(
+            ReturnType::BidiStream(
+                DataType::Struct(Identifier("Message".to_string())),
+                DataType::String
+            ),
+            chat.return_type
+        );
+        // A parameter can still be named `stream`.
+        let foo = &methods[&Identifier("foo".to_string())];
+        assert_eq!(
+            vec![(Identifier("stream".to_string()), DataType::I32)],
+            foo.non_self_params
+        );
+
+        // Streaming methods have no other parameters, and return a stream.
+        assert!(parse_interface(
+            b"service Foo { foo(&mut self, stream i32, x: i32) -> stream i32; }"
+        )
+        .is_err());
+        assert!(parse_interface(b"service Foo { foo(&mut self, stream i32) -> i32; }").is_err());
+        assert!(parse_interface(b"service Foo { oneway foo(&mut self, stream i32); }").is_err());
+    }
+
+    #[test]
+    fn test_parse_trailing_comma() {
+        let input = r#"
+            service Foo {
+                foo(&mut self, a: i32,) -> i32;
+                bar(&mut self,) -> i32;
+                baz( &mut self , a : i32 , b : i32 , ) -> i32;
+            }
+        "#;
+        let (_, interface) = parse_interface(input.as_bytes()).unwrap();
+        let methods = &interface.services[&Identifier("Foo".to_string())].methods;
+        assert_eq!(
+            1,
+            methods[&Identifier("foo".to_string())]
+                .non_self_params
+                .len()
+        );
+        assert_eq!(
+            0,
+            methods[&Identifier("bar".to_string())]
+                .non_self_params
+                .len()
+        );
+        assert_eq!(
+            2,
+            methods[&Identifier("baz".to_string())]
+                .non_self_params
+                .len()
+        );
+
+        for params in [
+            "&mut self,,",
+            "&mut self, a: i32,,",
+            ", &mut self",
+            "&mut self a: i32",
+        ] {
+            let input = format!("service Foo {{ foo({}) -> i32; }}", params);
+            assert!(parse_interface(input.as_bytes()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_describe_parse_error() {
+        let input = "struct Foo {\n    x: i32,\n    y i32,\n}\n";
+        let error = parse_interface(input.as_bytes()).unwrap_err();
+        assert_eq!(
+            "line 3, column 5:\n    y i32,\n    ^",
+            describe_parse_error(input.as_bytes(), &error)
+        );
+
+        let input = "service Foo {\n    foo(&mut self) -> i32;\n}\n\nstruct Bar {}\noops\n";
+        let error = parse_interface(input.as_bytes()).unwrap_err();
+        assert!(describe_parse_error(input.as_bytes(), &error).starts_with("line 6, column 1:"));
+    }
+
+    #[test]
+    fn test_duplicate_definitions() {
+        let input = "struct Foo {\n    x: i32,\n    x: Foo,\n}\n";
+        let error = parse_interface(input.as_bytes()).unwrap_err();
+        assert_eq!(
+            "line 3, column 5: duplicate field x in struct Foo\n    x: Foo,\n    ^\n\
+             line 2, column 5: x was first defined here\n    x: i32,\n    ^",
+            describe_parse_error(input.as_bytes(), &error)
+        );
+
+        let input = "service Foo {\n    foo(&mut self) -> i32;\n    foo(&mut self) -> i32;\n}\n";
+        let error = parse_interface(input.as_bytes()).unwrap_err();
+        assert!(describe_parse_error(input.as_bytes(), &error)
+            .starts_with("line 3, column 5: duplicate method foo in service Foo\n"));
+
+        // Structs and services can't have the same name either.
+        let input = "struct Foo {}\n\nservice Foo {}\n";
+        let error = parse_interface(input.as_bytes()).unwrap_err();
+        assert!(describe_parse_error(input.as_bytes(), &error)
+            .starts_with("line 3, column 9: duplicate definition of Foo\n"));
+    }
 }
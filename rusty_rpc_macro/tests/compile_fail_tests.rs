@@ -0,0 +1,9 @@
+//! Checks the errors for invalid interface files. trybuild compiles each test
+//! from its own crate in `target/tests/trybuild`, so the interface file paths
+//! in the tests are relative to that directory.
+
+#[test]
+fn compile_fail_tests() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
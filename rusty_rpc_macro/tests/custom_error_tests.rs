@@ -0,0 +1,77 @@
+use std::fmt;
+
+use rusty_rpc_lib::{start_client, start_server, RustyRpcError};
+use rusty_rpc_macro::{interface_file, service_server_impl};
+use tokio::net::{TcpListener, TcpSocket};
+
+interface_file!("tests/custom_error.interface", error = AccountError);
+
+#[derive(Debug)]
+enum AccountError {
+    InsufficientFunds,
+    Rpc(RustyRpcError),
+}
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::InsufficientFunds => write!(f, "Insufficient funds"),
+            AccountError::Rpc(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl From<RustyRpcError> for AccountError {
+    fn from(e: RustyRpcError) -> Self {
+        AccountError::Rpc(e)
+    }
+}
+
+struct AccountServer {
+    balance: i32,
+}
+impl Default for AccountServer {
+    fn default() -> Self {
+        AccountServer { balance: 100 }
+    }
+}
+#[service_server_impl]
+impl AccountService for AccountServer {
+    async fn balance(&mut self) -> Result<i32, AccountError> {
+        Ok(self.balance)
+    }
+    async fn withdraw(&mut self, amount: i32) -> Result<i32, AccountError> {
+        if amount > self.balance {
+            return Err(AccountError::InsufficientFunds);
+        }
+        self.balance -= amount;
+        Ok(self.balance)
+    }
+}
+
+#[tokio::test]
+async fn custom_error_type_test() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<AccountServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn AccountService, _>(stream).await;
+    // The proxy returns the custom error type too.
+    let balance: Result<i32, AccountError> = service.balance().await;
+    assert_eq!(100, balance.unwrap());
+    assert_eq!(70, service.withdraw(30).await.unwrap());
+    match service.withdraw(1000).await {
+        Err(AccountError::Rpc(RustyRpcError::ServerError(msg))) => {
+            assert_eq!("Insufficient funds", msg)
+        }
+        x => panic!("Expected a server error, got {:?}", x),
+    }
+    assert_eq!(70, service.balance().await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
@@ -0,0 +1,539 @@
+//! Sends random and corrupted messages to a server, which has to handle all of
+//! them without panicking. The random generator is seeded, so a failure can be
+//! reproduced with the seed that it reports.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use futures::{SinkExt, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rusty_rpc_lib::internal_for_macro::{
+    BytesRef, ClientMessage, MethodArgs, MethodId, ReturnValue, ServerMessage, ServiceId,
+    WireDuration, WireI128, WireTimestamp,
+};
+use rusty_rpc_lib::{
+    start_server_with_config, ByteCounts, ConnectionContext, RpcResult, ServerConfig,
+    ServerInterceptor, ServiceRefMut, StreamReceiver, StreamSender, Uuid, WireFormat,
+};
+use rusty_rpc_macro::{interface_file, service_server_impl};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+interface_file!("tests/fuzz_interface.interface");
+
+/// The number of connections that each test makes. Each one sends a few
+/// messages.
+const CONNECTIONS: u64 = 1000;
+
+#[derive(Default)]
+struct FuzzServer {
+    total: i32,
+    pair: [i32; 2],
+}
+#[service_server_impl]
+impl FuzzService for FuzzServer {
+    async fn echo(&mut self, sample: &Sample) -> RpcResult<Sample> {
+        Ok(sample.clone())
+    }
+    async fn mix(
+        &mut self,
+        a: i32,
+        b: i128,
+        c: &[u8],
+        d: &str,
+        e: Duration,
+        f: SystemTime,
+    ) -> RpcResult<Result<String, i32>> {
+        if a < 0 {
+            return Ok(Err(a));
+        }
+        Ok(Ok(format!("{} {:?} {} {:?} {:?}", b, c, d, e, f)))
+    }
+    async fn named(&mut self, a: i32, b: &str, c: &[u8]) -> RpcResult<i32> {
+        Ok(a.wrapping_add(b.len() as i32).wrapping_add(c.len() as i32))
+    }
+    async fn notify(&mut self, amount: i32) -> RpcResult<()> {
+        self.total = self.total.wrapping_add(amount);
+        Ok(())
+    }
+    async fn chat(
+        &mut self,
+        mut requests: StreamReceiver<Sample>,
+        mut responses: StreamSender<Sample>,
+    ) -> RpcResult<()> {
+        while let Some(sample) = requests.next().await {
+            responses.send(sample?).await?;
+        }
+        Ok(())
+    }
+    async fn borrow<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn LeafService + 'a>> {
+        Ok(ServiceRefMut::new(LeafServer(&mut self.total)))
+    }
+    async fn own(&mut self) -> RpcResult<ServiceRefMut<'static, dyn LeafService>> {
+        Ok(ServiceRefMut::new(OwnedLeafServer(self.total)))
+    }
+    async fn pair<'a>(
+        &'a mut self,
+    ) -> RpcResult<(
+        ServiceRefMut<'a, dyn LeafService + 'a>,
+        ServiceRefMut<'a, dyn LeafService + 'a>,
+    )> {
+        let [first, second] = &mut self.pair;
+        Ok((
+            ServiceRefMut::new(LeafServer(first)),
+            ServiceRefMut::new(LeafServer(second)),
+        ))
+    }
+    async fn handle<'a>(&'a mut self, value: i32) -> RpcResult<Handle<'a>> {
+        Ok(Handle {
+            value,
+            leaf: ServiceRefMut::new(LeafServer(&mut self.total)),
+        })
+    }
+}
+
+struct LeafServer<'a>(&'a mut i32);
+#[service_server_impl]
+impl<'a> LeafService for LeafServer<'a> {
+    async fn get(&mut self, value: i32) -> RpcResult<i32> {
+        *self.0 = self.0.wrapping_add(value);
+        Ok(*self.0)
+    }
+}
+
+struct OwnedLeafServer(i32);
+#[service_server_impl]
+impl LeafService for OwnedLeafServer {
+    async fn get(&mut self, value: i32) -> RpcResult<i32> {
+        self.0 = self.0.wrapping_add(value);
+        Ok(self.0)
+    }
+}
+
+/// Counts the connections that ended. A connection whose task panicked isn't
+/// counted.
+struct ClosedCounter(AtomicUsize);
+impl ServerInterceptor for ClosedCounter {
+    fn on_connection_closed(&self, _: &ConnectionContext, _: &ByteCounts) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Any value that the wire formats can hold, for building random arguments.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Value {
+    Nil(()),
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Str(String),
+    Bin(#[serde(with = "rusty_rpc_lib::internal_for_macro::serde_bytes")] Vec<u8>),
+    Array(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+fn random_value(rng: &mut StdRng, depth: u32) -> Value {
+    let kinds = if depth == 0 { 7 } else { 9 };
+    match rng.gen_range(0..kinds) {
+        0 => Value::Nil(()),
+        1 => Value::Bool(rng.gen()),
+        2 => {
+            let random = rng.gen();
+            Value::Int(pick(rng, &[-1, i32::MIN.into(), i64::MIN, random]))
+        }
+        3 => {
+            let random = rng.gen();
+            Value::UInt(pick(
+                rng,
+                &[0, 1, 999_999_999, 1_000_000_000, u64::MAX, random],
+            ))
+        }
+        4 => Value::Float(rng.gen()),
+        5 => Value::Str(random_name(rng)),
+        6 => {
+            let random = rng.gen_range(0..64);
+            let len = pick(rng, &[0, 15, 16, 17, random]);
+            Value::Bin(random_bytes(rng, len))
+        }
+        7 => {
+            let len = rng.gen_range(0..10);
+            Value::Array((0..len).map(|_| random_value(rng, depth - 1)).collect())
+        }
+        _ => {
+            let len = rng.gen_range(0..4);
+            Value::Map(
+                (0..len)
+                    .map(|_| (random_name(rng), random_value(rng, depth - 1)))
+                    .collect(),
+            )
+        }
+    }
+}
+
+/// A parameter or field name of the interface, or some other string.
+fn random_name(rng: &mut StdRng) -> String {
+    let names = ["a", "b", "c", "number", "label", "at", "", "\u{e9}"];
+    match rng.gen_range(0..4) {
+        0 => (0..rng.gen_range(0..8))
+            .map(|_| rng.gen::<char>())
+            .collect(),
+        _ => pick(rng, &names).to_string(),
+    }
+}
+
+fn random_bytes(rng: &mut StdRng, len: usize) -> Vec<u8> {
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+fn pick<T: Copy>(rng: &mut StdRng, items: &[T]) -> T {
+    items[rng.gen_range(0..items.len())]
+}
+
+fn valid_sample() -> Sample {
+    Sample {
+        number: 1,
+        big: -2,
+        huge: 3,
+        label: "label".to_string(),
+        name: "name".to_string(),
+        data: vec![4, 5],
+        wait: Duration::from_millis(6),
+        at: SystemTime::UNIX_EPOCH,
+        id: Uuid::nil(),
+        level: 1,
+    }
+}
+
+/// Arguments that the method accepts. A single argument is encoded by itself,
+/// and several arguments as a tuple.
+fn valid_arguments(wire_format: WireFormat, service_id: ServiceId, method_id: MethodId) -> Vec<u8> {
+    // Services other than the initial one are LeafServices.
+    if service_id.0 != 0 {
+        return wire_format.encode(&1);
+    }
+    match method_id.0 {
+        // FuzzService::echo
+        2 => wire_format.encode(&valid_sample()),
+        // FuzzService::handle and FuzzService::notify
+        3 | 6 => wire_format.encode(&1),
+        // FuzzService::mix
+        4 => wire_format.encode(&(
+            1,
+            WireI128(-2),
+            BytesRef(&[3, 4]),
+            "d",
+            WireDuration(Duration::from_millis(5)),
+            WireTimestamp(SystemTime::UNIX_EPOCH),
+        )),
+        // FuzzService::named
+        5 => wire_format.encode(&BTreeMap::from([
+            ("a", Value::Int(1)),
+            ("b", Value::Str("b".to_string())),
+            ("c", Value::Bin(vec![2, 3])),
+        ])),
+        _ => wire_format.encode(&()),
+    }
+}
+
+/// Usually `valid` with some random changes, or sometimes a random value.
+fn random_arguments(rng: &mut StdRng, wire_format: WireFormat, valid: Vec<u8>) -> Vec<u8> {
+    let mut arguments = match rng.gen_range(0..4) {
+        0 => wire_format.encode(&random_value(rng, 3)),
+        _ => valid,
+    };
+    if rng.gen_bool(0.5) {
+        corrupt(rng, &mut arguments);
+    }
+    arguments
+}
+
+/// The starts of a binary, an array and a map that claim to be far longer than
+/// any message, in MessagePack and then in CBOR.
+const HUGE_HEADERS: [&[u8]; 6] = [
+    &[0xc6, 0xff, 0xff, 0xff, 0xff],
+    &[0xdd, 0xff, 0xff, 0xff, 0xff],
+    &[0xdf, 0xff, 0xff, 0xff, 0xff],
+    &[0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+    &[0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+    &[0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+];
+
+/// Makes a few random changes to `bytes`.
+fn corrupt(rng: &mut StdRng, bytes: &mut Vec<u8>) {
+    for _ in 0..rng.gen_range(1..4) {
+        let position = rng.gen_range(0..=bytes.len());
+        match rng.gen_range(0..6) {
+            0 => bytes.truncate(position),
+            1 => bytes.insert(position, rng.gen()),
+            // Bytes that start or end a value in both formats.
+            2 => bytes.insert(position, pick(rng, &[0xc0, 0x9f, 0xff])),
+            3 => {
+                let header = pick(rng, &HUGE_HEADERS);
+                bytes.splice(position..position, header.iter().copied());
+            }
+            4 if position < bytes.len() => {
+                bytes.remove(position);
+            }
+            _ if position < bytes.len() => bytes[position] = rng.gen(),
+            _ => bytes.push(rng.gen()),
+        }
+    }
+}
+
+fn random_message(rng: &mut StdRng, wire_format: WireFormat, depth: u32) -> ClientMessage {
+    // An invalid service ID ends the connection, so the initial service is
+    // more likely.
+    let service_id = ServiceId(pick(rng, &[0, 0, 0, 1, 2, 3, 4]));
+    let method_id = MethodId(rng.gen_range(0..10));
+    let metadata = match rng.gen_bool(0.1) {
+        true => HashMap::from([(random_name(rng), random_name(rng))]),
+        false => HashMap::new(),
+    };
+    let kinds = if depth == 0 { 10 } else { 11 };
+    match rng.gen_range(0..kinds) {
+        0..=4 => {
+            let valid = valid_arguments(wire_format, service_id, method_id);
+            let arguments = MethodArgs(random_arguments(rng, wire_format, valid));
+            ClientMessage::CallMethod(service_id, method_id, arguments, metadata)
+        }
+        5 => {
+            let valid = valid_arguments(wire_format, service_id, method_id);
+            let arguments = MethodArgs(random_arguments(rng, wire_format, valid));
+            ClientMessage::Notify(service_id, method_id, arguments, metadata)
+        }
+        6 => ClientMessage::DropService(service_id),
+        7 => {
+            let valid = wire_format.encode(&valid_sample());
+            ClientMessage::StreamItem(random_arguments(rng, wire_format, valid))
+        }
+        8 => match rng.gen_range(0..3) {
+            0 => ClientMessage::Ping,
+            1 => ClientMessage::Cancel,
+            _ => ClientMessage::StreamEnd,
+        },
+        9 => ClientMessage::Authenticate(random_bytes(rng, 4)),
+        _ => {
+            let len = rng.gen_range(0..4);
+            ClientMessage::Batch(
+                (0..len)
+                    .map(|_| random_message(rng, wire_format, depth - 1))
+                    .collect(),
+            )
+        }
+    }
+}
+
+/// A frame that is usually a valid message, but sometimes isn't.
+fn random_frame(rng: &mut StdRng, wire_format: WireFormat) -> Vec<u8> {
+    match rng.gen_range(0..40) {
+        0..=1 => {
+            let len = rng.gen_range(0..32);
+            random_bytes(rng, len)
+        }
+        2..=4 => {
+            let mut frame = wire_format.encode(&random_message(rng, wire_format, 2));
+            corrupt(rng, &mut frame);
+            frame
+        }
+        // Deeper than anything that the decoders allow.
+        5 => {
+            let mut message = ClientMessage::Ping;
+            for _ in 0..rng.gen_range(50..500) {
+                message = ClientMessage::Batch(vec![message]);
+            }
+            wire_format.encode(&message)
+        }
+        _ => wire_format.encode(&random_message(rng, wire_format, 2)),
+    }
+}
+
+async fn send_frame(writer: &mut (impl AsyncWriteExt + Unpin), frame: &[u8]) -> io::Result<()> {
+    writer
+        .write_all(&(frame.len() as u32).to_be_bytes())
+        .await?;
+    writer.write_all(frame).await
+}
+
+/// Returns `None` if the server closed the connection instead.
+async fn receive_frame(reader: &mut (impl AsyncReadExt + Unpin)) -> Option<Vec<u8>> {
+    let mut length_bytes = [0; 4];
+    reader.read_exact(&mut length_bytes).await.ok()?;
+    let mut bytes = vec![0; u32::from_be_bytes(length_bytes) as usize];
+    reader.read_exact(&mut bytes).await.ok()?;
+    Some(bytes)
+}
+
+/// A server with a [FuzzServer] for each connection.
+struct FuzzTarget {
+    addr: SocketAddr,
+    closed_counter: Arc<ClosedCounter>,
+    server_handle: JoinHandle<()>,
+}
+impl FuzzTarget {
+    async fn start(wire_format: WireFormat) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let closed_counter = Arc::new(ClosedCounter(AtomicUsize::new(0)));
+        let config = ServerConfig {
+            wire_format,
+            interceptor: Some(closed_counter.clone()),
+            ..Default::default()
+        };
+        let server_handle = tokio::spawn(async move {
+            start_server_with_config(listener, config, (), |_: &()| FuzzServer::default())
+                .await
+                .unwrap()
+        });
+        FuzzTarget {
+            addr,
+            closed_counter,
+            server_handle,
+        }
+    }
+
+    fn closed_connections(&self) -> usize {
+        self.closed_counter.0.load(Ordering::SeqCst)
+    }
+
+    async fn stop(self) {
+        self.server_handle.abort();
+        let server_error = self
+            .server_handle
+            .await
+            .expect_err("Server somehow terminated on its own without crashing.");
+        assert!(server_error.is_cancelled(), "Server crashed.");
+    }
+}
+
+/// Sends a few random frames on a new connection, then closes it, and waits
+/// for the server to close its side.
+async fn fuzz_connection(addr: SocketAddr, seed: u64, wire_format: WireFormat) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let (mut reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let read_all = tokio::spawn(async move {
+        // The server might reset the connection, e.g. after a malformed frame
+        // that it didn't read completely.
+        let _ = reader.read_to_end(&mut Vec::new()).await;
+    });
+    for _ in 0..rng.gen_range(1..12) {
+        let frame = random_frame(&mut rng, wire_format);
+        // The server closes the connection after a malformed frame.
+        if send_frame(&mut writer, &frame).await.is_err() {
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+    timeout(Duration::from_secs(10), read_all)
+        .await
+        .unwrap_or_else(|_| panic!("Server didn't close connection {}.", seed))
+        .unwrap();
+}
+
+async fn fuzz_server(wire_format: WireFormat) {
+    let target = FuzzTarget::start(wire_format).await;
+    for seed in 0..CONNECTIONS {
+        fuzz_connection(target.addr, seed, wire_format).await;
+        assert_eq!(
+            seed + 1,
+            target.closed_connections() as u64,
+            "Handling connection {} panicked.",
+            seed
+        );
+    }
+    target.stop().await;
+}
+
+#[tokio::test]
+async fn fuzz_message_pack_test() {
+    fuzz_server(WireFormat::MessagePack).await;
+}
+
+#[tokio::test]
+async fn fuzz_cbor_test() {
+    fuzz_server(WireFormat::Cbor).await;
+}
+
+/// Messages that the fuzzer is unlikely to come up with, but that used to crash
+/// the server.
+#[tokio::test]
+async fn malformed_message_regression_test() {
+    for wire_format in [WireFormat::MessagePack, WireFormat::Cbor] {
+        let target = FuzzTarget::start(wire_format).await;
+        let call_echo = |sample_bytes: Vec<u8>| {
+            let arguments = MethodArgs(sample_bytes);
+            let message =
+                ClientMessage::CallMethod(ServiceId(0), MethodId(2), arguments, HashMap::new());
+            wire_format.encode(&message)
+        };
+
+        // A bytes field that claims to be an array with more items than fit in
+        // memory.
+        let mut sample = valid_sample();
+        sample.data = vec![0xab; 7];
+        let mut arguments = wire_format.encode(&sample);
+        let (binary_header_len, huge_array): (usize, &[u8]) = match wire_format {
+            WireFormat::MessagePack => (2, HUGE_HEADERS[1]),
+            WireFormat::Cbor => (1, HUGE_HEADERS[4]),
+        };
+        let data_start = arguments.windows(7).position(|x| x == [0xab; 7]).unwrap();
+        arguments.splice(
+            data_start - binary_header_len..data_start + 7,
+            huge_array.iter().copied(),
+        );
+        let mut stream = TcpStream::connect(target.addr).await.unwrap();
+        send_frame(&mut stream, &call_echo(arguments))
+            .await
+            .unwrap();
+        assert_eq!(None, receive_frame(&mut stream).await);
+
+        // The earliest timestamp that can be sent, which couldn't be sent back.
+        let mut sample = valid_sample();
+        sample.at = SystemTime::UNIX_EPOCH - Duration::from_secs(1 << 63);
+        let mut stream = TcpStream::connect(target.addr).await.unwrap();
+        send_frame(&mut stream, &call_echo(wire_format.encode(&sample)))
+            .await
+            .unwrap();
+        let response = receive_frame(&mut stream).await.unwrap();
+        match wire_format.decode(&response).unwrap() {
+            ServerMessage::MethodReturned(ReturnValue::Data(bytes)) => {
+                let echoed: Sample = wire_format.decode(&bytes).unwrap();
+                assert_eq!(
+                    SystemTime::UNIX_EPOCH - Duration::from_secs(1 << 63),
+                    echoed.at
+                );
+            }
+            x => panic!("Unexpected response: {:?}", x),
+        }
+        drop(stream);
+
+        // Batches nested so deeply that decoding them overflowed the stack.
+        let mut message = ClientMessage::Ping;
+        for _ in 0..400 {
+            message = ClientMessage::Batch(vec![message]);
+        }
+        let mut stream = TcpStream::connect(target.addr).await.unwrap();
+        send_frame(&mut stream, &wire_format.encode(&message))
+            .await
+            .unwrap();
+        assert_eq!(None, receive_frame(&mut stream).await);
+
+        timeout(Duration::from_secs(10), async {
+            while target.closed_connections() < 3 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Handling a connection panicked.");
+        target.stop().await;
+    }
+}
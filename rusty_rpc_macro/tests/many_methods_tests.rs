@@ -0,0 +1,58 @@
+use rusty_rpc_lib::{start_client, start_server, RpcResult};
+use rusty_rpc_macro::{interface_file, service_server_impl};
+use tokio::net::{TcpListener, TcpSocket};
+
+interface_file!("tests/many_methods.interface");
+
+#[derive(Default)]
+struct ManyMethodsServer;
+
+/// Implements `method_NN` to return `value * 100 + NN`, and calls each of
+/// them through a proxy, so that a call that runs the wrong method is noticed.
+macro_rules! many_methods {
+    ($($name:ident = $n:literal),* $(,)?) => {
+        #[service_server_impl]
+        impl ManyMethodsService for ManyMethodsServer {
+            $(
+                async fn $name(&mut self, value: i32) -> RpcResult<i32> {
+                    Ok(value * 100 + $n)
+                }
+            )*
+        }
+
+        #[tokio::test]
+        async fn many_methods_test() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server_handle =
+                tokio::spawn(async { start_server::<ManyMethodsServer>(listener).await.unwrap() });
+
+            let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+            let mut service = start_client::<dyn ManyMethodsService, _>(stream).await;
+            $(
+                assert_eq!(100 + $n, service.$name(1).await.unwrap());
+                assert_eq!(-300 + $n, service.$name(-3).await.unwrap());
+            )*
+            service.close().await.unwrap();
+
+            server_handle.abort();
+            let server_error = server_handle
+                .await
+                .expect_err("Server somehow terminated on its own without crashing.");
+            assert!(server_error.is_cancelled(), "Server crashed.");
+        }
+    };
+}
+
+many_methods! {
+    method_00 = 0, method_01 = 1, method_02 = 2, method_03 = 3, method_04 = 4,
+    method_05 = 5, method_06 = 6, method_07 = 7, method_08 = 8, method_09 = 9,
+    method_10 = 10, method_11 = 11, method_12 = 12, method_13 = 13, method_14 = 14,
+    method_15 = 15, method_16 = 16, method_17 = 17, method_18 = 18, method_19 = 19,
+    method_20 = 20, method_21 = 21, method_22 = 22, method_23 = 23, method_24 = 24,
+    method_25 = 25, method_26 = 26, method_27 = 27, method_28 = 28, method_29 = 29,
+    method_30 = 30, method_31 = 31, method_32 = 32, method_33 = 33, method_34 = 34,
+    method_35 = 35, method_36 = 36, method_37 = 37, method_38 = 38, method_39 = 39,
+    method_40 = 40, method_41 = 41, method_42 = 42, method_43 = 43, method_44 = 44,
+    method_45 = 45, method_46 = 46, method_47 = 47, method_48 = 48, method_49 = 49,
+}
@@ -0,0 +1,72 @@
+//! Uses the same protocol file from two modules, like a client and a server
+//! that are built from different files.
+
+use rusty_rpc_lib::{start_client, start_server, RustyRpcServiceClient};
+use tokio::net::{TcpListener, TcpSocket};
+
+use client_side::{Tally, TallyService};
+
+mod server_side {
+    use rusty_rpc_lib::RpcResult;
+    use rusty_rpc_macro::{interface_file, service_server_impl};
+
+    interface_file!("tests/shared_interface.interface");
+
+    #[derive(Default)]
+    pub struct TallyServer(i32);
+    #[service_server_impl]
+    impl TallyService for TallyServer {
+        async fn add(&mut self, amount: i32) -> RpcResult<Tally> {
+            self.0 += amount;
+            Ok(Tally { count: self.0 })
+        }
+        async fn reset(&mut self) -> RpcResult<Tally> {
+            self.0 = 0;
+            Ok(Tally { count: 0 })
+        }
+        async fn get(&mut self) -> RpcResult<Tally> {
+            Ok(Tally { count: self.0 })
+        }
+    }
+}
+
+mod client_side {
+    use rusty_rpc_macro::interface_file;
+
+    interface_file!("tests/shared_interface.interface");
+    // Another protocol file in the same module.
+    interface_file!("../examples/src/hello_world/hello_world.protocol");
+}
+
+#[test]
+fn same_method_ids_test() {
+    assert_eq!(
+        <dyn server_side::TallyService>::METHOD_IDS,
+        <dyn client_side::TallyService>::METHOD_IDS
+    );
+}
+
+#[tokio::test]
+async fn shared_interface_test() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async {
+        start_server::<server_side::TallyServer>(listener)
+            .await
+            .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn TallyService, _>(stream).await;
+    assert_eq!(Tally { count: 3 }, service.add(3).await.unwrap());
+    assert_eq!(Tally { count: 7 }, service.add(4).await.unwrap());
+    assert_eq!(Tally { count: 0 }, service.reset().await.unwrap());
+    assert_eq!(Tally { count: 0 }, service.get().await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
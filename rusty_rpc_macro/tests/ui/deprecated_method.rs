@@ -0,0 +1,26 @@
+#![deny(deprecated)]
+
+use rusty_rpc_lib::RpcResult;
+use rusty_rpc_macro::{interface_file, service_server_impl};
+
+interface_file!("../../../../rusty_rpc_macro/tests/ui/deprecated_method.interface");
+
+// Implementing a deprecated method is fine.
+struct CounterServer;
+#[service_server_impl]
+impl CounterService for CounterServer {
+    async fn get_count(&mut self) -> RpcResult<i32> {
+        Ok(0)
+    }
+    async fn get(&mut self) -> RpcResult<i32> {
+        Ok(0)
+    }
+}
+
+// Calling it is not.
+async fn call(service: &mut dyn CounterService) {
+    service.get().await.unwrap();
+    service.get_count().await.unwrap();
+}
+
+fn main() {}
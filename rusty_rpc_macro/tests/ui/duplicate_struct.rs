@@ -0,0 +1,5 @@
+use rusty_rpc_macro::interface_file;
+
+interface_file!("../../../../rusty_rpc_macro/tests/ui/duplicate_struct.interface");
+
+fn main() {}
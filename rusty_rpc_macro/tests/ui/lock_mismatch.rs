@@ -0,0 +1,8 @@
+use rusty_rpc_macro::interface_file;
+
+interface_file!(
+    "../../../../rusty_rpc_macro/tests/ui/lock_mismatch.interface",
+    lock
+);
+
+fn main() {}
@@ -0,0 +1,13 @@
+use rusty_rpc_macro::interface_file;
+
+// The hand-written struct has `z` instead of `y`.
+mod domain {
+    pub struct Point {
+        pub x: i32,
+        pub z: i32,
+    }
+}
+
+interface_file!("../../../../rusty_rpc_macro/tests/ui/mirror_mismatch.interface");
+
+fn main() {}
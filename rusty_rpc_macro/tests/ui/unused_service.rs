@@ -0,0 +1,17 @@
+#![deny(unused_must_use)]
+
+use rusty_rpc_lib::RpcResult;
+use rusty_rpc_macro::interface_file;
+
+interface_file!("../../../../rusty_rpc_macro/tests/ui/unused_service.interface");
+
+// Dropping a returned service without closing it would panic.
+async fn call(service: &mut dyn ParentService) -> RpcResult<()> {
+    service.get_child().await?;
+    service.get_children().await?;
+    // Closing it is fine.
+    service.get_child().await?.close().await?;
+    Ok(())
+}
+
+fn main() {}
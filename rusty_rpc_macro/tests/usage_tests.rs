@@ -1,13 +1,81 @@
-use std::io;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use rusty_rpc_lib::{start_client, start_server, RustyRpcServiceClient, ServiceRefMut};
-use rusty_rpc_macro::{interface_file, service_server_impl};
-use tokio::net::{TcpListener, TcpSocket};
+use futures::channel::mpsc;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use rusty_rpc_lib::internal_for_macro::{
+    rmp_serde, ByteBuf, Bytes, BytesRef, ClientMessage, MethodArgs, MethodId, ReturnValue,
+    ServerMessage, ServiceId,
+};
+use rusty_rpc_lib::{
+    batch, call_metadata, connect_client, metrics, start_client, start_client_with_byte_counts,
+    start_client_with_config, start_client_with_credential, start_client_with_stream_sink,
+    start_server, start_server_with, start_server_with_async, start_server_with_config,
+    stream_channel, with_call_metadata, ByteCounts, ClientConfig, ClientInterceptor,
+    ConnectionContext, IncomingBytes, MethodCall, MetricsSink, Next, RpcResult, RustyRpcError,
+    RustyRpcServiceClient, Server, ServerBuilder, ServerConfig, ServerInterceptor, ServiceRefMut,
+    StreamReceiver, StreamSender, Uuid, WireFormat,
+};
+use rusty_rpc_macro::{interface_file, interface_schema_file, service_server_impl};
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::time::{sleep, timeout};
+use tracing::Instrument;
+use tracing_test::traced_test;
 
-interface_file!("rusty_rpc_macro/tests/simple_interface_file.interface");
+interface_file!("tests/simple_interface_file.interface");
+
+/// Hand-written structs that the generated `GeoPoint` and `Route` mirror.
+mod domain {
+    #[derive(Debug, PartialEq)]
+    pub struct GeoPoint {
+        pub lat: i32,
+        pub lon: i32,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct Route {
+        pub name: String,
+        pub start: GeoPoint,
+        pub end: GeoPoint,
+    }
+}
+
+/// Sends a message without going through a service proxy.
+async fn send_raw_message(stream: &mut TcpStream, msg: ClientMessage) {
+    send_raw_frame(stream, &Bytes::from(msg)).await;
+}
+
+/// Receives a message without going through a service proxy.
+async fn receive_raw_message(stream: &mut TcpStream) -> ServerMessage {
+    ServerMessage::try_from(Bytes::from(receive_raw_frame(stream).await)).unwrap()
+}
+
+async fn send_raw_frame(stream: &mut TcpStream, bytes: &[u8]) {
+    stream
+        .write_all(&(bytes.len() as u32).to_be_bytes())
+        .await
+        .unwrap();
+    stream.write_all(bytes).await.unwrap();
+}
+
+async fn receive_raw_frame(stream: &mut TcpStream) -> Vec<u8> {
+    let mut length_bytes = [0; 4];
+    stream.read_exact(&mut length_bytes).await.unwrap();
+    let mut bytes = vec![0; u32::from_be_bytes(length_bytes) as usize];
+    stream.read_exact(&mut bytes).await.unwrap();
+    bytes
+}
 
 #[tokio::test]
-#[allow(unreachable_code)]
+#[allow(unreachable_code, clippy::diverging_sub_expression)]
 async fn test_types() {
     // Just test that stuff compiles.
     if false {
@@ -17,23 +85,23 @@ async fn test_types() {
         struct DummyService;
         #[service_server_impl]
         impl MyService for DummyService {
-            async fn foo(&mut self) -> io::Result<i32> {
+            async fn foo(&mut self) -> RpcResult<i32> {
                 Ok(123)
             }
-            async fn bar(&mut self, _a: i32) -> io::Result<i32> {
+            async fn bar(&mut self, _a: i32) -> RpcResult<i32> {
                 unimplemented!()
             }
-            async fn bar2(&mut self, _a: i32, _b: Foo) -> io::Result<Foo> {
+            async fn bar2(&mut self, _a: i32, _b: &Foo) -> RpcResult<Foo> {
                 unimplemented!()
             }
-            async fn baz<'a>(&'a mut self) -> io::Result<ServiceRefMut<dyn MyService + 'a>> {
+            async fn baz<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn MyService + 'a>> {
                 Ok(ServiceRefMut::new(DummyService))
             }
         }
 
         let mut service = DummyService;
         let _: i32 = service.bar(3).await.unwrap();
-        let _: Foo = service.bar2(3, foo.clone()).await.unwrap();
+        let _: Foo = service.bar2(3, &foo).await.unwrap();
 
         // Test that types have the right traits.
         fn need_rpc_struct(_: impl rusty_rpc_lib::internal_for_macro::RustyRpcStruct) {}
@@ -62,25 +130,26 @@ async fn test_types() {
 }
 
 #[tokio::test]
+#[allow(clippy::diverging_sub_expression)]
 async fn simple_usage() {
     #[derive(Default)]
     struct DummyService;
     #[service_server_impl]
     impl MyService for DummyService {
-        async fn foo(&mut self) -> io::Result<i32> {
+        async fn foo(&mut self) -> RpcResult<i32> {
             Ok(123)
         }
-        async fn bar(&mut self, arg: i32) -> io::Result<i32> {
+        async fn bar(&mut self, arg: i32) -> RpcResult<i32> {
             Ok(arg)
         }
-        async fn bar2(&mut self, arg1: i32, arg2: Foo) -> io::Result<Foo> {
+        async fn bar2(&mut self, arg1: i32, arg2: &Foo) -> RpcResult<Foo> {
             let val = arg1 + arg2.x + arg2.y.z;
             Ok(Foo {
                 x: val,
                 y: Bar { z: val },
             })
         }
-        async fn baz<'a>(&'a mut self) -> io::Result<ServiceRefMut<dyn MyService + 'a>> {
+        async fn baz<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn MyService + 'a>> {
             Ok(ServiceRefMut::new(ConstService(9999)))
         }
     }
@@ -88,16 +157,16 @@ async fn simple_usage() {
     struct ConstService(i32);
     #[service_server_impl]
     impl MyService for ConstService {
-        async fn foo(&mut self) -> io::Result<i32> {
+        async fn foo(&mut self) -> RpcResult<i32> {
             Ok(self.0)
         }
-        async fn bar(&mut self, _arg: i32) -> io::Result<i32> {
+        async fn bar(&mut self, _arg: i32) -> RpcResult<i32> {
             unimplemented!()
         }
-        async fn bar2(&mut self, _arg1: i32, _arg2: Foo) -> io::Result<Foo> {
+        async fn bar2(&mut self, _arg1: i32, _arg2: &Foo) -> RpcResult<Foo> {
             unimplemented!()
         }
-        async fn baz(&mut self) -> io::Result<ServiceRefMut<dyn MyService>> {
+        async fn baz<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn MyService + 'a>> {
             unimplemented!()
         }
     }
@@ -121,21 +190,25 @@ async fn simple_usage() {
         let bar2_output = service
             .bar2(
                 900,
-                Foo {
+                &Foo {
                     x: 80,
                     y: Bar { z: 7 },
                 },
             )
             .await
             .unwrap();
-        assert_eq!(987, bar2_output.x);
-        assert_eq!(987, bar2_output.y.z);
+        assert_eq!(
+            Foo {
+                x: 987,
+                y: Bar { z: 987 },
+            },
+            bar2_output
+        );
 
         let mut baz_output_service = service.baz().await.unwrap();
         let baz_foo_output = baz_output_service.foo().await.unwrap();
         assert_eq!(9999, baz_foo_output);
         baz_output_service.close().await.unwrap();
-        drop(baz_output_service);
 
         service.close().await.unwrap();
     });
@@ -149,23 +222,4991 @@ async fn simple_usage() {
 }
 
 #[tokio::test]
+#[allow(dead_code)]
 async fn mut_borrow_test() {
     struct ParentServer(i32);
     struct ChildServer<'a>(&'a mut ParentServer);
     #[service_server_impl]
     impl ParentService for ParentServer {
-        async fn get_child(&mut self) -> io::Result<ServiceRefMut<dyn ChildService>> {
+        async fn get_child<'a>(
+            &'a mut self,
+        ) -> RpcResult<ServiceRefMut<'a, dyn ChildService + 'a>> {
             Ok(ServiceRefMut::new(ChildServer(self)))
         }
     }
     #[service_server_impl]
     impl<'a> ChildService for ChildServer<'a> {
-        async fn get_value(&mut self) -> io::Result<i32> {
+        async fn get_value(&mut self) -> RpcResult<i32> {
             Ok(self.0 .0)
         }
-        async fn set_value(&mut self, new_value: i32) -> io::Result<i32> {
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
             self.0 .0 = new_value;
             Ok(new_value)
         }
     }
 }
+
+#[tokio::test]
+#[allow(clippy::diverging_sub_expression)]
+async fn chained_service_test() -> RpcResult<()> {
+    struct NestedServer(i32);
+    #[service_server_impl]
+    impl MyService for NestedServer {
+        async fn foo(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn bar(&mut self, arg: i32) -> RpcResult<i32> {
+            self.0 = arg;
+            Ok(arg)
+        }
+        async fn bar2(&mut self, _arg1: i32, _arg2: &Foo) -> RpcResult<Foo> {
+            unimplemented!()
+        }
+        async fn baz<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn MyService + 'a>> {
+            Ok(ServiceRefMut::new(NestedServer(self.0 + 1)))
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async move {
+        start_server_with(listener, (), |_| NestedServer(0))
+            .await
+            .unwrap()
+    });
+
+    // Without auto_close_on_drop, dropping the returned services would panic
+    // if they weren't closed.
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn MyService, _>(stream).await;
+    assert_eq!(1, service.baz_chained().await?.foo().await?);
+    assert_eq!(
+        2,
+        service
+            .baz_chained()
+            .await?
+            .baz_chained()
+            .await?
+            .foo()
+            .await?
+    );
+    // The returned services were closed, so the service they borrowed from
+    // can be used again.
+    assert_eq!(5, service.bar(5).await?);
+    assert_eq!(6, service.baz_chained().await?.foo().await?);
+    // Closing explicitly shows the errors.
+    let mut child = service.baz_chained().await?;
+    assert_eq!(6, child.foo().await?);
+    child.close().await?;
+    assert_eq!(5, service.foo().await?);
+    service.close().await?;
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+    Ok(())
+}
+
+#[tokio::test]
+async fn map_proxy_test() {
+    #[derive(Default)]
+    struct ParentServer(i32);
+    struct ChildServer<'a>(&'a mut i32);
+    #[service_server_impl]
+    impl ParentService for ParentServer {
+        async fn get_child<'a>(
+            &'a mut self,
+        ) -> RpcResult<ServiceRefMut<'a, dyn ChildService + 'a>> {
+            Ok(ServiceRefMut::new(ChildServer(&mut self.0)))
+        }
+    }
+    #[service_server_impl]
+    impl<'a> ChildService for ChildServer<'a> {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(*self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            *self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    type ChildProxy = <dyn ChildService as RustyRpcServiceClient>::ServiceProxy;
+    /// Records the calls before forwarding them to the server.
+    struct LoggingChild {
+        proxy: ChildProxy,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+    #[async_trait::async_trait]
+    impl ChildService for LoggingChild {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            self.calls.lock().unwrap().push("get_value".to_string());
+            self.proxy.get_value().await
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("set_value({})", new_value));
+            self.proxy.set_value(new_value).await
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<ParentServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut parent = start_client::<dyn ParentService, _>(stream).await;
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let mut child = parent
+        .get_child()
+        .await
+        .unwrap()
+        .map_proxy(|proxy| LoggingChild {
+            proxy,
+            calls: calls.clone(),
+        });
+    assert_eq!(3, child.set_value(3).await.unwrap());
+    assert_eq!(3, child.get_value().await.unwrap());
+    assert_eq!(vec!["set_value(3)", "get_value"], *calls.lock().unwrap());
+    ServiceRefMut::<dyn ChildService>::from_proxy(child.proxy)
+        .close()
+        .await
+        .unwrap();
+
+    // The wrapped child was closed, so the parent can be used again.
+    let mut child = parent.get_child().await.unwrap();
+    assert_eq!(3, child.get_value().await.unwrap());
+    child.close().await.unwrap();
+    parent.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn multiple_lifetimes_test() {
+    #[derive(Default)]
+    struct ParentServer {
+        x: i32,
+        y: i32,
+    }
+    /// Keeps the two values equal.
+    struct ChildServer<'a, 'b>(&'a mut i32, &'b mut i32);
+    #[service_server_impl]
+    impl ParentService for ParentServer {
+        async fn get_child<'a>(
+            &'a mut self,
+        ) -> RpcResult<ServiceRefMut<'a, dyn ChildService + 'a>> {
+            Ok(ServiceRefMut::new(ChildServer(&mut self.x, &mut self.y)))
+        }
+    }
+    #[service_server_impl]
+    impl<'a, 'b> ChildService for ChildServer<'a, 'b> {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(*self.0 + *self.1)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            *self.0 = new_value;
+            *self.1 = new_value;
+            Ok(new_value)
+        }
+    }
+    /// Like ChildServer, but scales the sum.
+    struct ScaledServer<'a, 'b, T>(&'a mut i32, &'b mut i32, T);
+    #[service_server_impl]
+    impl<'a, 'b, T: Copy + Into<i32> + Send + Sync> ChildService for ScaledServer<'a, 'b, T> {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok((*self.0 + *self.1) * self.2.into())
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            *self.0 = new_value;
+            *self.1 = new_value;
+            Ok(new_value)
+        }
+    }
+    #[derive(Default)]
+    struct ScaledParentServer(i32, i32);
+    #[service_server_impl]
+    impl ParentService for ScaledParentServer {
+        async fn get_child<'a>(
+            &'a mut self,
+        ) -> RpcResult<ServiceRefMut<'a, dyn ChildService + 'a>> {
+            Ok(ServiceRefMut::new(ScaledServer(
+                &mut self.0,
+                &mut self.1,
+                10u8,
+            )))
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<ParentServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn ParentService, _>(stream).await;
+    let mut child = service.get_child().await.unwrap();
+    child.set_value(3).await.unwrap();
+    assert_eq!(6, child.get_value().await.unwrap());
+    child.close().await.unwrap();
+    service.close().await.unwrap();
+
+    // The lifetimes can be followed by type parameters.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let scaled_server_handle =
+        tokio::spawn(async { start_server::<ScaledParentServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn ParentService, _>(stream).await;
+    let mut child = service.get_child().await.unwrap();
+    child.set_value(3).await.unwrap();
+    assert_eq!(60, child.get_value().await.unwrap());
+    child.close().await.unwrap();
+    service.close().await.unwrap();
+
+    for server_handle in [server_handle, scaled_server_handle] {
+        server_handle.abort();
+        let server_error = server_handle
+            .await
+            .expect_err("Server somehow terminated on its own without crashing.");
+        assert!(server_error.is_cancelled(), "Server crashed.");
+    }
+}
+
+#[tokio::test]
+async fn method_error_test() {
+    #[derive(Default)]
+    struct ParentServer {
+        value: i32,
+        calls: i32,
+    }
+    struct ChildServer<'a>(&'a mut i32);
+    #[service_server_impl]
+    impl ParentService for ParentServer {
+        async fn get_child<'a>(
+            &'a mut self,
+        ) -> RpcResult<ServiceRefMut<'a, dyn ChildService + 'a>> {
+            self.calls += 1;
+            if self.calls == 1 {
+                return Err(RustyRpcError::ServerError("Not ready yet.".to_string()));
+            }
+            Ok(ServiceRefMut::new(ChildServer(&mut self.value)))
+        }
+    }
+    #[service_server_impl]
+    impl<'a> ChildService for ChildServer<'a> {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(*self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            if new_value < 0 {
+                return Err(RustyRpcError::ServerError("Negative value.".to_string()));
+            }
+            *self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<ParentServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn ParentService, _>(stream).await;
+    // The failed call must unlock the parent, or else the next call would fail
+    // because the parent is still in use.
+    assert!(matches!(
+        service.get_child().await,
+        Err(RustyRpcError::ServerError(_))
+    ));
+    let mut child = service.get_child().await.unwrap();
+    assert_eq!(5, child.set_value(5).await.unwrap());
+    assert!(matches!(
+        child.set_value(-1).await,
+        Err(RustyRpcError::ServerError(_))
+    ));
+    assert_eq!(5, child.get_value().await.unwrap());
+    child.close().await.unwrap();
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn call_parent_while_child_alive_test() {
+    #[derive(Default)]
+    struct ParentServer(i32);
+    struct ChildServer<'a>(&'a mut i32);
+    #[service_server_impl]
+    impl ParentService for ParentServer {
+        async fn get_child<'a>(
+            &'a mut self,
+        ) -> RpcResult<ServiceRefMut<'a, dyn ChildService + 'a>> {
+            Ok(ServiceRefMut::new(ChildServer(&mut self.0)))
+        }
+    }
+    #[service_server_impl]
+    impl<'a> ChildService for ChildServer<'a> {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(*self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            *self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<ParentServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn ParentService, _>(stream).await;
+    // The borrow checker prevents this on a single proxy, but not across
+    // clones.
+    let mut parent = service.clone();
+    let mut child = service.get_child().await.unwrap();
+    assert_eq!(3, child.set_value(3).await.unwrap());
+    // The parent is borrowed by the child, so it can't be called. The
+    // connection stays usable.
+    match parent.get_child().await {
+        Err(RustyRpcError::ServerError(msg)) => assert!(msg.contains("still in use")),
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Called the parent while the child was alive."),
+    }
+    assert_eq!(3, child.get_value().await.unwrap());
+    child.close().await.unwrap();
+    // Once the child is closed, the parent can be called again.
+    let mut child = parent.get_child().await.unwrap();
+    assert_eq!(3, child.get_value().await.unwrap());
+    child.close().await.unwrap();
+    parent.close().await.unwrap();
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn service_in_struct_test() {
+    #[derive(Default)]
+    struct SearchServer(i32);
+    struct ChildServer<'a>(&'a mut i32);
+    #[service_server_impl]
+    impl SearchService for SearchServer {
+        async fn search<'a>(&'a mut self, query: i32) -> RpcResult<SearchResult<'a>> {
+            Ok(SearchResult {
+                score: query * 2,
+                detail: ServiceRefMut::new(ChildServer(&mut self.0)),
+            })
+        }
+    }
+    #[service_server_impl]
+    impl<'a> ChildService for ChildServer<'a> {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(*self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            *self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<SearchServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn SearchService, _>(stream).await;
+    let SearchResult { score, mut detail } = service.search(21).await.unwrap();
+    assert_eq!(42, score);
+    assert_eq!(7, detail.set_value(7).await.unwrap());
+    detail.close().await.unwrap();
+    let SearchResult { score, mut detail } = service.search(1).await.unwrap();
+    assert_eq!(2, score);
+    assert_eq!(7, detail.get_value().await.unwrap());
+    detail.close().await.unwrap();
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn owned_child_service_test() {
+    #[derive(Default)]
+    struct CounterFactoryServer(Arc<Mutex<i32>>);
+    struct CounterServer(Arc<Mutex<i32>>);
+    #[service_server_impl]
+    impl CounterFactoryService for CounterFactoryServer {
+        async fn get_counter(&mut self) -> RpcResult<ServiceRefMut<'static, dyn CounterService>> {
+            Ok(ServiceRefMut::new(CounterServer(self.0.clone())))
+        }
+    }
+    #[service_server_impl]
+    impl CounterService for CounterServer {
+        async fn increment(&mut self) -> RpcResult<i32> {
+            let mut counter = self.0.lock().unwrap();
+            *counter += 1;
+            Ok(*counter)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async move {
+        start_server::<CounterFactoryServer>(listener)
+            .await
+            .unwrap()
+    });
+
+    let client_handle = tokio::spawn(async move {
+        let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+        let mut factory = start_client::<dyn CounterFactoryService, _>(stream).await;
+
+        // Both counters can be alive at once, since neither borrows the factory.
+        let mut counter_1 = factory.get_counter().await.unwrap();
+        let mut counter_2 = factory.get_counter().await.unwrap();
+        assert_eq!(1, counter_1.increment().await.unwrap());
+        assert_eq!(2, counter_2.increment().await.unwrap());
+
+        // The counters still work after the factory is gone.
+        factory.close().await.unwrap();
+        assert_eq!(3, counter_1.increment().await.unwrap());
+        assert_eq!(4, counter_2.increment().await.unwrap());
+
+        counter_1.close().await.unwrap();
+        counter_2.close().await.unwrap();
+    });
+
+    client_handle.await.expect("Client crashed.");
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn multiple_service_return_test() {
+    #[derive(Default)]
+    struct PairServer(i32, i32);
+    struct HalfServer<'a>(&'a mut i32);
+    #[service_server_impl]
+    impl PairService for PairServer {
+        async fn split<'a>(
+            &'a mut self,
+        ) -> RpcResult<(
+            ServiceRefMut<'a, dyn ChildService + 'a>,
+            ServiceRefMut<'a, dyn ChildService + 'a>,
+        )> {
+            Ok((
+                ServiceRefMut::new(HalfServer(&mut self.0)),
+                ServiceRefMut::new(HalfServer(&mut self.1)),
+            ))
+        }
+    }
+    #[service_server_impl]
+    impl<'a> ChildService for HalfServer<'a> {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(*self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            *self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async move { start_server::<PairServer>(listener).await.unwrap() });
+
+    let client_handle = tokio::spawn(async move {
+        let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+        let mut service = start_client::<dyn PairService, _>(stream).await;
+
+        let (mut first, mut second) = service.split().await.unwrap();
+        first.set_value(1).await.unwrap();
+        second.set_value(2).await.unwrap();
+        assert_eq!(1, first.get_value().await.unwrap());
+        assert_eq!(2, second.get_value().await.unwrap());
+        first.close().await.unwrap();
+        second.close().await.unwrap();
+
+        // The parent is unlocked once both children are closed.
+        let (mut first, mut second) = service.split().await.unwrap();
+        assert_eq!(1, first.get_value().await.unwrap());
+        assert_eq!(2, second.get_value().await.unwrap());
+        second.close().await.unwrap();
+        first.close().await.unwrap();
+
+        service.close().await.unwrap();
+    });
+
+    client_handle.await.expect("Client crashed.");
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn shared_state_test() {
+    struct KeyValueServer(Arc<Mutex<HashMap<i32, i32>>>);
+    #[service_server_impl]
+    impl KeyValueService for KeyValueServer {
+        async fn get(&mut self, key: i32) -> RpcResult<i32> {
+            Ok(*self.0.lock().unwrap().get(&key).unwrap_or(&0))
+        }
+        async fn set(&mut self, key: i32, value: i32) -> RpcResult<i32> {
+            self.0.lock().unwrap().insert(key, value);
+            Ok(value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let shared_map = Arc::new(Mutex::new(HashMap::new()));
+    let server_handle = tokio::spawn(async move {
+        start_server_with(listener, shared_map, |map: &Arc<Mutex<_>>| {
+            KeyValueServer(map.clone())
+        })
+        .await
+        .unwrap()
+    });
+
+    let client_handle = tokio::spawn(async move {
+        let stream_1 = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+        let mut service_1 = start_client::<dyn KeyValueService, _>(stream_1).await;
+        let stream_2 = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+        let mut service_2 = start_client::<dyn KeyValueService, _>(stream_2).await;
+
+        service_1.set(1, 10).await.unwrap();
+        assert_eq!(10, service_2.get(1).await.unwrap());
+
+        service_2.set(2, 20).await.unwrap();
+        assert_eq!(20, service_1.get(2).await.unwrap());
+
+        service_1.close().await.unwrap();
+        service_2.close().await.unwrap();
+    });
+
+    client_handle.await.expect("Client crashed.");
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn async_factory_test() {
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // The factory can't create a service until a value is sent on the channel.
+    let (sender, receiver) = tokio::sync::watch::channel(None);
+    let server_handle = tokio::spawn(async move {
+        start_server_with_async(listener, move || {
+            let mut receiver = receiver.clone();
+            async move {
+                while receiver.borrow().is_none() {
+                    receiver.changed().await.unwrap();
+                }
+                let initial_value = receiver.borrow().unwrap();
+                ValueServer(initial_value)
+            }
+        })
+        .await
+        .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn ChildService, _>(stream).await;
+    let get_value = tokio::spawn(async move {
+        let value = service.get_value().await.unwrap();
+        service.close().await.unwrap();
+        value
+    });
+    sleep(Duration::from_millis(50)).await;
+    assert!(!get_value.is_finished());
+    sender.send(Some(42)).unwrap();
+    assert_eq!(42, get_value.await.unwrap());
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn connect_client_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<ValueServer>(listener).await.unwrap() });
+    let mut service = connect_client::<dyn ChildService, _>(addr, ClientConfig::default())
+        .await
+        .unwrap();
+    assert_eq!(5, service.set_value(5).await.unwrap());
+    service.close().await.unwrap();
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+
+    // Nothing listens on this port anymore.
+    let closed_addr = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = ClientConfig {
+        connect_retries: 2,
+        connect_backoff: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let start_time = Instant::now();
+    let result = connect_client::<dyn ChildService, _>(closed_addr, config).await;
+    // Waits 50ms, then 100ms, between the three attempts.
+    assert!(start_time.elapsed() >= Duration::from_millis(150));
+    match result {
+        Err(RustyRpcError::Io(e)) => assert!(e.to_string().contains("after 3 attempts")),
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Connected to a closed port."),
+    }
+}
+
+#[tokio::test]
+async fn generated_client_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<ValueServer>(listener).await.unwrap() });
+
+    let mut service_1 = ChildServiceClient::connect(addr).await.unwrap();
+    assert_eq!(5, service_1.set_value(5).await.unwrap());
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service_2 = ChildServiceClient::from_stream(stream).await;
+    // Each connection has its own initial service.
+    assert_eq!(0, service_2.get_value().await.unwrap());
+    assert_eq!(5, service_1.get_value().await.unwrap());
+    service_1.close().await.unwrap();
+    service_2.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[test]
+fn blocking_client_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    // The server runs on its own runtime, in another thread.
+    let (addr_sender, addr_receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addr_sender.send(listener.local_addr().unwrap()).unwrap();
+            start_server::<ValueServer>(listener).await.unwrap()
+        })
+    });
+    let addr = addr_receiver.recv().unwrap();
+
+    let mut client = ChildServiceBlockingClient::connect(addr).unwrap();
+    assert_eq!(0, client.get_value().unwrap());
+    assert_eq!(5, client.set_value(5).unwrap());
+    assert_eq!(5, client.get_value().unwrap());
+    client.close().unwrap();
+}
+
+#[tokio::test]
+async fn tcp_nodelay_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<ValueServer>(listener).await.unwrap() });
+
+    // Both sides set TCP_NODELAY by default.
+    assert!(ServerConfig::default().tcp_nodelay);
+    assert!(ClientConfig::default().tcp_nodelay);
+    let mut service = connect_client::<dyn ChildService, _>(addr, ClientConfig::default())
+        .await
+        .unwrap();
+    // With Nagle's algorithm and delayed ACKs, each round trip can take tens
+    // of milliseconds.
+    let start_time = Instant::now();
+    for i in 0..500 {
+        assert_eq!(i, service.set_value(i).await.unwrap());
+    }
+    assert!(start_time.elapsed() < Duration::from_secs(5));
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn clone_proxy_test() {
+    #[derive(Default)]
+    struct KeyValueServer(HashMap<i32, i32>);
+    #[service_server_impl]
+    impl KeyValueService for KeyValueServer {
+        async fn get(&mut self, key: i32) -> RpcResult<i32> {
+            Ok(*self.0.get(&key).unwrap_or(&0))
+        }
+        async fn set(&mut self, key: i32, value: i32) -> RpcResult<i32> {
+            self.0.insert(key, value);
+            Ok(value)
+        }
+    }
+
+    struct DropCountingInterceptor(AtomicUsize);
+    #[async_trait::async_trait]
+    impl ClientInterceptor for DropCountingInterceptor {
+        async fn intercept(
+            &self,
+            msg: ClientMessage,
+            next: &mut Next<'_>,
+        ) -> RpcResult<ServerMessage> {
+            if let ClientMessage::DropService(_) = msg {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+            next.run(msg).await
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<KeyValueServer>(listener).await.unwrap() });
+
+    let interceptor = Arc::new(DropCountingInterceptor(AtomicUsize::new(0)));
+    let config = ClientConfig {
+        interceptors: vec![interceptor.clone()],
+        ..Default::default()
+    };
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client_with_config::<dyn KeyValueService, _>(stream, config).await;
+    let tasks: Vec<_> = (0..2)
+        .map(|i| {
+            let mut service = service.clone();
+            tokio::spawn(async move {
+                for key in 0..10 {
+                    service.set(key * 2 + i, i).await.unwrap();
+                }
+                service.close().await.unwrap();
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    // The service is still alive, since one clone isn't closed yet.
+    assert_eq!(0, interceptor.0.load(Ordering::SeqCst));
+    assert_eq!(0, service.get(10).await.unwrap());
+    assert_eq!(1, service.get(11).await.unwrap());
+    service.close().await.unwrap();
+    assert_eq!(1, interceptor.0.load(Ordering::SeqCst));
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn chunked_return_value_test() {
+    // Prepends a long payload to whatever it concatenates, so that a short
+    // request can get a long response.
+    struct BlobServer(Vec<u8>);
+    #[service_server_impl]
+    impl BlobService for BlobServer {
+        async fn checksum(&mut self, data: &[u8]) -> RpcResult<i32> {
+            Ok(data.len() as i32)
+        }
+        async fn concat(&mut self, first: &[u8], second: &[u8]) -> RpcResult<Vec<u8>> {
+            Ok([&self.0, first, second].concat())
+        }
+        async fn wrap(&mut self, data: &[u8], tag: i32) -> RpcResult<Blob> {
+            Ok(Blob {
+                data: data.to_vec(),
+                tag,
+            })
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        chunk_size: 1000,
+        ..Default::default()
+    };
+    let payload: Vec<u8> = (0..5000).map(|x| (x * 7) as u8).collect();
+    let server_payload = payload.clone();
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, config, server_payload, |payload| {
+            BlobServer(payload.clone())
+        })
+        .await
+        .unwrap()
+    });
+
+    // The return value is much longer than the client accepts in one frame,
+    // but each chunk fits.
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let config = ClientConfig {
+        max_frame_length: 1024,
+        ..Default::default()
+    };
+    let mut service = start_client_with_config::<dyn BlobService, _>(stream, config).await;
+    let returned = service.concat(&[1, 2], &[3]).await.unwrap();
+    assert_eq!([&payload[..], &[1, 2, 3]].concat(), returned);
+    // Short return values are still sent in one frame.
+    assert_eq!(3, service.checksum(&[1, 2, 3]).await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn named_args_test() {
+    struct GreeterServer;
+    #[service_server_impl]
+    impl GreeterService for GreeterServer {
+        async fn greet(&mut self, times: i32, name: &str, title: &str) -> RpcResult<String> {
+            let name = match title {
+                "" => name.to_string(),
+                _ => format!("{} {}", title, name),
+            };
+            let greeting = vec![format!("Hello, {}!", name); times.max(1) as usize];
+            Ok(greeting.join(" "))
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async move {
+        start_server_with(listener, (), |_| GreeterServer)
+            .await
+            .unwrap()
+    });
+
+    // OldGreeterService is GreeterService from before `times` and `title`
+    // were added. Clients that don't know about the new parameters still work, and the
+    // missing arguments get their default values.
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn OldGreeterService, _>(stream).await;
+    assert_eq!("Hello, Alice!", service.greet("Alice").await.unwrap());
+    service.close().await.unwrap();
+
+    // The arguments are matched by name, not by position.
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn GreeterService, _>(stream).await;
+    assert_eq!(
+        "Hello, Dr. Bob! Hello, Dr. Bob!",
+        service.greet(2, "Bob", "Dr.").await.unwrap()
+    );
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn oneway_test() {
+    #[derive(Default)]
+    struct TotalServer(i32);
+    #[service_server_impl]
+    impl NotificationService for TotalServer {
+        async fn notify(&mut self, amount: i32) -> RpcResult<()> {
+            if amount < 0 {
+                return Err(RustyRpcError::ServerError("Negative amount.".into()));
+            }
+            self.0 += amount;
+            Ok(())
+        }
+        async fn get_total(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<TotalServer>(listener).await.unwrap() });
+
+    // If the server responded to the notifications, get_total would get one
+    // of those responses instead of its own.
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn NotificationService, _>(stream).await;
+    for amount in 1..=100 {
+        service.notify(amount).await.unwrap();
+    }
+    // Failed notifications don't get a response either.
+    service.notify(-1).await.unwrap();
+    assert_eq!(5050, service.get_total().await.unwrap());
+    service.close().await.unwrap();
+
+    // Even notifications for methods that don't exist get no response, so the
+    // first response is the pong. NotificationService::notify has ID 1.
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    for method_id in [1, 999] {
+        let arguments = rmp_serde::to_vec(&5).unwrap();
+        send_raw_message(
+            &mut stream,
+            ClientMessage::Notify(
+                ServiceId(0),
+                MethodId(method_id),
+                MethodArgs(arguments),
+                HashMap::new(),
+            ),
+        )
+        .await;
+    }
+    send_raw_message(&mut stream, ClientMessage::Ping).await;
+    assert!(matches!(
+        receive_raw_message(&mut stream).await,
+        ServerMessage::Pong
+    ));
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn oneway_backpressure_test() {
+    // Handling a notification takes forever.
+    #[derive(Default)]
+    struct StuckServer;
+    #[service_server_impl]
+    impl NotificationService for StuckServer {
+        async fn notify(&mut self, _amount: i32) -> RpcResult<()> {
+            std::future::pending().await
+        }
+        async fn get_total(&mut self) -> RpcResult<i32> {
+            Ok(0)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<StuckServer>(listener).await.unwrap() });
+
+    // Many notifications. NotificationService::notify has ID 1.
+    let mut frames = Vec::new();
+    while frames.len() < 1024 * 1024 {
+        let message = Bytes::from(ClientMessage::Notify(
+            ServiceId(0),
+            MethodId(1),
+            MethodArgs(rmp_serde::to_vec(&1).unwrap()),
+            HashMap::new(),
+        ));
+        frames.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        frames.extend_from_slice(&message);
+    }
+
+    // The server doesn't read past the first notification while it is being
+    // handled, so the client can only send as much as the socket buffers
+    // hold.
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut megabytes_sent = 0;
+    let send_all = async {
+        for _ in 0..256 {
+            stream.write_all(&frames).await.unwrap();
+            megabytes_sent += 1;
+        }
+    };
+    assert!(timeout(Duration::from_secs(1), send_all).await.is_err());
+    assert!(megabytes_sent < 64, "Sent {} MiB", megabytes_sent);
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn bidi_stream_test() {
+    #[derive(Default)]
+    struct ChatServer {
+        message_count: i32,
+    }
+    #[service_server_impl]
+    impl ChatService for ChatServer {
+        async fn chat(
+            &mut self,
+            mut requests: StreamReceiver<ChatMessage>,
+            mut responses: StreamSender<ChatMessage>,
+        ) -> RpcResult<()> {
+            while let Some(message) = requests.next().await {
+                self.message_count += 1;
+                let reply = ChatMessage {
+                    author: "echo".to_string(),
+                    text: message?.text,
+                };
+                responses.send(reply).await?;
+            }
+            Ok(())
+        }
+        async fn message_count(&mut self) -> RpcResult<i32> {
+            Ok(self.message_count)
+        }
+    }
+    let message = |author: &str, text: &str| ChatMessage {
+        author: author.to_string(),
+        text: text.to_string(),
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async { start_server::<ChatServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn ChatService, _>(stream).await;
+
+    // Each reply arrives while the call is still going on.
+    let (mut sender, mut receiver) = service.chat_stream().await;
+    for text in ["hello", "world"] {
+        sender.send(message("me", text)).await.unwrap();
+        let reply = receiver.next().await.unwrap().unwrap();
+        assert_eq!(message("echo", text), reply);
+    }
+    sender.close().await.unwrap();
+    assert!(receiver.next().await.is_none());
+
+    // The trait method sends what it receives from a channel, and sends the
+    // replies into another channel.
+    let (mut request_sender, requests) = stream_channel();
+    let (responses, response_receiver) = stream_channel();
+    let talk = async {
+        for text in ["a", "b", "c"] {
+            request_sender.send(message("me", text)).await.unwrap();
+        }
+        request_sender.close().await.unwrap();
+    };
+    let (result, (), replies) = futures::join!(
+        service.chat(requests, responses),
+        talk,
+        response_receiver.map(Result::unwrap).collect::<Vec<_>>()
+    );
+    result.unwrap();
+    assert_eq!(
+        vec![
+            message("echo", "a"),
+            message("echo", "b"),
+            message("echo", "c")
+        ],
+        replies
+    );
+
+    // The connection is usable again once the calls are over.
+    assert_eq!(5, service.message_count().await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn incremental_test() {
+    fn checksum(bytes: &[u8]) -> u128 {
+        bytes.iter().map(|&x| u128::from(x)).sum()
+    }
+    // Handles each piece as it arrives.
+    struct PieceServer(Arc<Mutex<Vec<usize>>>);
+    #[service_server_impl]
+    impl UploadService for PieceServer {
+        async fn upload(&mut self, data: &[u8]) -> RpcResult<u128> {
+            Ok(checksum(data))
+        }
+        async fn upload_incremental(&mut self, mut data: IncomingBytes) -> RpcResult<u128> {
+            let mut sum = 0;
+            while let Some(piece) = data.next().await {
+                let piece = piece?;
+                self.0.lock().unwrap().push(piece.len());
+                sum += checksum(&piece);
+            }
+            Ok(sum)
+        }
+    }
+    // Waits for all of the bytes.
+    #[derive(Default)]
+    struct WholeServer;
+    #[service_server_impl]
+    impl UploadService for WholeServer {
+        async fn upload(&mut self, data: &[u8]) -> RpcResult<u128> {
+            Ok(checksum(data))
+        }
+    }
+
+    let data: Vec<u8> = (0..200_000).map(|x| x as u8).collect();
+    let piece_lengths = Arc::new(Mutex::new(Vec::new()));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let piece_addr = listener.local_addr().unwrap();
+    let shared_piece_lengths = piece_lengths.clone();
+    let piece_server_handle = tokio::spawn(async move {
+        start_server_with(listener, shared_piece_lengths, |x| PieceServer(x.clone()))
+            .await
+            .unwrap()
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let whole_addr = listener.local_addr().unwrap();
+    let whole_server_handle =
+        tokio::spawn(async { start_server::<WholeServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4()
+        .unwrap()
+        .connect(piece_addr)
+        .await
+        .unwrap();
+    let mut service = start_client::<dyn UploadService, _>(stream).await;
+    assert_eq!(checksum(&data), service.upload(&data).await.unwrap());
+    assert_eq!(
+        vec![65536, 65536, 65536, 3392],
+        *piece_lengths.lock().unwrap()
+    );
+    // No pieces at all.
+    assert_eq!(0, service.upload(&[]).await.unwrap());
+    assert_eq!(4, piece_lengths.lock().unwrap().len());
+    service.close().await.unwrap();
+
+    let stream = TcpSocket::new_v4()
+        .unwrap()
+        .connect(whole_addr)
+        .await
+        .unwrap();
+    let mut service = start_client::<dyn UploadService, _>(stream).await;
+    assert_eq!(checksum(&data), service.upload(&data).await.unwrap());
+    assert_eq!(checksum(&data), service.upload(&data).await.unwrap());
+    service.close().await.unwrap();
+
+    // The client must send exactly as many bytes as it announced.
+    // UploadService::upload has method ID 0.
+    for (announced, sent) in [(1u64, vec![1, 2]), (3, vec![1, 2])] {
+        let mut stream = TcpSocket::new_v4()
+            .unwrap()
+            .connect(whole_addr)
+            .await
+            .unwrap();
+        send_raw_message(
+            &mut stream,
+            ClientMessage::CallMethod(
+                ServiceId(0),
+                MethodId(0),
+                MethodArgs(rmp_serde::to_vec(&announced).unwrap()),
+                HashMap::new(),
+            ),
+        )
+        .await;
+        send_raw_message(&mut stream, ClientMessage::StreamItem(sent)).await;
+        send_raw_message(&mut stream, ClientMessage::StreamEnd).await;
+        match receive_raw_message(&mut stream).await {
+            ServerMessage::Error(msg) if announced == 1 => {
+                assert!(msg.contains("more bytes than it announced"), "{}", msg)
+            }
+            ServerMessage::Error(msg) => {
+                assert_eq!(RustyRpcError::ConnectionClosed.to_string(), msg)
+            }
+            x => panic!("Expected an error, got {:?}", x),
+        }
+    }
+
+    for server_handle in [piece_server_handle, whole_server_handle] {
+        server_handle.abort();
+        let server_error = server_handle
+            .await
+            .expect_err("Server somehow terminated on its own without crashing.");
+        assert!(server_error.is_cancelled(), "Server crashed.");
+    }
+}
+
+#[tokio::test]
+async fn weak_service_ref_test() {
+    #[derive(Default)]
+    struct KeyValueServer(HashMap<i32, i32>);
+    #[service_server_impl]
+    impl KeyValueService for KeyValueServer {
+        async fn get(&mut self, key: i32) -> RpcResult<i32> {
+            Ok(*self.0.get(&key).unwrap_or(&0))
+        }
+        async fn set(&mut self, key: i32, value: i32) -> RpcResult<i32> {
+            self.0.insert(key, value);
+            Ok(value)
+        }
+    }
+
+    struct DropCountingInterceptor(AtomicUsize);
+    #[async_trait::async_trait]
+    impl ClientInterceptor for DropCountingInterceptor {
+        async fn intercept(
+            &self,
+            msg: ClientMessage,
+            next: &mut Next<'_>,
+        ) -> RpcResult<ServerMessage> {
+            if let ClientMessage::DropService(_) = msg {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+            next.run(msg).await
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<KeyValueServer>(listener).await.unwrap() });
+
+    let interceptor = Arc::new(DropCountingInterceptor(AtomicUsize::new(0)));
+    let config = ClientConfig {
+        interceptors: vec![interceptor.clone()],
+        ..Default::default()
+    };
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client_with_config::<dyn KeyValueService, _>(stream, config).await;
+    let weak = service.downgrade();
+
+    // An upgraded reference is usable, and closing it doesn't drop the service
+    // while the owner is still open.
+    let mut upgraded = weak.upgrade().unwrap();
+    assert_eq!(5, upgraded.set(1, 5).await.unwrap());
+    upgraded.close().await.unwrap();
+    assert_eq!(0, interceptor.0.load(Ordering::SeqCst));
+    assert_eq!(5, service.get(1).await.unwrap());
+
+    // Dropping a weak reference doesn't close anything.
+    drop(weak.clone());
+    assert_eq!(0, interceptor.0.load(Ordering::SeqCst));
+
+    // Once the owner is closed, the weak reference can't be upgraded anymore.
+    service.close().await.unwrap();
+    assert_eq!(1, interceptor.0.load(Ordering::SeqCst));
+    assert!(weak.upgrade().is_none());
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn lossy_utf8_test() {
+    #[derive(Default)]
+    struct Latin1Server;
+    #[service_server_impl]
+    impl Latin1Service for Latin1Server {
+        async fn echo(&mut self, greeting: &Greeting) -> RpcResult<String> {
+            Ok(greeting.text.clone())
+        }
+    }
+
+    // Turns the `?` of "caf?" into a Latin-1 `é`, which isn't valid UTF-8, as
+    // a peer that doesn't use UTF-8 would send it.
+    struct Latin1Interceptor;
+    #[async_trait::async_trait]
+    impl ClientInterceptor for Latin1Interceptor {
+        async fn intercept(
+            &self,
+            mut msg: ClientMessage,
+            next: &mut Next<'_>,
+        ) -> RpcResult<ServerMessage> {
+            if let ClientMessage::CallMethod(_, _, MethodArgs(args), _) = &mut msg {
+                if let Some(i) = args.windows(4).position(|x| x == b"caf?") {
+                    args[i + 3] = 0xe9;
+                }
+            }
+            next.run(msg).await
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<Latin1Server>(listener).await.unwrap() });
+
+    let config = ClientConfig {
+        interceptors: vec![Arc::new(Latin1Interceptor)],
+        ..Default::default()
+    };
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client_with_config::<dyn Latin1Service, _>(stream, config).await;
+    let greeting = Greeting {
+        text: "caf?".to_string(),
+        sender: "bob".to_string(),
+    };
+    assert_eq!("caf\u{FFFD}", service.echo(&greeting).await.unwrap());
+    // Fields without @lossy_utf8 still have to be valid UTF-8. Like any other
+    // malformed message, this makes the server close the connection.
+    let greeting = Greeting {
+        text: "hi".to_string(),
+        sender: "caf?".to_string(),
+    };
+    assert!(matches!(
+        service.echo(&greeting).await,
+        Err(RustyRpcError::ConnectionClosed)
+    ));
+    assert!(service.close().await.is_err());
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+#[allow(clippy::diverging_sub_expression)]
+async fn server_builder_test() {
+    struct ChainServer(i32);
+    #[service_server_impl]
+    impl MyService for ChainServer {
+        async fn foo(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn bar(&mut self, _arg: i32) -> RpcResult<i32> {
+            unimplemented!()
+        }
+        async fn bar2(&mut self, _arg1: i32, _arg2: &Foo) -> RpcResult<Foo> {
+            unimplemented!()
+        }
+        async fn baz<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn MyService + 'a>> {
+            Ok(ServiceRefMut::new(ChainServer(self.0 + 1)))
+        }
+    }
+
+    struct RequestCounter(AtomicUsize);
+    impl ServerInterceptor for RequestCounter {
+        fn on_request(&self, _: ServiceId, _: MethodId) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let counter = Arc::new(RequestCounter(AtomicUsize::new(0)));
+    let builder = ServerBuilder::new()
+        .with_max_services_per_connection(2)
+        .with_max_frame_length(1024)
+        .with_idle_timeout(Duration::from_secs(60))
+        .with_interceptor(counter.clone())
+        .with_tcp_nodelay(false)
+        .with_wire_format(WireFormat::Cbor);
+    let config = builder.clone().build();
+    assert_eq!(2, config.max_services_per_connection);
+    assert_eq!(1024, config.max_frame_length);
+    assert_eq!(Some(Duration::from_secs(60)), config.idle_timeout);
+    assert!(!config.tcp_nodelay);
+    // Options that weren't set keep their defaults.
+    assert_eq!(ServerConfig::default().chunk_size, config.chunk_size);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async move {
+        builder
+            .serve_with(listener, 10, |start| ChainServer(*start))
+            .await
+            .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let client_config = ClientConfig {
+        wire_format: WireFormat::Cbor,
+        ..Default::default()
+    };
+    let mut service_0 = start_client_with_config::<dyn MyService, _>(stream, client_config).await;
+    assert_eq!(10, service_0.foo().await.unwrap());
+    let mut service_1 = service_0.baz().await.unwrap();
+    assert_eq!(11, service_1.foo().await.unwrap());
+    // All the options are in effect at the same time.
+    assert!(matches!(
+        service_1.baz().await,
+        Err(RustyRpcError::ServerError(_))
+    ));
+    assert_eq!(4, counter.0.load(Ordering::SeqCst));
+    service_1.close().await.unwrap();
+    service_0.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn time_types_test() {
+    #[derive(Default)]
+    struct ReminderServer;
+    #[service_server_impl]
+    impl ReminderService for ReminderServer {
+        async fn snooze(&mut self, reminder: &Reminder) -> RpcResult<SystemTime> {
+            Ok(reminder.due + reminder.snooze)
+        }
+        async fn time_until(&mut self, from: SystemTime, to: SystemTime) -> RpcResult<Duration> {
+            to.duration_since(from)
+                .map_err(|_| RustyRpcError::ServerError("Negative duration.".to_string()))
+        }
+    }
+
+    assert_eq!(UNIX_EPOCH, Reminder::default().due);
+    // Both are sent as seconds and nanoseconds, counting from the Unix epoch.
+    let before_epoch = UNIX_EPOCH - Duration::from_micros(1500);
+    let encoded = WireFormat::MessagePack.encode(&Reminder {
+        due: before_epoch,
+        snooze: Duration::from_millis(3),
+    });
+    assert_eq!(
+        ((-1, 998_500_000), (0, 3_000_000)),
+        rmp_serde::from_slice::<((i64, u32), (u64, u32))>(&encoded).unwrap()
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<ReminderServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn ReminderService, _>(stream).await;
+    let reminder = Reminder {
+        due: before_epoch,
+        snooze: Duration::from_millis(3),
+    };
+    assert_eq!(
+        UNIX_EPOCH + Duration::from_micros(1500),
+        service.snooze(&reminder).await.unwrap()
+    );
+    let after_epoch = UNIX_EPOCH + Duration::from_nanos(7);
+    assert_eq!(
+        Duration::from_nanos(1_500_007),
+        service.time_until(before_epoch, after_epoch).await.unwrap()
+    );
+    assert!(matches!(
+        service.time_until(after_epoch, before_epoch).await,
+        Err(RustyRpcError::ServerError(_))
+    ));
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn uuid_test() {
+    #[derive(Default)]
+    struct TicketServer(HashMap<Uuid, Uuid>);
+    #[service_server_impl]
+    impl TicketService for TicketServer {
+        async fn open(&mut self, owner: Uuid) -> RpcResult<Ticket> {
+            let id = Uuid::new_v4();
+            self.0.insert(id, owner);
+            Ok(Ticket { id, owner })
+        }
+        async fn owner_of(&mut self, id: Uuid) -> RpcResult<Result<Uuid, Uuid>> {
+            Ok(self.0.get(&id).copied().ok_or(id))
+        }
+    }
+
+    let owner = Uuid::new_v4();
+    // Sent as the 16 bytes of the UUID.
+    let encoded = WireFormat::MessagePack.encode(&Ticket {
+        id: Uuid::nil(),
+        owner,
+    });
+    assert_eq!(
+        (vec![0; 16], owner.as_bytes().to_vec()),
+        rmp_serde::from_slice::<(ByteBuf, ByteBuf)>(&encoded)
+            .map(|(x, y)| (x.0, y.0))
+            .unwrap()
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<TicketServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn TicketService, _>(stream).await;
+    let ticket = service.open(owner).await.unwrap();
+    assert_eq!(owner, ticket.owner);
+    assert_ne!(Uuid::nil(), ticket.id);
+    assert_eq!(Ok(owner), service.owner_of(ticket.id).await.unwrap());
+    let unknown = Uuid::new_v4();
+    assert_eq!(Err(unknown), service.owner_of(unknown).await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn on_connection_closed_test() {
+    type Log = Arc<Mutex<Vec<String>>>;
+    struct CounterFactoryServer(Log, i32);
+    struct CounterServer(Log, i32);
+    impl Drop for CounterServer {
+        fn drop(&mut self) {
+            self.0.lock().unwrap().push(format!("dropped {}", self.1));
+        }
+    }
+    #[service_server_impl]
+    impl CounterFactoryService for CounterFactoryServer {
+        async fn get_counter(&mut self) -> RpcResult<ServiceRefMut<'static, dyn CounterService>> {
+            self.1 += 1;
+            Ok(ServiceRefMut::new(CounterServer(self.0.clone(), self.1)))
+        }
+        async fn on_connection_closed(&mut self) {
+            self.0
+                .lock()
+                .unwrap()
+                .push("factory disconnected".to_string());
+        }
+    }
+    #[service_server_impl]
+    impl CounterService for CounterServer {
+        async fn increment(&mut self) -> RpcResult<i32> {
+            Ok(0)
+        }
+        async fn on_drop(&mut self) {
+            self.0.lock().unwrap().push(format!("closed {}", self.1));
+        }
+        async fn on_connection_closed(&mut self) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("disconnected {}", self.1));
+        }
+    }
+
+    let log: Log = Arc::new(Mutex::new(Vec::new()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_log = log.clone();
+    let server_handle = tokio::spawn(async move {
+        start_server_with(listener, server_log, |log: &Log| {
+            CounterFactoryServer(log.clone(), 0)
+        })
+        .await
+        .unwrap()
+    });
+
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    // CounterFactoryService::get_counter has ID 0.
+    for _ in 0..2 {
+        let arguments = rmp_serde::to_vec(&()).unwrap();
+        send_raw_message(
+            &mut stream,
+            ClientMessage::CallMethod(
+                ServiceId(0),
+                MethodId(0),
+                MethodArgs(arguments),
+                HashMap::new(),
+            ),
+        )
+        .await;
+        assert!(matches!(
+            receive_raw_message(&mut stream).await,
+            ServerMessage::MethodReturned(ReturnValue::Service(_))
+        ));
+    }
+    // A service that the client closes only gets on_drop.
+    send_raw_message(&mut stream, ClientMessage::DropService(ServiceId(1))).await;
+    assert!(matches!(
+        receive_raw_message(&mut stream).await,
+        ServerMessage::DropServiceDone
+    ));
+    assert_eq!(vec!["closed 1", "dropped 1"], *log.lock().unwrap());
+    // Hang up without closing the others.
+    drop(stream);
+
+    timeout(Duration::from_secs(5), async {
+        while log.lock().unwrap().len() < 5 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Services were not notified of the closed connection.");
+    {
+        let log = log.lock().unwrap();
+        // The factory and the counter don't borrow from each other, so they can
+        // be notified in either order, but each is notified before it is dropped.
+        let position = |entry: &str| log.iter().position(|x| x == entry).unwrap();
+        assert!(position("disconnected 2") < position("dropped 2"));
+        assert!(log.contains(&"factory disconnected".to_string()));
+        assert_eq!(5, log.len());
+    }
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn duplicate_service_id_test() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client_handle = tokio::spawn(async move {
+        let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+        let mut factory = start_client::<dyn CounterFactoryService, _>(stream).await;
+        let first = factory.get_counter().await.unwrap();
+        let second = factory.get_counter().await.unwrap();
+        first.close().await.unwrap();
+        second.close().await.unwrap();
+        factory.close().await.unwrap();
+    });
+
+    // A server that returns the same service twice.
+    let (mut stream, _) = listener.accept().await.unwrap();
+    for _ in 0..2 {
+        let message = ClientMessage::try_from(Bytes::from(receive_raw_frame(&mut stream).await));
+        assert!(matches!(
+            message,
+            Ok(ClientMessage::CallMethod(ServiceId(0), MethodId(0), _, _))
+        ));
+        let response = ServerMessage::MethodReturned(ReturnValue::Service(ServiceId(1)));
+        send_raw_frame(&mut stream, &Bytes::from(response)).await;
+    }
+    for service_id in [1, 0] {
+        let message = ClientMessage::try_from(Bytes::from(receive_raw_frame(&mut stream).await));
+        assert!(
+            matches!(message, Ok(ClientMessage::DropService(ServiceId(id))) if id == service_id)
+        );
+        send_raw_frame(&mut stream, &Bytes::from(ServerMessage::DropServiceDone)).await;
+    }
+
+    client_handle.await.expect("Client crashed.");
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    assert!(received.is_empty(), "Client sent another message.");
+}
+
+#[tokio::test]
+async fn max_response_length_test() {
+    // Prepends a long payload to whatever it concatenates.
+    struct BlobServer(Vec<u8>);
+    #[service_server_impl]
+    impl BlobService for BlobServer {
+        async fn checksum(&mut self, data: &[u8]) -> RpcResult<i32> {
+            Ok(data.len() as i32)
+        }
+        async fn concat(&mut self, first: &[u8], second: &[u8]) -> RpcResult<Vec<u8>> {
+            Ok([&self.0, first, second].concat())
+        }
+        async fn wrap(&mut self, data: &[u8], tag: i32) -> RpcResult<Blob> {
+            Ok(Blob {
+                data: data.to_vec(),
+                tag,
+            })
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        chunk_size: 1000,
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, config, (), |_| BlobServer(vec![7; 200_000]))
+            .await
+            .unwrap()
+    });
+
+    // Each chunk is short, but the whole response is too long.
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let config = ClientConfig {
+        max_response_length: 4096,
+        ..Default::default()
+    };
+    let mut service = start_client_with_config::<dyn BlobService, _>(stream, config).await;
+    match service.concat(&[1], &[2]).await {
+        Err(RustyRpcError::MalformedMessage(msg)) => {
+            assert!(msg.contains("maximum response length"), "{}", msg)
+        }
+        x => panic!("Expected a malformed message error, got {:?}", x),
+    }
+    // The rest of the long response is skipped, and the connection still
+    // works.
+    assert_eq!(3, service.checksum(&[1, 2, 3]).await.unwrap());
+    let blob = service.wrap(&[5; 3000], 1).await.unwrap();
+    assert_eq!(vec![5; 3000], blob.data);
+    assert!(service.wrap(&[5; 5000], 2).await.is_err());
+    assert_eq!(1, service.checksum(&[1]).await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn mock_test() {
+    // Client logic that is written against the service trait, so that it
+    // works with both a proxy and a mock.
+    async fn swap(service: &mut (impl KeyValueService + ?Sized), a: i32, b: i32) -> RpcResult<()> {
+        let value_a = service.get(a).await?;
+        let value_b = service.get(b).await?;
+        service.set(a, value_b).await?;
+        service.set(b, value_a).await?;
+        Ok(())
+    }
+
+    let mut service = KeyValueServiceMock::with_state(HashMap::from([(1, 10), (2, 20)]))
+        .on_get(|map, key| Ok(map[&key]))
+        .on_set(|map, key, value| Ok(map.insert(key, value).unwrap_or(0)));
+    swap(&mut service, 1, 2).await.unwrap();
+    assert_eq!(HashMap::from([(1, 20), (2, 10)]), service.state);
+
+    // Errors can be returned too.
+    let mut service =
+        KeyValueServiceMock::new().on_get(|_, _| Err(RustyRpcError::ServerError("oops".into())));
+    match swap(&mut service, 1, 2).await {
+        Err(RustyRpcError::ServerError(msg)) => assert_eq!("oops", msg),
+        x => panic!("Expected a server error, got {:?}", x),
+    }
+
+    // The closures can change the state.
+    let mut service = NameServiceMock::with_state("Alice".to_string())
+        .on_get_name(|name| Ok(name.clone()))
+        .on_set_name(|name, new_name| {
+            *name = new_name.to_string();
+            Ok(0)
+        });
+    assert_eq!("Alice", service.get_name().await.unwrap());
+    service.set_name("Bob").await.unwrap();
+    assert_eq!("Bob", service.get_name().await.unwrap());
+
+    // Mocks are services, so they can be returned from other mocks.
+    let mut service = ParentServiceMock::with_state(5).on_get_child(|value| {
+        let value = *value;
+        let child = ChildServiceMock::new().on_get_value(move |_| Ok(value));
+        Ok(ServiceRefMut::new(child))
+    });
+    let child = service.get_child().await.unwrap();
+    assert!(child.is_local());
+    child.close().await.unwrap();
+
+    // Calling a method whose closure wasn't set panics.
+    let mut service = KeyValueServiceMock::new().on_get(|_, _| Ok(0));
+    let error = tokio::spawn(async move { service.set(1, 2).await })
+        .await
+        .expect_err("Calling a method without a closure somehow didn't panic.");
+    assert!(error.is_panic());
+}
+
+#[tokio::test]
+async fn char_test() {
+    struct GlyphServer;
+    #[service_server_impl]
+    impl GlyphService for GlyphServer {
+        async fn glyph(&mut self, symbol: char) -> RpcResult<Glyph> {
+            Ok(Glyph {
+                symbol,
+                width: symbol.len_utf8() as i32,
+            })
+        }
+        async fn next(&mut self, symbol: char) -> RpcResult<char> {
+            Ok(char::from_u32(u32::from(symbol) + 1).unwrap_or(symbol))
+        }
+    }
+
+    // Sent as the Unicode scalar value.
+    let crab = '\u{1F980}';
+    let encoded = WireFormat::MessagePack.encode(&Glyph {
+        symbol: crab,
+        width: 4,
+    });
+    assert_eq!(
+        (0x1F980, 4),
+        rmp_serde::from_slice::<(u32, i32)>(&encoded).unwrap()
+    );
+    // Surrogates aren't Unicode scalar values.
+    let surrogate = rmp_serde::to_vec(&(0xD800, 1)).unwrap();
+    match WireFormat::MessagePack.decode::<Glyph>(&surrogate) {
+        Err(RustyRpcError::MalformedMessage(msg)) => {
+            assert!(msg.contains("Unicode scalar value"), "{}", msg)
+        }
+        x => panic!("Expected a malformed message error, got {:?}", x),
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async move {
+        start_server_with(listener, (), |_| GlyphServer)
+            .await
+            .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn GlyphService, _>(stream).await;
+    assert_eq!(
+        Glyph {
+            symbol: crab,
+            width: 4
+        },
+        service.glyph(crab).await.unwrap()
+    );
+    assert_eq!('\u{1F981}', service.next(crab).await.unwrap());
+    assert_eq!('b', service.next('a').await.unwrap());
+    service.close().await.unwrap();
+
+    // A surrogate argument closes the connection instead of crashing the
+    // server. GlyphService::next has method ID 1.
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    send_raw_message(
+        &mut stream,
+        ClientMessage::CallMethod(
+            ServiceId(0),
+            MethodId(1),
+            MethodArgs(rmp_serde::to_vec(&0xD800).unwrap()),
+            HashMap::new(),
+        ),
+    )
+    .await;
+    let mut buf = [0; 16];
+    let bytes_read = stream.read(&mut buf).await.unwrap_or(0);
+    assert_eq!(0, bytes_read, "Server should close the connection.");
+
+    // Other connections should still work.
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn GlyphService, _>(stream).await;
+    assert_eq!('b', service.next('a').await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn max_frame_length_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        max_frame_length: 1024,
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, config, (), |_| ValueServer::default())
+            .await
+            .unwrap()
+    });
+
+    // Announce a frame that is longer than the limit. The length prefix is a
+    // big-endian u32.
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    stream.write_all(&2048u32.to_be_bytes()).await.unwrap();
+    stream.write_all(&[0; 16]).await.unwrap();
+    let mut buf = [0; 16];
+    let bytes_read = stream.read(&mut buf).await.unwrap_or(0);
+    assert_eq!(0, bytes_read, "Server should close the connection.");
+
+    // Other connections should still work.
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn ChildService, _>(stream).await;
+    assert_eq!(5, service.set_value(5).await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+#[allow(clippy::diverging_sub_expression)]
+async fn max_services_test() {
+    #[derive(Default)]
+    struct ChainServer;
+    #[service_server_impl]
+    impl MyService for ChainServer {
+        async fn foo(&mut self) -> RpcResult<i32> {
+            Ok(1)
+        }
+        async fn bar(&mut self, _arg: i32) -> RpcResult<i32> {
+            unimplemented!()
+        }
+        async fn bar2(&mut self, _arg1: i32, _arg2: &Foo) -> RpcResult<Foo> {
+            unimplemented!()
+        }
+        async fn baz<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn MyService + 'a>> {
+            Ok(ServiceRefMut::new(ChainServer))
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        max_services_per_connection: 3,
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, config, (), |_| ChainServer)
+            .await
+            .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service_0 = start_client::<dyn MyService, _>(stream).await;
+    let mut service_1 = service_0.baz().await.unwrap();
+    let mut service_2 = service_1.baz().await.unwrap();
+    assert!(matches!(
+        service_2.baz().await,
+        Err(RustyRpcError::ServerError(_))
+    ));
+
+    // The connection and the services should still work.
+    assert_eq!(1, service_2.foo().await.unwrap());
+    service_2.close().await.unwrap();
+
+    // Closing a service frees up space for a new one.
+    let service_2 = service_1.baz().await.unwrap();
+    service_2.close().await.unwrap();
+
+    service_1.close().await.unwrap();
+    service_0.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn debug_service_ref_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let local = ServiceRefMut::<dyn ChildService>::new(ValueServer(1));
+    assert_eq!("OwnedLocalService(..)", format!("{:?}", local));
+    assert!(local.is_local());
+    assert!(!local.is_remote());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<ValueServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let remote = start_client::<dyn ChildService, _>(stream).await;
+    assert_eq!("RemoteServiceRefMut(ServiceId(0))", format!("{:?}", remote));
+    assert!(!remote.is_local());
+    assert!(remote.is_remote());
+    remote.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn auto_close_on_drop_test() {
+    struct ParentServer(Arc<AtomicBool>);
+    struct ChildServer(Arc<AtomicBool>);
+    impl Drop for ChildServer {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+    #[service_server_impl]
+    impl ParentService for ParentServer {
+        async fn get_child<'a>(
+            &'a mut self,
+        ) -> RpcResult<ServiceRefMut<'a, dyn ChildService + 'a>> {
+            Ok(ServiceRefMut::new(ChildServer(self.0.clone())))
+        }
+    }
+    #[service_server_impl]
+    impl ChildService for ChildServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let child_dropped = Arc::new(AtomicBool::new(false));
+    let child_dropped_clone = child_dropped.clone();
+    let server_handle = tokio::spawn(async move {
+        start_server_with(listener, child_dropped_clone, |flag: &Arc<AtomicBool>| {
+            ParentServer(flag.clone())
+        })
+        .await
+        .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let config = ClientConfig {
+        auto_close_on_drop: true,
+        ..Default::default()
+    };
+    let mut parent = start_client_with_config::<dyn ParentService, _>(stream, config).await;
+
+    let child = parent.get_child().await.unwrap();
+    drop(child);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !child_dropped.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Server did not drop the service.");
+
+    // The parent is usable again, even if the child is dropped right before.
+    let mut child = parent.get_child().await.unwrap();
+    assert_eq!(3, child.set_value(3).await.unwrap());
+    drop(child);
+    let child = parent.get_child().await.unwrap();
+    drop(child);
+    parent.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn close_local_service_test() {
+    struct ValueServer(Arc<AtomicBool>);
+    impl Drop for ValueServer {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            Ok(new_value)
+        }
+    }
+
+    let dropped = Arc::new(AtomicBool::new(false));
+    let local = ServiceRefMut::<dyn ChildService>::new(ValueServer(dropped.clone()));
+    local.close().await.unwrap();
+    assert!(dropped.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn on_drop_test() {
+    #[derive(Default)]
+    struct CounterFactoryServer(Arc<Mutex<Vec<&'static str>>>);
+    struct CounterServer(Arc<Mutex<Vec<&'static str>>>);
+    impl Drop for CounterServer {
+        fn drop(&mut self) {
+            self.0.lock().unwrap().push("dropped");
+        }
+    }
+    #[service_server_impl]
+    impl CounterFactoryService for CounterFactoryServer {
+        async fn get_counter(&mut self) -> RpcResult<ServiceRefMut<'static, dyn CounterService>> {
+            Ok(ServiceRefMut::new(CounterServer(self.0.clone())))
+        }
+    }
+    #[service_server_impl]
+    impl CounterService for CounterServer {
+        async fn increment(&mut self) -> RpcResult<i32> {
+            Ok(0)
+        }
+        async fn on_drop(&mut self) {
+            // The client waits for this, even though it takes a while.
+            sleep(Duration::from_millis(50)).await;
+            self.0.lock().unwrap().push("closed");
+        }
+    }
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_log = log.clone();
+    let server_handle = tokio::spawn(async move {
+        start_server_with(listener, server_log, |log: &Arc<Mutex<_>>| {
+            CounterFactoryServer(log.clone())
+        })
+        .await
+        .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut factory = start_client::<dyn CounterFactoryService, _>(stream).await;
+    let mut counter = factory.get_counter().await.unwrap();
+    counter.increment().await.unwrap();
+    assert!(log.lock().unwrap().is_empty());
+    counter.close().await.unwrap();
+    assert_eq!(vec!["closed", "dropped"], *log.lock().unwrap());
+    factory.close().await.unwrap();
+
+    // Closing an owned local service also calls on_drop.
+    log.lock().unwrap().clear();
+    let local = ServiceRefMut::<dyn CounterService>::new(CounterServer(log.clone()));
+    local.close().await.unwrap();
+    assert_eq!(vec!["closed", "dropped"], *log.lock().unwrap());
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn invalid_method_id_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<ValueServer>(listener).await.unwrap() });
+
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let arguments = rmp_serde::to_vec(&()).unwrap();
+    send_raw_message(
+        &mut stream,
+        ClientMessage::CallMethod(
+            ServiceId(0),
+            MethodId(999),
+            MethodArgs(arguments),
+            HashMap::new(),
+        ),
+    )
+    .await;
+    assert!(matches!(
+        receive_raw_message(&mut stream).await,
+        ServerMessage::Error(_)
+    ));
+
+    // The connection should still work. ChildService::get_value has ID 0.
+    let arguments = rmp_serde::to_vec(&()).unwrap();
+    send_raw_message(
+        &mut stream,
+        ClientMessage::CallMethod(
+            ServiceId(0),
+            MethodId(0),
+            MethodArgs(arguments),
+            HashMap::new(),
+        ),
+    )
+    .await;
+    match receive_raw_message(&mut stream).await {
+        ServerMessage::MethodReturned(ReturnValue::Data(bytes)) => {
+            assert_eq!(0, rmp_serde::from_slice::<i32>(&bytes).unwrap())
+        }
+        _ => panic!("Expected a return value."),
+    }
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn concurrent_calls_run_one_at_a_time_test() {
+    static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+    static MAX_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+    #[derive(Default)]
+    struct PairServer(i32, i32);
+    struct SlowHalfServer<'a>(&'a mut i32);
+    #[service_server_impl]
+    impl PairService for PairServer {
+        async fn split<'a>(
+            &'a mut self,
+        ) -> RpcResult<(
+            ServiceRefMut<'a, dyn ChildService + 'a>,
+            ServiceRefMut<'a, dyn ChildService + 'a>,
+        )> {
+            Ok((
+                ServiceRefMut::new(SlowHalfServer(&mut self.0)),
+                ServiceRefMut::new(SlowHalfServer(&mut self.1)),
+            ))
+        }
+    }
+    #[service_server_impl]
+    impl<'a> ChildService for SlowHalfServer<'a> {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            let in_flight = IN_FLIGHT.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_IN_FLIGHT.fetch_max(in_flight, Ordering::SeqCst);
+            sleep(Duration::from_millis(20)).await;
+            IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+            Ok(*self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            *self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async move { start_server::<PairServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn PairService, _>(stream).await;
+    let (mut first, mut second) = service.split().await.unwrap();
+    // The client sends the second call only after the first one returns.
+    let (first_value, second_value) = futures::join!(first.get_value(), second.get_value());
+    assert_eq!(0, first_value.unwrap());
+    assert_eq!(0, second_value.unwrap());
+    assert_eq!(1, MAX_IN_FLIGHT.load(Ordering::SeqCst));
+    first.close().await.unwrap();
+    second.close().await.unwrap();
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+#[allow(clippy::diverging_sub_expression)]
+async fn invalid_drop_service_test() {
+    #[derive(Default)]
+    struct ChainServer;
+    #[service_server_impl]
+    impl MyService for ChainServer {
+        async fn foo(&mut self) -> RpcResult<i32> {
+            Ok(1)
+        }
+        async fn bar(&mut self, _arg: i32) -> RpcResult<i32> {
+            unimplemented!()
+        }
+        async fn bar2(&mut self, _arg1: i32, _arg2: &Foo) -> RpcResult<Foo> {
+            unimplemented!()
+        }
+        async fn baz<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn MyService + 'a>> {
+            Ok(ServiceRefMut::new(ChainServer))
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<ChainServer>(listener).await.unwrap() });
+
+    async fn drop_service(stream: &mut TcpStream, service_id: u64) -> ServerMessage {
+        send_raw_message(stream, ClientMessage::DropService(ServiceId(service_id))).await;
+        receive_raw_message(stream).await
+    }
+
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+
+    // Nonexistent service.
+    let response = drop_service(&mut stream, 123).await;
+    assert!(matches!(response, ServerMessage::Error(_)));
+
+    // MyService::baz has ID 2.
+    let arguments = rmp_serde::to_vec(&()).unwrap();
+    send_raw_message(
+        &mut stream,
+        ClientMessage::CallMethod(
+            ServiceId(0),
+            MethodId(2),
+            MethodArgs(arguments),
+            HashMap::new(),
+        ),
+    )
+    .await;
+    let child_id = match receive_raw_message(&mut stream).await {
+        ServerMessage::MethodReturned(ReturnValue::Service(service_id)) => service_id.0,
+        _ => panic!("Expected a service."),
+    };
+    // The initial service is in use by the child.
+    let response = drop_service(&mut stream, 0).await;
+    assert!(matches!(response, ServerMessage::Error(_)));
+
+    // Double drop.
+    let response = drop_service(&mut stream, child_id).await;
+    assert!(matches!(response, ServerMessage::DropServiceDone));
+    let response = drop_service(&mut stream, child_id).await;
+    assert!(matches!(response, ServerMessage::Error(_)));
+
+    // The connection should still work.
+    let response = drop_service(&mut stream, 0).await;
+    assert!(matches!(response, ServerMessage::DropServiceDone));
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[test]
+fn method_ids_test() {
+    fn method_id<T: RustyRpcServiceClient + ?Sized>(name: &str) -> Option<MethodId> {
+        T::METHOD_IDS
+            .iter()
+            .find(|(method_name, _)| *method_name == name)
+            .map(|(_, method_id)| *method_id)
+    }
+
+    // Methods are numbered in the order of their names.
+    assert_eq!(
+        vec![
+            ("bar", MethodId(0)),
+            ("bar2", MethodId(1)),
+            ("baz", MethodId(2)),
+            ("foo", MethodId(3)),
+        ],
+        <dyn MyService>::METHOD_IDS.to_vec()
+    );
+    assert_eq!(Some(MethodId(3)), method_id::<dyn MyService>("foo"));
+    assert_eq!(
+        Some(MethodId(0)),
+        method_id::<dyn ChildService>("get_value")
+    );
+    assert_eq!(None, method_id::<dyn ChildService>("foo"));
+    // Explicit IDs are included too.
+    assert_eq!(
+        Some(MethodId(10)),
+        method_id::<dyn VersionedService>("get_version")
+    );
+    assert_eq!(Some(MethodId(0)), method_id::<dyn VersionedService>("add"));
+    assert!(<dyn EmptyService>::METHOD_IDS.is_empty());
+}
+
+#[test]
+fn interface_schema_test() {
+    let schema: serde_json::Value = serde_json::from_str(interface_schema_file!(
+        "tests/simple_interface_file.interface"
+    ))
+    .unwrap();
+    assert_eq!(
+        json!({
+            "fields": {
+                "x": { "field_type": "I32", "default_value": null, "rust_name": null, "skip_if_default": false, "lossy_utf8": false },
+                "y": { "field_type": { "Struct": "Bar" }, "default_value": null, "rust_name": null, "skip_if_default": false, "lossy_utf8": false },
+            },
+            "extra_derives": [],
+            "mirrors": [],
+            "copy": false,
+            "non_exhaustive": false,
+            "rust_name": null,
+        }),
+        schema["structs"]["Foo"]
+    );
+    assert_eq!(
+        json!({ "field_type": "I32", "default_value": { "Int": 5 }, "rust_name": null, "skip_if_default": false, "lossy_utf8": false }),
+        schema["structs"]["WithDefaults"]["fields"]["a"]
+    );
+    let my_service_methods = &schema["services"]["MyService"]["methods"];
+    assert_eq!(
+        json!({
+            "id": null,
+            "deprecated": null,
+            "rust_name": null,
+            "named_args": false,
+            "incremental": false,
+            "oneway": false,
+            "non_self_params": [["arg1", "I32"], ["arg2", { "Struct": "Foo" }]],
+            "return_type": { "Data": { "Struct": "Foo" } },
+        }),
+        my_service_methods["bar2"]
+    );
+    assert_eq!(
+        json!({ "ServiceRefMut": "MyService" }),
+        my_service_methods["baz"]["return_type"]
+    );
+    assert_eq!(
+        json!(10),
+        schema["services"]["VersionedService"]["methods"]["get_version"]["id"]
+    );
+    assert_eq!(
+        json!({ "note": "use new_value instead" }),
+        schema["services"]["LegacyService"]["methods"]["old_value"]["deprecated"]
+    );
+    // The schema has the names from the interface file, with the Rust names on
+    // the side.
+    assert_eq!(
+        json!("Measurement"),
+        schema["structs"]["Reading"]["rust_name"]
+    );
+    assert_eq!(
+        json!("fetch"),
+        schema["services"]["SensorService"]["methods"]["get"]["rust_name"]
+    );
+    assert_eq!(
+        json!(true),
+        schema["services"]["GreeterService"]["methods"]["greet"]["named_args"]
+    );
+    assert_eq!(
+        json!([["domain", "Route"]]),
+        schema["structs"]["Route"]["mirrors"]
+    );
+
+    // The same JSON is also written next to the protocol file.
+    let written_schema = std::fs::read_to_string(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/simple_interface_file.json"
+    ))
+    .unwrap();
+    assert_eq!(
+        schema,
+        serde_json::from_str::<serde_json::Value>(&written_schema).unwrap()
+    );
+}
+
+#[test]
+fn default_field_values_test() {
+    let value = WithDefaults::default();
+    assert_eq!(5, value.a);
+    assert_eq!(-3, value.b);
+    assert_eq!(0, value.c);
+    assert_eq!(0, value.d.z);
+
+    // Struct update syntax works with the defaults.
+    let value = WithDefaults {
+        c: 7,
+        ..Default::default()
+    };
+    assert_eq!(5, value.a);
+    assert_eq!(7, value.c);
+}
+
+#[test]
+fn struct_builder_test() {
+    let foo = Foo::builder()
+        .with_x(1)
+        .with_y(Bar::builder().with_z(2).build().unwrap())
+        .build()
+        .unwrap();
+    assert_eq!(1, foo.x);
+    assert_eq!(2, foo.y.z);
+
+    let error = Foo::builder().with_x(1).build().unwrap_err();
+    assert_eq!("Foo", error.struct_name);
+    assert_eq!("y", error.field_name);
+
+    // Fields with default values are optional.
+    let value = WithDefaults::builder()
+        .with_c(3)
+        .with_d(Bar { z: 4 })
+        .build()
+        .unwrap();
+    assert_eq!(5, value.a);
+    assert_eq!(3, value.c);
+    assert!(WithDefaults::builder().with_c(3).build().is_err());
+}
+
+#[test]
+fn struct_eq_hash_test() {
+    let foo_1 = Foo {
+        x: 1,
+        y: Bar { z: 2 },
+    };
+    let foo_2 = foo_1.clone();
+    assert_eq!(foo_1, foo_2);
+    assert_ne!(
+        foo_1,
+        Foo {
+            x: 1,
+            y: Bar { z: 3 },
+        }
+    );
+
+    let set = std::collections::HashSet::from([foo_1, foo_2]);
+    assert_eq!(1, set.len());
+}
+
+#[test]
+fn extra_derives_test() {
+    let mut points = vec![
+        Point { x: 2, y: 1 },
+        Point { x: 1, y: 2 },
+        Point { x: 1, y: 1 },
+    ];
+    points.sort();
+    assert_eq!(
+        vec![
+            Point { x: 1, y: 1 },
+            Point { x: 1, y: 2 },
+            Point { x: 2, y: 1 },
+        ],
+        points
+    );
+}
+
+#[tokio::test]
+async fn server_idle_timeout_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_config = ServerConfig {
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, server_config, (), |_| ValueServer::default())
+            .await
+            .unwrap()
+    });
+
+    // A client that sends heartbeats stays connected while idle.
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let client_config = ClientConfig {
+        heartbeat_interval: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let mut service = start_client_with_config::<dyn ChildService, _>(stream, client_config).await;
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(5, service.set_value(5).await.unwrap());
+    service.close().await.unwrap();
+
+    // A client that doesn't gets disconnected.
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    send_raw_message(&mut stream, ClientMessage::Ping).await;
+    assert!(matches!(
+        receive_raw_message(&mut stream).await,
+        ServerMessage::Pong
+    ));
+    let mut remaining = Vec::new();
+    timeout(Duration::from_secs(2), stream.read_to_end(&mut remaining))
+        .await
+        .expect("Server didn't close the idle connection.")
+        .unwrap();
+    assert!(remaining.is_empty());
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn server_idle_timeout_drops_services_test() {
+    struct FactoryServer(Arc<AtomicUsize>);
+    struct CounterServer(Arc<AtomicUsize>);
+    impl Drop for FactoryServer {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    impl Drop for CounterServer {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    #[service_server_impl]
+    impl CounterFactoryService for FactoryServer {
+        async fn get_counter(&mut self) -> RpcResult<ServiceRefMut<'static, dyn CounterService>> {
+            Ok(ServiceRefMut::new(CounterServer(self.0.clone())))
+        }
+    }
+    #[service_server_impl]
+    impl CounterService for CounterServer {
+        async fn increment(&mut self) -> RpcResult<i32> {
+            Ok(1)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let drop_count = Arc::new(AtomicUsize::new(0));
+    let server_config = ServerConfig {
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let server_drop_count = drop_count.clone();
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, server_config, server_drop_count, |x| {
+            FactoryServer(x.clone())
+        })
+        .await
+        .unwrap()
+    });
+
+    // Get a service, then go quiet while holding it.
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let arguments = rmp_serde::to_vec(&()).unwrap();
+    send_raw_message(
+        &mut stream,
+        ClientMessage::CallMethod(
+            ServiceId(0),
+            MethodId(0),
+            MethodArgs(arguments),
+            HashMap::new(),
+        ),
+    )
+    .await;
+    assert!(matches!(
+        receive_raw_message(&mut stream).await,
+        ServerMessage::MethodReturned(ReturnValue::Service(ServiceId(1)))
+    ));
+    assert_eq!(0, drop_count.load(Ordering::SeqCst));
+
+    let mut remaining = Vec::new();
+    timeout(Duration::from_secs(2), stream.read_to_end(&mut remaining))
+        .await
+        .expect("Server didn't close the idle connection.")
+        .unwrap();
+    timeout(Duration::from_secs(2), async {
+        while drop_count.load(Ordering::SeqCst) < 2 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Server didn't drop the services of the idle connection.");
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn multiple_listeners_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    struct CounterServer(Arc<AtomicUsize>);
+    #[service_server_impl]
+    impl CounterService for CounterServer {
+        async fn increment(&mut self) -> RpcResult<i32> {
+            Ok(self.0.fetch_add(1, Ordering::SeqCst) as i32 + 1)
+        }
+    }
+
+    let value_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let value_addr = value_listener.local_addr().unwrap();
+    let counter_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let counter_addr = counter_listener.local_addr().unwrap();
+    let (shutdown_sender, shutdown_receiver) = futures::channel::oneshot::channel::<()>();
+    let server_handle = tokio::spawn(
+        Server::new(ServerConfig::default())
+            .listener(value_listener, (), |_| ValueServer(7))
+            .listener(
+                counter_listener,
+                Arc::new(AtomicUsize::new(0)),
+                |count: &Arc<AtomicUsize>| CounterServer(count.clone()),
+            )
+            .run_until(async {
+                shutdown_receiver.await.unwrap();
+            }),
+    );
+
+    let stream = TcpSocket::new_v4()
+        .unwrap()
+        .connect(value_addr)
+        .await
+        .unwrap();
+    let mut value_service = start_client::<dyn ChildService, _>(stream).await;
+    assert_eq!(7, value_service.get_value().await.unwrap());
+    for expected in 1..=2 {
+        let stream = TcpSocket::new_v4()
+            .unwrap()
+            .connect(counter_addr)
+            .await
+            .unwrap();
+        let mut counter_service = start_client::<dyn CounterService, _>(stream).await;
+        assert_eq!(expected, counter_service.increment().await.unwrap());
+        counter_service.close().await.unwrap();
+    }
+
+    // Shutting down stops both listeners, but not the open connections.
+    shutdown_sender.send(()).unwrap();
+    server_handle.await.unwrap().unwrap();
+    for addr in [value_addr, counter_addr] {
+        assert!(TcpSocket::new_v4().unwrap().connect(addr).await.is_err());
+    }
+    assert_eq!(3, value_service.set_value(3).await.unwrap());
+    value_service.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn client_heartbeat_timeout_test() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client_handle = tokio::spawn(async move {
+        let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+        let config = ClientConfig {
+            auto_close_on_drop: true,
+            heartbeat_interval: Some(Duration::from_millis(50)),
+            heartbeat_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let mut service = start_client_with_config::<dyn ChildService, _>(stream, config).await;
+        sleep(Duration::from_millis(500)).await;
+        assert!(matches!(
+            service.get_value().await,
+            Err(RustyRpcError::ConnectionClosed)
+        ));
+    });
+
+    // This server never responds, as if it were dead.
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut received = Vec::new();
+    timeout(Duration::from_secs(2), stream.read_to_end(&mut received))
+        .await
+        .expect("Client didn't close the connection.")
+        .unwrap();
+    let message = ClientMessage::try_from(Bytes::copy_from_slice(&received[4..])).unwrap();
+    assert!(matches!(message, ClientMessage::Ping));
+
+    client_handle.await.expect("Client crashed.");
+}
+
+#[tokio::test]
+async fn heartbeat_during_call_test() {
+    #[derive(Default)]
+    struct SlowServer(i32);
+    #[service_server_impl]
+    impl ChildService for SlowServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            sleep(Duration::from_millis(500)).await;
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let config = ClientConfig {
+        heartbeat_interval: Some(Duration::from_millis(50)),
+        heartbeat_timeout: Duration::from_millis(100),
+        ..Default::default()
+    };
+
+    // A method that takes longer than the heartbeats is fine, since the server
+    // answers them while the method runs.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async { start_server::<SlowServer>(listener).await.unwrap() });
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client_with_config::<dyn ChildService, _>(stream, config.clone()).await;
+    assert_eq!(5, service.set_value(5).await.unwrap());
+    assert_eq!(5, service.get_value().await.unwrap());
+    service.close().await.unwrap();
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+
+    // A call to a server that dies while the call is pending fails instead of
+    // waiting forever.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        receive_raw_frame(&mut stream).await;
+        // The server stops responding without closing the connection, as if
+        // its host went away.
+        std::future::pending::<()>().await;
+        drop(stream);
+    });
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client_with_config::<dyn ChildService, _>(stream, config).await;
+    let result = timeout(Duration::from_secs(2), service.get_value())
+        .await
+        .expect("The call waited for a dead server.");
+    assert!(matches!(result, Err(RustyRpcError::Timeout)));
+    assert!(matches!(
+        service.get_value().await,
+        Err(RustyRpcError::ConnectionClosed)
+    ));
+    service.close().await.unwrap_err();
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn heartbeat_during_oneway_test() {
+    #[derive(Default)]
+    struct SlowTotalServer(i32);
+    #[service_server_impl]
+    impl NotificationService for SlowTotalServer {
+        async fn notify(&mut self, amount: i32) -> RpcResult<()> {
+            sleep(Duration::from_millis(500)).await;
+            self.0 += amount;
+            Ok(())
+        }
+        async fn get_total(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<SlowTotalServer>(listener).await.unwrap() });
+    let config = ClientConfig {
+        heartbeat_interval: Some(Duration::from_millis(50)),
+        heartbeat_timeout: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client_with_config::<dyn NotificationService, _>(stream, config).await;
+
+    // The server answers the heartbeats of the idle client while the oneway
+    // call runs.
+    service.notify(5).await.unwrap();
+    sleep(Duration::from_millis(300)).await;
+    // The call waits behind the oneway call, and its heartbeats are answered
+    // meanwhile.
+    assert_eq!(5, service.get_total().await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn error_variants_test() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client_handle = tokio::spawn(async move {
+        let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+        let config = ClientConfig {
+            auto_close_on_drop: true,
+            ..Default::default()
+        };
+        let mut service = start_client_with_config::<dyn ChildService, _>(stream, config).await;
+        assert!(matches!(
+            service.get_value().await,
+            Err(RustyRpcError::MalformedMessage(_))
+        ));
+        match service.get_value().await {
+            Err(RustyRpcError::ServerError(msg)) => assert_eq!("Something went wrong.", msg),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        match service.get_value().await {
+            Err(RustyRpcError::MalformedMessage(msg)) => {
+                assert_eq!("Server sent pong instead of return value.", msg)
+            }
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        match service.get_value().await {
+            Err(RustyRpcError::MalformedMessage(msg)) => {
+                assert_eq!("Server returned service instead of data.", msg)
+            }
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert!(matches!(
+            service.get_value().await,
+            Err(RustyRpcError::ConnectionClosed)
+        ));
+    });
+
+    // A misbehaving server.
+    let (mut stream, _) = listener.accept().await.unwrap();
+    receive_raw_frame(&mut stream).await;
+    // 0xc1 is never valid in MessagePack.
+    send_raw_frame(&mut stream, &[0xc1]).await;
+    receive_raw_frame(&mut stream).await;
+    let error = ServerMessage::Error("Something went wrong.".to_string());
+    send_raw_frame(&mut stream, &Bytes::from(error)).await;
+    receive_raw_frame(&mut stream).await;
+    send_raw_frame(&mut stream, &Bytes::from(ServerMessage::Pong)).await;
+    receive_raw_frame(&mut stream).await;
+    let service = ServerMessage::MethodReturned(ReturnValue::Service(ServiceId(1)));
+    send_raw_frame(&mut stream, &Bytes::from(service)).await;
+    receive_raw_frame(&mut stream).await;
+    drop(stream);
+
+    client_handle.await.expect("Client crashed.");
+}
+
+#[tokio::test]
+async fn cancel_call_test() {
+    /// Records whether the call it is in was dropped before it finished.
+    struct CallState(Arc<Mutex<&'static str>>);
+    impl Drop for CallState {
+        fn drop(&mut self) {
+            let mut state = self.0.lock().unwrap();
+            if *state == "running" {
+                *state = "cancelled";
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct SlowServer(Arc<Mutex<&'static str>>);
+    #[service_server_impl]
+    impl ChildService for SlowServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            *self.0.lock().unwrap() = "running";
+            let _call_state = CallState(self.0.clone());
+            sleep(Duration::from_secs(10)).await;
+            *self.0.lock().unwrap() = "finished";
+            Ok(1)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            Ok(new_value)
+        }
+    }
+
+    let state = Arc::new(Mutex::new("not started"));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_state = state.clone();
+    let server_handle = tokio::spawn(async move {
+        start_server_with(listener, server_state, |state: &Arc<Mutex<_>>| {
+            SlowServer(state.clone())
+        })
+        .await
+        .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn ChildService, _>(stream).await;
+    // Dropping the future of the call cancels it.
+    timeout(Duration::from_millis(100), service.get_value())
+        .await
+        .expect_err("Slow call somehow finished.");
+    timeout(Duration::from_secs(5), async {
+        while *state.lock().unwrap() != "cancelled" {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Server did not stop the call.");
+
+    // The response to the cancelled call doesn't get mixed up with later ones.
+    assert_eq!(5, service.set_value(5).await.unwrap());
+    service.close().await.unwrap();
+
+    // A cancellation that arrives after the call finished is ignored.
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    send_raw_message(&mut stream, ClientMessage::Cancel).await;
+    // ChildService::set_value has ID 1.
+    let arguments = rmp_serde::to_vec(&7).unwrap();
+    send_raw_message(
+        &mut stream,
+        ClientMessage::CallMethod(
+            ServiceId(0),
+            MethodId(1),
+            MethodArgs(arguments),
+            HashMap::new(),
+        ),
+    )
+    .await;
+    match receive_raw_message(&mut stream).await {
+        ServerMessage::MethodReturned(ReturnValue::Data(bytes)) => {
+            assert_eq!(7, rmp_serde::from_slice::<i32>(&bytes).unwrap())
+        }
+        _ => panic!("Expected a return value."),
+    }
+
+    assert_eq!("cancelled", *state.lock().unwrap());
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn server_interceptor_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            sleep(Duration::from_millis(50)).await;
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Event {
+        Request(u64, u64),
+        Response(u64, u64),
+    }
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<Event>>,
+        set_value_elapsed: Mutex<Option<Duration>>,
+    }
+    impl ServerInterceptor for Recorder {
+        fn on_request(&self, service_id: ServiceId, method_id: MethodId) {
+            let event = Event::Request(service_id.0, method_id.0);
+            self.events.lock().unwrap().push(event);
+        }
+        fn on_response(&self, service_id: ServiceId, method_id: MethodId, elapsed: Duration) {
+            let event = Event::Response(service_id.0, method_id.0);
+            self.events.lock().unwrap().push(event);
+            // ChildService::set_value has ID 1.
+            if method_id.0 == 1 {
+                *self.set_value_elapsed.lock().unwrap() = Some(elapsed);
+            }
+        }
+    }
+
+    let recorder = Arc::new(Recorder::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        interceptor: Some(recorder.clone()),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, config, (), |_| ValueServer::default())
+            .await
+            .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn ChildService, _>(stream).await;
+    service.get_value().await.unwrap();
+    service.set_value(1).await.unwrap();
+    service.get_value().await.unwrap();
+    service.close().await.unwrap();
+
+    assert_eq!(
+        vec![
+            Event::Request(0, 0),
+            Event::Response(0, 0),
+            Event::Request(0, 1),
+            Event::Response(0, 1),
+            Event::Request(0, 0),
+            Event::Response(0, 0),
+        ],
+        *recorder.events.lock().unwrap()
+    );
+    assert!(recorder.set_value_elapsed.lock().unwrap().unwrap() >= Duration::from_millis(50));
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn client_interceptor_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    struct CountingInterceptor(AtomicUsize);
+    #[async_trait::async_trait]
+    impl ClientInterceptor for CountingInterceptor {
+        async fn intercept(
+            &self,
+            msg: ClientMessage,
+            next: &mut Next<'_>,
+        ) -> RpcResult<ServerMessage> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            next.run(msg).await
+        }
+    }
+    struct DelayInterceptor(Duration);
+    #[async_trait::async_trait]
+    impl ClientInterceptor for DelayInterceptor {
+        async fn intercept(
+            &self,
+            msg: ClientMessage,
+            next: &mut Next<'_>,
+        ) -> RpcResult<ServerMessage> {
+            sleep(self.0).await;
+            next.run(msg).await
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<ValueServer>(listener).await.unwrap() });
+
+    let counter = Arc::new(CountingInterceptor(AtomicUsize::new(0)));
+    let delay = Duration::from_millis(20);
+    let config = ClientConfig {
+        interceptors: vec![counter.clone(), Arc::new(DelayInterceptor(delay))],
+        ..Default::default()
+    };
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client_with_config::<dyn ChildService, _>(stream, config).await;
+    let start_time = Instant::now();
+    assert_eq!(5, service.set_value(5).await.unwrap());
+    assert_eq!(5, service.get_value().await.unwrap());
+    assert!(start_time.elapsed() >= delay * 2);
+    service.close().await.unwrap();
+    // Closing the service is also a request.
+    assert_eq!(3, counter.0.load(Ordering::SeqCst));
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+#[traced_test]
+async fn tracing_spans_test() {
+    #[derive(Default)]
+    struct TracedServer;
+    #[service_server_impl]
+    impl MyService for TracedServer {
+        async fn foo(&mut self) -> RpcResult<i32> {
+            tracing::info!("foo was called");
+            Ok(1)
+        }
+        async fn bar(&mut self, arg: i32) -> RpcResult<i32> {
+            tracing::info!("bar was called");
+            Ok(arg)
+        }
+        async fn bar2(&mut self, _arg1: i32, arg2: &Foo) -> RpcResult<Foo> {
+            Ok(arg2.clone())
+        }
+        async fn baz<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn MyService + 'a>> {
+            Ok(ServiceRefMut::new(TracedServer))
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // The connection spans are under the span of this test, so that
+    // logs_contain sees them.
+    let server_handle = tokio::spawn(
+        async { start_server::<TracedServer>(listener).await.unwrap() }.in_current_span(),
+    );
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    let mut service = start_client::<dyn MyService, _>(stream).await;
+    assert_eq!(1, service.foo().await.unwrap());
+    let mut child = service.baz().await.unwrap();
+    assert_eq!(5, child.bar(5).await.unwrap());
+    child.close().await.unwrap();
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+
+    assert!(logs_contain(&format!(
+        "connection{{peer_addr={}}}:call{{service_id=0 method_id=3 request_id=0}}:",
+        client_addr
+    )));
+    assert!(logs_contain("foo was called"));
+    // The call on the returned service is under the call that returned it.
+    assert!(logs_contain(
+        "call{service_id=0 method_id=2 request_id=1}:call{service_id=1 method_id=0 request_id=2}:"
+    ));
+    assert!(logs_contain("bar was called"));
+}
+
+#[tokio::test]
+async fn call_metadata_test() {
+    #[derive(Default)]
+    struct TraceIdServer;
+    #[service_server_impl]
+    impl NameService for TraceIdServer {
+        async fn get_name(&mut self) -> RpcResult<String> {
+            let trace_id = call_metadata().get("trace_id").cloned();
+            Ok(trace_id.unwrap_or_default())
+        }
+        async fn set_name(&mut self, _name: &str) -> RpcResult<i32> {
+            Ok(call_metadata().len() as i32)
+        }
+        async fn greet(&mut self, greeting: &str) -> RpcResult<String> {
+            Ok(greeting.to_string())
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<TraceIdServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn NameService, _>(stream).await;
+    let metadata = HashMap::from([("trace_id".to_string(), "abc123".to_string())]);
+    with_call_metadata(metadata, async {
+        assert_eq!("abc123", service.get_name().await.unwrap());
+        assert_eq!(1, service.set_name("").await.unwrap());
+    })
+    .await;
+    // Calls outside of with_call_metadata have no metadata.
+    assert!(call_metadata().is_empty());
+    assert_eq!("", service.get_name().await.unwrap());
+    assert_eq!(0, service.set_name("").await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn metrics_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    #[derive(Default)]
+    struct InMemoryMetrics {
+        counters: Mutex<HashMap<&'static str, u64>>,
+        gauges: Mutex<HashMap<&'static str, i64>>,
+        histograms: Mutex<HashMap<&'static str, Vec<f64>>>,
+    }
+    impl InMemoryMetrics {
+        fn counter(&self, name: &'static str) -> u64 {
+            *self.counters.lock().unwrap().get(name).unwrap_or(&0)
+        }
+        fn gauge(&self, name: &'static str) -> i64 {
+            *self.gauges.lock().unwrap().get(name).unwrap_or(&0)
+        }
+        async fn wait_for_gauge(&self, name: &'static str, value: i64) {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while self.gauge(name) != value {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("Gauge did not reach the expected value.");
+        }
+    }
+    impl MetricsSink for InMemoryMetrics {
+        fn increment_counter(&self, name: &'static str, value: u64) {
+            *self.counters.lock().unwrap().entry(name).or_default() += value;
+        }
+        fn add_to_gauge(&self, name: &'static str, delta: i64) {
+            *self.gauges.lock().unwrap().entry(name).or_default() += delta;
+        }
+        fn record_histogram(&self, name: &'static str, value: f64) {
+            let mut histograms = self.histograms.lock().unwrap();
+            histograms.entry(name).or_default().push(value);
+        }
+    }
+
+    let sink = Arc::new(InMemoryMetrics::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        metrics: sink.clone(),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, config, (), |_| ValueServer::default())
+            .await
+            .unwrap()
+    });
+
+    let stream_1 = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service_1 = start_client::<dyn ChildService, _>(stream_1).await;
+    let stream_2 = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service_2 = start_client::<dyn ChildService, _>(stream_2).await;
+    sink.wait_for_gauge(metrics::ACTIVE_CONNECTIONS, 2).await;
+
+    service_1.set_value(1).await.unwrap();
+    service_2.get_value().await.unwrap();
+    assert_eq!(2, sink.counter(metrics::CALLS_TOTAL));
+    let call_durations = sink.histograms.lock().unwrap()[metrics::CALL_DURATION_SECONDS].clone();
+    assert_eq!(2, call_durations.len());
+    assert!(sink.counter(metrics::RECEIVED_BYTES_TOTAL) > 0);
+    assert!(sink.counter(metrics::SENT_BYTES_TOTAL) > 0);
+
+    // Closing the initial service drops the client's end of the connection.
+    service_1.close().await.unwrap();
+    sink.wait_for_gauge(metrics::ACTIVE_CONNECTIONS, 1).await;
+    service_2.close().await.unwrap();
+    sink.wait_for_gauge(metrics::ACTIVE_CONNECTIONS, 0).await;
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn byte_counts_test() {
+    struct BlobServer;
+    #[service_server_impl]
+    impl BlobService for BlobServer {
+        async fn checksum(&mut self, data: &[u8]) -> RpcResult<i32> {
+            Ok(data.len() as i32)
+        }
+        async fn concat(&mut self, first: &[u8], second: &[u8]) -> RpcResult<Vec<u8>> {
+            Ok([first, second].concat())
+        }
+        async fn wrap(&mut self, data: &[u8], tag: i32) -> RpcResult<Blob> {
+            Ok(Blob {
+                data: data.to_vec(),
+                tag,
+            })
+        }
+    }
+
+    struct CountsRecorder(mpsc::UnboundedSender<(u64, u64)>);
+    impl ServerInterceptor for CountsRecorder {
+        fn on_connection_closed(&self, _: &ConnectionContext, byte_counts: &ByteCounts) {
+            let counts = (byte_counts.sent(), byte_counts.received());
+            self.0.unbounded_send(counts).unwrap();
+        }
+    }
+
+    let (sender, mut receiver) = mpsc::unbounded();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        interceptor: Some(Arc::new(CountsRecorder(sender))),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, config, (), |_| BlobServer)
+            .await
+            .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let (mut service, client_counts) =
+        start_client_with_byte_counts::<dyn BlobService, _>(stream, ClientConfig::default()).await;
+    assert_eq!((0, 0), (client_counts.sent(), client_counts.received()));
+
+    // The request holds the whole payload, but the response is just a number.
+    assert_eq!(1000, service.checksum(&[7; 1000]).await.unwrap());
+    assert!(client_counts.sent() >= 1000);
+    assert!(client_counts.received() > 0);
+    assert!(client_counts.received() < 100);
+
+    // The response holds both payloads.
+    let sent_before = client_counts.sent();
+    let received_before = client_counts.received();
+    service.concat(&[1; 300], &[2; 400]).await.unwrap();
+    assert!(client_counts.sent() - sent_before >= 700);
+    assert!(client_counts.received() - received_before >= 700);
+
+    service.close().await.unwrap();
+    let (server_sent, server_received) = timeout(Duration::from_secs(5), receiver.next())
+        .await
+        .unwrap()
+        .unwrap();
+    // Each side counts what the other side counts, in the other direction.
+    assert_eq!(client_counts.sent(), server_received);
+    assert_eq!(client_counts.received(), server_sent);
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn accept_filter_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let blocked_ip = IpAddr::from([127, 0, 0, 2]);
+    let config = ServerConfig {
+        accept_filter: Some(Arc::new(move |peer_addr: SocketAddr| async move {
+            peer_addr.ip() != blocked_ip
+        })),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, config, (), |_| ValueServer::default())
+            .await
+            .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn ChildService, _>(stream).await;
+    assert_eq!(5, service.set_value(5).await.unwrap());
+    service.close().await.unwrap();
+
+    // The server closes the connection without reading anything.
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::new(blocked_ip, 0)).unwrap();
+    let mut stream = socket.connect(addr).await.unwrap();
+    let mut remaining = Vec::new();
+    timeout(Duration::from_secs(2), stream.read_to_end(&mut remaining))
+        .await
+        .expect("Server didn't close the rejected connection.")
+        .unwrap();
+    assert!(remaining.is_empty());
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn authentication_test() {
+    #[derive(Default)]
+    struct ValueServer(i32);
+    #[service_server_impl]
+    impl ChildService for ValueServer {
+        async fn get_value(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn set_value(&mut self, new_value: i32) -> RpcResult<i32> {
+            self.0 = new_value;
+            Ok(new_value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        authenticator: Some(Arc::new(|credential: Vec<u8>| async move {
+            credential == b"good token"
+        })),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, config, (), |_| ValueServer::default())
+            .await
+            .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client_with_credential::<dyn ChildService, _>(
+        stream,
+        ClientConfig::default(),
+        b"good token".to_vec(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(5, service.set_value(5).await.unwrap());
+    service.close().await.unwrap();
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let result = start_client_with_credential::<dyn ChildService, _>(
+        stream,
+        ClientConfig::default(),
+        b"bad token".to_vec(),
+    )
+    .await;
+    assert!(matches!(result, Err(RustyRpcError::AuthenticationFailed)));
+
+    // A client without a credential can't call any methods.
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let config = ClientConfig {
+        auto_close_on_drop: true,
+        ..Default::default()
+    };
+    let mut service = start_client_with_config::<dyn ChildService, _>(stream, config).await;
+    assert!(matches!(
+        service.get_value().await,
+        Err(RustyRpcError::ServerError(_))
+    ));
+    // The server closed the connection.
+    assert!(service.get_value().await.is_err());
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn authorization_test() {
+    #[derive(Default)]
+    struct KeyValueServer(HashMap<i32, i32>);
+    #[service_server_impl]
+    impl KeyValueService for KeyValueServer {
+        async fn get(&mut self, key: i32) -> RpcResult<i32> {
+            Ok(*self.0.get(&key).unwrap_or(&0))
+        }
+        async fn set(&mut self, key: i32, value: i32) -> RpcResult<i32> {
+            self.0.insert(key, value);
+            Ok(value)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        authenticator: Some(Arc::new(|_| async { true })),
+        authorizer: Some(Arc::new(|call: &MethodCall<'_>| {
+            assert_eq!("KeyValueService", call.service_name);
+            let is_admin = call.context.credential.as_deref() == Some(b"admin".as_slice());
+            if call.method_name == Some("set") && !is_admin {
+                Err("Only admins can set values.".to_string())
+            } else {
+                Ok(())
+            }
+        })),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, config, (), |_| KeyValueServer::default())
+            .await
+            .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client_with_credential::<dyn KeyValueService, _>(
+        stream,
+        ClientConfig::default(),
+        b"guest".to_vec(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(0, service.get(1).await.unwrap());
+    assert!(matches!(
+        service.set(1, 10).await,
+        Err(RustyRpcError::ServerError(_))
+    ));
+    // The connection is still usable after a denied call.
+    assert_eq!(0, service.get(1).await.unwrap());
+    service.close().await.unwrap();
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client_with_credential::<dyn KeyValueService, _>(
+        stream,
+        ClientConfig::default(),
+        b"admin".to_vec(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(10, service.set(1, 10).await.unwrap());
+    assert_eq!(10, service.get(1).await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn service_id_reuse_test() {
+    #[derive(Default)]
+    struct CounterFactoryServer;
+    struct CounterServer(i32);
+    #[service_server_impl]
+    impl CounterFactoryService for CounterFactoryServer {
+        async fn get_counter(&mut self) -> RpcResult<ServiceRefMut<'static, dyn CounterService>> {
+            Ok(ServiceRefMut::new(CounterServer(0)))
+        }
+    }
+    #[service_server_impl]
+    impl CounterService for CounterServer {
+        async fn increment(&mut self) -> RpcResult<i32> {
+            self.0 += 1;
+            Ok(self.0)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async move {
+        start_server::<CounterFactoryServer>(listener)
+            .await
+            .unwrap()
+    });
+
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    // Avoid waiting for delayed ACKs, since there are many round trips.
+    stream.set_nodelay(true).unwrap();
+    // CounterFactoryService::get_counter has ID 0.
+    async fn get_counter(stream: &mut TcpStream) -> u64 {
+        let arguments = rmp_serde::to_vec(&()).unwrap();
+        send_raw_message(
+            stream,
+            ClientMessage::CallMethod(
+                ServiceId(0),
+                MethodId(0),
+                MethodArgs(arguments),
+                HashMap::new(),
+            ),
+        )
+        .await;
+        match receive_raw_message(stream).await {
+            ServerMessage::MethodReturned(ReturnValue::Service(ServiceId(service_id))) => {
+                service_id
+            }
+            other => panic!("Unexpected response: {:?}", other),
+        }
+    }
+
+    // Churn through many services, dropping some of them and keeping others
+    // alive, and check that a new service never gets the ID of a live one.
+    let mut live_service_ids = vec![0];
+    for i in 0..500 {
+        let service_id = get_counter(&mut stream).await;
+        assert!(!live_service_ids.contains(&service_id));
+        live_service_ids.push(service_id);
+        if i % 3 != 0 {
+            let dropped_service_id = live_service_ids.remove(1 + i % (live_service_ids.len() - 1));
+            send_raw_message(
+                &mut stream,
+                ClientMessage::DropService(ServiceId(dropped_service_id)),
+            )
+            .await;
+            assert!(matches!(
+                receive_raw_message(&mut stream).await,
+                ServerMessage::DropServiceDone
+            ));
+        }
+    }
+    // IDs are reused, so they stay small.
+    assert!(live_service_ids
+        .iter()
+        .all(|&id| id <= live_service_ids.len() as u64 * 2));
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn abrupt_disconnect_test() {
+    struct CounterFactoryServer(Arc<AtomicUsize>);
+    struct CounterServer(Arc<AtomicUsize>);
+    impl Drop for CounterFactoryServer {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    impl Drop for CounterServer {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    #[service_server_impl]
+    impl CounterFactoryService for CounterFactoryServer {
+        async fn get_counter(&mut self) -> RpcResult<ServiceRefMut<'static, dyn CounterService>> {
+            Ok(ServiceRefMut::new(CounterServer(self.0.clone())))
+        }
+    }
+    #[service_server_impl]
+    impl CounterService for CounterServer {
+        async fn increment(&mut self) -> RpcResult<i32> {
+            Ok(0)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let drop_count = Arc::new(AtomicUsize::new(0));
+    let server_drop_count = drop_count.clone();
+    let server_handle = tokio::spawn(async move {
+        start_server_with(listener, server_drop_count, |count: &Arc<AtomicUsize>| {
+            CounterFactoryServer(count.clone())
+        })
+        .await
+        .unwrap()
+    });
+
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    // CounterFactoryService::get_counter has ID 0.
+    let get_counter = || {
+        let arguments = rmp_serde::to_vec(&()).unwrap();
+        ClientMessage::CallMethod(
+            ServiceId(0),
+            MethodId(0),
+            MethodArgs(arguments),
+            HashMap::new(),
+        )
+    };
+    for _ in 0..3 {
+        send_raw_message(&mut stream, get_counter()).await;
+        assert!(matches!(
+            receive_raw_message(&mut stream).await,
+            ServerMessage::MethodReturned(ReturnValue::Service(_))
+        ));
+    }
+    // Hang up in the middle of a call, without closing any services.
+    send_raw_message(&mut stream, get_counter()).await;
+    drop(stream);
+
+    // The factory and all the counters are dropped, including the one that was
+    // created for the call that never got its response.
+    timeout(Duration::from_secs(5), async {
+        while drop_count.load(Ordering::SeqCst) < 5 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Services of the closed connection were not dropped.");
+    assert_eq!(5, drop_count.load(Ordering::SeqCst));
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+#[allow(clippy::diverging_sub_expression)]
+async fn nested_services_dropped_on_disconnect_test() {
+    struct NestedServer(i32, Arc<Mutex<Vec<i32>>>);
+    impl Drop for NestedServer {
+        fn drop(&mut self) {
+            self.1.lock().unwrap().push(self.0);
+        }
+    }
+    #[service_server_impl]
+    impl MyService for NestedServer {
+        async fn foo(&mut self) -> RpcResult<i32> {
+            Ok(self.0)
+        }
+        async fn bar(&mut self, _arg: i32) -> RpcResult<i32> {
+            unimplemented!()
+        }
+        async fn bar2(&mut self, _arg1: i32, _arg2: &Foo) -> RpcResult<Foo> {
+            unimplemented!()
+        }
+        async fn baz<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn MyService + 'a>> {
+            Ok(ServiceRefMut::new(NestedServer(self.0 + 1, self.1.clone())))
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let drop_order = Arc::new(Mutex::new(Vec::new()));
+    let server_drop_order = drop_order.clone();
+    let server_handle = tokio::spawn(async move {
+        start_server_with(listener, server_drop_order, |log: &Arc<Mutex<_>>| {
+            NestedServer(0, log.clone())
+        })
+        .await
+        .unwrap()
+    });
+
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    // Each service borrows from the previous one. MyService::baz has ID 2.
+    let mut service_id = 0;
+    for _ in 0..4 {
+        let arguments = rmp_serde::to_vec(&()).unwrap();
+        send_raw_message(
+            &mut stream,
+            ClientMessage::CallMethod(
+                ServiceId(service_id),
+                MethodId(2),
+                MethodArgs(arguments),
+                HashMap::new(),
+            ),
+        )
+        .await;
+        service_id = match receive_raw_message(&mut stream).await {
+            ServerMessage::MethodReturned(ReturnValue::Service(ServiceId(service_id))) => {
+                service_id
+            }
+            other => panic!("Unexpected response: {:?}", other),
+        };
+    }
+    // Disconnect without closing any of them.
+    drop(stream);
+
+    timeout(Duration::from_secs(5), async {
+        while drop_order.lock().unwrap().len() < 5 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Services of the closed connection were not dropped.");
+    // Children are dropped before the services they borrow from.
+    assert_eq!(vec![4, 3, 2, 1, 0], *drop_order.lock().unwrap());
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn explicit_method_id_test() {
+    #[derive(Default)]
+    struct VersionedServer;
+    #[service_server_impl]
+    impl VersionedService for VersionedServer {
+        async fn get_version(&mut self) -> RpcResult<i32> {
+            Ok(2)
+        }
+        async fn add(&mut self, a: i32, b: i32) -> RpcResult<i32> {
+            Ok(a + b)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<VersionedServer>(listener).await.unwrap() });
+
+    // An old client that only knows get_version still calls it by its ID.
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let arguments = rmp_serde::to_vec(&()).unwrap();
+    send_raw_message(
+        &mut stream,
+        ClientMessage::CallMethod(
+            ServiceId(0),
+            MethodId(10),
+            MethodArgs(arguments),
+            HashMap::new(),
+        ),
+    )
+    .await;
+    match receive_raw_message(&mut stream).await {
+        ServerMessage::MethodReturned(ReturnValue::Data(bytes)) => {
+            assert_eq!(2, rmp_serde::from_slice::<i32>(&bytes).unwrap())
+        }
+        other => panic!("Unexpected response: {:?}", other),
+    }
+    // The method without an explicit ID gets the first free one.
+    let arguments = rmp_serde::to_vec(&(1, 2)).unwrap();
+    send_raw_message(
+        &mut stream,
+        ClientMessage::CallMethod(
+            ServiceId(0),
+            MethodId(0),
+            MethodArgs(arguments),
+            HashMap::new(),
+        ),
+    )
+    .await;
+    match receive_raw_message(&mut stream).await {
+        ServerMessage::MethodReturned(ReturnValue::Data(bytes)) => {
+            assert_eq!(3, rmp_serde::from_slice::<i32>(&bytes).unwrap())
+        }
+        other => panic!("Unexpected response: {:?}", other),
+    }
+
+    let mut service = VersionedServiceClient::connect(addr).await.unwrap();
+    assert_eq!(2, service.get_version().await.unwrap());
+    assert_eq!(7, service.add(3, 4).await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn inherited_methods_test() {
+    #[derive(Default)]
+    struct ResettableCounterServer(i32);
+    #[service_server_impl]
+    impl ResettableCounterService for ResettableCounterServer {
+        async fn increment(&mut self) -> RpcResult<i32> {
+            self.0 += 1;
+            Ok(self.0)
+        }
+        async fn add(&mut self, amount: i32) -> RpcResult<i32> {
+            self.0 += amount;
+            Ok(self.0)
+        }
+        async fn reset(&mut self) -> RpcResult<i32> {
+            self.0 = 0;
+            Ok(self.0)
+        }
+    }
+
+    // The inherited method keeps its ID from the base service, even though
+    // `add` comes before it by name.
+    assert_eq!(
+        vec![
+            ("add", MethodId(1)),
+            ("increment", MethodId(0)),
+            ("reset", MethodId(2)),
+        ],
+        <dyn ResettableCounterService>::METHOD_IDS.to_vec()
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async {
+        start_server::<ResettableCounterServer>(listener)
+            .await
+            .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn ResettableCounterService, _>(stream).await;
+    assert_eq!(1, service.increment().await.unwrap());
+    assert_eq!(6, service.add(5).await.unwrap());
+    assert_eq!(0, service.reset().await.unwrap());
+    assert_eq!(1, service.increment().await.unwrap());
+    service.close().await.unwrap();
+
+    // A client of the base service can use the derived service.
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn CounterService, _>(stream).await;
+    assert_eq!(1, service.increment().await.unwrap());
+    assert_eq!(2, service.increment().await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn rust_name_test() {
+    #[derive(Default)]
+    struct SensorServer(i32);
+    // The type already has a method named `get`, so the service method is
+    // named `fetch` in Rust, to keep `server.get()` unambiguous.
+    impl SensorServer {
+        fn get(&self) -> i32 {
+            self.0
+        }
+    }
+    #[service_server_impl]
+    impl SensorService for SensorServer {
+        async fn fetch(&mut self) -> RpcResult<Measurement> {
+            Ok(Measurement { celsius: self.0 })
+        }
+        async fn reset_sensor(&mut self, reading: &Measurement) -> RpcResult<i32> {
+            self.0 = reading.celsius;
+            Ok(self.0)
+        }
+    }
+
+    // The wire still uses the names from the interface file.
+    assert_eq!(
+        vec![("get", MethodId(0)), ("reset", MethodId(5))],
+        <dyn SensorService>::METHOD_IDS.to_vec()
+    );
+    assert_eq!(0, SensorServer::default().get());
+    let encoded = WireFormat::Cbor.encode(&Measurement { celsius: 21 });
+    assert_eq!(
+        HashMap::from([("value".to_string(), 21)]),
+        WireFormat::Cbor
+            .decode::<HashMap<String, i32>>(&encoded)
+            .unwrap()
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<SensorServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn SensorService, _>(stream).await;
+    assert_eq!(Measurement { celsius: 0 }, service.fetch().await.unwrap());
+    let reading = Measurement::builder().with_celsius(-4).build().unwrap();
+    assert_eq!(-4, service.reset_sensor(&reading).await.unwrap());
+    assert_eq!(reading, service.fetch().await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn result_return_type_test() {
+    #[derive(Default)]
+    struct DirectoryServer;
+    #[service_server_impl]
+    impl DirectoryService for DirectoryServer {
+        async fn lookup(&mut self, key: i32) -> RpcResult<Result<String, NotFound>> {
+            Ok(match key {
+                1 => Ok("one".to_string()),
+                _ => Err(NotFound { key }),
+            })
+        }
+        async fn read_file(&mut self, key: i32) -> RpcResult<Result<Vec<u8>, i32>> {
+            Ok(match key {
+                1 => Ok(vec![1, 2, 3]),
+                _ => Err(-1),
+            })
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<DirectoryServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn DirectoryService, _>(stream).await;
+    assert_eq!(Ok("one".to_string()), service.lookup(1).await.unwrap());
+    assert_eq!(Err(NotFound { key: 2 }), service.lookup(2).await.unwrap());
+    assert_eq!(Ok(vec![1, 2, 3]), service.read_file(1).await.unwrap());
+    assert_eq!(Err(-1), service.read_file(2).await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[test]
+fn skip_if_default_test() {
+    let profile = Profile {
+        name: "a".to_string(),
+        ..Default::default()
+    };
+    for wire_format in [WireFormat::MessagePack, WireFormat::Cbor] {
+        // The same fields, as they would be encoded without @skip_if_default.
+        let all_fields = wire_format.encode(&("a", "", 1, BytesRef(&[])));
+        let encoded = wire_format.encode(&profile);
+        assert!(encoded.len() < all_fields.len());
+        assert_eq!(profile, wire_format.decode::<Profile>(&encoded).unwrap());
+
+        let full_profile = Profile {
+            name: "b".to_string(),
+            nickname: "c".to_string(),
+            level: 0,
+            avatar: vec![1, 2],
+        };
+        let encoded = wire_format.encode(&full_profile);
+        assert_eq!(
+            full_profile,
+            wire_format.decode::<Profile>(&encoded).unwrap()
+        );
+    }
+}
+
+#[test]
+fn mirror_test() {
+    let route = domain::Route {
+        name: "home".to_string(),
+        start: domain::GeoPoint { lat: 1, lon: 2 },
+        end: domain::GeoPoint { lat: 3, lon: 4 },
+    };
+    // The nested structs are converted too.
+    let wire_route = Route::from(route);
+    assert_eq!(
+        Route {
+            name: "home".to_string(),
+            start: GeoPoint { lat: 1, lon: 2 },
+            end: GeoPoint { lat: 3, lon: 4 },
+        },
+        wire_route
+    );
+    let route: domain::Route = wire_route.into();
+    assert_eq!(domain::GeoPoint { lat: 3, lon: 4 }, route.end);
+    assert_eq!("home", route.name);
+}
+
+#[tokio::test]
+async fn copy_struct_test() {
+    struct MapServer;
+    #[service_server_impl]
+    impl MapService for MapServer {
+        async fn distance(&mut self, from: &Coordinate, to: &Coordinate) -> RpcResult<i32> {
+            // Copied out of the references.
+            let (from, to) = (*from, *to);
+            Ok((from.x - to.x).abs() + (from.y - to.y).abs())
+        }
+    }
+
+    fn shifted(mut coordinate: Coordinate, by: i32) -> Coordinate {
+        coordinate.x += by;
+        coordinate
+    }
+
+    let origin = Coordinate { x: 0, y: 0 };
+    // Passed by value twice, without cloning.
+    let right = shifted(origin, 3);
+    let left = shifted(origin, -4);
+    assert_eq!(Coordinate { x: 0, y: 0 }, origin);
+    let box3 = Box3 {
+        corner: right,
+        depth: 5,
+    };
+    let copied = box3;
+    assert_eq!(box3, copied);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async move {
+        start_server_with(listener, (), |_| MapServer)
+            .await
+            .unwrap()
+    });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn MapService, _>(stream).await;
+    assert_eq!(7, service.distance(&left, &right).await.unwrap());
+    assert_eq!(3, service.distance(&origin, &box3.corner).await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn int128_test() {
+    #[derive(Default)]
+    struct LedgerServer(Balance);
+    #[service_server_impl]
+    impl LedgerService for LedgerServer {
+        async fn deposit(&mut self, amount: u128) -> RpcResult<u128> {
+            self.0.amount = amount;
+            Ok(self.0.amount)
+        }
+        async fn adjust(&mut self, change: i128) -> RpcResult<Result<Balance, i128>> {
+            if change == i128::MIN {
+                return Ok(Err(change));
+            }
+            self.0.change = change;
+            Ok(Ok(self.0.clone()))
+        }
+        async fn balance(&mut self) -> RpcResult<Balance> {
+            Ok(self.0.clone())
+        }
+    }
+
+    assert_eq!(-1, Balance::default().change);
+    // MessagePack has no 128-bit integers, so they are sent as bytes.
+    let encoded = WireFormat::MessagePack.encode(&Balance {
+        amount: u128::MAX,
+        change: 0,
+    });
+    assert_eq!(
+        (vec![0xff; 16], vec![0; 16]),
+        rmp_serde::from_slice::<(ByteBuf, ByteBuf)>(&encoded)
+            .map(|(x, y)| (x.0, y.0))
+            .unwrap()
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<LedgerServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn LedgerService, _>(stream).await;
+    assert_eq!(u128::MAX, service.deposit(u128::MAX).await.unwrap());
+    assert_eq!(
+        Ok(Balance {
+            amount: u128::MAX,
+            change: i128::MAX,
+        }),
+        service.adjust(i128::MAX).await.unwrap()
+    );
+    assert_eq!(Err(i128::MIN), service.adjust(i128::MIN).await.unwrap());
+    assert_eq!(
+        Balance {
+            amount: u128::MAX,
+            change: i128::MAX,
+        },
+        service.balance().await.unwrap()
+    );
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn deprecated_method_test() {
+    #[derive(Default)]
+    struct LegacyServer;
+    // Implementing deprecated methods doesn't warn.
+    #[service_server_impl]
+    impl LegacyService for LegacyServer {
+        async fn old_value(&mut self) -> RpcResult<i32> {
+            Ok(1)
+        }
+        async fn old_name(&mut self) -> RpcResult<i32> {
+            Ok(2)
+        }
+        async fn new_value(&mut self) -> RpcResult<i32> {
+            Ok(3)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<LegacyServer>(listener).await.unwrap() });
+
+    let mut service = LegacyServiceClient::connect(addr).await.unwrap();
+    assert_eq!(3, service.new_value().await.unwrap());
+    // Deprecated methods warn when used, but still work.
+    #[allow(deprecated)]
+    {
+        assert_eq!(1, service.old_value().await.unwrap());
+        assert_eq!(2, service.old_name().await.unwrap());
+    }
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn keyword_identifiers_test() {
+    #[derive(Default)]
+    struct MatchServer;
+    #[service_server_impl]
+    impl MatchService for MatchServer {
+        async fn r#match(&mut self, token: &Token) -> RpcResult<Token> {
+            Ok(Token {
+                r#type: token.r#type + 1,
+            })
+        }
+    }
+
+    // Field names are serialized without the `r#` prefix.
+    let token = Token::builder().with_type(5).build().unwrap();
+    assert_eq!(json!({ "type": 5 }), serde_json::to_value(&token).unwrap());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<MatchServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn MatchService, _>(stream).await;
+    assert_eq!(6, service.r#match(&token).await.unwrap().r#type);
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn empty_struct_and_service_test() {
+    #[derive(Default)]
+    struct EmptyFactoryServer;
+    struct EmptyServer;
+    #[service_server_impl]
+    impl EmptyFactoryService for EmptyFactoryServer {
+        async fn get_empty<'a>(
+            &'a mut self,
+            _empty: &Empty,
+        ) -> RpcResult<ServiceRefMut<'a, dyn EmptyService + 'a>> {
+            Ok(ServiceRefMut::new(EmptyServer))
+        }
+    }
+    #[service_server_impl]
+    impl EmptyService for EmptyServer {}
+
+    let value = WithEmpty {
+        empty: Empty {},
+        x: 3,
+    };
+    let bytes = rmp_serde::to_vec(&value).unwrap();
+    assert_eq!(value, rmp_serde::from_slice::<WithEmpty>(&bytes).unwrap());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<EmptyFactoryServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn EmptyFactoryService, _>(stream).await;
+    let empty = service.get_empty(&Empty {}).await.unwrap();
+    empty.close().await.unwrap();
+    service.close().await.unwrap();
+
+    // Calling a method on an empty service is an error, not a crash.
+    // EmptyFactoryService::get_empty has ID 0.
+    let mut stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let arguments = rmp_serde::to_vec(&Empty {}).unwrap();
+    send_raw_message(
+        &mut stream,
+        ClientMessage::CallMethod(
+            ServiceId(0),
+            MethodId(0),
+            MethodArgs(arguments),
+            HashMap::new(),
+        ),
+    )
+    .await;
+    let empty_service_id = match receive_raw_message(&mut stream).await {
+        ServerMessage::MethodReturned(ReturnValue::Service(service_id)) => service_id,
+        other => panic!("Unexpected response: {:?}", other),
+    };
+    let arguments = rmp_serde::to_vec(&()).unwrap();
+    send_raw_message(
+        &mut stream,
+        ClientMessage::CallMethod(
+            empty_service_id,
+            MethodId(0),
+            MethodArgs(arguments),
+            HashMap::new(),
+        ),
+    )
+    .await;
+    assert!(matches!(
+        receive_raw_message(&mut stream).await,
+        ServerMessage::Error(_)
+    ));
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn bytes_test() {
+    #[derive(Default)]
+    struct BlobServer;
+    #[service_server_impl]
+    impl BlobService for BlobServer {
+        // `data` borrows from the received message, so it isn't copied.
+        async fn checksum(&mut self, data: &[u8]) -> RpcResult<i32> {
+            Ok(data
+                .iter()
+                .fold(0i32, |acc, &x| acc.wrapping_mul(31) ^ x as i32))
+        }
+        async fn concat(&mut self, first: &[u8], second: &[u8]) -> RpcResult<Vec<u8>> {
+            Ok([first, second].concat())
+        }
+        async fn wrap(&mut self, data: &[u8], tag: i32) -> RpcResult<Blob> {
+            Ok(Blob {
+                data: data.to_vec(),
+                tag,
+            })
+        }
+    }
+
+    // Bytes are written as a binary, not as an array of integers, which would
+    // take two bytes for each of these.
+    let blob = Blob {
+        data: vec![255; 1000],
+        tag: 1,
+    };
+    let serialized_blob = rmp_serde::to_vec(&blob).unwrap();
+    assert!(serialized_blob.len() < 1010);
+    assert_eq!(
+        blob,
+        rmp_serde::from_slice::<Blob>(&serialized_blob).unwrap()
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async { start_server::<BlobServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn BlobService, _>(stream).await;
+    let large_payload: Vec<u8> = (0..1_000_000).map(|x| x as u8).collect();
+    let expected_checksum = large_payload
+        .iter()
+        .fold(0i32, |acc, &x| acc.wrapping_mul(31) ^ x as i32);
+    assert_eq!(
+        expected_checksum,
+        service.checksum(&large_payload).await.unwrap()
+    );
+    assert_eq!(
+        b"hello world".to_vec(),
+        service.concat(b"hello ", b"world").await.unwrap()
+    );
+    assert_eq!(
+        Blob {
+            data: b"abc".to_vec(),
+            tag: 3
+        },
+        service.wrap(b"abc", 3).await.unwrap()
+    );
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn struct_param_by_reference_test() {
+    #[derive(Default)]
+    struct BlobStoreServer(Vec<Blob>);
+    #[service_server_impl]
+    impl BlobStoreService for BlobStoreServer {
+        async fn store(&mut self, blob: &Blob) -> RpcResult<i32> {
+            self.0.push(blob.clone());
+            Ok(self.0.len() as i32)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<BlobStoreServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn BlobStoreService, _>(stream).await;
+    let mut blob = Blob {
+        data: vec![42; 1 << 20],
+        tag: 1,
+    };
+    assert_eq!(1, service.store(&blob).await.unwrap());
+    // The client still owns the struct, so it can send it again without
+    // cloning it.
+    blob.tag = 2;
+    assert_eq!(2, service.store(&blob).await.unwrap());
+    assert_eq!(1 << 20, blob.data.len());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn string_test() {
+    struct NameServer(String);
+    impl Default for NameServer {
+        fn default() -> Self {
+            NameServer("world".to_string())
+        }
+    }
+    #[service_server_impl]
+    impl NameService for NameServer {
+        async fn get_name(&mut self) -> RpcResult<String> {
+            self.get_name_borrowed().await.map(Cow::into_owned)
+        }
+        async fn set_name(&mut self, name: &str) -> RpcResult<i32> {
+            self.0 = name.to_string();
+            Ok(name.len() as i32)
+        }
+        async fn greet(&mut self, greeting: &str) -> RpcResult<String> {
+            self.greet_borrowed(greeting).await.map(Cow::into_owned)
+        }
+        // The server calls these instead, so the name is serialized straight
+        // from the service, without a clone.
+        async fn get_name_borrowed<'a>(&'a mut self) -> RpcResult<Cow<'a, str>> {
+            Ok(Cow::Borrowed(&self.0))
+        }
+        async fn greet_borrowed<'a>(&'a mut self, greeting: &str) -> RpcResult<Cow<'a, str>> {
+            if greeting.is_empty() {
+                Ok(Cow::Borrowed(&self.0))
+            } else {
+                Ok(Cow::Owned(format!("{}, {}!", greeting, self.0)))
+            }
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async { start_server::<NameServer>(listener).await.unwrap() });
+
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client::<dyn NameService, _>(stream).await;
+    assert_eq!("world", service.get_name().await.unwrap());
+    assert_eq!(6, service.set_name("Ferris").await.unwrap());
+    assert_eq!("Hello, Ferris!", service.greet("Hello").await.unwrap());
+    assert_eq!("Ferris", service.greet("").await.unwrap());
+    // Non-ASCII strings survive the round trip.
+    service.set_name("フェリス").await.unwrap();
+    assert_eq!("フェリス", service.get_name().await.unwrap());
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn cbor_test() {
+    #[derive(Default)]
+    struct DummyService;
+    #[service_server_impl]
+    impl MyService for DummyService {
+        async fn foo(&mut self) -> RpcResult<i32> {
+            Ok(123)
+        }
+        async fn bar(&mut self, arg: i32) -> RpcResult<i32> {
+            Ok(arg)
+        }
+        async fn bar2(&mut self, arg1: i32, arg2: &Foo) -> RpcResult<Foo> {
+            let val = arg1 + arg2.x + arg2.y.z;
+            Ok(Foo {
+                x: val,
+                y: Bar { z: val },
+            })
+        }
+        async fn baz<'a>(&'a mut self) -> RpcResult<ServiceRefMut<'a, dyn MyService + 'a>> {
+            Ok(ServiceRefMut::new(DummyService))
+        }
+    }
+
+    #[derive(Default)]
+    struct NameServer(String);
+    #[service_server_impl]
+    impl NameService for NameServer {
+        async fn get_name(&mut self) -> RpcResult<String> {
+            Ok(self.0.clone())
+        }
+        async fn set_name(&mut self, name: &str) -> RpcResult<i32> {
+            self.0 = name.to_string();
+            Ok(name.len() as i32)
+        }
+        async fn greet(&mut self, greeting: &str) -> RpcResult<String> {
+            Ok(format!("{}, {}!", greeting, self.0))
+        }
+    }
+
+    let server_config = ServerConfig {
+        wire_format: WireFormat::Cbor,
+        ..Default::default()
+    };
+    let client_config = ClientConfig {
+        wire_format: WireFormat::Cbor,
+        ..Default::default()
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let first_server_config = server_config.clone();
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, first_server_config, (), |_| DummyService)
+            .await
+            .unwrap()
+    });
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service =
+        start_client_with_config::<dyn MyService, _>(stream, client_config.clone()).await;
+    assert_eq!(123, service.foo().await.unwrap());
+    assert_eq!(-5, service.bar(-5).await.unwrap());
+    let foo = Foo {
+        x: 1,
+        y: Bar { z: 2 },
+    };
+    assert_eq!(
+        Foo {
+            x: 6,
+            y: Bar { z: 6 }
+        },
+        service.bar2(3, &foo).await.unwrap()
+    );
+    let mut child = service.baz().await.unwrap();
+    assert_eq!(7, child.bar(7).await.unwrap());
+    child.close().await.unwrap();
+    service.close().await.unwrap();
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async move {
+        start_server_with_config(listener, server_config, (), |_| NameServer::default())
+            .await
+            .unwrap()
+    });
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client_with_config::<dyn NameService, _>(stream, client_config).await;
+    assert_eq!(6, service.set_name("Ferris").await.unwrap());
+    assert_eq!("Ferris", service.get_name().await.unwrap());
+    assert_eq!("Hello, Ferris!", service.greet("Hello").await.unwrap());
+    service.close().await.unwrap();
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+#[tokio::test]
+async fn batch_test() {
+    #[derive(Default)]
+    struct KeyValueServer(HashMap<i32, i32>);
+    #[service_server_impl]
+    impl KeyValueService for KeyValueServer {
+        async fn get(&mut self, key: i32) -> RpcResult<i32> {
+            self.0
+                .get(&key)
+                .copied()
+                .ok_or_else(|| RustyRpcError::ServerError(format!("No value for key {}", key)))
+        }
+        async fn set(&mut self, key: i32, value: i32) -> RpcResult<i32> {
+            self.0.insert(key, value);
+            Ok(value)
+        }
+    }
+
+    struct MessageCountingInterceptor(AtomicUsize);
+    #[async_trait::async_trait]
+    impl ClientInterceptor for MessageCountingInterceptor {
+        async fn intercept(
+            &self,
+            msg: ClientMessage,
+            next: &mut Next<'_>,
+        ) -> RpcResult<ServerMessage> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            next.run(msg).await
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle =
+        tokio::spawn(async { start_server::<KeyValueServer>(listener).await.unwrap() });
+
+    let interceptor = Arc::new(MessageCountingInterceptor(AtomicUsize::new(0)));
+    let config = ClientConfig {
+        interceptors: vec![interceptor.clone()],
+        ..Default::default()
+    };
+    let stream = TcpSocket::new_v4().unwrap().connect(addr).await.unwrap();
+    let mut service = start_client_with_config::<dyn KeyValueService, _>(stream, config).await;
+    for key in 1..=3 {
+        service.set(key, key * 10).await.unwrap();
+    }
+    assert_eq!(3, interceptor.0.load(Ordering::SeqCst));
+
+    let (mut a, mut b, mut c) = (service.clone(), service.clone(), service.clone());
+    let (x, y, z) = batch(&service, async {
+        tokio::join!(a.get(1), b.get(2), c.get(3))
+    })
+    .await;
+    assert_eq!((10, 20, 30), (x.unwrap(), y.unwrap(), z.unwrap()));
+    assert_eq!(4, interceptor.0.load(Ordering::SeqCst));
+
+    // A failing call doesn't affect the other calls in its batch.
+    let (x, y, z) = batch(&service, async {
+        tokio::join!(a.get(1), b.get(4), c.set(4, 40))
+    })
+    .await;
+    assert_eq!(10, x.unwrap());
+    match y {
+        Err(RustyRpcError::ServerError(msg)) => assert!(msg.contains("No value for key 4")),
+        _ => panic!("Expected a server error."),
+    }
+    assert_eq!(40, z.unwrap());
+    assert_eq!(5, interceptor.0.load(Ordering::SeqCst));
+    // The calls are handled in order, so the get above happened before the set.
+    assert_eq!(40, service.get(4).await.unwrap());
+
+    for proxy in [a, b, c, service] {
+        proxy.close().await.unwrap();
+    }
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}
+
+/// Receives messages from one channel, and sends them to another.
+struct ChannelStreamSink(
+    mpsc::UnboundedReceiver<ServerMessage>,
+    mpsc::UnboundedSender<ClientMessage>,
+);
+impl Stream for ChannelStreamSink {
+    type Item = RpcResult<ServerMessage>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_next_unpin(cx).map(|x| x.map(Ok))
+    }
+}
+impl Sink<ClientMessage> for ChannelStreamSink {
+    type Error = RustyRpcError;
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<RpcResult<()>> {
+        self.1
+            .poll_ready_unpin(cx)
+            .map_err(|_| RustyRpcError::ConnectionClosed)
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: ClientMessage) -> RpcResult<()> {
+        self.1
+            .start_send_unpin(item)
+            .map_err(|_| RustyRpcError::ConnectionClosed)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<RpcResult<()>> {
+        self.1
+            .poll_flush_unpin(cx)
+            .map_err(|_| RustyRpcError::ConnectionClosed)
+    }
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<RpcResult<()>> {
+        self.1
+            .poll_close_unpin(cx)
+            .map_err(|_| RustyRpcError::ConnectionClosed)
+    }
+}
+
+#[tokio::test]
+async fn in_memory_stream_sink_test() {
+    let (client_sender, mut server_receiver) = mpsc::unbounded::<ClientMessage>();
+    let (server_sender, client_receiver) = mpsc::unbounded::<ServerMessage>();
+    let stream_sink = ChannelStreamSink(client_receiver, client_sender);
+
+    // Plays the server's role, without any networking.
+    let server_handle = tokio::spawn(async move {
+        let mut calls = 0;
+        while let Some(msg) = server_receiver.next().await {
+            let response = match msg {
+                // ChildService::get_value has ID 0.
+                ClientMessage::CallMethod(ServiceId(0), MethodId(0), _, _) => {
+                    calls += 1;
+                    let bytes = rmp_serde::to_vec(&(calls * 10)).unwrap();
+                    ServerMessage::MethodReturned(ReturnValue::Data(bytes))
+                }
+                ClientMessage::DropService(ServiceId(0)) => ServerMessage::DropServiceDone,
+                _ => ServerMessage::Error("Unexpected message".to_string()),
+            };
+            server_sender.unbounded_send(response).unwrap();
+        }
+        calls
+    });
+
+    let mut service =
+        start_client_with_stream_sink::<dyn ChildService, _>(stream_sink, ClientConfig::default());
+    assert_eq!(10, service.get_value().await.unwrap());
+    assert_eq!(20, service.get_value().await.unwrap());
+    service.close().await.unwrap();
+
+    // Dropping the last proxy closes the connection, which ends the server.
+    assert_eq!(2, server_handle.await.unwrap());
+}
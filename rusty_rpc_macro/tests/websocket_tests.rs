@@ -0,0 +1,61 @@
+use rusty_rpc_lib::{connect_websocket_client, start_websocket_server, ClientConfig, RpcResult};
+use rusty_rpc_macro::{interface_file, service_server_impl};
+use tokio::net::TcpListener;
+
+interface_file!("../examples/src/hello_world/hello_world.protocol");
+
+#[derive(Default)]
+struct MyServiceServer;
+#[service_server_impl]
+impl MyService for MyServiceServer {
+    async fn foo(&mut self) -> RpcResult<i32> {
+        Ok(123)
+    }
+    async fn bar(&mut self, arg: i32) -> RpcResult<i32> {
+        Ok(arg)
+    }
+    async fn baz(&mut self, arg1: i32, arg2: &Foo) -> RpcResult<Foo> {
+        let val = arg1 + arg2.x + arg2.y.z;
+        Ok(Foo {
+            x: val,
+            y: Bar { z: val },
+        })
+    }
+}
+
+#[tokio::test]
+async fn hello_world_over_websocket_test() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = tokio::spawn(async {
+        start_websocket_server::<MyServiceServer>(listener)
+            .await
+            .unwrap()
+    });
+
+    let url = format!("ws://{}", addr);
+    let mut service = connect_websocket_client::<dyn MyService>(&url, ClientConfig::default())
+        .await
+        .unwrap();
+    assert_eq!(123, service.foo().await.unwrap());
+    assert_eq!(2, service.bar(2).await.unwrap());
+    let baz_output = service
+        .baz(
+            900,
+            &Foo {
+                x: 80,
+                y: Bar { z: 7 },
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(987, baz_output.x);
+    assert_eq!(987, baz_output.y.z);
+    service.close().await.unwrap();
+
+    server_handle.abort();
+    let server_error = server_handle
+        .await
+        .expect_err("Server somehow terminated on its own without crashing.");
+    assert!(server_error.is_cancelled(), "Server crashed.");
+}